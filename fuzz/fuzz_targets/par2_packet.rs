@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nzb_streamer::archive::{self, packet::parse_packet};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((_, length)) = parse_packet(data) {
+        assert!(length <= data.len());
+    }
+
+    let _ = archive::parse_buffer(data);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nzb_streamer::archive::rar::analyse_rar_buffer;
+
+fuzz_target!(|data: &[u8]| {
+    // offsets are used to slice the segment, so they must stay in bounds
+    if let Ok((offset, _)) = analyse_rar_buffer(data) {
+        assert!(offset as usize <= data.len());
+    }
+});
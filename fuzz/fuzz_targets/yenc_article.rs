@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nzb_streamer::nntp::yenc::{extract_filename, extract_yenc_data};
+
+fuzz_target!(|data: &[u8]| {
+    let body = extract_yenc_data(data);
+    let _ = yenc::decode_buffer(&body);
+
+    if let Ok(subject) = std::str::from_utf8(data) {
+        let _ = extract_filename(subject);
+    }
+});
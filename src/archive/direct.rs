@@ -0,0 +1,15 @@
+use crate::{archive::error::ArchiveError, scheduler::adaptive::FirstSegment};
+
+/// Bare video posts and byte-split files (`.001`, `.002`, ...) carry stream
+/// data from their very first byte, so all we need is each volume's length,
+/// which the yEnc header gives us up front.
+///
+/// # Returns
+/// - `(0, file_size)` for the volume the segment belongs to
+pub fn analyse_direct(segment: &FirstSegment) -> Result<(u64, u64), ArchiveError> {
+    let length = segment
+        .file_size
+        .ok_or_else(|| ArchiveError::UnknownFileSize(segment.nzb.subject.clone()))?;
+
+    Ok((0, length))
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,6 +19,39 @@ pub enum ArchiveError {
     #[error("RAR signature not found in buffer")]
     MalformedRar,
 
+    #[error("RAR header of type {header_type:#04x} declares invalid size {header_size}")]
+    InvalidRarHeader { header_type: u8, header_size: u16 },
+
+    #[error("Archive is encrypted and the NZB does not provide a password")]
+    Encrypted,
+
+    #[error("Archive is encrypted with the NZB password, encrypted archives can't be streamed")]
+    EncryptedWithPassword,
+
+    #[error("Malformed 7-Zip archive: {0}")]
+    MalformedSevenZip(&'static str),
+
+    #[error("Malformed ZIP archive: {0}")]
+    MalformedZip(&'static str),
+
+    #[error("'{0}' is compressed, only store-mode archives can be streamed")]
+    CompressedEntry(String),
+
+    #[error("Error decompressing archive header: {0}")]
+    Decompress(String),
+
+    #[error("File '{0}' is not a supported archive volume or video")]
+    UnsupportedVolume(PathBuf),
+
+    #[error("{0} archives are not supported yet")]
+    Unsupported(&'static str),
+
+    #[error("Could not determine the size of '{0}' from its yEnc header")]
+    UnknownFileSize(String),
+
     #[error("Could not create download task for subject {0}")]
     FilenameNotFound(String),
+
+    #[error("{size} bytes at offset {offset} run past the end of the archive")]
+    RangeOutOfBounds { offset: u64, size: u64 },
 }
@@ -0,0 +1,225 @@
+use std::path::Path;
+
+use crate::archive::{error::ArchiveError, rar::RarExt};
+
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mkv", "mp4", "m4v", "avi", "ts", "m2ts", "webm", "mov", "wmv",
+];
+
+/// What a posted file is, judging by its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Par2,
+    Rar,
+    /// `.7z` or `.7z.001` volumes
+    SevenZip,
+    /// `.zip` with optional `.z01`, `.z02`, ... volumes before it
+    Zip,
+    /// Plain byte-split files (`.001`, `.002`, ...)
+    Split,
+    /// Video posted without an archive
+    Video,
+}
+
+impl ContentKind {
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let filename = filename.to_ascii_lowercase();
+        let (stem, ext) = filename.rsplit_once('.')?;
+
+        match ext {
+            "par2" => Some(ContentKind::Par2),
+            "rar" => Some(ContentKind::Rar),
+            "7z" => Some(ContentKind::SevenZip),
+            "zip" => Some(ContentKind::Zip),
+            _ if is_numbered(ext) && stem.ends_with(".7z") => Some(ContentKind::SevenZip),
+            _ if is_numbered(ext) => Some(ContentKind::Split),
+            _ if ext.strip_prefix('r').is_some_and(is_numbered) => Some(ContentKind::Rar),
+            _ if ext.strip_prefix('z').is_some_and(is_numbered) => Some(ContentKind::Zip),
+            _ if VIDEO_EXTENSIONS.contains(&ext) => Some(ContentKind::Video),
+            _ => None,
+        }
+    }
+
+    pub fn is_video(filename: &str) -> bool {
+        Self::from_filename(filename) == Some(ContentKind::Video)
+    }
+}
+
+/// Position of a volume within its set, used to order download tasks.
+///
+/// - `.rar` is 0, `.r00` is 1, `.r01` is 2, ...
+/// - `.part01.rar` is 1, `.part02.rar` is 2, ...
+/// - `.001` (and `.7z.001`) is 1, `.002` is 2, ...
+/// - `.z01` is 1, `.z02` is 2, ... and `.zip` comes last, as split ZIPs
+///   write the central directory into the `.zip`
+/// - anything else is a single volume, so 0
+pub fn volume_index(path: &Path) -> u32 {
+    let filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    if let Some(part) = filename
+        .strip_suffix(".rar")
+        .and_then(|stem| stem.rsplit_once(".part"))
+        .and_then(|(_, part)| part.parse().ok())
+    {
+        return part;
+    }
+
+    if filename.ends_with(".zip") {
+        return u32::MAX;
+    }
+
+    if let Some(part) = filename
+        .rsplit_once(".z")
+        .filter(|(_, ext)| is_numbered(ext))
+        .and_then(|(_, ext)| ext.parse().ok())
+    {
+        return part;
+    }
+
+    match RarExt::from_filename(path) {
+        Some(RarExt::Main) => 0,
+        Some(RarExt::Part(n)) => n + 1,
+        None => filename
+            .rsplit_once('.')
+            .filter(|(_, ext)| is_numbered(ext))
+            .and_then(|(_, ext)| ext.parse().ok())
+            .unwrap_or(0),
+    }
+}
+
+/// Splits an absolute byte range of a multi-volume archive (i.e. all volumes
+/// concatenated) across the volumes it was posted in.
+///
+/// # Returns
+/// - `(volume, offset, length)` for each volume holding part of the range,
+///   where `offset` is relative to the start of that volume
+/// - [`ArchiveError::RangeOutOfBounds`] if the range doesn't fit in the
+///   volumes. Offsets come from archive headers, so they can't be trusted.
+pub fn volume_layout(
+    offset: u64,
+    size: u64,
+    volume_sizes: &[u64],
+) -> Result<Vec<(usize, u64, u64)>, ArchiveError> {
+    let out_of_bounds = ArchiveError::RangeOutOfBounds { offset, size };
+    let total = volume_sizes
+        .iter()
+        .try_fold(0u64, |total, size| total.checked_add(*size));
+    let range_end = match (offset.checked_add(size), total) {
+        (Some(range_end), Some(total)) if range_end <= total => range_end,
+        _ => return Err(out_of_bounds),
+    };
+
+    let mut volume_start = 0;
+    let mut layout = Vec::new();
+
+    for (index, &volume_size) in volume_sizes.iter().enumerate() {
+        // can't overflow, the sizes summed fine above
+        let volume_end = volume_start + volume_size;
+        let start = offset.max(volume_start);
+        let end = range_end.min(volume_end);
+
+        if start < end {
+            layout.push((index, start - volume_start, end - start));
+        }
+
+        volume_start = volume_end;
+    }
+
+    Ok(layout)
+}
+
+fn is_numbered(ext: &str) -> bool {
+    !ext.is_empty() && ext.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_kind() {
+        let cases = [
+            ("movie.par2", Some(ContentKind::Par2)),
+            ("movie.vol00+01.PAR2", Some(ContentKind::Par2)),
+            ("movie.rar", Some(ContentKind::Rar)),
+            ("movie.part01.rar", Some(ContentKind::Rar)),
+            ("movie.r00", Some(ContentKind::Rar)),
+            ("movie.7z", Some(ContentKind::SevenZip)),
+            ("movie.7z.001", Some(ContentKind::SevenZip)),
+            ("movie.mkv.001", Some(ContentKind::Split)),
+            ("movie.zip", Some(ContentKind::Zip)),
+            ("movie.z01", Some(ContentKind::Zip)),
+            ("movie.mkv", Some(ContentKind::Video)),
+            ("movie.MP4", Some(ContentKind::Video)),
+            ("movie.nfo", None),
+            ("movie", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                ContentKind::from_filename(input),
+                expected,
+                "Failed for input: {input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_volume_index() {
+        let cases = [
+            ("movie.rar", 0),
+            ("movie.r00", 1),
+            ("movie.r10", 11),
+            ("movie.part01.rar", 1),
+            ("movie.part10.rar", 10),
+            ("movie.7z.001", 1),
+            ("movie.mkv.003", 3),
+            ("movie.z01", 1),
+            ("movie.z12", 12),
+            ("movie.zip", u32::MAX),
+            ("movie.mkv", 0),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                volume_index(Path::new(input)),
+                expected,
+                "Failed for input: {input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_volume_layout() {
+        assert_eq!(
+            volume_layout(32, 250, &[100, 100, 100]).unwrap(),
+            vec![(0, 32, 68), (1, 0, 100), (2, 0, 82)]
+        );
+        assert_eq!(
+            volume_layout(150, 20, &[100, 100]).unwrap(),
+            vec![(1, 50, 20)]
+        );
+        assert_eq!(
+            volume_layout(150, 50, &[100, 100]).unwrap(),
+            vec![(1, 50, 50)]
+        );
+    }
+
+    #[test]
+    fn test_volume_layout_out_of_bounds() {
+        for (offset, size) in [(150, 51), (250, 0), (u64::MAX, 1), (1, u64::MAX)] {
+            assert!(
+                matches!(
+                    volume_layout(offset, size, &[100, 100]),
+                    Err(ArchiveError::RangeOutOfBounds { .. })
+                ),
+                "Failed for {offset}+{size}"
+            );
+        }
+        assert!(volume_layout(0, 1, &[u64::MAX, 1]).is_err());
+    }
+}
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::archive::{
     error::ArchiveError,
@@ -8,10 +11,18 @@ use crate::archive::{
     par2::{FileInfo, Par2Manifest},
 };
 
+pub mod direct;
 pub mod error;
+pub mod kind;
 pub mod packet;
 pub mod par2;
 pub mod rar;
+pub mod sevenz;
+pub mod zip;
+
+/// Archive headers and directories are read in one go, and none is anywhere
+/// near this big. Corrupt ones can claim any size up to the whole archive.
+pub const MAX_HEADER_SIZE: u64 = 64 * 1024 * 1024;
 
 pub fn parse_file(path: &Path) -> Result<Par2Manifest, ArchiveError> {
     let buffer = std::fs::read(path)?;
@@ -19,24 +30,57 @@ pub fn parse_file(path: &Path) -> Result<Par2Manifest, ArchiveError> {
 }
 
 pub fn parse_buffer(buffer: &[u8]) -> Result<Par2Manifest, ArchiveError> {
-    let packets = scan_for_packets(buffer);
+    parse_packets(scan_for_packets(buffer))
+}
 
+/// Builds the manifest from the packets of one PAR2 file, in any order
+pub fn parse_packets(packets: Vec<Packet>) -> Result<Par2Manifest, ArchiveError> {
     let mut files = HashMap::new();
+    let mut file_ids = HashSet::new();
+    let mut unicode_names = HashMap::new();
+    let mut slice_crcs = HashMap::new();
+    let mut slice_size = 0;
     let mut found_main = false;
 
     for packet in packets {
         match packet {
-            Packet::Main(_) => found_main = true,
+            Packet::Main(main) => {
+                found_main = true;
+                slice_size = main.slice_size;
+                file_ids.extend(main.file_ids);
+            }
             Packet::FileDesc(desc) => {
                 files.insert(
-                    desc.filename.clone(),
+                    desc.file_id,
                     FileInfo {
                         real_filename: desc.filename,
                         hash16k: desc.hash16k.into(),
+                        size: desc.filesize,
+                        slice_crcs: Vec::new(),
                     },
                 );
             }
-            _ => {} // ignore IFSC packets - we're not doing recovery for now
+            Packet::UnicodeFilename(unicode) => {
+                unicode_names.insert(unicode.file_id, unicode.filename);
+            }
+            Packet::IFSC(ifsc) => {
+                slice_crcs.insert(ifsc.file_id, ifsc.crcs);
+            }
+            Packet::Creator(creator) => debug!("PAR2 created by {}", creator.creator),
+            _ => {} // recovery slices are left to par2cmdline
+        }
+    }
+
+    // packet order isn't guaranteed, so only swap in unicode names and slice
+    // checksums once every FileDesc has been seen
+    for (file_id, filename) in unicode_names {
+        if let Some(info) = files.get_mut(&file_id) {
+            info.real_filename = filename;
+        }
+    }
+    for (file_id, crcs) in slice_crcs {
+        if let Some(info) = files.get_mut(&file_id) {
+            info.slice_crcs = crcs;
         }
     }
 
@@ -50,10 +94,10 @@ pub fn parse_buffer(buffer: &[u8]) -> Result<Par2Manifest, ArchiveError> {
         );
     }
 
-    Ok(Par2Manifest::new(files))
+    Ok(Par2Manifest::new(files, file_ids, slice_size))
 }
 
-fn scan_for_packets(buffer: &[u8]) -> Vec<Packet> {
+pub fn scan_for_packets(buffer: &[u8]) -> Vec<Packet> {
     let mut packets = Vec::new();
     let mut cursor = 0;
 
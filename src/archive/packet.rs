@@ -1,17 +1,31 @@
+use std::ops::Range;
+
 use byteorder::{ByteOrder, LittleEndian};
+use md5::{Digest, Md5};
+use tracing::warn;
 
 const PAR_PKT_ID: &[u8] = b"PAR2\x00PKT";
 const PAR_MAIN_ID: &[u8] = b"PAR 2.0\x00Main\x00\x00\x00\x00";
 const PAR_FILE_ID: &[u8] = b"PAR 2.0\x00FileDesc";
 const PAR_SLICE_ID: &[u8] = b"PAR 2.0\x00IFSC\x00\x00\x00\x00";
+const PAR_CREATOR_ID: &[u8] = b"PAR 2.0\x00Creator\x00";
+const PAR_RECOVERY_ID: &[u8] = b"PAR 2.0\x00RecvSlic";
+const PAR_UNICODE_ID: &[u8] = b"PAR 2.0\x00UniFileN";
 
 const HEADER_SIZE: usize = 32;
+const MAGIC_SIZE: usize = 8;
+const LENGTH_SIZE: usize = 8;
 const HEADER_FIELD_SIZE: usize = 16;
 const CRC_ENTRY_SIZE: usize = 20;
 
+/// Bytes at the start of every packet that [`parse_header`] reads: the
+/// magic, length, MD5, recovery set ID and type
+pub const PACKET_HEADER_SIZE: usize = HEADER_SIZE + 2 * HEADER_FIELD_SIZE;
+
 #[derive(Debug, Clone)]
 pub struct MainPacket {
     pub slice_size: u64,
+    pub file_ids: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -28,38 +42,88 @@ pub struct IFSCPacket {
     pub crcs: Vec<u32>,
 }
 
+#[derive(Debug, Clone)]
+pub struct CreatorPacket {
+    pub creator: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveryPacket {
+    pub exponent: u32,
+    /// Where the slice sits in the packet, rather than a copy of it
+    pub data: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnicodeFilenamePacket {
+    pub file_id: String,
+    pub filename: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum Packet {
     Main(MainPacket),
     FileDesc(FileDescPacket),
     IFSC(IFSCPacket),
+    Creator(CreatorPacket),
+    Recovery(RecoveryPacket),
+    UnicodeFilename(UnicodeFilenamePacket),
+    /// Well-formed packet of a type we don't understand (e.g. client
+    /// extensions). Returned so the scanner can skip it whole.
+    Unknown,
 }
 
-/// Parses a potential PAR2 packet at the start of the buffer.
-/// Checks magic ID, validates packet length, then extracts
-/// the packet body.
+/// How long the packet at the start of a file is and whether it holds a
+/// recovery slice, from its header alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub length: u64,
+    pub recovery: bool,
+}
+
+/// Reads the header of a potential PAR2 packet, so a recovery slice can be
+/// skipped without downloading it. Its MD5 can't be checked without the
+/// body, that's left to [`parse_packet`].
 ///
 /// # Returns
-/// - `Some((packet, length))` on valid packet
-/// - `None` if not a PAR2 packet or invalid packet structure
-pub fn parse_packet(input: &[u8]) -> Option<(Packet, usize)> {
-    let mut cursor = input;
+/// - `None` if not a PAR2 packet or its length breaks the spec
+pub fn parse_header(input: &[u8]) -> Option<PacketHeader> {
+    if !input.starts_with(PAR_PKT_ID) {
+        return None;
+    }
 
-    if !cursor.starts_with(PAR_PKT_ID) {
+    let length = LittleEndian::read_u64(input.get(MAGIC_SIZE..MAGIC_SIZE + LENGTH_SIZE)?);
+    // the spec requires packets to be 4-byte aligned and at least a full header
+    if length < PACKET_HEADER_SIZE as u64 || !length.is_multiple_of(4) {
         return None;
     }
 
-    take(&mut cursor, PAR_PKT_ID.len())?;
+    let packet_type = input.get(HEADER_SIZE + HEADER_FIELD_SIZE..PACKET_HEADER_SIZE)?;
+    Some(PacketHeader {
+        length,
+        recovery: packet_type == PAR_RECOVERY_ID,
+    })
+}
+
+/// Parses a potential PAR2 packet at the start of the buffer.
+/// Checks magic ID, validates packet length and MD5, then extracts
+/// the packet body.
+///
+/// # Returns
+/// - `Some((packet, length))` on valid packet
+/// - `None` if not a PAR2 packet, invalid packet structure or hash mismatch
+pub fn parse_packet(input: &[u8]) -> Option<(Packet, usize)> {
+    let header = parse_header(input)?;
+    let pack_len = usize::try_from(header.length).ok()?;
 
-    let pack_bytes = take(&mut cursor, 8)?;
-    let pack_len = LittleEndian::read_u64(pack_bytes) as usize;
+    let mut body = input.get(HEADER_SIZE..pack_len)?;
+    let expected_hash = &input[MAGIC_SIZE + LENGTH_SIZE..HEADER_SIZE];
 
-    if pack_len < HEADER_SIZE || pack_len > input.len() {
+    if Md5::digest(body).as_slice() != expected_hash {
+        warn!("Discarding PAR2 packet with mismatched MD5");
         return None;
     }
 
-    let mut body = input.get(HEADER_SIZE..pack_len)?;
-
     take(&mut body, HEADER_FIELD_SIZE)?; // Skip recovery set ID
     let packet_type = take(&mut body, HEADER_FIELD_SIZE)?;
 
@@ -67,7 +131,10 @@ pub fn parse_packet(input: &[u8]) -> Option<(Packet, usize)> {
         PAR_MAIN_ID => parse_main_packet(body),
         PAR_FILE_ID => parse_file_packet(body),
         PAR_SLICE_ID => parse_slice_packet(body),
-        _ => return None,
+        PAR_CREATOR_ID => parse_creator_packet(body),
+        PAR_RECOVERY_ID => parse_recovery_packet(body),
+        PAR_UNICODE_ID => parse_unicode_packet(body),
+        _ => Some(Packet::Unknown),
     }?;
 
     Some((packet, pack_len))
@@ -77,18 +144,28 @@ pub fn parse_packet(input: &[u8]) -> Option<(Packet, usize)> {
 ///
 /// # Structure
 /// ```text
-/// 0         16        32        40
-/// +---------+---------+---------+
-/// | Recovery| Packet  | Slice   |
-/// | Set ID  | Type    | Size    |
-/// | (16B)   | (16B)   | (8B)    |
-/// +---------+---------+---------+
+/// 0         16        32        40        44        ...
+/// +---------+---------+---------+---------+---------+
+/// | Recovery| Packet  | Slice   | File    | File    |
+/// | Set ID  | Type    | Size    | Count   | IDs     |
+/// | (16B)   | (16B)   | (8B)    | (4B)    | (16B*N) |
+/// +---------+---------+---------+---------+---------+
 /// ```
 fn parse_main_packet(mut body: &[u8]) -> Option<Packet> {
     let bytes = take(&mut body, 8)?;
     let slice_size = LittleEndian::read_u64(bytes);
+    take(&mut body, 4)?; // skip recovery set file count
+
+    // recovery set IDs followed by non-recovery set IDs, both 16 bytes each
+    let file_ids = body
+        .chunks_exact(HEADER_FIELD_SIZE)
+        .map(hex::encode)
+        .collect();
 
-    Some(Packet::Main(MainPacket { slice_size }))
+    Some(Packet::Main(MainPacket {
+        slice_size,
+        file_ids,
+    }))
 }
 
 /// Parses a FileDesc packet body
@@ -109,8 +186,10 @@ fn parse_file_packet(mut body: &[u8]) -> Option<Packet> {
     let filesize = LittleEndian::read_u64(take(&mut body, 8)?);
 
     // find the NUL terminator
+    // names are meant to be ASCII, but posters ignore that, so don't reject
+    // the packet over it. A UniFileN packet will carry the real name.
     let filename_offset = body.split(|&b| b == 0).next()?;
-    let filename = String::from_utf8(filename_offset.to_vec()).ok()?;
+    let filename = String::from_utf8_lossy(filename_offset).into_owned();
 
     Some(Packet::FileDesc(FileDescPacket {
         file_id,
@@ -134,7 +213,7 @@ fn parse_file_packet(mut body: &[u8]) -> Option<Packet> {
 fn parse_slice_packet(mut body: &[u8]) -> Option<Packet> {
     let file_id = hex::encode(take(&mut body, HEADER_FIELD_SIZE)?);
 
-    if body.len() % CRC_ENTRY_SIZE != 0 {
+    if !body.len().is_multiple_of(CRC_ENTRY_SIZE) {
         return None;
     }
 
@@ -146,9 +225,215 @@ fn parse_slice_packet(mut body: &[u8]) -> Option<Packet> {
     Some(Packet::IFSC(IFSCPacket { file_id, crcs }))
 }
 
+/// Parses a Creator packet body
+///
+/// # Structure
+/// ```text
+/// 0         16        32        ...
+/// +---------+---------+---------+
+/// | Recovery| Packet  | Client  |
+/// | Set ID  | Type    | Name    |
+/// | (16B)   | (16B)   | (NUL)   |
+/// +---------+---------+---------+
+/// ```
+fn parse_creator_packet(body: &[u8]) -> Option<Packet> {
+    let creator = body.split(|&b| b == 0).next()?;
+    let creator = String::from_utf8_lossy(creator).into_owned();
+
+    Some(Packet::Creator(CreatorPacket { creator }))
+}
+
+/// Parses a RecvSlic (recovery slice) packet body
+///
+/// # Structure
+/// ```text
+/// 0         16        32        36        ...
+/// +---------+---------+---------+---------+
+/// | Recovery| Packet  | Exponent| Recovery|
+/// | Set ID  | Type    | (4B)    | Data    |
+/// | (16B)   | (16B)   |         |         |
+/// +---------+---------+---------+---------+
+/// ```
+fn parse_recovery_packet(mut body: &[u8]) -> Option<Packet> {
+    let exponent = LittleEndian::read_u32(take(&mut body, 4)?);
+    let start = PACKET_HEADER_SIZE + 4;
+
+    Some(Packet::Recovery(RecoveryPacket {
+        exponent,
+        data: start..start + body.len(),
+    }))
+}
+
+/// Parses a UniFileN (unicode filename) packet body
+///
+/// # Structure
+/// ```text
+/// 0         16        32        48        ...
+/// +---------+---------+---------+---------+
+/// | Recovery| Packet  | File ID | Name    |
+/// | Set ID  | Type    | (16B)   | (UTF-16 |
+/// | (16B)   | (16B)   |         | LE, NUL)|
+/// +---------+---------+---------+---------+
+/// ```
+fn parse_unicode_packet(mut body: &[u8]) -> Option<Packet> {
+    let file_id = hex::encode(take(&mut body, HEADER_FIELD_SIZE)?);
+
+    let units: Vec<u16> = body
+        .chunks_exact(2)
+        .map(LittleEndian::read_u16)
+        .take_while(|&unit| unit != 0)
+        .collect();
+    let filename = String::from_utf16(&units).ok()?;
+
+    Some(Packet::UnicodeFilename(UnicodeFilenamePacket {
+        file_id,
+        filename,
+    }))
+}
+
 fn take<'a>(slice: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
     let (head, tail) = slice.get(..len).map(|h| (h, &slice[len..]))?;
 
     *slice = tail;
     Some(head)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const SET_ID: [u8; 16] = [7; 16];
+
+    fn encode_packet(packet_type: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut hashed = SET_ID.to_vec();
+        hashed.extend_from_slice(packet_type);
+        hashed.extend_from_slice(body);
+        while !hashed.len().is_multiple_of(4) {
+            hashed.push(0);
+        }
+
+        let mut packet = PAR_PKT_ID.to_vec();
+        packet.extend_from_slice(&((HEADER_SIZE + hashed.len()) as u64).to_le_bytes());
+        packet.extend_from_slice(&Md5::digest(&hashed));
+        packet.extend_from_slice(&hashed);
+        packet
+    }
+
+    #[test]
+    fn test_parse_unicode_filename() {
+        let name = "Amélie.2001.mkv";
+        let mut body = vec![0xAB; 16];
+        body.extend(name.encode_utf16().flat_map(u16::to_le_bytes));
+
+        let packet = encode_packet(PAR_UNICODE_ID, &body);
+        let (parsed, length) = parse_packet(&packet).unwrap();
+
+        assert_eq!(length, packet.len());
+        match parsed {
+            Packet::UnicodeFilename(unicode) => {
+                assert_eq!(unicode.file_id, "ab".repeat(16));
+                assert_eq!(unicode.filename, name);
+            }
+            other => panic!("Unexpected packet {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_creator_and_unknown() {
+        let packet = encode_packet(PAR_CREATOR_ID, b"ParPar v0.4.2\0");
+        assert!(matches!(
+            parse_packet(&packet),
+            Some((Packet::Creator(CreatorPacket { creator }), _)) if creator == "ParPar v0.4.2"
+        ));
+
+        let unknown = encode_packet(b"PAR 2.0\0Unknown\0", b"whatever");
+        assert!(matches!(parse_packet(&unknown), Some((Packet::Unknown, _))));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut body = 3u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0xAB; 1024]);
+        let packet = encode_packet(PAR_RECOVERY_ID, &body);
+
+        let header = parse_header(&packet[..PACKET_HEADER_SIZE]).unwrap();
+        assert_eq!(header.length, packet.len() as u64);
+        assert!(header.recovery);
+
+        match parse_packet(&packet) {
+            Some((Packet::Recovery(recovery), _)) => {
+                assert_eq!(recovery.exponent, 3);
+                assert_eq!(&packet[recovery.data], &[0xAB; 1024]);
+            }
+            other => panic!("Unexpected packet {other:?}"),
+        }
+
+        let packet = encode_packet(PAR_CREATOR_ID, b"ParPar v0.4.2\0");
+        assert!(!parse_header(&packet).unwrap().recovery);
+        assert!(parse_header(&packet[..PACKET_HEADER_SIZE - 1]).is_none());
+        assert!(parse_header(b"not a packet at all, not even close to one").is_none());
+    }
+
+    #[test]
+    fn test_rejects_corrupted_packet() {
+        let mut packet = encode_packet(PAR_CREATOR_ID, b"ParPar v0.4.2\0");
+        let last = packet.len() - 5;
+        packet[last] ^= 0xFF;
+
+        assert!(parse_packet(&packet).is_none());
+    }
+
+    fn encode_file_desc(
+        file_id: &[u8; 16],
+        hash16k: &[u8; 16],
+        filesize: u64,
+        name: &str,
+    ) -> Vec<u8> {
+        let mut body = file_id.to_vec();
+        body.extend_from_slice(&[0; 16]); // full MD5
+        body.extend_from_slice(hash16k);
+        body.extend_from_slice(&filesize.to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+
+        encode_packet(PAR_FILE_ID, &body)
+    }
+
+    proptest! {
+        #[test]
+        fn test_file_desc_roundtrip(
+            file_id in any::<[u8; 16]>(),
+            hash16k in any::<[u8; 16]>(),
+            filesize in any::<u64>(),
+            name in "[a-zA-Z0-9._-]{1,64}",
+        ) {
+            let packet = encode_file_desc(&file_id, &hash16k, filesize, &name);
+            let (parsed, length) = parse_packet(&packet).unwrap();
+
+            prop_assert_eq!(length, packet.len());
+            match parsed {
+                Packet::FileDesc(desc) => {
+                    prop_assert_eq!(desc.file_id, hex::encode(file_id));
+                    prop_assert_eq!(desc.hash16k, hash16k.to_vec());
+                    prop_assert_eq!(desc.filesize, filesize);
+                    prop_assert_eq!(desc.filename, name);
+                }
+                other => prop_assert!(false, "Unexpected packet {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_parse_packet_never_panics(
+            declared_length in any::<u64>(),
+            tail in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let mut input = PAR_PKT_ID.to_vec();
+            input.extend_from_slice(&declared_length.to_le_bytes());
+            input.extend_from_slice(&tail);
+
+            if let Some((_, length)) = parse_packet(&input) {
+                prop_assert!(length <= input.len());
+            }
+        }
+    }
+}
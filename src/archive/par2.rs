@@ -1,16 +1,18 @@
 use bytes::Bytes;
 use derive_more::Constructor;
-use futures::StreamExt;
 use itertools::Itertools;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, hash_map::Entry},
     path::{Path, PathBuf},
 };
+use tracing::debug;
 
 use crate::{
     archive::{
+        direct::analyse_direct,
         error::ArchiveError,
-        rar::{RarExt, analyse_rar_buffer},
+        kind::{ContentKind, volume_index, volume_layout},
+        rar::{self, analyse_rar_buffer},
     },
     nntp::yenc::extract_filename,
     scheduler::adaptive::FirstSegment,
@@ -18,13 +20,31 @@ use crate::{
 
 #[derive(Debug, Constructor)]
 pub struct Par2Manifest {
+    /// File descriptions keyed by PAR2 file ID
     pub files: HashMap<String, FileInfo>,
+    /// File IDs the Main packet says belong to the recovery set
+    pub file_ids: HashSet<String>,
+    /// Size of the slices the IFSC checksums cover, 0 without a Main packet
+    pub slice_size: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub real_filename: String,
     pub hash16k: Bytes,
+    pub size: u64,
+    /// CRC32 of each slice, the last one zero-padded to the slice size
+    pub slice_crcs: Vec<u32>,
+}
+
+/// How a downloaded file compares with its PAR2 description
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Intact,
+    /// The number of slices that need repairing
+    Damaged(usize),
+    /// The set has no slice checksums for the file
+    Unknown,
 }
 
 #[derive(Debug, Clone, Constructor)]
@@ -56,9 +76,92 @@ impl DownloadTask {
     pub fn bytes(&self) -> &Bytes {
         &self.bytes
     }
+
+    /// The part of the first segment that belongs to the stream, i.e. with
+    /// archive headers stripped and trailing data past `length` dropped
+    pub fn first_segment_data(&self) -> Bytes {
+        let len = self.bytes.len() as u64;
+        let start = self.offset.min(len) as usize;
+        let end = (self.offset + self.length).min(len) as usize;
+
+        self.bytes.slice(start..end)
+    }
 }
 
 impl Par2Manifest {
+    /// Folds the packets of another PAR2 file from the same set into this one.
+    /// Volume files repeat the FileDesc packets, so this lets us fill in
+    /// descriptions missing from a truncated or corrupt index file.
+    pub fn merge(&mut self, other: Par2Manifest) {
+        self.file_ids.extend(other.file_ids);
+        if self.slice_size == 0 {
+            self.slice_size = other.slice_size;
+        }
+
+        for (file_id, info) in other.files {
+            match self.files.entry(file_id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(info);
+                }
+                Entry::Occupied(mut entry) if entry.get().slice_crcs.is_empty() => {
+                    entry.get_mut().slice_crcs = info.slice_crcs;
+                }
+                Entry::Occupied(_) => {}
+            }
+        }
+    }
+
+    pub fn file_named(&self, filename: &str) -> Option<&FileInfo> {
+        self.files
+            .values()
+            .find(|info| info.real_filename == filename)
+    }
+
+    /// Checks `data` slice by slice against the file's IFSC checksums. A file
+    /// of the wrong size is damaged in every slice it doesn't fully cover.
+    pub fn verify(&self, info: &FileInfo, data: &[u8]) -> Verification {
+        if self.slice_size == 0 || info.slice_crcs.is_empty() {
+            return Verification::Unknown;
+        }
+
+        let slice_size = self.slice_size as usize;
+        let expected = &data[..data.len().min(info.size as usize)];
+        let damaged = info
+            .slice_crcs
+            .iter()
+            .enumerate()
+            .filter(|&(index, &crc)| {
+                let start = index * slice_size;
+                let end = (start + slice_size).min(info.size as usize);
+                match expected.get(start..end) {
+                    Some(slice) => slice_crc(slice, slice_size) != crc,
+                    None => true,
+                }
+            })
+            .count();
+
+        match damaged {
+            0 if data.len() as u64 == info.size => Verification::Intact,
+            0 => Verification::Damaged(1),
+            damaged => Verification::Damaged(damaged),
+        }
+    }
+
+    /// File IDs declared by the Main packet without a matching FileDesc packet
+    pub fn unresolved(&self) -> Vec<&str> {
+        self.file_ids
+            .iter()
+            .filter(|id| !self.files.contains_key(*id))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether every file in the recovery set has been described.
+    /// Without a Main packet we have no way of knowing, so never complete.
+    pub fn is_complete(&self) -> bool {
+        !self.file_ids.is_empty() && self.unresolved().is_empty()
+    }
+
     pub fn hash_to_filename(&self) -> HashMap<Bytes, &str> {
         self.files
             .values()
@@ -77,37 +180,58 @@ impl Par2Manifest {
     }
 }
 
+/// PAR2 checksums the last slice of a file as if it were padded with zeros
+fn slice_crc(slice: &[u8], slice_size: usize) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(slice);
+
+    let padding = [0; 4096];
+    let mut remaining = slice_size.saturating_sub(slice.len());
+    while remaining > 0 {
+        let len = remaining.min(padding.len());
+        hasher.update(&padding[..len]);
+        remaining -= len;
+    }
+
+    hasher.finalize()
+}
+
+/// The real name of the file the tasks make up: the name in the RAR file
+/// header, or the volume's own name for direct and split posts
+pub fn content_name(tasks: &[DownloadTask]) -> Option<String> {
+    let task = tasks.first()?;
+    let filename = task.path().file_name()?.to_str()?;
+
+    match ContentKind::from_filename(filename)? {
+        ContentKind::Rar => rar::file_name(task.bytes()),
+        ContentKind::Video => Some(filename.to_string()),
+        // `movie.mkv.001` is the first part of `movie.mkv`
+        ContentKind::Split => filename.rsplit_once('.').map(|(name, _)| name.to_string()),
+        _ => None,
+    }
+}
+
 pub async fn create_download_tasks(
     hash_to_real: HashMap<Bytes, &str>,
     downloads: &[FirstSegment],
     session_dir: &Path,
+    password: Option<&str>,
 ) -> Result<Vec<DownloadTask>, ArchiveError> {
     let mut tasks = Vec::new();
     for segment in downloads {
         let real_name = hash_to_real
             .get(&segment.hash16k)
             .ok_or(ArchiveError::FilenameNotFound(segment.nzb.subject.clone()))?;
-        let path = session_dir.join(real_name);
-        let is_first = RarExt::from_filename(&path).is_some_and(|ext| ext == RarExt::Main);
-        let (offset, length) = analyse_rar_buffer(&segment.bytes, is_first).await?;
-        let data = segment.bytes.slice(offset as usize..);
-        tokio::fs::write(&path, &data).await?;
-
-        tasks.push(DownloadTask::new(
-            path,
-            segment.nzb.clone(), // TODO: don't clone
-            length,
-            offset,
-            data,
-        ));
-    }
-
-    let tasks: Vec<_> = tasks
-        .into_iter()
-        .sorted_by_key(|task| RarExt::from_filename(task.path()).unwrap())
-        .collect();
+        let task = create_download_task(segment, real_name, session_dir, password)?;
+        let data = segment.bytes.slice(task.offset as usize..);
+        tokio::fs::write(&task.path, &data).await?;
 
-    println!(
+        tasks.push(task);
+    }
+
+    let tasks = sort_by_volume(tasks);
+
+    debug!(
         "tasks: {:?}",
         tasks.iter().map(|t| t.path.clone()).collect::<Vec<_>>()
     );
@@ -117,30 +241,151 @@ pub async fn create_download_tasks(
 pub async fn create_download_tasks_plain(
     downloads: &[FirstSegment],
     session_dir: &Path,
+    password: Option<&str>,
 ) -> Result<Vec<DownloadTask>, ArchiveError> {
-    let mut tasks = Vec::new();
-    for segment in downloads {
-        let filename = extract_filename(&segment.nzb.subject)
-            .ok_or(ArchiveError::FilenameNotFound(segment.nzb.subject.clone()))?;
-        let path = session_dir.join(filename);
-        let is_first = RarExt::from_filename(&path).is_some_and(|ext| ext == RarExt::Main);
-        let (offset, length) = analyse_rar_buffer(&segment.bytes, is_first).await?;
-        let data = segment.bytes.clone(); //.slice(offset as usize..);
-        //tokio::fs::write(&path, &data).await?;
-
-        tasks.push(DownloadTask::new(
-            path,
-            segment.nzb.clone(), // TODO: don't clone
-            length,
-            offset,
-            data,
-        ));
-    }
-
-    let tasks: Vec<_> = tasks
+    let tasks = downloads
+        .iter()
+        .map(|segment| {
+            let filename = extract_filename(&segment.nzb.subject)
+                .ok_or(ArchiveError::FilenameNotFound(segment.nzb.subject.clone()))?;
+            create_download_task(segment, filename, session_dir, password)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(sort_by_volume(tasks))
+}
+
+/// Works out where the stream data sits in a volume, using the backend for
+/// whatever kind of file `filename` says it is. The task keeps the whole first
+/// segment, `offset` and `length` describe the stream data within the volume.
+fn create_download_task(
+    segment: &FirstSegment,
+    filename: &str,
+    session_dir: &Path,
+    password: Option<&str>,
+) -> Result<DownloadTask, ArchiveError> {
+    let path = session_path(session_dir, filename)?;
+
+    let (offset, length) = match ContentKind::from_filename(filename) {
+        Some(ContentKind::Rar) => {
+            analyse_rar_buffer(&segment.bytes).map_err(|e| with_password_context(e, password))?
+        }
+        Some(ContentKind::Video | ContentKind::Split) => analyse_direct(segment)?,
+        Some(ContentKind::SevenZip) => return Err(ArchiveError::Unsupported("Obfuscated 7-Zip")),
+        Some(ContentKind::Zip) => return Err(ArchiveError::Unsupported("Obfuscated ZIP")),
+        Some(ContentKind::Par2) | None => return Err(ArchiveError::UnsupportedVolume(path)),
+    };
+
+    Ok(DownloadTask::new(
+        path,
+        segment.nzb.clone(), // TODO: don't clone
+        length,
+        offset,
+        segment.bytes.clone(),
+    ))
+}
+
+/// Lays a stored archive entry out across the volumes it spans, for formats
+/// whose headers give the entry's absolute `offset` and `size` within the
+/// archive. `downloads` must be the first segments of every volume, in volume
+/// order.
+pub fn create_download_tasks_spanning(
+    downloads: &[FirstSegment],
+    offset: u64,
+    size: u64,
+    session_dir: &Path,
+) -> Result<Vec<DownloadTask>, ArchiveError> {
+    let volume_sizes = downloads
+        .iter()
+        .map(|segment| analyse_direct(segment).map(|(_, length)| length))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    volume_layout(offset, size, &volume_sizes)?
         .into_iter()
-        .sorted_by_key(|task| RarExt::from_filename(task.path()).unwrap())
-        .collect();
+        .map(|(volume, offset, length)| {
+            let segment = &downloads[volume];
+            let filename = extract_filename(&segment.nzb.subject)
+                .ok_or(ArchiveError::FilenameNotFound(segment.nzb.subject.clone()))?;
 
-    Ok(tasks)
+            Ok(DownloadTask::new(
+                session_path(session_dir, filename)?,
+                segment.nzb.clone(), // TODO: don't clone
+                length,
+                offset,
+                segment.bytes.clone(),
+            ))
+        })
+        .collect()
+}
+
+fn sort_by_volume(tasks: Vec<DownloadTask>) -> Vec<DownloadTask> {
+    tasks
+        .into_iter()
+        .sorted_by_key(|task| volume_index(task.path()))
+        .collect()
+}
+
+/// Joins a filename taken from untrusted PAR2/NZB data onto the session
+/// directory, dropping any directory components so it can't escape.
+fn session_path(session_dir: &Path, name: &str) -> Result<PathBuf, ArchiveError> {
+    Path::new(name)
+        .file_name()
+        .map(|filename| session_dir.join(filename))
+        .ok_or_else(|| ArchiveError::FilenameNotFound(name.to_string()))
+}
+
+/// We can't decrypt yet, but knowing whether the NZB carried a password tells
+/// the user if the release is unusable or just unsupported.
+fn with_password_context(error: ArchiveError, password: Option<&str>) -> ArchiveError {
+    match error {
+        ArchiveError::Encrypted if password.is_some() => ArchiveError::EncryptedWithPassword,
+        error => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(data: &[u8], slice_size: usize) -> (Par2Manifest, FileInfo) {
+        let info = FileInfo {
+            real_filename: "movie.part1.rar".to_string(),
+            hash16k: Bytes::new(),
+            size: data.len() as u64,
+            slice_crcs: data
+                .chunks(slice_size)
+                .map(|slice| slice_crc(slice, slice_size))
+                .collect(),
+        };
+        let files = HashMap::from([("id".to_string(), info.clone())]);
+
+        (
+            Par2Manifest::new(files, HashSet::new(), slice_size as u64),
+            info,
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let (manifest, info) = manifest(&data, 256);
+        assert_eq!(manifest.verify(&info, &data), Verification::Intact);
+
+        let mut damaged = data.clone();
+        damaged[10] ^= 0xFF;
+        damaged[900] ^= 0xFF;
+        assert_eq!(manifest.verify(&info, &damaged), Verification::Damaged(2));
+
+        // a missing tail segment leaves the last slices short
+        assert_eq!(
+            manifest.verify(&info, &data[..600]),
+            Verification::Damaged(2)
+        );
+
+        let unknown = FileInfo {
+            slice_crcs: Vec::new(),
+            ..info
+        };
+        assert_eq!(manifest.verify(&unknown, &data), Verification::Unknown);
+    }
 }
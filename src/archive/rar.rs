@@ -1,95 +1,141 @@
-use bytes::Bytes;
-use std::{
-    io::{Cursor, SeekFrom},
-    path::Path,
-};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt},
-};
+use byteorder::{ByteOrder, LittleEndian};
+use std::path::Path;
+use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::archive::error::ArchiveError;
 
 const RAR_SIGNATURE: [u8; 7] = [0x52, 0x61, 0x72, 0x21, 0x1A, 0x07, 0x00];
-const MKV_SIGNATURE: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
 
 const RAR_MAIN_HEAD: u8 = 0x73;
 const RAR_FILE_HEAD: u8 = 0x74;
 const RAR_ENDARC_HEAD: u8 = 0x7B;
 
-pub async fn analyse_rar_volume(path: &Path, is_first: bool) -> Result<(u64, u64), ArchiveError> {
+/// Main header flag: every block after the main header is encrypted
+const MHD_PASSWORD: u16 = 0x0080;
+/// File header flag: the file data is encrypted
+const LHD_PASSWORD: u16 = 0x0004;
+/// File header flag: 64-bit sizes, with the high halves before the name
+const LHD_LARGE: u16 = 0x0100;
+
+/// CRC (2B), type (1B), flags (2B), size (2B)
+const BASE_HEADER_SIZE: u16 = 7;
+/// Base header plus packed (4B) and unpacked (4B) sizes
+const FILE_HEADER_MIN_SIZE: u16 = BASE_HEADER_SIZE + 8;
+/// Where the name starts in a file header without 64-bit sizes
+const FILE_NAME_OFFSET: usize = 32;
+
+pub async fn analyse_rar_volume(path: &Path) -> Result<(u64, u64), ArchiveError> {
     let mut file = File::open(path).await?;
     let file_size = file.metadata().await?.len();
 
     let mut buffer = vec![0u8; 1024.min(file_size as usize)]; // TODO: how much of the file do we need?
     file.read_exact(&mut buffer).await?;
 
-    analyse_rar_buffer(&buffer.into(), is_first).await
+    analyse_rar_buffer(&buffer)
+}
+
+/// Walks the RAR4 block headers in the buffer until the first file header.
+///
+/// # Returns
+/// - `(offset, length)` of the stored file data within the buffer
+/// - `(0, 0)` if the archive ends before a file header
+pub fn analyse_rar_buffer(buffer: &[u8]) -> Result<(u64, u64), ArchiveError> {
+    let Some(header) = find_file_header(buffer)? else {
+        return Ok((0, 0));
+    };
+
+    let pack_size = buffer
+        .get(header.start + 7..header.start + 11)
+        .map(LittleEndian::read_u32)
+        .ok_or(ArchiveError::IncompleteData)?;
+
+    let data_offset = header.start + header.size as usize;
+    if data_offset > buffer.len() {
+        return Err(ArchiveError::IncompleteData);
+    }
+
+    Ok((data_offset as u64, pack_size as u64))
 }
 
-pub async fn analyse_rar_buffer(
-    buffer: &Bytes,
-    is_first: bool,
-) -> Result<(u64, u64), ArchiveError> {
-    let rar_offset = buffer
-        .windows(7)
+/// The name of the first file in the archive, without the directories RAR
+/// keeps it under
+pub fn file_name(buffer: &[u8]) -> Option<String> {
+    let header = find_file_header(buffer).ok()??;
+
+    let name_size = buffer
+        .get(header.start + 26..header.start + 28)
+        .map(LittleEndian::read_u16)? as usize;
+    let name_start = header.start
+        + match header.flags & LHD_LARGE {
+            0 => FILE_NAME_OFFSET,
+            _ => FILE_NAME_OFFSET + 8,
+        };
+    let name = buffer.get(name_start..name_start + name_size)?;
+
+    // with LHD_UNICODE the encoded unicode name follows the plain one after a NUL
+    let name = name.split(|&b| b == 0).next()?;
+    let name = String::from_utf8_lossy(name);
+
+    name.rsplit(['\\', '/'])
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// Where a file header starts in a buffer, and what it says about itself
+struct FileHeader {
+    start: usize,
+    flags: u16,
+    size: u16,
+}
+
+fn find_file_header(buffer: &[u8]) -> Result<Option<FileHeader>, ArchiveError> {
+    let mut cursor = buffer
+        .windows(RAR_SIGNATURE.len())
         .position(|w| w == RAR_SIGNATURE)
-        .map(|offset| offset as u64 + 7)
+        .map(|offset| offset + RAR_SIGNATURE.len())
         .ok_or(ArchiveError::MalformedRar)?;
 
-    let mut cursor = Cursor::new(buffer);
-    cursor.seek(SeekFrom::Start(rar_offset)).await?;
+    while let Some(header) = buffer.get(cursor..cursor + BASE_HEADER_SIZE as usize) {
+        let header_type = header[2];
+        let flags = LittleEndian::read_u16(&header[3..5]);
+        let header_size = LittleEndian::read_u16(&header[5..7]);
 
-    let mut data_offset = 0;
-    let mut data_length = 0;
-
-    while let Ok(_header_crc) = cursor.read_u16_le().await {
-        let header_type = cursor.read_u8().await?;
-        let _ = cursor.read_u16_le().await?; // flags
-        let header_size = cursor.read_u16_le().await?;
+        // a header smaller than its own fields would never advance the cursor
+        if header_size < BASE_HEADER_SIZE {
+            return Err(ArchiveError::InvalidRarHeader {
+                header_type,
+                header_size,
+            });
+        }
 
         match header_type {
-            RAR_MAIN_HEAD => {
-                cursor
-                    .seek(SeekFrom::Current(header_size as i64 - 7))
-                    .await?;
-            }
+            RAR_MAIN_HEAD if flags & MHD_PASSWORD != 0 => return Err(ArchiveError::Encrypted),
+            RAR_MAIN_HEAD => cursor += header_size as usize,
             RAR_FILE_HEAD => {
-                let pack_size = cursor.read_u32_le().await? as u64;
-                let _ = cursor.read_u32_le().await?; // unpacked size
-
-                // Skip remaining header
-                let skip_size = header_size as i64 - 7 - 8;
-                cursor.seek(SeekFrom::Current(skip_size)).await?;
-
-                data_offset = cursor.stream_position().await?;
-                data_length = pack_size;
-
-                // For first volume, find MKV signature
-                // if is_first {
-                //     let mut search_buf = vec![0u8; 1024.min(pack_size as usize)];
-                //     let current_pos = data_offset;
-                //     cursor.read_exact(&mut search_buf).await?;
-
-                //     if let Some(mkv_offset) = search_buf.windows(4).position(|w| w == MKV_SIGNATURE)
-                //     {
-                //         data_offset = current_pos + mkv_offset as u64;
-                //         data_length = pack_size - mkv_offset as u64;
-                //     }
-                // }
-
-                break;
+                if header_size < FILE_HEADER_MIN_SIZE {
+                    return Err(ArchiveError::InvalidRarHeader {
+                        header_type,
+                        header_size,
+                    });
+                }
+
+                if flags & LHD_PASSWORD != 0 {
+                    return Err(ArchiveError::Encrypted);
+                }
+
+                return Ok(Some(FileHeader {
+                    start: cursor,
+                    flags,
+                    size: header_size,
+                }));
             }
             RAR_ENDARC_HEAD => break,
-            _ => {
-                cursor
-                    .seek(SeekFrom::Current(header_size as i64 - 7))
-                    .await?;
-            }
+            _ => cursor += header_size as usize,
         }
     }
 
-    Ok((data_offset, data_length))
+    Ok(None)
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -103,7 +149,7 @@ impl RarExt {
         if filename.extension().is_some_and(|ext| ext == "rar") {
             Some(RarExt::Main)
         } else {
-            extract_rar_number(filename.file_name().unwrap().to_str().unwrap()).map(RarExt::Part)
+            extract_rar_number(filename.file_name()?.to_str()?).map(RarExt::Part)
         }
     }
 }
@@ -137,6 +183,89 @@ fn extract_rar_number(filename: &str) -> Option<u32> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    /// Builds a minimal stored RAR4 volume: signature, main header, then a
+    /// file header for `name` followed by `data`
+    fn encode_rar(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut volume = RAR_SIGNATURE.to_vec();
+
+        // main header: base header + 6 reserved bytes
+        volume.extend_from_slice(&[0, 0, RAR_MAIN_HEAD, 0, 0]);
+        volume.extend_from_slice(&13u16.to_le_bytes());
+        volume.extend_from_slice(&[0; 6]);
+
+        let header_size = 32 + name.len() as u16;
+        volume.extend_from_slice(&[0, 0, RAR_FILE_HEAD, 0, 0]);
+        volume.extend_from_slice(&header_size.to_le_bytes());
+        volume.extend_from_slice(&(data.len() as u32).to_le_bytes()); // packed
+        volume.extend_from_slice(&(data.len() as u32).to_le_bytes()); // unpacked
+        volume.extend_from_slice(&[0; 9]); // host OS, CRC, time
+        volume.extend_from_slice(&[29, 0x30]); // version, store method
+        volume.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        volume.extend_from_slice(&[0; 4]); // attributes
+        volume.extend_from_slice(name);
+        volume.extend_from_slice(data);
+
+        volume
+    }
+
+    proptest! {
+        #[test]
+        fn test_analyse_rar_roundtrip(
+            prefix in prop::collection::vec(any::<u8>(), 0..64),
+            name in prop::collection::vec(any::<u8>(), 1..64),
+            data in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            prop_assume!(!prefix.contains(&RAR_SIGNATURE[0]));
+
+            let mut buffer = prefix.clone();
+            buffer.extend(encode_rar(&name, &data));
+
+            let (offset, length) = analyse_rar_buffer(&buffer).unwrap();
+            prop_assert_eq!(offset as usize, buffer.len() - data.len());
+            prop_assert_eq!(length as usize, data.len());
+        }
+
+        #[test]
+        fn test_analyse_rar_never_panics(buffer in prop::collection::vec(any::<u8>(), 0..512)) {
+            if let Ok((offset, _)) = analyse_rar_buffer(&buffer) {
+                prop_assert!(offset as usize <= buffer.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_name() {
+        let volume = encode_rar(b"Movie.2024\\movie.mkv", b"data");
+        assert_eq!(file_name(&volume).as_deref(), Some("movie.mkv"));
+
+        let unicode = encode_rar(b"movie.mkv\0\x01\x02", b"data");
+        assert_eq!(file_name(&unicode).as_deref(), Some("movie.mkv"));
+    }
+
+    #[test]
+    fn test_rejects_encrypted_file() {
+        let mut volume = encode_rar(b"movie.mkv", b"data");
+        let file_header = RAR_SIGNATURE.len() + 13;
+        volume[file_header + 3] |= LHD_PASSWORD as u8;
+
+        assert!(matches!(
+            analyse_rar_buffer(&volume),
+            Err(ArchiveError::Encrypted)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_undersized_header() {
+        let mut volume = RAR_SIGNATURE.to_vec();
+        volume.extend_from_slice(&[0, 0, RAR_FILE_HEAD, 0, 0, 3, 0]);
+
+        assert!(matches!(
+            analyse_rar_buffer(&volume),
+            Err(ArchiveError::InvalidRarHeader { header_size: 3, .. })
+        ));
+    }
 
     #[test]
     fn test_extract_rar_number() {
@@ -0,0 +1,788 @@
+use std::io::Cursor;
+
+use byteorder::{ByteOrder, LittleEndian};
+use lzma_rs::decompress::{Options, UnpackedSize};
+
+use crate::archive::{MAX_HEADER_SIZE, error::ArchiveError};
+
+const SIGNATURE: [u8; 6] = [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+const SIGNATURE_HEADER_SIZE: u64 = 32;
+
+const K_END: u8 = 0x00;
+const K_HEADER: u8 = 0x01;
+const K_ARCHIVE_PROPERTIES: u8 = 0x02;
+const K_ADDITIONAL_STREAMS_INFO: u8 = 0x03;
+const K_MAIN_STREAMS_INFO: u8 = 0x04;
+const K_FILES_INFO: u8 = 0x05;
+const K_PACK_INFO: u8 = 0x06;
+const K_UNPACK_INFO: u8 = 0x07;
+const K_SUBSTREAMS_INFO: u8 = 0x08;
+const K_SIZE: u8 = 0x09;
+const K_CRC: u8 = 0x0A;
+const K_FOLDER: u8 = 0x0B;
+const K_CODERS_UNPACK_SIZE: u8 = 0x0C;
+const K_NUM_UNPACK_STREAM: u8 = 0x0D;
+const K_EMPTY_STREAM: u8 = 0x0E;
+const K_NAME: u8 = 0x11;
+const K_ENCODED_HEADER: u8 = 0x17;
+
+const METHOD_COPY: &[u8] = &[0x00];
+const METHOD_LZMA: &[u8] = &[0x03, 0x01, 0x01];
+
+/// Where the end header sits, as absolute offsets into the archive (i.e. all
+/// volumes concatenated)
+#[derive(Debug, Clone, Copy)]
+pub struct NextHeader {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A file inside the archive and where its data lives
+#[derive(Debug, Clone)]
+pub struct SevenZipEntry {
+    pub name: String,
+    /// Absolute offset of the file data, only meaningful when `stored`
+    pub offset: u64,
+    pub size: u64,
+    /// Whether the data is stored with the copy method and so streamable as-is
+    pub stored: bool,
+}
+
+#[derive(Debug)]
+pub enum Header {
+    Plain(Vec<SevenZipEntry>),
+    /// The header is itself packed (usually LZMA) somewhere else in the
+    /// archive. Fetch `offset..offset + size` and [`EncodedHeader::decode`] it.
+    Encoded(EncodedHeader),
+}
+
+#[derive(Debug)]
+pub struct EncodedHeader {
+    pub offset: u64,
+    pub size: u64,
+    folder: Folder,
+}
+
+#[derive(Debug, Clone)]
+struct Coder {
+    method: Vec<u8>,
+    properties: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Folder {
+    coders: Vec<Coder>,
+    /// Coder output streams, and which of them isn't bound to another
+    /// coder's input, i.e. the folder's final output
+    out_streams: usize,
+    main_out: usize,
+    unpack_size: u64,
+    crc_defined: bool,
+}
+
+#[derive(Debug, Default)]
+struct StreamsInfo {
+    pack_pos: u64,
+    pack_sizes: Vec<u64>,
+    folders: Vec<Folder>,
+    /// Sizes of the files in each folder, in order
+    substream_sizes: Vec<Vec<u64>>,
+}
+
+/// Parses the 32 byte signature header at the start of the first volume.
+///
+/// # Structure
+/// ```text
+/// 0         6         8         12        20        28        32
+/// +---------+---------+---------+---------+---------+---------+
+/// | Sig     | Version | Start   | Next    | Next    | Next    |
+/// | (6B)    | (2B)    | Header  | Header  | Header  | Header  |
+/// |         |         | CRC (4B)| Off (8B)| Size(8B)| CRC (4B)|
+/// +---------+---------+---------+---------+---------+---------+
+/// ```
+pub fn parse_signature_header(buffer: &[u8]) -> Result<NextHeader, ArchiveError> {
+    let header = buffer
+        .get(..SIGNATURE_HEADER_SIZE as usize)
+        .ok_or(ArchiveError::IncompleteData)?;
+
+    if header[..6] != SIGNATURE {
+        return Err(ArchiveError::MalformedSevenZip("signature not found"));
+    }
+
+    let offset = LittleEndian::read_u64(&header[12..20]);
+    let size = LittleEndian::read_u64(&header[20..28]);
+
+    let offset =
+        SIGNATURE_HEADER_SIZE
+            .checked_add(offset)
+            .ok_or(ArchiveError::MalformedSevenZip(
+                "next header offset overflows",
+            ))?;
+
+    Ok(NextHeader { offset, size })
+}
+
+/// Parses the end header, which is either the header itself or a
+/// description of where the packed header lives.
+pub fn parse_header(buffer: &[u8]) -> Result<Header, ArchiveError> {
+    let mut reader = Reader::new(buffer);
+
+    match reader.byte()? {
+        K_HEADER => read_header(&mut reader).map(Header::Plain),
+        K_ENCODED_HEADER => {
+            let streams = read_streams_info(&mut reader)?;
+            let folder =
+                streams
+                    .folders
+                    .first()
+                    .cloned()
+                    .ok_or(ArchiveError::MalformedSevenZip(
+                        "encoded header has no folder",
+                    ))?;
+            let size =
+                streams
+                    .pack_sizes
+                    .first()
+                    .copied()
+                    .ok_or(ArchiveError::MalformedSevenZip(
+                        "encoded header has no stream",
+                    ))?;
+
+            Ok(Header::Encoded(EncodedHeader {
+                offset: SIGNATURE_HEADER_SIZE.checked_add(streams.pack_pos).ok_or(
+                    ArchiveError::MalformedSevenZip("packed header offset overflows"),
+                )?,
+                size,
+                folder,
+            }))
+        }
+        _ => Err(ArchiveError::MalformedSevenZip("unknown header type")),
+    }
+}
+
+impl EncodedHeader {
+    /// Unpacks the header. Only the methods 7-Zip actually uses for headers
+    /// (LZMA, or copy when compression is off) are supported.
+    pub fn decode(&self, packed: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+        let [coder] = self.folder.coders.as_slice() else {
+            return Err(ArchiveError::Unsupported("Multi-coder 7-Zip headers"));
+        };
+
+        let decoded = match coder.method.as_slice() {
+            METHOD_COPY => packed.to_vec(),
+            METHOD_LZMA => {
+                // 7z stores the 5 byte LZMA properties in the coder, not the stream
+                let mut input = coder.properties.clone();
+                input.extend_from_slice(packed);
+
+                let options = Options {
+                    unpacked_size: UnpackedSize::UseProvided(Some(self.folder.unpack_size)),
+                    ..Default::default()
+                };
+
+                // the size is the archive's word, so it isn't allocated up front
+                if self.folder.unpack_size > MAX_HEADER_SIZE {
+                    return Err(ArchiveError::MalformedSevenZip(
+                        "header is implausibly large",
+                    ));
+                }
+
+                let mut output = Vec::new();
+                lzma_rs::lzma_decompress_with_options(
+                    &mut Cursor::new(input),
+                    &mut output,
+                    &options,
+                )
+                .map_err(|e| ArchiveError::Decompress(e.to_string()))?;
+                output
+            }
+            _ => return Err(ArchiveError::Unsupported("7-Zip header compression method")),
+        };
+
+        Ok(decoded)
+    }
+}
+
+fn read_header(reader: &mut Reader) -> Result<Vec<SevenZipEntry>, ArchiveError> {
+    let mut streams = StreamsInfo::default();
+    let mut names = Vec::new();
+    let mut empty_streams = Vec::new();
+
+    loop {
+        match reader.byte()? {
+            K_END => break,
+            K_ARCHIVE_PROPERTIES => skip_properties(reader)?,
+            K_ADDITIONAL_STREAMS_INFO => {
+                read_streams_info(reader)?;
+            }
+            K_MAIN_STREAMS_INFO => streams = read_streams_info(reader)?,
+            K_FILES_INFO => (names, empty_streams) = read_files_info(reader)?,
+            _ => {
+                return Err(ArchiveError::MalformedSevenZip(
+                    "unexpected header property",
+                ));
+            }
+        }
+    }
+
+    // files with data map onto the substreams of each folder in order, the
+    // folders' packed streams one after the other
+    let overflow = || ArchiveError::MalformedSevenZip("stream offsets overflow");
+    let mut substreams = Vec::new();
+    let mut folder_offset = SIGNATURE_HEADER_SIZE
+        .checked_add(streams.pack_pos)
+        .ok_or_else(overflow)?;
+    for (folder_index, (folder, sizes)) in streams
+        .folders
+        .iter()
+        .zip(&streams.substream_sizes)
+        .enumerate()
+    {
+        let stored = matches!(folder.coders.as_slice(), [coder] if coder.method == METHOD_COPY);
+
+        let mut offset = folder_offset;
+        for &size in sizes {
+            substreams.push((offset, size, stored));
+            offset = offset.checked_add(size).ok_or_else(overflow)?;
+        }
+
+        let pack_size = streams.pack_sizes.get(folder_index).copied();
+        folder_offset = folder_offset
+            .checked_add(pack_size.unwrap_or_default())
+            .ok_or_else(overflow)?;
+    }
+
+    let mut entries = Vec::new();
+    let mut substreams = substreams.into_iter();
+
+    for (index, name) in names.into_iter().enumerate() {
+        if empty_streams.get(index).copied().unwrap_or(false) {
+            continue;
+        }
+
+        let (offset, size, stored) = substreams
+            .next()
+            .ok_or(ArchiveError::MalformedSevenZip("more files than streams"))?;
+
+        entries.push(SevenZipEntry {
+            name,
+            offset,
+            size,
+            stored,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_streams_info(reader: &mut Reader) -> Result<StreamsInfo, ArchiveError> {
+    let mut info = StreamsInfo::default();
+
+    loop {
+        match reader.byte()? {
+            K_END => break,
+            K_PACK_INFO => {
+                info.pack_pos = reader.number()?;
+                let count = reader.count()?;
+
+                loop {
+                    match reader.byte()? {
+                        K_END => break,
+                        K_SIZE => {
+                            info.pack_sizes = (0..count)
+                                .map(|_| reader.number())
+                                .collect::<Result<_, _>>()?;
+                        }
+                        K_CRC => {
+                            read_digests(reader, count)?;
+                        }
+                        _ => return Err(ArchiveError::MalformedSevenZip("unexpected pack info")),
+                    }
+                }
+            }
+            K_UNPACK_INFO => info.folders = read_unpack_info(reader)?,
+            K_SUBSTREAMS_INFO => {
+                info.substream_sizes = read_substreams_info(reader, &info.folders)?
+            }
+            _ => return Err(ArchiveError::MalformedSevenZip("unexpected streams info")),
+        }
+    }
+
+    // without a SubStreamsInfo block every folder holds a single file
+    if info.substream_sizes.is_empty() {
+        info.substream_sizes = info
+            .folders
+            .iter()
+            .map(|folder| vec![folder.unpack_size])
+            .collect();
+    }
+
+    Ok(info)
+}
+
+fn read_unpack_info(reader: &mut Reader) -> Result<Vec<Folder>, ArchiveError> {
+    if reader.byte()? != K_FOLDER {
+        return Err(ArchiveError::MalformedSevenZip("expected folder list"));
+    }
+
+    let count = reader.count()?;
+    if reader.byte()? != 0 {
+        return Err(ArchiveError::Unsupported("External 7-Zip folder lists"));
+    }
+
+    let mut folders = (0..count)
+        .map(|_| read_folder(reader))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if reader.byte()? != K_CODERS_UNPACK_SIZE {
+        return Err(ArchiveError::MalformedSevenZip("expected unpack sizes"));
+    }
+
+    // one size per coder output, we only care about the folder's final output
+    for folder in folders.iter_mut() {
+        for out in 0..folder.out_streams {
+            let size = reader.number()?;
+            if out == folder.main_out {
+                folder.unpack_size = size;
+            }
+        }
+    }
+
+    loop {
+        match reader.byte()? {
+            K_END => break,
+            K_CRC => {
+                let defined = read_digests(reader, folders.len())?;
+                for (folder, defined) in folders.iter_mut().zip(defined) {
+                    folder.crc_defined = defined;
+                }
+            }
+            _ => return Err(ArchiveError::MalformedSevenZip("unexpected unpack info")),
+        }
+    }
+
+    Ok(folders)
+}
+
+/// Reads a folder's coders and bindings. Unpack sizes come later.
+fn read_folder(reader: &mut Reader) -> Result<Folder, ArchiveError> {
+    let coder_count = reader.count()?;
+    let mut coders = Vec::with_capacity(coder_count);
+    let mut total_in = 0;
+    let mut total_out = 0;
+
+    for _ in 0..coder_count {
+        let flags = reader.byte()?;
+        if flags & 0x80 != 0 {
+            return Err(ArchiveError::Unsupported("7-Zip alternative coder methods"));
+        }
+
+        let method = reader.bytes((flags & 0x0F) as usize)?.to_vec();
+        let (inputs, outputs) = if flags & 0x10 != 0 {
+            (reader.count()?, reader.count()?)
+        } else {
+            (1, 1)
+        };
+        total_in += inputs;
+        total_out += outputs;
+
+        let properties = if flags & 0x20 != 0 {
+            let size = reader.count()?;
+            reader.bytes(size)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        coders.push(Coder { method, properties });
+    }
+
+    let bind_pairs = total_out
+        .checked_sub(1)
+        .ok_or(ArchiveError::MalformedSevenZip("folder has no outputs"))?;
+    let mut bound_outs = Vec::with_capacity(bind_pairs);
+    for _ in 0..bind_pairs {
+        reader.number()?; // in index
+        bound_outs.push(reader.number()?);
+    }
+
+    let main_out = (0..total_out)
+        .find(|&out| !bound_outs.contains(&(out as u64)))
+        .ok_or(ArchiveError::MalformedSevenZip(
+            "folder has no unbound output",
+        ))?;
+
+    let packed_streams =
+        total_in
+            .checked_sub(bind_pairs)
+            .ok_or(ArchiveError::MalformedSevenZip(
+                "folder has more bindings than inputs",
+            ))?;
+    if packed_streams > 1 {
+        for _ in 0..packed_streams {
+            reader.number()?;
+        }
+    }
+
+    Ok(Folder {
+        coders,
+        out_streams: total_out,
+        main_out,
+        ..Default::default()
+    })
+}
+
+fn read_substreams_info(
+    reader: &mut Reader,
+    folders: &[Folder],
+) -> Result<Vec<Vec<u64>>, ArchiveError> {
+    let mut counts = vec![1; folders.len()];
+    let mut sizes: Vec<Vec<u64>> = folders
+        .iter()
+        .map(|folder| vec![folder.unpack_size])
+        .collect();
+
+    loop {
+        match reader.byte()? {
+            K_END => break,
+            K_NUM_UNPACK_STREAM => {
+                for count in counts.iter_mut() {
+                    *count = reader.count()?;
+                }
+                sizes = folders
+                    .iter()
+                    .zip(&counts)
+                    .map(|(folder, &count)| match count {
+                        0 => Vec::new(),
+                        _ => vec![folder.unpack_size],
+                    })
+                    .collect();
+            }
+            K_SIZE => {
+                // all but the last size are explicit, the last is what's left
+                for ((folder, &count), folder_sizes) in folders.iter().zip(&counts).zip(&mut sizes)
+                {
+                    if count == 0 {
+                        continue;
+                    }
+
+                    folder_sizes.clear();
+                    let mut remaining = folder.unpack_size;
+                    for _ in 1..count {
+                        let size = reader.number()?;
+                        remaining = remaining
+                            .checked_sub(size)
+                            .ok_or(ArchiveError::MalformedSevenZip("substreams exceed folder"))?;
+                        folder_sizes.push(size);
+                    }
+                    folder_sizes.push(remaining);
+                }
+            }
+            K_CRC => {
+                // digests are only listed for substreams whose CRC isn't
+                // already known from the folder
+                let total = folders
+                    .iter()
+                    .zip(&counts)
+                    .map(|(folder, &count)| match count {
+                        1 if folder.crc_defined => 0,
+                        count => count,
+                    })
+                    .sum();
+                read_digests(reader, total)?;
+            }
+            _ => {
+                return Err(ArchiveError::MalformedSevenZip(
+                    "unexpected substreams info",
+                ));
+            }
+        }
+    }
+
+    Ok(sizes)
+}
+
+/// Returns file names and which files have no data stream (directories and
+/// empty files)
+fn read_files_info(reader: &mut Reader) -> Result<(Vec<String>, Vec<bool>), ArchiveError> {
+    let count = reader.count()?;
+    let mut names = vec![String::new(); count];
+    let mut empty_streams = vec![false; count];
+
+    loop {
+        let property = reader.byte()?;
+        if property == K_END {
+            break;
+        }
+
+        let size = reader.count()?;
+        let mut data = Reader::new(reader.bytes(size)?);
+
+        match property {
+            K_EMPTY_STREAM => empty_streams = data.bit_vector(count)?,
+            K_NAME => {
+                if data.byte()? != 0 {
+                    return Err(ArchiveError::Unsupported("External 7-Zip file names"));
+                }
+
+                let units: Vec<u16> = data
+                    .rest()
+                    .chunks_exact(2)
+                    .map(LittleEndian::read_u16)
+                    .collect();
+
+                names = units
+                    .split(|&unit| unit == 0)
+                    .take(count)
+                    .map(String::from_utf16_lossy)
+                    .collect();
+            }
+            _ => {} // times, attributes and so on don't matter for streaming
+        }
+    }
+
+    Ok((names, empty_streams))
+}
+
+fn skip_properties(reader: &mut Reader) -> Result<(), ArchiveError> {
+    loop {
+        if reader.byte()? == K_END {
+            return Ok(());
+        }
+
+        let size = reader.count()?;
+        reader.bytes(size)?;
+    }
+}
+
+/// Skips over a list of CRC32 digests, returning which entries have one
+fn read_digests(reader: &mut Reader, count: usize) -> Result<Vec<bool>, ArchiveError> {
+    let defined = if reader.byte()? == 0 {
+        reader.bit_vector(count)?
+    } else {
+        vec![true; count]
+    };
+
+    let digests = defined.iter().filter(|&&d| d).count();
+    reader.bytes(digests * 4)?;
+
+    Ok(defined)
+}
+
+/// Bounds-checked reader over header bytes. 7z headers come from untrusted
+/// posts, so every read can fail rather than panic.
+struct Reader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ArchiveError> {
+        if len > self.buffer.len() {
+            return Err(ArchiveError::IncompleteData);
+        }
+
+        let (head, tail) = self.buffer.split_at(len);
+        self.buffer = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8, ArchiveError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// 7z variable length integer: the number of leading 1 bits in the
+    /// first byte says how many little-endian bytes follow
+    fn number(&mut self) -> Result<u64, ArchiveError> {
+        let first = self.byte()?;
+        let mut mask = 0x80;
+        let mut value = 0;
+
+        for i in 0..8 {
+            if first & mask == 0 {
+                let high = (first & (mask.wrapping_sub(1))) as u64;
+                return Ok(value | (high << (8 * i)));
+            }
+
+            value |= (self.byte()? as u64) << (8 * i);
+            mask >>= 1;
+        }
+
+        Ok(value)
+    }
+
+    /// A number used as a count or length. Anything that doesn't fit in the
+    /// remaining buffer can't be valid, which stops huge allocations.
+    fn count(&mut self) -> Result<usize, ArchiveError> {
+        let count = self.number()?;
+        usize::try_from(count)
+            .ok()
+            .filter(|&count| count <= self.buffer.len().max(1) * 8)
+            .ok_or(ArchiveError::MalformedSevenZip("count out of range"))
+    }
+
+    fn bit_vector(&mut self, count: usize) -> Result<Vec<bool>, ArchiveError> {
+        let bytes = self.bytes(count.div_ceil(8))?;
+
+        Ok((0..count)
+            .map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16_name(name: &str) -> Vec<u8> {
+        name.encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    /// Header for a store-mode archive of two files in one folder, written
+    /// the way 7-Zip lays it out with `-mx0 -mhc=off`
+    fn stored_header(sizes: [u8; 2], names: [&str; 2]) -> Vec<u8> {
+        let total = sizes[0] + sizes[1];
+        let mut header = vec![
+            K_HEADER,
+            K_MAIN_STREAMS_INFO,
+            K_PACK_INFO,
+            0, // pack pos
+            1, // one pack stream
+            K_SIZE,
+            total,
+            K_END,
+            K_UNPACK_INFO,
+            K_FOLDER,
+            1, // one folder
+            0, // not external
+            1, // one coder
+            0x01,
+            0x00, // copy method
+            K_CODERS_UNPACK_SIZE,
+            total,
+            K_END,
+            K_SUBSTREAMS_INFO,
+            K_NUM_UNPACK_STREAM,
+            2,
+            K_SIZE,
+            sizes[0],
+            K_END,
+            K_END,
+            K_FILES_INFO,
+            2, // two files
+        ];
+
+        let mut name_data = vec![0];
+        for name in names {
+            name_data.extend(utf16_name(name));
+        }
+        header.push(K_NAME);
+        header.push(name_data.len() as u8);
+        header.extend(name_data);
+        header.extend([K_END, K_END]);
+
+        header
+    }
+
+    #[test]
+    fn test_parse_signature_header() {
+        let mut buffer = SIGNATURE.to_vec();
+        buffer.extend_from_slice(&[0, 4]); // version
+        buffer.extend_from_slice(&[0; 4]); // start header CRC
+        buffer.extend_from_slice(&1000u64.to_le_bytes());
+        buffer.extend_from_slice(&50u64.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+
+        let next = parse_signature_header(&buffer).unwrap();
+        assert_eq!(next.offset, 1032);
+        assert_eq!(next.size, 50);
+
+        assert!(parse_signature_header(b"Rar!\x1a\x07\x00").is_err());
+    }
+
+    #[test]
+    fn test_parse_stored_header() {
+        let header = stored_header([10, 20], ["sample.mkv", "movie.mkv"]);
+
+        let Header::Plain(entries) = parse_header(&header).unwrap() else {
+            panic!("Expected plain header");
+        };
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "sample.mkv");
+        assert_eq!((entries[0].offset, entries[0].size), (32, 10));
+        assert_eq!(entries[1].name, "movie.mkv");
+        assert_eq!((entries[1].offset, entries[1].size), (42, 20));
+        assert!(entries.iter().all(|entry| entry.stored));
+    }
+
+    #[test]
+    fn test_offsets_overflow() {
+        let mut header = stored_header([10, 20], ["sample.mkv", "movie.mkv"]);
+        // pack pos of u64::MAX
+        header.splice(3..4, [0xFF; 9]);
+
+        assert!(matches!(
+            parse_header(&header),
+            Err(ArchiveError::MalformedSevenZip(_))
+        ));
+
+        let encoded = [
+            [K_ENCODED_HEADER, K_MAIN_STREAMS_INFO, K_PACK_INFO].as_slice(),
+            &[0xFF; 9],
+            &[1, K_SIZE, 5, K_END],
+            &[K_UNPACK_INFO, K_FOLDER, 1, 0, 1, 0x01, 0x00],
+            &[K_CODERS_UNPACK_SIZE, 5, K_END, K_END],
+        ]
+        .concat();
+        assert!(matches!(
+            parse_header(&encoded),
+            Err(ArchiveError::MalformedSevenZip(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_rejects_huge_header() {
+        let header = EncodedHeader {
+            offset: SIGNATURE_HEADER_SIZE,
+            size: 16,
+            folder: Folder {
+                coders: vec![Coder {
+                    method: METHOD_LZMA.to_vec(),
+                    properties: vec![0x5D, 0, 0, 1, 0],
+                }],
+                out_streams: 1,
+                unpack_size: 1 << 63,
+                ..Default::default()
+            },
+        };
+
+        assert!(matches!(
+            header.decode(&[0; 16]),
+            Err(ArchiveError::MalformedSevenZip(_))
+        ));
+    }
+
+    #[test]
+    fn test_number_encoding() {
+        let cases: [(&[u8], u64); 4] = [
+            (&[0x7F], 0x7F),
+            (&[0x80, 0xFF], 0xFF),
+            (&[0xC1, 0x02, 0x03], 0x01_0302),
+            (&[0xFF, 1, 0, 0, 0, 0, 0, 0, 0], 1),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Reader::new(input).number().unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::archive::error::ArchiveError;
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4B50;
+const CENTRAL_FILE_HEADER_SIGNATURE: u32 = 0x0201_4B50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4B50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4B50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4B50;
+
+pub const LOCAL_FILE_HEADER_SIZE: usize = 30;
+const CENTRAL_FILE_HEADER_SIZE: usize = 46;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+pub const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: u64 = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+
+/// The end record sits within the last 22 bytes plus a comment of at most
+/// 64 KiB, so this much of the last volume always contains it
+pub const MAX_END_RECORD_SIZE: u64 = (END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize) as u64;
+
+/// Far more than the central directory of a release's few files takes, so
+/// a bigger one is taken to be corrupt rather than downloaded
+pub const MAX_CENTRAL_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// A position within a split archive. ZIP offsets are relative to the volume
+/// ("disk") they point into rather than the whole archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskOffset {
+    pub disk: u32,
+    pub offset: u64,
+}
+
+/// Where the central directory lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CentralDirectory {
+    pub start: DiskOffset,
+    pub size: u64,
+    pub entries: u64,
+}
+
+impl CentralDirectory {
+    /// Checks the directory is small enough to download before its size,
+    /// read from the end record, is trusted with an allocation
+    pub fn ensure_bounded(&self) -> Result<(), ArchiveError> {
+        if self.size > MAX_CENTRAL_DIRECTORY_SIZE {
+            return Err(ArchiveError::MalformedZip(
+                "central directory is implausibly large",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum EndRecord {
+    Plain(CentralDirectory),
+    /// The archive uses ZIP64 and the real sizes live in a separate record.
+    /// Fetch [`ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE`] bytes at `DiskOffset` and
+    /// parse them with [`parse_zip64_end_record`].
+    Zip64(DiskOffset),
+}
+
+/// A file in the central directory
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    /// Where the entry's local file header starts
+    pub header: DiskOffset,
+    pub compressed_size: u64,
+    pub method: u16,
+    pub encrypted: bool,
+}
+
+impl ZipEntry {
+    /// Checks the entry can be streamed as-is, i.e. isn't encrypted or
+    /// compressed
+    pub fn ensure_stored(&self) -> Result<(), ArchiveError> {
+        if self.encrypted {
+            return Err(ArchiveError::Encrypted);
+        }
+
+        match self.method {
+            METHOD_STORED => Ok(()),
+            METHOD_DEFLATE => Err(ArchiveError::CompressedEntry(self.name.clone())),
+            _ => Err(ArchiveError::Unsupported("ZIP compression method")),
+        }
+    }
+}
+
+/// Finds the end of central directory record in the tail of the last volume.
+///
+/// # Structure
+/// ```text
+/// 0         4         6         8         10        12        16        20        22
+/// +---------+---------+---------+---------+---------+---------+---------+---------+
+/// | Sig     | Disk    | CD      | Entries | Entries | CD      | CD      | Comment |
+/// | (4B)    | (2B)    | Disk(2B)| Disk(2B)| (2B)    | Size(4B)| Off (4B)| Len (2B)|
+/// +---------+---------+---------+---------+---------+---------+---------+---------+
+/// ```
+///
+/// A ZIP64 archive puts a 20 byte locator right before this record.
+pub fn parse_end_record(tail: &[u8]) -> Result<EndRecord, ArchiveError> {
+    // search backwards, the comment could contain the signature too
+    let position = (0..=tail.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE))
+        .rev()
+        .find(|&i| {
+            tail.get(i..i + END_OF_CENTRAL_DIRECTORY_SIZE)
+                .is_some_and(|record| {
+                    let comment_len = LittleEndian::read_u16(&record[20..22]) as usize;
+                    LittleEndian::read_u32(record) == END_OF_CENTRAL_DIRECTORY_SIGNATURE
+                        && i + END_OF_CENTRAL_DIRECTORY_SIZE + comment_len <= tail.len()
+                })
+        })
+        .ok_or(ArchiveError::MalformedZip(
+            "end of central directory not found",
+        ))?;
+
+    if let Some(locator) = position
+        .checked_sub(ZIP64_LOCATOR_SIZE)
+        .and_then(|start| tail.get(start..position))
+        .filter(|locator| LittleEndian::read_u32(locator) == ZIP64_LOCATOR_SIGNATURE)
+    {
+        return Ok(EndRecord::Zip64(DiskOffset {
+            disk: LittleEndian::read_u32(&locator[4..8]),
+            offset: LittleEndian::read_u64(&locator[8..16]),
+        }));
+    }
+
+    let record = &tail[position..position + END_OF_CENTRAL_DIRECTORY_SIZE];
+    Ok(EndRecord::Plain(CentralDirectory {
+        start: DiskOffset {
+            disk: LittleEndian::read_u16(&record[6..8]) as u32,
+            offset: LittleEndian::read_u32(&record[16..20]) as u64,
+        },
+        size: LittleEndian::read_u32(&record[12..16]) as u64,
+        entries: LittleEndian::read_u16(&record[10..12]) as u64,
+    }))
+}
+
+/// Turns a disk-relative position into an absolute offset into the archive
+/// (i.e. all volumes concatenated), given each volume's size in order
+pub fn absolute_offset(location: DiskOffset, volume_sizes: &[u64]) -> Result<u64, ArchiveError> {
+    absolute_range(location, 0, volume_sizes)
+}
+
+/// Like [`absolute_offset`], also checking that `size` bytes from there are
+/// within the archive. The records are read from the archive, so a corrupt
+/// one could point anywhere.
+pub fn absolute_range(
+    location: DiskOffset,
+    size: u64,
+    volume_sizes: &[u64],
+) -> Result<u64, ArchiveError> {
+    let disk = location.disk as usize;
+    if disk >= volume_sizes.len() {
+        return Err(ArchiveError::MalformedZip(
+            "disk number past the last volume",
+        ));
+    }
+
+    let start = volume_sizes[..disk]
+        .iter()
+        .try_fold(location.offset, |start, size| start.checked_add(*size));
+    let total = volume_sizes
+        .iter()
+        .try_fold(0u64, |total, size| total.checked_add(*size));
+
+    match (start, total) {
+        (Some(start), Some(total))
+            if start < total && start.checked_add(size).is_some_and(|end| end <= total) =>
+        {
+            Ok(start)
+        }
+        _ => Err(ArchiveError::MalformedZip(
+            "offset past the end of the archive",
+        )),
+    }
+}
+
+/// Parses the ZIP64 end of central directory record, which has the same
+/// fields as the regular one widened to 32 and 64 bits
+pub fn parse_zip64_end_record(buffer: &[u8]) -> Result<CentralDirectory, ArchiveError> {
+    let record = buffer
+        .get(..ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE as usize)
+        .ok_or(ArchiveError::IncompleteData)?;
+
+    if LittleEndian::read_u32(record) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+        return Err(ArchiveError::MalformedZip(
+            "ZIP64 end of central directory not found",
+        ));
+    }
+
+    Ok(CentralDirectory {
+        start: DiskOffset {
+            disk: LittleEndian::read_u32(&record[20..24]),
+            offset: LittleEndian::read_u64(&record[48..56]),
+        },
+        size: LittleEndian::read_u64(&record[40..48]),
+        entries: LittleEndian::read_u64(&record[32..40]),
+    })
+}
+
+/// Parses the file headers of the central directory.
+///
+/// # Structure
+/// ```text
+/// 0         4         10        12        20        24        28        30        32        34        36        42        46
+/// +---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+
+/// | Sig     | Versions| Method  | Time,   | Packed  | Unpacked| Name    | Extra   | Comment | Disk    | Attrs   | Header  |
+/// | (4B)    | Flags   | (2B)    | Date,   | Size(4B)| Size(4B)| Len (2B)| Len (2B)| Len (2B)| (2B)    | (6B)    | Off (4B)|
+/// |         | (6B)    |         | CRC (8B)|         |         |         |         |         |         |         |         |
+/// +---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+---------+
+/// ```
+/// followed by the name, extra fields and comment.
+pub fn parse_central_directory(buffer: &[u8]) -> Result<Vec<ZipEntry>, ArchiveError> {
+    let mut entries = Vec::new();
+    let mut cursor = 0;
+
+    while let Some(header) = buffer.get(cursor..cursor + CENTRAL_FILE_HEADER_SIZE) {
+        if LittleEndian::read_u32(header) != CENTRAL_FILE_HEADER_SIGNATURE {
+            break;
+        }
+
+        let flags = LittleEndian::read_u16(&header[8..10]);
+        let method = LittleEndian::read_u16(&header[10..12]);
+        let name_len = LittleEndian::read_u16(&header[28..30]) as usize;
+        let extra_len = LittleEndian::read_u16(&header[30..32]) as usize;
+        let comment_len = LittleEndian::read_u16(&header[32..34]) as usize;
+
+        let name_start = cursor + CENTRAL_FILE_HEADER_SIZE;
+        let extra_start = name_start + name_len;
+        let name = buffer
+            .get(name_start..extra_start)
+            .ok_or(ArchiveError::IncompleteData)?;
+        let extra = buffer
+            .get(extra_start..extra_start + extra_len)
+            .ok_or(ArchiveError::IncompleteData)?;
+
+        let mut compressed_size = LittleEndian::read_u32(&header[20..24]) as u64;
+        let mut disk = LittleEndian::read_u16(&header[34..36]) as u32;
+        let mut offset = LittleEndian::read_u32(&header[42..46]) as u64;
+
+        // fields that don't fit are set to all ones and moved to the ZIP64
+        // extra field, which lists only those, in this order
+        if let Some(mut zip64) = find_extra_field(extra, ZIP64_EXTRA_FIELD) {
+            let mut next_u64 = || -> Result<u64, ArchiveError> {
+                let value = zip64.get(..8).ok_or(ArchiveError::IncompleteData)?;
+                zip64 = &zip64[8..];
+                Ok(LittleEndian::read_u64(value))
+            };
+
+            if LittleEndian::read_u32(&header[24..28]) == u32::MAX {
+                next_u64()?; // unpacked size
+            }
+            if compressed_size == u32::MAX as u64 {
+                compressed_size = next_u64()?;
+            }
+            if offset == u32::MAX as u64 {
+                offset = next_u64()?;
+            }
+            if disk == u16::MAX as u32 {
+                disk = zip64
+                    .get(..4)
+                    .map(LittleEndian::read_u32)
+                    .ok_or(ArchiveError::IncompleteData)?;
+            }
+        }
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            header: DiskOffset { disk, offset },
+            compressed_size,
+            method,
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+        });
+
+        cursor = extra_start + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+/// Returns the length of the local file header at the start of `buffer`,
+/// i.e. how far past the header offset the entry's data starts. The name and
+/// extra field here can differ from the central directory's copy.
+///
+/// # Structure
+/// ```text
+/// 0         4         26        28        30
+/// +---------+---------+---------+---------+
+/// | Sig     | Fields  | Name    | Extra   |
+/// | (4B)    | (22B)   | Len (2B)| Len (2B)|
+/// +---------+---------+---------+---------+
+/// ```
+pub fn local_header_size(buffer: &[u8]) -> Result<u64, ArchiveError> {
+    let header = buffer
+        .get(..LOCAL_FILE_HEADER_SIZE)
+        .ok_or(ArchiveError::IncompleteData)?;
+
+    if LittleEndian::read_u32(header) != LOCAL_FILE_HEADER_SIGNATURE {
+        return Err(ArchiveError::MalformedZip("local file header not found"));
+    }
+
+    let name_len = LittleEndian::read_u16(&header[26..28]) as u64;
+    let extra_len = LittleEndian::read_u16(&header[28..30]) as u64;
+
+    Ok(LOCAL_FILE_HEADER_SIZE as u64 + name_len + extra_len)
+}
+
+fn find_extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let field_id = LittleEndian::read_u16(&extra[0..2]);
+        let size = LittleEndian::read_u16(&extra[2..4]) as usize;
+        let data = extra.get(4..4 + size)?;
+
+        if field_id == id {
+            return Some(data);
+        }
+        extra = &extra[4 + size..];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn central_header(name: &str, method: u16, size: u32, disk: u16, offset: u32) -> Vec<u8> {
+        let mut header = CENTRAL_FILE_HEADER_SIGNATURE.to_le_bytes().to_vec();
+        header.extend_from_slice(&[20, 0, 20, 0, 0, 0]); // versions, flags
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&[0; 8]); // time, date, CRC
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&[0; 4]); // extra, comment
+        header.extend_from_slice(&disk.to_le_bytes());
+        header.extend_from_slice(&[0; 6]); // attributes
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+
+        header
+    }
+
+    fn end_record(disk: u16, size: u32, offset: u32, comment: &[u8]) -> Vec<u8> {
+        let mut record = END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes().to_vec();
+        record.extend_from_slice(&disk.to_le_bytes());
+        record.extend_from_slice(&disk.to_le_bytes());
+        record.extend_from_slice(&[1, 0, 1, 0]); // entries
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        record.extend_from_slice(comment);
+
+        record
+    }
+
+    #[test]
+    fn test_parse_end_record() {
+        let mut tail = vec![0xAB; 100];
+        tail.extend(end_record(2, 56, 1234, b"comment"));
+
+        let EndRecord::Plain(directory) = parse_end_record(&tail).unwrap() else {
+            panic!("Expected plain end record");
+        };
+
+        assert_eq!(
+            directory.start,
+            DiskOffset {
+                disk: 2,
+                offset: 1234
+            }
+        );
+        assert_eq!(directory.size, 56);
+        assert_eq!(directory.entries, 1);
+        assert!(directory.ensure_bounded().is_ok());
+
+        let tail = end_record(0, u32::MAX, 0, b"");
+        let EndRecord::Plain(directory) = parse_end_record(&tail).unwrap() else {
+            panic!("Expected plain end record");
+        };
+        assert!(directory.ensure_bounded().is_err());
+
+        assert!(parse_end_record(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_parse_zip64_locator() {
+        let mut tail = ZIP64_LOCATOR_SIGNATURE.to_le_bytes().to_vec();
+        tail.extend_from_slice(&3u32.to_le_bytes());
+        tail.extend_from_slice(&5_000_000_000u64.to_le_bytes());
+        tail.extend_from_slice(&4u32.to_le_bytes());
+        tail.extend(end_record(u16::MAX, u32::MAX, u32::MAX, b""));
+
+        let EndRecord::Zip64(location) = parse_end_record(&tail).unwrap() else {
+            panic!("Expected ZIP64 end record");
+        };
+
+        assert_eq!(
+            location,
+            DiskOffset {
+                disk: 3,
+                offset: 5_000_000_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_central_directory() {
+        let mut directory = central_header("sample.mkv", METHOD_STORED, 100, 0, 4);
+        directory.extend(central_header("movie.mkv", METHOD_DEFLATE, 5000, 1, 20));
+
+        let entries = parse_central_directory(&directory).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "sample.mkv");
+        assert_eq!(entries[0].header, DiskOffset { disk: 0, offset: 4 });
+        assert!(entries[0].ensure_stored().is_ok());
+
+        assert_eq!(
+            entries[1].header,
+            DiskOffset {
+                disk: 1,
+                offset: 20
+            }
+        );
+        assert_eq!(entries[1].compressed_size, 5000);
+        assert!(matches!(
+            entries[1].ensure_stored(),
+            Err(ArchiveError::CompressedEntry(name)) if name == "movie.mkv"
+        ));
+    }
+
+    #[test]
+    fn test_absolute_offset() {
+        let sizes = [100, 100, 50];
+
+        let location = DiskOffset {
+            disk: 2,
+            offset: 10,
+        };
+        assert_eq!(absolute_offset(location, &sizes).unwrap(), 210);
+
+        let location = DiskOffset { disk: 3, offset: 0 };
+        assert!(absolute_offset(location, &sizes).is_err());
+
+        let location = DiskOffset {
+            disk: 1,
+            offset: u64::MAX,
+        };
+        assert!(absolute_offset(location, &sizes).is_err());
+    }
+
+    #[test]
+    fn test_absolute_range() {
+        let sizes = [100, 100, 50];
+        let location = DiskOffset {
+            disk: 1,
+            offset: 100,
+        };
+
+        assert_eq!(absolute_range(location, 50, &sizes).unwrap(), 200);
+        assert!(absolute_range(location, 51, &sizes).is_err());
+        assert!(absolute_range(location, u64::MAX, &sizes).is_err());
+    }
+
+    #[test]
+    fn test_local_header_size() {
+        let mut header = LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes().to_vec();
+        header.extend_from_slice(&[0; 22]);
+        header.extend_from_slice(&9u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+
+        assert_eq!(local_header_size(&header).unwrap(), 43);
+        assert!(local_header_size(&header[..20]).is_err());
+    }
+}
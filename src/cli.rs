@@ -0,0 +1,206 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    archive::par2::DownloadTask,
+    error::RestError,
+    media,
+    nntp::yenc::extract_filename,
+    nzb::{self, error::NzbError},
+    scheduler::{adaptive::AdaptiveScheduler, error::SchedulerError},
+    server::AppState,
+    session::{
+        SessionMode, SessionOutput,
+        create::create_session,
+        resolve::{resolve_par2_manifest, resolve_tasks},
+    },
+};
+
+/// Starts a download session for an NZB on disk and waits for it to finish
+///
+/// # Returns
+/// - Where the file was saved, `None` if the download failed
+pub async fn download(state: &AppState, path: &Path) -> Result<Option<SessionOutput>, RestError> {
+    let content = read_nzb(path).await?;
+    let name = path.file_name().map(|name| name.to_string_lossy());
+
+    let session = create_session(state, &content, name.as_deref(), SessionMode::Download).await?;
+    info!("Downloading {}", session.title);
+
+    session.finished().await;
+
+    Ok(session.output.get().cloned())
+}
+
+/// Prints what streaming an NZB on disk would involve, without streaming it
+pub async fn inspect(scheduler: &Arc<AdaptiveScheduler>, path: &Path) -> Result<(), RestError> {
+    let nzb = nzb::parse(&read_nzb(path).await?)?;
+    println!("{}", nzb.meta.title.as_deref().unwrap_or("untitled"));
+
+    println!("\nFiles");
+    let classified = [
+        ("PAR2", &nzb.par2),
+        ("RAR", &nzb.rar),
+        ("7z", &nzb.sevenzip),
+        ("ZIP", &nzb.zip),
+        ("split", &nzb.split),
+        ("video", &nzb.video),
+        ("obfuscated", &nzb.obfuscated),
+    ];
+    for (kind, files) in classified {
+        for file in files {
+            let size: u64 = file
+                .segments
+                .iter()
+                .map(|segment| segment.size as u64)
+                .sum();
+            println!("  {kind:<10} {size:>14}  {}", posted_name(file));
+        }
+    }
+    let availability = scheduler.check_availability(&nzb, None).await?;
+
+    // read once, obfuscated files are named from it below
+    let mut par2 = None;
+    if !nzb.par2.is_empty() {
+        println!("\nPAR2 set");
+        match resolve_par2_manifest(&nzb, scheduler).await {
+            Ok(manifest) => {
+                for info in manifest
+                    .files
+                    .values()
+                    .sorted_by(|a, b| a.real_filename.cmp(&b.real_filename))
+                {
+                    println!(
+                        "  {:<40} {:>14}  {} slices",
+                        info.real_filename,
+                        info.size,
+                        info.slice_crcs.len()
+                    );
+                }
+                par2 = Some(manifest);
+            }
+            Err(e) => println!("  unreadable: {e}"),
+        }
+    }
+
+    let scratch_dir = scratch_dir().await?;
+    let resolved = resolve_tasks(nzb, par2, scheduler, &scratch_dir).await;
+    let _ = tokio::fs::remove_dir_all(&scratch_dir).await;
+    let (tasks, filename) = resolved?;
+
+    // obfuscated volumes are listed under the names PAR2 gives them
+    println!(
+        "\nLayout of {}",
+        filename.as_deref().unwrap_or("unnamed file")
+    );
+    let mut stream_offset = 0;
+    for task in &tasks {
+        let name = task
+            .path()
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let posted = posted_name(task.nzb());
+        let renamed = if posted == name {
+            String::new()
+        } else {
+            format!(" (posted as {posted})")
+        };
+        println!(
+            "  {name}{renamed}: {} bytes at {}, stream offset {stream_offset}",
+            task.length(),
+            task.offset()
+        );
+        stream_offset += task.length();
+    }
+
+    let first_data = tasks
+        .first()
+        .map(DownloadTask::first_segment_data)
+        .unwrap_or_default();
+    let media = media::probe(&first_data, stream_offset);
+    println!("\nContainer");
+    println!("  {:?} ({})", media.container, media.mime_type);
+    for track in &media.tracks {
+        println!(
+            "  track {}: {:?} {} {}",
+            track.number,
+            track.kind,
+            track.codec,
+            track.language.as_deref().unwrap_or("und")
+        );
+    }
+
+    println!("\nArticles");
+    for damaged in &availability.damaged {
+        println!("  {}: {} missing", damaged.name, damaged.missing);
+    }
+    println!(
+        "  {} of {} available ({:.1}%)",
+        availability.available, availability.articles, availability.available_percent
+    );
+    println!(
+        "  {} bytes missing, {} bytes of PAR2 recovery data, {}",
+        availability.missing_bytes,
+        availability.recovery_bytes,
+        if availability.repairable {
+            "repairable"
+        } else {
+            "not repairable"
+        }
+    );
+
+    Ok(())
+}
+
+/// Writes the file an NZB on disk makes up to stdout, in order
+pub async fn cat(scheduler: &Arc<AdaptiveScheduler>, path: &Path) -> Result<(), RestError> {
+    let nzb = nzb::parse(&read_nzb(path).await?)?;
+
+    let scratch_dir = scratch_dir().await?;
+    let resolved = resolve_tasks(nzb, None, scheduler, &scratch_dir).await;
+    let _ = tokio::fs::remove_dir_all(&scratch_dir).await;
+    let (tasks, filename) = resolved?;
+    info!(
+        "Writing {} to stdout",
+        filename.as_deref().unwrap_or("stream")
+    );
+
+    match scheduler
+        .write_stream(&tasks, &mut tokio::io::stdout())
+        .await
+    {
+        Ok(written) => info!("Wrote {written} bytes"),
+        // the player quit, that's its call
+        Err(SchedulerError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+async fn read_nzb(path: &Path) -> Result<String, RestError> {
+    let bytes = tokio::fs::read(path).await.map_err(NzbError::Read)?;
+    Ok(nzb::source::decode(&bytes)?)
+}
+
+/// Somewhere for the commands to put the first segments they download, which
+/// they remove once the tasks are resolved
+async fn scratch_dir() -> Result<PathBuf, SchedulerError> {
+    let dir = std::env::temp_dir().join(format!("nzb-streamer-{}", Uuid::new_v4()));
+    tokio::fs::create_dir_all(&dir).await?;
+
+    Ok(dir)
+}
+
+/// The filename in the subject, or the subject if there isn't one
+fn posted_name(file: &nzb_rs::File) -> &str {
+    extract_filename(&file.subject).unwrap_or(&file.subject)
+}
@@ -0,0 +1,36 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+use tokio::task::JoinError;
+
+use crate::scheduler::error::SchedulerError;
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("Error downloading volumes")]
+    Scheduler(#[from] SchedulerError),
+
+    #[error("Error writing download to disk")]
+    Io(#[from] io::Error),
+
+    #[error("Error in background verification")]
+    Join(#[from] JoinError),
+
+    #[error("{0} segments are missing and there's no PAR2 data to check the download against")]
+    Incomplete(usize),
+
+    #[error("Volumes are damaged and the NZB has no PAR2 files to repair them")]
+    NoRecoveryData,
+
+    #[error("Could not run par2, is par2cmdline installed?")]
+    Par2Unavailable(#[source] io::Error),
+
+    #[error("PAR2 repair failed: {0}")]
+    RepairFailed(String),
+
+    #[error("Volumes still damaged after PAR2 repair: {}", .0.join(", "))]
+    Damaged(Vec<String>),
+
+    #[error("Volume '{0}' is shorter than its archive headers say")]
+    Truncated(PathBuf),
+}
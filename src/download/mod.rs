@@ -0,0 +1,390 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use memmap2::{Mmap, MmapMut};
+use parking_lot::RwLock;
+use tokio::{process::Command, task};
+use tracing::{info, warn};
+
+use crate::{
+    archive::{
+        self,
+        par2::{DownloadTask, Par2Manifest, Verification},
+    },
+    download::error::DownloadError,
+    nntp::yenc::extract_filename,
+    scheduler::{adaptive::AdaptiveScheduler, share::Tenant},
+};
+
+pub mod error;
+
+/// par2cmdline, which every Usenet client ships with or depends on
+const PAR2_BINARY: &str = "par2";
+
+/// How much of a volume is copied into the session file per lock of the mmap
+const EXTRACT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// What the PAR2 set says about the downloaded volumes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    Intact,
+    /// Names of the volumes that need repairing
+    Damaged(Vec<String>),
+    /// No PAR2 checksums cover the volumes
+    Unverified,
+}
+
+/// Downloads the volumes behind `tasks` and the PAR2 files in full into
+/// `dir`, repairs them if the PAR2 set says they're damaged, then copies the
+/// stream data out of them into `mmap`.
+///
+/// `par2` should be in the order [`crate::nzb::Nzb::par2_candidates`] gives,
+/// so par2cmdline starts from the index file.
+pub async fn download_in_full(
+    scheduler: &AdaptiveScheduler,
+    tasks: Vec<DownloadTask>,
+    par2: &[&nzb_rs::File],
+    dir: &Path,
+    mmap: Arc<RwLock<MmapMut>>,
+    tenant: &Arc<Tenant>,
+) -> Result<(), DownloadError> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let volumes: Vec<_> = tasks
+        .iter()
+        .map(|task| (task.nzb(), volume_path(dir, task)))
+        .collect();
+    let par2_files: Vec<_> = par2
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            // only the name, a subject can't put the file outside `dir`
+            let name = extract_filename(&file.subject)
+                .and_then(|name| Path::new(name).file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| format!("{index}.par2"));
+            (*file, dir.join(name))
+        })
+        .collect();
+
+    let files: Vec<_> = volumes.iter().chain(&par2_files).cloned().collect();
+    let missing = scheduler.download_files(&files, tenant).await?;
+
+    // the PAR2 files come after the volumes
+    let volume_paths: Vec<_> = volumes
+        .into_iter()
+        .zip(missing)
+        .map(|((_, path), missing)| (path, missing))
+        .collect();
+    let par2_paths: Vec<_> = par2_files.into_iter().map(|(_, path)| path).collect();
+    let missing: usize = volume_paths.iter().map(|(_, missing)| missing).sum();
+
+    match check(&par2_paths, &volume_paths).await? {
+        Check::Intact => info!("Verified {} volumes against PAR2", volume_paths.len()),
+        Check::Unverified if missing == 0 => {
+            warn!("No PAR2 checksums for the volumes, extracting unverified")
+        }
+        Check::Unverified => return Err(DownloadError::Incomplete(missing)),
+        Check::Damaged(damaged) => {
+            info!("Repairing {}", damaged.join(", "));
+            repair(&par2_paths, dir).await?;
+
+            if let Check::Damaged(damaged) = check(&par2_paths, &volume_paths).await? {
+                return Err(DownloadError::Damaged(damaged));
+            }
+        }
+    }
+
+    let dir = dir.to_path_buf();
+    task::spawn_blocking(move || extract(&tasks, &dir, &mmap)).await?
+}
+
+/// Reads the PAR2 files and checks the volumes against them, off the async
+/// runtime since it reads every byte
+async fn check(par2: &[PathBuf], volumes: &[(PathBuf, usize)]) -> Result<Check, DownloadError> {
+    let (par2, volumes) = (par2.to_vec(), volumes.to_vec());
+
+    task::spawn_blocking(move || match read_manifest(&par2) {
+        Some(manifest) => verify(&manifest, &volumes),
+        None => Ok(Check::Unverified),
+    })
+    .await?
+}
+
+/// Merges the packets of every readable PAR2 file
+pub fn read_manifest(par2: &[PathBuf]) -> Option<Par2Manifest> {
+    par2.iter()
+        .filter_map(|path| {
+            archive::parse_file(path)
+                .inspect_err(|e| warn!("Skipping unusable PAR2 file {}: {e}", path.display()))
+                .ok()
+        })
+        .reduce(|mut manifest, other| {
+            manifest.merge(other);
+            manifest
+        })
+}
+
+/// Checks each volume against the slice checksums of the PAR2 file
+/// description with its name
+///
+/// # Arguments
+/// - `volumes`: each volume's path, with the number of its segments that
+///   couldn't be downloaded
+pub fn verify(
+    manifest: &Par2Manifest,
+    volumes: &[(PathBuf, usize)],
+) -> Result<Check, DownloadError> {
+    let mut damaged = Vec::new();
+    let mut verified = false;
+
+    for (path, missing) in volumes {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let verification = match manifest.file_named(&name) {
+            Some(info) => {
+                let file = std::fs::File::open(path)?;
+                let data = unsafe { Mmap::map(&file)? };
+                manifest.verify(info, &data)
+            }
+            None => {
+                warn!("'{name}' isn't described by the PAR2 set, can't verify it");
+                Verification::Unknown
+            }
+        };
+
+        match verification {
+            Verification::Intact => verified = true,
+            Verification::Damaged(slices) => {
+                warn!("'{name}' has {slices} damaged slices");
+                damaged.push(name.into_owned());
+            }
+            // there's nothing to check it against, but a hole is still a hole
+            Verification::Unknown if *missing > 0 => {
+                warn!("'{name}' is missing {missing} segments");
+                damaged.push(name.into_owned());
+            }
+            Verification::Unknown => {}
+        }
+    }
+
+    Ok(match (damaged.is_empty(), verified) {
+        (false, _) => Check::Damaged(damaged),
+        (true, true) => Check::Intact,
+        (true, false) => Check::Unverified,
+    })
+}
+
+/// Runs par2cmdline once, so a missing install shows up at startup rather
+/// than when a download first needs repairing
+pub async fn check_par2() -> Result<(), DownloadError> {
+    Command::new(PAR2_BINARY)
+        .arg("-V")
+        .output()
+        .await
+        .map_err(DownloadError::Par2Unavailable)?;
+
+    Ok(())
+}
+
+/// Runs `par2 repair` in `dir`. par2cmdline finds the volumes by the names in
+/// the PAR2 set and writes repaired ones in their place.
+pub async fn repair(par2: &[PathBuf], dir: &Path) -> Result<(), DownloadError> {
+    let (index, others) = par2.split_first().ok_or(DownloadError::NoRecoveryData)?;
+
+    let output = Command::new(PAR2_BINARY)
+        .arg("repair")
+        .arg(index)
+        .args(others)
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(DownloadError::Par2Unavailable)?;
+
+    if !output.status.success() {
+        // par2cmdline explains itself in the last line it prints
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let reason = stdout
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(str::trim)
+            .map(str::to_string)
+            .unwrap_or_else(|| output.status.to_string());
+        return Err(DownloadError::RepairFailed(reason));
+    }
+
+    Ok(())
+}
+
+/// Copies each volume's stream data into the session file, one after the
+/// other, the same layout the streaming downloads fill in piece by piece
+pub fn extract(
+    tasks: &[DownloadTask],
+    dir: &Path,
+    mmap: &RwLock<MmapMut>,
+) -> Result<(), DownloadError> {
+    let mut offset = 0;
+
+    for task in tasks {
+        let path = volume_path(dir, task);
+        let file = std::fs::File::open(&path)?;
+        let volume = unsafe { Mmap::map(&file)? };
+
+        let start = *task.offset() as usize;
+        let data = volume
+            .get(start..start + *task.length() as usize)
+            .ok_or(DownloadError::Truncated(path))?;
+
+        for chunk in data.chunks(EXTRACT_CHUNK_SIZE) {
+            let mut guard = mmap.write();
+            guard
+                .get_mut(offset..offset + chunk.len())
+                .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?
+                .copy_from_slice(chunk);
+            offset += chunk.len();
+        }
+    }
+
+    mmap.read().flush()?;
+    Ok(())
+}
+
+/// Moves `source` to `dest` in one step, so nothing watching the destination
+/// sees half a file. Across filesystems it's copied next to `dest` first and
+/// renamed into place.
+pub async fn move_into(source: &Path, dest: &Path) -> io::Result<()> {
+    match tokio::fs::rename(source, dest).await {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result,
+    }
+
+    copy_into(source, dest).await?;
+    tokio::fs::remove_file(source).await
+}
+
+/// Copies `source` next to `dest` under a hidden name, then renames it into
+/// place
+async fn copy_into(source: &Path, dest: &Path) -> io::Result<()> {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dest.with_file_name(format!(".{name}.partial"));
+    tokio::fs::copy(source, &partial).await?;
+    tokio::fs::rename(&partial, dest).await
+}
+
+/// Creates the folder a finished download is saved in, named after its
+/// title, directly under `complete_dir`
+pub async fn output_dir(complete_dir: &Path, title: &str) -> io::Result<PathBuf> {
+    let dir = complete_dir.join(path_component(title));
+    if dir.parent() != Some(complete_dir) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "'{title}' would be saved outside {}",
+                complete_dir.display()
+            ),
+        ));
+    }
+
+    tokio::fs::create_dir_all(&dir).await?;
+    Ok(dir)
+}
+
+/// `name` as a single ordinary path component. Separators and control
+/// characters become underscores, as do names that are only dots.
+pub fn path_component(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => name.replace(['.', ' '], "_") + "_",
+        _ => name,
+    }
+}
+
+/// Where a full download keeps a task's volume, under the name the task has
+fn volume_path(dir: &Path, task: &DownloadTask) -> PathBuf {
+    dir.join(task.path().file_name().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::archive::par2::FileInfo;
+
+    #[test]
+    fn test_verify_missing_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let described = dir.path().join("movie.part1.rar");
+        let undescribed = dir.path().join("movie.part2.rar");
+        std::fs::write(&described, b"volume one").unwrap();
+        std::fs::write(&undescribed, b"volume two").unwrap();
+
+        let info = FileInfo {
+            real_filename: "movie.part1.rar".to_string(),
+            hash16k: Bytes::new(),
+            size: 10,
+            slice_crcs: vec![crc32fast::hash(b"volume one")],
+        };
+        let files = HashMap::from([("id".to_string(), info)]);
+        let manifest = Par2Manifest::new(files, HashSet::new(), 10);
+
+        let complete = [(described.clone(), 0), (undescribed.clone(), 0)];
+        assert_eq!(verify(&manifest, &complete).unwrap(), Check::Intact);
+
+        // the PAR2 set can't vouch for a volume it doesn't describe
+        let holed = [(described, 0), (undescribed, 2)];
+        assert_eq!(
+            verify(&manifest, &holed).unwrap(),
+            Check::Damaged(vec!["movie.part2.rar".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_move_into() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("stream");
+        let dest = dir.path().join("Movie.2024.mkv");
+        std::fs::write(&source, b"video").unwrap();
+
+        move_into(&source, &dest).await.unwrap();
+
+        assert!(!source.exists());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"video");
+    }
+
+    #[test]
+    fn test_path_component() {
+        assert_eq!(path_component("Movie.2024"), "Movie.2024");
+        assert_eq!(path_component("../../etc"), ".._.._etc");
+        assert_eq!(path_component("a\\b\nc"), "a_b_c");
+        assert_eq!(path_component(".."), "___");
+        assert_eq!(path_component(""), "_");
+    }
+
+    #[tokio::test]
+    async fn test_output_dir() {
+        let complete = tempfile::tempdir().unwrap();
+
+        for title in ["Movie", "..", "/etc", "../x", ""] {
+            let dir = output_dir(complete.path(), title).await.unwrap();
+            assert_eq!(dir.parent(), Some(complete.path()));
+            assert!(dir.is_dir());
+        }
+    }
+}
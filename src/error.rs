@@ -11,13 +11,14 @@ use thiserror::Error;
 use tracing::error;
 
 use crate::{
-    archive::error::ArchiveError, nntp::error::NntpError, nzb::error::NzbError,
-    scheduler::error::SchedulerError,
+    archive::error::ArchiveError, media::error::MediaError, newznab::error::NewznabError,
+    nntp::error::NntpError, nzb::error::NzbError, scheduler::error::SchedulerError,
+    stremio::error::StremioError,
 };
 
 #[derive(Debug, Error)]
 pub enum RestError {
-    #[error("Error encountered reading upload as NZB file")]
+    #[error("Error encountered reading NZB file")]
     NzbParse(#[from] NzbError),
 
     #[error("Error encountered during file upload")]
@@ -32,6 +33,12 @@ pub enum RestError {
     #[error("Error encountered attempting to parse archive")]
     Par2(#[from] ArchiveError),
 
+    #[error("NZB does not contain any streamable files")]
+    NoPlayableContent,
+
+    #[error("Only {0:.1}% of the release's articles are available, more than PAR2 can repair")]
+    Unavailable(f64),
+
     #[error("Invalid range header")]
     InvalidRange,
 
@@ -44,11 +51,35 @@ pub enum RestError {
     #[error("Error in NNTP client")]
     Nntp(#[from] NntpError),
 
+    #[error("Error accessing the cache")]
+    Io(#[from] std::io::Error),
+
     #[error("Error in background downloads")]
     BackgroundDownload(#[from] tokio::task::JoinError),
 
     #[error("Error in scheduler")]
     Scheduler(#[from] SchedulerError),
+
+    #[error("Error reading media headers")]
+    Media(#[from] MediaError),
+
+    #[error("Video can't be segmented for HLS")]
+    HlsUnavailable,
+
+    #[error("HLS segment not found")]
+    SegmentNotFound,
+
+    #[error("Subtitle track not found")]
+    SubtitleTrackNotFound,
+
+    #[error("Error in Stremio addon")]
+    Stremio(#[from] StremioError),
+
+    #[error("Error searching indexer")]
+    Newznab(#[from] NewznabError),
+
+    #[error("No indexer configured, set [newznab] or NEWZNAB_URL and NEWZNAB_APIKEY")]
+    IndexerNotConfigured,
 }
 
 impl IntoResponse for RestError {
@@ -56,20 +87,53 @@ impl IntoResponse for RestError {
         error!("{}: {:?}", self, self.source());
 
         let status = match self {
+            RestError::NzbParse(NzbError::PathNotAllowed(_) | NzbError::PathsDisabled) => {
+                StatusCode::FORBIDDEN
+            }
+            RestError::NzbParse(NzbError::Fetch(_)) => StatusCode::BAD_GATEWAY,
             RestError::NzbParse(_) => StatusCode::BAD_REQUEST,
             RestError::MultiPart(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RestError::MissingNzb => StatusCode::BAD_REQUEST,
             RestError::Utf8Parse(_) => StatusCode::BAD_REQUEST,
+            RestError::Par2(ArchiveError::Encrypted | ArchiveError::EncryptedWithPassword) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
             RestError::Par2(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::NoPlayableContent => StatusCode::UNPROCESSABLE_ENTITY,
+            RestError::Unavailable(_) => StatusCode::UNPROCESSABLE_ENTITY,
             RestError::InvalidRange => StatusCode::BAD_REQUEST,
             RestError::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
             RestError::SessionNotFound => StatusCode::NOT_FOUND,
             RestError::Nntp(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RestError::BackgroundDownload(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RestError::Scheduler(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::Media(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::HlsUnavailable => StatusCode::UNPROCESSABLE_ENTITY,
+            RestError::SegmentNotFound => StatusCode::NOT_FOUND,
+            RestError::SubtitleTrackNotFound => StatusCode::NOT_FOUND,
+            RestError::Stremio(StremioError::Library(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::Stremio(_) => StatusCode::NOT_FOUND,
+            RestError::Newznab(NewznabError::MissingQuery(_)) => StatusCode::BAD_REQUEST,
+            RestError::Newznab(NewznabError::NoResults) => StatusCode::NOT_FOUND,
+            RestError::Newznab(NewznabError::Config(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            RestError::Newznab(_) => StatusCode::BAD_GATEWAY,
+            RestError::IndexerNotConfigured => StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        // encryption and indexer errors are the user's to fix, so tell them exactly what's wrong
+        let message = match &self {
+            RestError::Par2(
+                e @ (ArchiveError::Encrypted | ArchiveError::EncryptedWithPassword),
+            ) => e.to_string(),
+            RestError::Newznab(e) => e.to_string(),
+            RestError::NzbParse(e @ (NzbError::PathNotAllowed(_) | NzbError::PathsDisabled)) => {
+                e.to_string()
+            }
+            _ => self.to_string(),
         };
 
-        let payload = Json(json!({"message": self.to_string()}));
+        let payload = Json(json!({"message": message}));
 
         (status, payload).into_response()
     }
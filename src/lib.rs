@@ -1,6 +1,16 @@
 pub mod archive;
+pub mod cli;
+pub mod download;
 pub mod error;
+pub mod media;
+pub mod metrics;
+pub mod newznab;
 pub mod nntp;
 pub mod nzb;
+pub mod sabnzbd;
 pub mod scheduler;
+pub mod server;
+pub mod session;
+pub mod settings;
 pub mod stream;
+pub mod stremio;
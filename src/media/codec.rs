@@ -0,0 +1,340 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+use crate::media::error::MediaError;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+const H264_NAL_AUD: u8 = 9;
+const H264_AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xF0];
+const H265_NAL_AUD: u8 = 35;
+const H265_AUD: [u8; 7] = [0, 0, 0, 1, 0x46, 0x01, 0x50];
+
+const ADTS_HEADER_SIZE: usize = 7;
+/// ADTS frame lengths are 13 bits and include the header
+const ADTS_MAX_FRAME_SIZE: usize = (1 << 13) - 1;
+
+/// MPEG-TS stream types, ISO/IEC 13818-1 table 2-34 and ATSC A/52
+const STREAM_TYPE_MP3: u8 = 0x03;
+const STREAM_TYPE_AAC: u8 = 0x0F;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_H265: u8 = 0x24;
+const STREAM_TYPE_AC3: u8 = 0x81;
+const STREAM_TYPE_EAC3: u8 = 0x87;
+
+/// PES stream IDs, AC-3 goes in private stream 1
+const STREAM_ID_VIDEO: u8 = 0xE0;
+const STREAM_ID_AUDIO: u8 = 0xC0;
+const STREAM_ID_PRIVATE: u8 = 0xBD;
+
+/// A codec we can carry in MPEG-TS without transcoding, with whatever its
+/// frames need to become a bytestream there
+#[derive(Debug, Clone)]
+pub enum Codec {
+    H264 {
+        nal_length_size: usize,
+        parameter_sets: Vec<Bytes>,
+    },
+    H265 {
+        nal_length_size: usize,
+        parameter_sets: Vec<Bytes>,
+    },
+    Aac(AacConfig),
+    Ac3,
+    Eac3,
+    Mp3,
+}
+
+/// The parts of an AAC AudioSpecificConfig an ADTS header repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AacConfig {
+    pub object_type: u8,
+    pub sample_rate_index: u8,
+    pub channels: u8,
+}
+
+impl Codec {
+    /// Parses an AVCDecoderConfigurationRecord (`avcC`), as found in MKV
+    /// CodecPrivate and MP4 sample entries
+    pub fn from_avcc(record: &[u8]) -> Result<Self, MediaError> {
+        let nal_length_size =
+            (*record.get(4).ok_or(MediaError::IncompleteData)? & 0x03) as usize + 1;
+        let mut rest = record.get(5..).ok_or(MediaError::IncompleteData)?;
+        let mut parameter_sets = Vec::new();
+
+        // SPS count is the low 5 bits, PPS count a whole byte
+        for mask in [0x1F, 0xFF] {
+            let (&count, tail) = rest.split_first().ok_or(MediaError::IncompleteData)?;
+            rest = tail;
+
+            for _ in 0..count & mask {
+                let (set, tail) = read_length_prefixed(rest).ok_or(MediaError::IncompleteData)?;
+                parameter_sets.push(Bytes::copy_from_slice(set));
+                rest = tail;
+            }
+        }
+
+        Ok(Codec::H264 {
+            nal_length_size,
+            parameter_sets,
+        })
+    }
+
+    /// Parses an HEVCDecoderConfigurationRecord (`hvcC`)
+    pub fn from_hvcc(record: &[u8]) -> Result<Self, MediaError> {
+        let nal_length_size =
+            (*record.get(21).ok_or(MediaError::IncompleteData)? & 0x03) as usize + 1;
+        let (&arrays, mut rest) = record
+            .get(22..)
+            .and_then(<[u8]>::split_first)
+            .ok_or(MediaError::IncompleteData)?;
+
+        let mut parameter_sets = Vec::new();
+        for _ in 0..arrays {
+            let count = rest
+                .get(1..3)
+                .map(BigEndian::read_u16)
+                .ok_or(MediaError::IncompleteData)?;
+            rest = &rest[3..];
+
+            for _ in 0..count {
+                let (set, tail) = read_length_prefixed(rest).ok_or(MediaError::IncompleteData)?;
+                parameter_sets.push(Bytes::copy_from_slice(set));
+                rest = tail;
+            }
+        }
+
+        Ok(Codec::H265 {
+            nal_length_size,
+            parameter_sets,
+        })
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, Codec::H264 { .. } | Codec::H265 { .. })
+    }
+
+    pub fn stream_type(&self) -> u8 {
+        match self {
+            Codec::H264 { .. } => STREAM_TYPE_H264,
+            Codec::H265 { .. } => STREAM_TYPE_H265,
+            Codec::Aac(_) => STREAM_TYPE_AAC,
+            Codec::Ac3 => STREAM_TYPE_AC3,
+            Codec::Eac3 => STREAM_TYPE_EAC3,
+            Codec::Mp3 => STREAM_TYPE_MP3,
+        }
+    }
+
+    pub fn stream_id(&self) -> u8 {
+        match self {
+            Codec::H264 { .. } | Codec::H265 { .. } => STREAM_ID_VIDEO,
+            Codec::Aac(_) | Codec::Mp3 => STREAM_ID_AUDIO,
+            Codec::Ac3 | Codec::Eac3 => STREAM_ID_PRIVATE,
+        }
+    }
+
+    /// Converts a frame from its container form to the bytestream MPEG-TS
+    /// carries: Annex B with in-band parameter sets for video, ADTS for AAC.
+    /// The other audio codecs are already self-framing.
+    pub fn to_elementary(
+        &self,
+        frame: &[u8],
+        keyframe: bool,
+        out: &mut Vec<u8>,
+    ) -> Result<(), MediaError> {
+        match self {
+            Codec::H264 {
+                nal_length_size,
+                parameter_sets,
+            } => {
+                out.extend_from_slice(&H264_AUD);
+                write_annex_b(
+                    frame,
+                    *nal_length_size,
+                    keyframe,
+                    parameter_sets,
+                    out,
+                    |nal| nal & 0x1F == H264_NAL_AUD,
+                )
+            }
+            Codec::H265 {
+                nal_length_size,
+                parameter_sets,
+            } => {
+                out.extend_from_slice(&H265_AUD);
+                write_annex_b(
+                    frame,
+                    *nal_length_size,
+                    keyframe,
+                    parameter_sets,
+                    out,
+                    |nal| (nal >> 1) & 0x3F == H265_NAL_AUD,
+                )
+            }
+            Codec::Aac(config) => {
+                out.extend_from_slice(&config.adts_header(frame.len())?);
+                out.extend_from_slice(frame);
+                Ok(())
+            }
+            Codec::Ac3 | Codec::Eac3 | Codec::Mp3 => {
+                out.extend_from_slice(frame);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AacConfig {
+    /// Reads the object type, sample rate and channels from the start of an
+    /// AudioSpecificConfig
+    pub fn parse(config: &[u8]) -> Result<Self, MediaError> {
+        let bits = config
+            .get(..2)
+            .map(BigEndian::read_u16)
+            .ok_or(MediaError::IncompleteData)?;
+
+        let object_type = (bits >> 11) as u8;
+        let sample_rate_index = ((bits >> 7) & 0x0F) as u8;
+        let channels = ((bits >> 3) & 0x0F) as u8;
+
+        // 31 escapes to a longer object type and 15 to an explicit 24 bit
+        // sample rate, neither of which ADTS can express
+        if object_type == 31 || sample_rate_index == 15 {
+            return Err(MediaError::UnsupportedCodec(
+                "AAC with escaped object type or sample rate".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            object_type,
+            sample_rate_index,
+            channels,
+        })
+    }
+
+    fn adts_header(&self, payload_len: usize) -> Result<[u8; ADTS_HEADER_SIZE], MediaError> {
+        let frame_len = payload_len + ADTS_HEADER_SIZE;
+        if frame_len > ADTS_MAX_FRAME_SIZE {
+            return Err(MediaError::Malformed {
+                container: "AAC",
+                reason: "frame too large for ADTS",
+            });
+        }
+
+        // ADTS only has 2 bits for the profile. HE-AAC (SBR, PS) is signalled
+        // as its LC core and decoders pick up the extension implicitly.
+        let profile = match self.object_type {
+            1..=4 => self.object_type - 1,
+            _ => 1,
+        };
+
+        Ok([
+            0xFF,
+            0xF1, // MPEG-4, layer 0, no CRC
+            (profile << 6) | (self.sample_rate_index << 2) | (self.channels >> 2),
+            ((self.channels & 0x03) << 6) | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            ((frame_len & 0x07) << 5) as u8 | 0x1F, // buffer fullness 0x7FF: VBR
+            0xFC,
+        ])
+    }
+}
+
+/// Rewrites length-prefixed NAL units as start code prefixed ones, putting
+/// the parameter sets in front of keyframes so every segment decodes on its
+/// own. Existing access unit delimiters are dropped as we write our own.
+fn write_annex_b(
+    frame: &[u8],
+    nal_length_size: usize,
+    keyframe: bool,
+    parameter_sets: &[Bytes],
+    out: &mut Vec<u8>,
+    is_aud: impl Fn(u8) -> bool,
+) -> Result<(), MediaError> {
+    if keyframe {
+        for set in parameter_sets {
+            out.extend_from_slice(&START_CODE);
+            out.extend_from_slice(set);
+        }
+    }
+
+    let mut rest = frame;
+    while !rest.is_empty() {
+        let len = rest
+            .get(..nal_length_size)
+            .ok_or(MediaError::IncompleteData)?
+            .iter()
+            .fold(0, |len, &byte| (len << 8) | byte as usize);
+        let nal = rest
+            .get(nal_length_size..nal_length_size + len)
+            .ok_or(MediaError::IncompleteData)?;
+        rest = &rest[nal_length_size + len..];
+
+        if nal.first().is_some_and(|&header| is_aud(header)) {
+            continue;
+        }
+
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nal);
+    }
+
+    Ok(())
+}
+
+/// Splits off a 16 bit length-prefixed blob
+fn read_length_prefixed(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = data.get(..2).map(BigEndian::read_u16)? as usize;
+    let blob = data.get(2..2 + len)?;
+
+    Some((blob, &data[2 + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_avcc_to_annex_b() {
+        let sps = [0x67, 0x64, 0x00, 0x1F];
+        let pps = [0x68, 0xEB];
+        let mut avcc = vec![1, 0x64, 0x00, 0x1F, 0xFF, 0xE1];
+        avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(&sps);
+        avcc.push(1);
+        avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(&pps);
+
+        let codec = Codec::from_avcc(&avcc).unwrap();
+
+        // an in-band AUD followed by an IDR slice
+        let frame = [0, 0, 0, 2, 0x09, 0xF0, 0, 0, 0, 3, 0x65, 0xAA, 0xBB];
+        let mut out = Vec::new();
+        codec.to_elementary(&frame, true, &mut out).unwrap();
+
+        let mut expected = H264_AUD.to_vec();
+        expected.extend([0, 0, 0, 1, 0x67, 0x64, 0x00, 0x1F]);
+        expected.extend([0, 0, 0, 1, 0x68, 0xEB]);
+        expected.extend([0, 0, 0, 1, 0x65, 0xAA, 0xBB]);
+        assert_eq!(out, expected);
+
+        let mut out = Vec::new();
+        assert!(codec.to_elementary(&frame[..8], false, &mut out).is_err());
+    }
+
+    #[test]
+    fn test_adts_header() {
+        // AAC LC, 48kHz, stereo
+        let config = AacConfig::parse(&[0x11, 0x90]).unwrap();
+        assert_eq!(
+            config,
+            AacConfig {
+                object_type: 2,
+                sample_rate_index: 3,
+                channels: 2,
+            }
+        );
+
+        let header = config.adts_header(100).unwrap();
+        assert_eq!(header, [0xFF, 0xF1, 0x4C, 0x80, 0x0D, 0x7F, 0xFC]);
+    }
+}
@@ -0,0 +1,116 @@
+use serde::Serialize;
+
+use crate::media::ebml;
+
+const TS_SYNC_BYTE: u8 = 0x47;
+const TS_PACKET_SIZE: usize = 188;
+/// M2TS (Blu-ray) prefixes every TS packet with a 4 byte timestamp
+const M2TS_PACKET_SIZE: usize = 192;
+
+/// The container format of the streamed file, judging by its magic bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Matroska,
+    WebM,
+    Mp4,
+    QuickTime,
+    MpegTs,
+    Avi,
+    #[default]
+    Unknown,
+}
+
+impl Container {
+    /// Sniffs the container from the start of the file
+    pub fn sniff(data: &[u8]) -> Self {
+        if data.starts_with(&ebml::EBML_MAGIC) {
+            return match ebml::doc_type(data).as_deref() {
+                Some("webm") => Container::WebM,
+                _ => Container::Matroska,
+            };
+        }
+
+        if data.get(4..8) == Some(b"ftyp") {
+            return match data.get(8..12) {
+                Some(b"qt  ") => Container::QuickTime,
+                _ => Container::Mp4,
+            };
+        }
+
+        if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"AVI ") {
+            return Container::Avi;
+        }
+
+        if is_transport_stream(data, 0, TS_PACKET_SIZE)
+            || is_transport_stream(data, 4, M2TS_PACKET_SIZE)
+        {
+            return Container::MpegTs;
+        }
+
+        Container::Unknown
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Container::Matroska => "video/x-matroska",
+            Container::WebM => "video/webm",
+            Container::Mp4 => "video/mp4",
+            Container::QuickTime => "video/quicktime",
+            Container::MpegTs => "video/mp2t",
+            Container::Avi => "video/x-msvideo",
+            Container::Unknown => "application/octet-stream",
+        }
+    }
+
+    /// For naming the file when it's saved
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::WebM => "webm",
+            Container::Mp4 => "mp4",
+            Container::QuickTime => "mov",
+            Container::MpegTs => "ts",
+            Container::Avi => "avi",
+            Container::Unknown => "bin",
+        }
+    }
+}
+
+/// A single 0x47 is too common to go on, so require a few packets in a row
+fn is_transport_stream(data: &[u8], start: usize, packet_size: usize) -> bool {
+    (0..3).all(|packet| data.get(start + packet * packet_size) == Some(&TS_SYNC_BYTE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let mut ts = vec![0; TS_PACKET_SIZE * 3];
+        ts.iter_mut()
+            .step_by(TS_PACKET_SIZE)
+            .for_each(|byte| *byte = TS_SYNC_BYTE);
+
+        let mut m2ts = vec![0; M2TS_PACKET_SIZE * 3];
+        m2ts.iter_mut()
+            .skip(4)
+            .step_by(M2TS_PACKET_SIZE)
+            .for_each(|byte| *byte = TS_SYNC_BYTE);
+
+        let cases: [(&[u8], Container); 7] = [
+            (&[0x1A, 0x45, 0xDF, 0xA3, 0x80], Container::Matroska),
+            (b"\0\0\0\x20ftypisom", Container::Mp4),
+            (b"\0\0\0\x14ftypqt  ", Container::QuickTime),
+            (b"RIFF\0\0\0\0AVI LIST", Container::Avi),
+            (&ts, Container::MpegTs),
+            (&m2ts, Container::MpegTs),
+            (b"Rar!\x1a\x07\x00", Container::Unknown),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Container::sniff(input), expected);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+/// A seek point from the container's index: the cluster holding the frame
+/// at `time_secs` starts at `offset` in the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CuePoint {
+    pub time_secs: f64,
+    pub offset: u64,
+}
+
+/// Maps between playback time and byte offsets using the container's index,
+/// which players read before every seek
+#[derive(Debug, Clone, Default)]
+pub struct CueIndex {
+    /// Sorted by offset, which is also time order
+    points: Vec<CuePoint>,
+}
+
+impl CueIndex {
+    pub fn new(mut points: Vec<CuePoint>) -> Self {
+        points.sort_by_key(|point| point.offset);
+        points.dedup_by_key(|point| point.offset);
+
+        Self { points }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn points(&self) -> &[CuePoint] {
+        &self.points
+    }
+
+    /// Start of the cluster to play from to reach `time_secs`
+    pub fn offset_at(&self, time_secs: f64) -> Option<u64> {
+        let index = self
+            .points
+            .partition_point(|point| point.time_secs <= time_secs);
+
+        index.checked_sub(1).map(|index| self.points[index].offset)
+    }
+
+    /// Playback time reached once the file is available up to `offset`. This
+    /// is the time of the last cluster starting before it, so it errs short.
+    pub fn time_at(&self, offset: u64) -> Option<f64> {
+        self.point_before(offset).map(|point| point.time_secs)
+    }
+
+    /// Start of the cluster containing `offset`. A player seeking into the
+    /// middle of a cluster needs the data from its start.
+    pub fn cluster_start(&self, offset: u64) -> Option<u64> {
+        self.point_before(offset).map(|point| point.offset)
+    }
+
+    fn point_before(&self, offset: u64) -> Option<&CuePoint> {
+        let index = self.points.partition_point(|point| point.offset <= offset);
+        index.checked_sub(1).map(|index| &self.points[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cue_lookup() {
+        let index = CueIndex::new(vec![
+            CuePoint {
+                time_secs: 10.0,
+                offset: 2000,
+            },
+            CuePoint {
+                time_secs: 0.0,
+                offset: 100,
+            },
+            CuePoint {
+                time_secs: 5.0,
+                offset: 1000,
+            },
+        ]);
+
+        assert_eq!(index.offset_at(7.5), Some(1000));
+        assert_eq!(index.offset_at(10.0), Some(2000));
+        assert_eq!(index.time_at(1999), Some(5.0));
+        assert_eq!(index.time_at(50), None);
+        assert_eq!(index.cluster_start(1500), Some(1000));
+    }
+}
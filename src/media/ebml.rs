@@ -0,0 +1,874 @@
+use crate::media::{
+    Frame, Metadata, Track, TrackKind,
+    codec::{AacConfig, Codec},
+    cues::{CueIndex, CuePoint},
+    error::MediaError,
+    ts::CLOCK_RATE,
+};
+
+pub const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+const EBML: u32 = 0x1A45_DFA3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_TYPE: u32 = 0x83;
+const NAME: u32 = 0x536E;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+const CONTENT_ENCODING: u32 = 0x6240;
+const CONTENT_COMPRESSION: u32 = 0x5034;
+const CONTENT_COMP_ALGO: u32 = 0x4254;
+const CONTENT_COMP_SETTINGS: u32 = 0x4255;
+const LANGUAGE: u32 = 0x22_B59C;
+const LANGUAGE_IETF: u32 = 0x22_B59D;
+const CLUSTER: u32 = 0x1F43_B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const REFERENCE_BLOCK: u32 = 0xFB;
+const BLOCK_DURATION: u32 = 0x9B;
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// An element header is at most a 4 byte ID and an 8 byte size
+pub const MAX_HEADER_SIZE: u64 = 12;
+
+const TRACK_TYPE_VIDEO: u64 = 0x01;
+const TRACK_TYPE_AUDIO: u64 = 0x02;
+const TRACK_TYPE_SUBTITLE: u64 = 0x11;
+
+/// 1ms in nanoseconds, used when the Info element doesn't set a scale
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+/// Matroska's default when a track has no Language element
+const DEFAULT_LANGUAGE: &str = "eng";
+/// ContentCompAlgo for header stripping, the only one we can undo cheaply
+const HEADER_STRIPPING: u64 = 3;
+
+/// Block flags
+const KEYFRAME: u8 = 0x80;
+const LACING: u8 = 0x06;
+const XIPH_LACING: u8 = 0x02;
+const FIXED_LACING: u8 = 0x04;
+const EBML_LACING: u8 = 0x06;
+
+/// Where things are in the Segment, for reading the Cues and Clusters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentLayout {
+    /// Offset of the Segment's data in the file. SeekHead and Cues positions
+    /// are relative to this.
+    pub data_offset: u64,
+    pub timestamp_scale: u64,
+    /// Offset of the Cues element in the file, if the SeekHead lists it
+    pub cues_offset: Option<u64>,
+    /// Offset of the first Cluster in the file, if the header before it
+    /// was all in the data read
+    pub clusters_offset: Option<u64>,
+}
+
+/// What remuxing a track needs from its TrackEntry
+#[derive(Debug, Clone, Default)]
+pub struct TrackConfig {
+    pub number: u64,
+    pub codec_id: String,
+    pub codec_private: Vec<u8>,
+    /// Bytes header stripping removed from the start of every frame
+    pub stripped_header: Vec<u8>,
+    /// Compressed or encrypted with something other than header stripping
+    pub encoded: bool,
+}
+
+impl TrackConfig {
+    pub fn codec(&self) -> Result<Codec, MediaError> {
+        if self.encoded {
+            return Err(MediaError::UnsupportedCodec(format!(
+                "{} with content encoding",
+                self.codec_id
+            )));
+        }
+
+        match self.codec_id.as_str() {
+            "V_MPEG4/ISO/AVC" => Codec::from_avcc(&self.codec_private),
+            "V_MPEGH/ISO/HEVC" => Codec::from_hvcc(&self.codec_private),
+            id if id.starts_with("A_AAC") => AacConfig::parse(&self.codec_private).map(Codec::Aac),
+            "A_AC3" => Ok(Codec::Ac3),
+            "A_EAC3" => Ok(Codec::Eac3),
+            "A_MPEG/L3" => Ok(Codec::Mp3),
+            id => Err(MediaError::UnsupportedCodec(id.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Element<'a> {
+    pub id: u32,
+    /// Offset of the element's header within the buffer being iterated
+    pub offset: usize,
+    /// Offset of the element's data within the buffer being iterated
+    pub data_offset: usize,
+    /// Declared data size, `None` for elements of unknown size (muxers
+    /// writing to a pipe do this for Segment and Cluster)
+    pub size: Option<u64>,
+    /// The element's data, cut short if the buffer ends first
+    pub data: &'a [u8],
+}
+
+/// Iterates the elements at one level of an EBML buffer.
+///
+/// We usually only have the start of the file, so an element running past the
+/// end of the buffer is still yielded with whatever data there is, and ends
+/// the iteration. So does a malformed header.
+pub struct Elements<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+pub fn children(buffer: &[u8]) -> Elements<'_> {
+    Elements {
+        buffer,
+        position: 0,
+    }
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.buffer.get(self.position..)?;
+        let (id, id_len) = read_id(rest)?;
+        let (size, size_len) = read_size(rest.get(id_len..)?)?;
+
+        let header_len = id_len + size_len;
+        let available = rest.len() - header_len;
+        let data_len = size.map_or(available, |size| size.min(available as u64) as usize);
+
+        let element = Element {
+            id,
+            offset: self.position,
+            data_offset: self.position + header_len,
+            size,
+            data: &rest[header_len..header_len + data_len],
+        };
+
+        self.position = match size {
+            Some(size) if data_len as u64 == size => self.position + header_len + data_len,
+            _ => self.buffer.len(),
+        };
+
+        Some(element)
+    }
+}
+
+/// Reads the DocType from the EBML header, `matroska` or `webm`
+pub fn doc_type(data: &[u8]) -> Option<String> {
+    let header = children(data).next().filter(|element| element.id == EBML)?;
+
+    children(header.data)
+        .find(|element| element.id == DOC_TYPE)
+        .map(|element| read_string(element.data))
+}
+
+/// Reads the duration and tracks from the Segment's Info and Tracks
+/// elements, which muxers put before the first Cluster
+pub fn probe(data: &[u8]) -> Result<Metadata, MediaError> {
+    let mut elements = children(data);
+    if elements.next().map(|element| element.id) != Some(EBML) {
+        return Err(MediaError::Malformed {
+            container: "Matroska",
+            reason: "missing EBML header",
+        });
+    }
+
+    let segment = elements
+        .find(|element| element.id == SEGMENT)
+        .ok_or(MediaError::IncompleteData)?;
+
+    let mut metadata = Metadata::default();
+    let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+    let mut duration = None;
+
+    for element in children(segment.data) {
+        match element.id {
+            INFO => {
+                for child in children(element.data) {
+                    match child.id {
+                        TIMESTAMP_SCALE => timestamp_scale = read_uint(child.data),
+                        DURATION => duration = read_float(child.data),
+                        _ => {}
+                    }
+                }
+            }
+            TRACKS => {
+                metadata.tracks = children(element.data)
+                    .filter(|entry| entry.id == TRACK_ENTRY)
+                    .map(|entry| read_track(entry.data))
+                    .collect();
+            }
+            // everything from here on is media data
+            CLUSTER => break,
+            _ => {}
+        }
+    }
+
+    metadata.duration_secs = duration.map(|ticks| ticks * timestamp_scale as f64 / 1e9);
+
+    Ok(metadata)
+}
+
+/// Finds the Cues through the SeekHead at the start of the Segment. Most
+/// muxers write the Cues after the last Cluster, where we'd reach them last.
+pub fn segment_layout(data: &[u8]) -> Result<SegmentLayout, MediaError> {
+    let segment = children(data)
+        .find(|element| element.id == SEGMENT)
+        .ok_or(MediaError::IncompleteData)?;
+
+    let data_offset = segment.data_offset as u64;
+    let mut layout = SegmentLayout {
+        data_offset,
+        timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+        cues_offset: None,
+        clusters_offset: None,
+    };
+
+    for element in children(segment.data) {
+        match element.id {
+            SEEK_HEAD => {
+                layout.cues_offset = children(element.data)
+                    .filter(|seek| seek.id == SEEK)
+                    .find_map(|seek| {
+                        let mut id = None;
+                        let mut position = None;
+                        for child in children(seek.data) {
+                            match child.id {
+                                SEEK_ID => id = Some(read_uint(child.data)),
+                                SEEK_POSITION => position = Some(read_uint(child.data)),
+                                _ => {}
+                            }
+                        }
+
+                        position.filter(|_| id == Some(CUES as u64))
+                    })
+                    .map(|position| data_offset + position)
+                    .or(layout.cues_offset);
+            }
+            INFO => {
+                if let Some(scale) = children(element.data)
+                    .find(|child| child.id == TIMESTAMP_SCALE)
+                    .map(|child| read_uint(child.data))
+                {
+                    layout.timestamp_scale = scale;
+                }
+            }
+            // some muxers put the Cues up front instead
+            CUES => layout.cues_offset = Some(data_offset + element.offset as u64),
+            CLUSTER => {
+                layout.clusters_offset = Some(data_offset + element.offset as u64);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(layout)
+}
+
+/// Total length of the element starting at `header`, including the header
+pub fn element_len(header: &[u8]) -> Option<u64> {
+    let (_, id_len) = read_id(header)?;
+    let (size, size_len) = read_size(header.get(id_len..)?)?;
+
+    size.map(|size| (id_len + size_len) as u64 + size)
+}
+
+/// Parses the Cues element at the start of `data` into an index of cluster
+/// offsets by time
+pub fn parse_cues(data: &[u8], layout: &SegmentLayout) -> Result<CueIndex, MediaError> {
+    let cues = children(data)
+        .next()
+        .filter(|element| element.id == CUES)
+        .ok_or(MediaError::Malformed {
+            container: "Matroska",
+            reason: "expected Cues element",
+        })?;
+
+    let points = children(cues.data)
+        .filter(|element| element.id == CUE_POINT)
+        .filter_map(|cue_point| {
+            let mut time = None;
+            let mut position = None;
+            for child in children(cue_point.data) {
+                match child.id {
+                    CUE_TIME => time = Some(read_uint(child.data)),
+                    // every track's position points at the same cluster, the
+                    // first will do
+                    CUE_TRACK_POSITIONS if position.is_none() => {
+                        position = children(child.data)
+                            .find(|element| element.id == CUE_CLUSTER_POSITION)
+                            .map(|element| read_uint(element.data));
+                    }
+                    _ => {}
+                }
+            }
+
+            Some(CuePoint {
+                time_secs: time? as f64 * layout.timestamp_scale as f64 / 1e9,
+                offset: layout.data_offset + position?,
+            })
+        })
+        .collect();
+
+    Ok(CueIndex::new(points))
+}
+
+/// Reads the TrackEntries with what remuxing needs from each
+pub fn track_configs(data: &[u8]) -> Result<Vec<TrackConfig>, MediaError> {
+    let segment = children(data)
+        .find(|element| element.id == SEGMENT)
+        .ok_or(MediaError::IncompleteData)?;
+
+    let tracks = children(segment.data)
+        .take_while(|element| element.id != CLUSTER)
+        .find(|element| element.id == TRACKS)
+        .ok_or(MediaError::IncompleteData)?;
+
+    Ok(children(tracks.data)
+        .filter(|entry| entry.id == TRACK_ENTRY)
+        .map(|entry| read_track_config(entry.data))
+        .collect())
+}
+
+/// Reads the frames from the Clusters in `data`, which must start at a
+/// Cluster. A Cluster or block cut short by the end of `data` is skipped.
+pub fn read_frames<'a>(data: &'a [u8], layout: &SegmentLayout) -> Vec<Frame<'a>> {
+    read_clusters(data, layout, None)
+}
+
+/// Like `read_frames`, but only keeps one track's frames
+pub fn read_track_frames<'a>(data: &'a [u8], layout: &SegmentLayout, track: u64) -> Vec<Frame<'a>> {
+    read_clusters(data, layout, Some(track))
+}
+
+fn read_clusters<'a>(data: &'a [u8], layout: &SegmentLayout, track: Option<u64>) -> Vec<Frame<'a>> {
+    let mut frames = Vec::new();
+    for cluster in children(data).filter(|element| element.id == CLUSTER) {
+        read_cluster(cluster.data, layout.timestamp_scale, track, &mut frames);
+    }
+
+    frames
+}
+
+fn read_cluster<'a>(
+    data: &'a [u8],
+    timestamp_scale: u64,
+    only_track: Option<u64>,
+    frames: &mut Vec<Frame<'a>>,
+) {
+    let to_ticks =
+        |time: u128| (time * timestamp_scale as u128 * CLOCK_RATE as u128 / 1_000_000_000) as u64;
+    let mut cluster_time = 0;
+
+    for element in children(data) {
+        if element.size != Some(element.data.len() as u64) {
+            break;
+        }
+
+        let (block, duration) = match element.id {
+            CLUSTER_TIMESTAMP => {
+                cluster_time = read_uint(element.data);
+                continue;
+            }
+            SIMPLE_BLOCK => (read_block(element.data, None), None),
+            BLOCK_GROUP => {
+                let mut block = None;
+                let mut referenced = false;
+                let mut duration = None;
+                for child in children(element.data) {
+                    match child.id {
+                        BLOCK => block = Some(child.data),
+                        REFERENCE_BLOCK => referenced = true,
+                        BLOCK_DURATION => duration = Some(read_uint(child.data)),
+                        _ => {}
+                    }
+                }
+
+                (
+                    block.and_then(|block| read_block(block, Some(!referenced))),
+                    duration,
+                )
+            }
+            // a Cluster of unknown size runs until the next one starts
+            CLUSTER => {
+                read_cluster(element.data, timestamp_scale, only_track, frames);
+                continue;
+            }
+            _ => continue,
+        };
+
+        let Some((track, relative_time, keyframe, data)) = block else {
+            continue;
+        };
+        if only_track.is_some_and(|only_track| only_track != track) {
+            continue;
+        }
+
+        let time = (cluster_time as i64 + relative_time as i64).max(0) as u128;
+        frames.push(Frame {
+            track,
+            pts: to_ticks(time),
+            dts: None,
+            keyframe,
+            duration: duration.map(|duration| to_ticks(duration as u128)),
+            data,
+        });
+    }
+}
+
+/// Splits a SimpleBlock or Block into its track, relative timestamp,
+/// keyframe flag and frames. Only SimpleBlocks carry the keyframe flag, a
+/// Block's comes from its BlockGroup.
+fn read_block(data: &[u8], keyframe: Option<bool>) -> Option<(u64, i16, bool, Vec<&[u8]>)> {
+    let (track, len) = read_size(data)?;
+    let rest = data.get(len..)?;
+    let relative_time = i16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    let flags = *rest.get(2)?;
+    let payload = &rest[3..];
+
+    let frames = match flags & LACING {
+        0 => vec![payload],
+        lacing => read_laces(payload, lacing)?,
+    };
+
+    Some((
+        track?,
+        relative_time,
+        keyframe.unwrap_or(flags & KEYFRAME != 0),
+        frames,
+    ))
+}
+
+/// Splits laced frames. The last frame's size is never stored, it takes
+/// whatever's left.
+fn read_laces(payload: &[u8], lacing: u8) -> Option<Vec<&[u8]>> {
+    let (&count, mut rest) = payload.split_first()?;
+    let count = count as usize + 1;
+    let mut sizes = Vec::with_capacity(count);
+
+    match lacing {
+        // each size is a run of 255s plus the byte that ends it
+        XIPH_LACING => {
+            for _ in 1..count {
+                let mut size = 0;
+                loop {
+                    let (&byte, tail) = rest.split_first()?;
+                    rest = tail;
+                    size += byte as usize;
+                    if byte != 0xFF {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        FIXED_LACING => {
+            if rest.len() % count != 0 {
+                return None;
+            }
+            sizes.resize(count - 1, rest.len() / count);
+        }
+        // the first size in full, the rest as signed differences from the
+        // one before
+        EBML_LACING if count > 1 => {
+            let (first, len) = read_size(rest)?;
+            let mut size = first? as i64;
+            rest = &rest[len..];
+            sizes.push(size as usize);
+
+            for _ in 2..count {
+                let (difference, len) = read_size(rest)?;
+                let bias = (1i64 << (7 * len - 1)) - 1;
+                size += difference? as i64 - bias;
+                rest = &rest[len..];
+                sizes.push(usize::try_from(size).ok()?);
+            }
+        }
+        _ => {}
+    }
+
+    let mut frames = Vec::with_capacity(count);
+    for size in sizes {
+        let (frame, tail) = rest.split_at_checked(size)?;
+        frames.push(frame);
+        rest = tail;
+    }
+    frames.push(rest);
+
+    Some(frames)
+}
+
+fn read_track_config(data: &[u8]) -> TrackConfig {
+    let mut config = TrackConfig::default();
+
+    for element in children(data) {
+        match element.id {
+            TRACK_NUMBER => config.number = read_uint(element.data),
+            CODEC_ID => config.codec_id = read_string(element.data),
+            CODEC_PRIVATE => config.codec_private = element.data.to_vec(),
+            CONTENT_ENCODINGS => {
+                for encoding in children(element.data).filter(|e| e.id == CONTENT_ENCODING) {
+                    let compression = children(encoding.data)
+                        .find(|child| child.id == CONTENT_COMPRESSION)
+                        .map(|compression| {
+                            let mut algorithm = 0;
+                            let mut settings = Vec::new();
+                            for child in children(compression.data) {
+                                match child.id {
+                                    CONTENT_COMP_ALGO => algorithm = read_uint(child.data),
+                                    CONTENT_COMP_SETTINGS => settings = child.data.to_vec(),
+                                    _ => {}
+                                }
+                            }
+                            (algorithm, settings)
+                        });
+
+                    match compression {
+                        Some((HEADER_STRIPPING, settings)) => config.stripped_header = settings,
+                        _ => config.encoded = true,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    config
+}
+
+fn read_track(data: &[u8]) -> Track {
+    let mut number = 0;
+    let mut kind = TrackKind::Other;
+    let mut codec = String::new();
+    let mut language = None;
+    let mut language_ietf = None;
+    let mut name = None;
+
+    for element in children(data) {
+        match element.id {
+            TRACK_NUMBER => number = read_uint(element.data),
+            TRACK_TYPE => {
+                kind = match read_uint(element.data) {
+                    TRACK_TYPE_VIDEO => TrackKind::Video,
+                    TRACK_TYPE_AUDIO => TrackKind::Audio,
+                    TRACK_TYPE_SUBTITLE => TrackKind::Subtitle,
+                    _ => TrackKind::Other,
+                }
+            }
+            CODEC_ID => codec = read_string(element.data),
+            LANGUAGE => language = Some(read_string(element.data)),
+            LANGUAGE_IETF => language_ietf = Some(read_string(element.data)),
+            NAME => name = Some(read_string(element.data)),
+            _ => {}
+        }
+    }
+
+    // the BCP 47 tag takes precedence over the older ISO 639-2 one
+    let language = language_ietf
+        .or(language)
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+
+    Track {
+        number,
+        kind,
+        codec,
+        language: (language != "und").then_some(language),
+        name: name.filter(|name| !name.is_empty()),
+    }
+}
+
+/// Element IDs are 1-4 byte variable length integers, kept with their marker
+/// bits as that's how the spec writes them
+fn read_id(buffer: &[u8]) -> Option<(u32, usize)> {
+    let len = buffer.first()?.leading_zeros() as usize + 1;
+    if len > 4 {
+        return None;
+    }
+
+    let id = buffer
+        .get(..len)?
+        .iter()
+        .fold(0, |id, &byte| (id << 8) | byte as u32);
+
+    Some((id, len))
+}
+
+/// Sizes are 1-8 byte variable length integers with the marker bit stripped.
+/// All value bits set means the size is unknown.
+fn read_size(buffer: &[u8]) -> Option<(Option<u64>, usize)> {
+    let first = *buffer.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+
+    let value = buffer
+        .get(1..len)?
+        .iter()
+        .fold(first as u64 & (0xFF >> len), |value, &byte| {
+            (value << 8) | byte as u64
+        });
+
+    let unknown = (1u64 << (7 * len)) - 1;
+    Some(((value != unknown).then_some(value), len))
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .take(8)
+        .fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+fn read_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn read_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Builds EBML for the tests of this and the modules reading it
+#[cfg(test)]
+pub(crate) mod testing {
+    /// Encodes an element with an 8 byte size, which every reader must accept
+    pub fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let mut element: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&byte| byte == 0)
+            .collect();
+        element.push(0x01);
+        element.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        element.extend_from_slice(data);
+
+        element
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::element;
+    use super::*;
+
+    fn track(
+        number: u8,
+        kind: u8,
+        codec: &str,
+        language: Option<&str>,
+        name: Option<&str>,
+    ) -> Vec<u8> {
+        let mut entry = element(TRACK_NUMBER, &[number]);
+        entry.extend(element(TRACK_TYPE, &[kind]));
+        entry.extend(element(CODEC_ID, codec.as_bytes()));
+        if let Some(language) = language {
+            entry.extend(element(LANGUAGE, language.as_bytes()));
+        }
+        if let Some(name) = name {
+            entry.extend(element(NAME, name.as_bytes()));
+        }
+
+        element(TRACK_ENTRY, &entry)
+    }
+
+    fn matroska_header(doc_type: &str, segment_children: &[u8]) -> Vec<u8> {
+        let mut file = element(EBML, &element(DOC_TYPE, doc_type.as_bytes()));
+
+        // unknown-size Segment, as written when muxing to a pipe
+        file.extend_from_slice(&SEGMENT.to_be_bytes());
+        file.push(0xFF);
+        file.extend_from_slice(segment_children);
+
+        file
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut info = element(TIMESTAMP_SCALE, &1_000_000u32.to_be_bytes());
+        info.extend(element(DURATION, &5_400_000f64.to_be_bytes()));
+
+        let mut tracks = track(1, 1, "V_MPEG4/ISO/AVC", None, None);
+        tracks.extend(track(2, 2, "A_AC3", Some("ger"), None));
+        tracks.extend(track(3, 0x11, "S_TEXT/UTF8", Some("und"), Some("SDH")));
+
+        let mut segment = element(INFO, &info);
+        segment.extend(element(TRACKS, &tracks));
+        segment.extend(element(CLUSTER, &[0; 16]));
+
+        let file = matroska_header("matroska", &segment);
+        assert_eq!(doc_type(&file).as_deref(), Some("matroska"));
+
+        let metadata = probe(&file).unwrap();
+        assert_eq!(metadata.duration_secs, Some(5400.0));
+        assert_eq!(
+            metadata.tracks,
+            vec![
+                Track {
+                    number: 1,
+                    kind: TrackKind::Video,
+                    codec: "V_MPEG4/ISO/AVC".to_string(),
+                    language: Some("eng".to_string()),
+                    name: None,
+                },
+                Track {
+                    number: 2,
+                    kind: TrackKind::Audio,
+                    codec: "A_AC3".to_string(),
+                    language: Some("ger".to_string()),
+                    name: None,
+                },
+                Track {
+                    number: 3,
+                    kind: TrackKind::Subtitle,
+                    codec: "S_TEXT/UTF8".to_string(),
+                    language: None,
+                    name: Some("SDH".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cues_from_seek_head() {
+        let mut seek = element(SEEK_ID, &CUES.to_be_bytes());
+        seek.extend(element(SEEK_POSITION, &500u32.to_be_bytes()));
+        let mut segment = element(SEEK_HEAD, &element(SEEK, &seek));
+        segment.extend(element(CLUSTER, &[0; 16]));
+
+        let file = matroska_header("matroska", &segment);
+        let layout = segment_layout(&file).unwrap();
+
+        // EBML header (12 + 10 + 8) then the Segment's 4 byte ID and 1 byte size
+        assert_eq!(layout.data_offset, 35);
+        assert_eq!(layout.cues_offset, Some(535));
+        assert_eq!(layout.timestamp_scale, DEFAULT_TIMESTAMP_SCALE);
+        assert_eq!(
+            layout.clusters_offset,
+            Some(35 + element(SEEK_HEAD, &element(SEEK, &seek)).len() as u64)
+        );
+
+        let cue_point = |time: &[u8], cluster: u8| {
+            let mut cue_point = element(CUE_TIME, time);
+            cue_point.extend(element(
+                CUE_TRACK_POSITIONS,
+                &element(CUE_CLUSTER_POSITION, &[cluster]),
+            ));
+            element(CUE_POINT, &cue_point)
+        };
+
+        let mut cue_points = cue_point(&[0], 0x10);
+        cue_points.extend(cue_point(&2000u16.to_be_bytes(), 0x20));
+        let cues = element(CUES, &cue_points);
+        assert_eq!(element_len(&cues), Some(cues.len() as u64));
+
+        let index = parse_cues(&cues, &layout).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.offset_at(1.5), Some(35 + 0x10));
+        assert_eq!(index.time_at(35 + 0x20), Some(2.0));
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let layout = SegmentLayout {
+            data_offset: 0,
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            cues_offset: None,
+            clusters_offset: None,
+        };
+
+        // track 1 keyframe at +0ms, then two Xiph-laced frames of track 2 at
+        // +20ms, then a BlockGroup referencing an earlier frame at +40ms
+        let mut cluster = element(CLUSTER_TIMESTAMP, &1000u16.to_be_bytes());
+        cluster.extend(element(SIMPLE_BLOCK, &[0x81, 0x00, 0x00, KEYFRAME, 0xAA]));
+        cluster.extend(element(
+            SIMPLE_BLOCK,
+            &[0x82, 0x00, 0x14, XIPH_LACING, 0x01, 0x02, 0xB1, 0xB1, 0xB2],
+        ));
+        let mut group = element(BLOCK, &[0x81, 0x00, 0x28, 0x00, 0xCC]);
+        group.extend(element(REFERENCE_BLOCK, &[0xD8]));
+        group.extend(element(BLOCK_DURATION, &[0x14]));
+        cluster.extend(element(BLOCK_GROUP, &group));
+
+        let data = element(CLUSTER, &cluster);
+        let frames = read_frames(&data, &layout);
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!((frames[0].track, frames[0].pts), (1, 90_000));
+        assert!(frames[0].keyframe);
+        assert_eq!(frames[1].data, vec![&[0xB1, 0xB1][..], &[0xB2][..]]);
+        assert_eq!(frames[1].pts, 91_800);
+        assert!(!frames[2].keyframe);
+        assert_eq!(frames[2].duration, Some(1800));
+        assert_eq!(frames[2].data, vec![&[0xCC][..]]);
+        assert_eq!(read_track_frames(&data, &layout, 2).len(), 1);
+
+        // a cut-off Cluster's partial blocks are left out
+        assert_eq!(read_frames(&data[..data.len() - 3], &layout).len(), 2);
+    }
+
+    #[test]
+    fn test_ebml_lacing() {
+        // three frames of 2, 3 and 1 bytes: 2 in full, then +1 as a signed
+        // 1 byte difference
+        let payload = [0x02, 0x82, 0xC0, 0xA1, 0xA1, 0xA2, 0xA2, 0xA2, 0xA3];
+        let frames = read_laces(&payload, EBML_LACING).unwrap();
+
+        assert_eq!(frames, vec![&[0xA1, 0xA1][..], &[0xA2; 3][..], &[0xA3][..]]);
+    }
+
+    #[test]
+    fn test_truncated_element() {
+        let file = element(INFO, &[0; 100]);
+        let elements: Vec<_> = children(&file[..50]).collect();
+
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].size, Some(100));
+        assert_eq!(elements[0].data.len(), 50 - elements[0].data_offset);
+    }
+
+    #[test]
+    fn test_read_size() {
+        let cases: [(&[u8], Option<u64>, usize); 4] = [
+            (&[0x81], Some(1), 1),
+            (&[0x40, 0x02], Some(2), 2),
+            (&[0xFF], None, 1),
+            (&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF], None, 8),
+        ];
+
+        for (input, size, len) in cases {
+            assert_eq!(
+                read_size(input),
+                Some((size, len)),
+                "Failed for input: {input:?}"
+            );
+        }
+
+        assert_eq!(read_size(&[0x00]), None);
+    }
+}
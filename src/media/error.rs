@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MediaError {
+    #[error("Media header is truncated")]
+    IncompleteData,
+
+    #[error("Malformed {container} header: {reason}")]
+    Malformed {
+        container: &'static str,
+        reason: &'static str,
+    },
+
+    #[error("Unsupported codec: {0}")]
+    UnsupportedCodec(String),
+
+    #[error("No H.264 or H.265 video track to segment")]
+    NoVideoTrack,
+}
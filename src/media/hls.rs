@@ -0,0 +1,491 @@
+use std::{fmt::Write, ops::Range};
+
+use tracing::debug;
+
+use crate::media::{
+    Frame,
+    codec::Codec,
+    cues::CueIndex,
+    ebml::{self, SegmentLayout},
+    error::MediaError,
+    mp4::{self, Sample, SampleTrack},
+    ts::{CLOCK_RATE, PCR_DELAY, TsMuxer},
+};
+
+/// Segments run from one keyframe to the first keyframe at least this much
+/// later
+const TARGET_SEGMENT_SECS: f64 = 6.0;
+
+/// Timestamps start here rather than at zero so the PCR, which trails them,
+/// and decode times, which can lead them, never go negative
+const TIMESTAMP_OFFSET: u64 = PCR_DELAY + CLOCK_RATE;
+
+/// One media segment of the playlist, covering whole keyframe intervals
+#[derive(Debug, Clone, PartialEq)]
+pub struct HlsSegment {
+    pub start_secs: f64,
+    pub duration_secs: f64,
+    /// The bytes of the file the segment is remuxed from
+    pub range: Range<u64>,
+}
+
+#[derive(Debug)]
+struct HlsTrack {
+    /// Matroska's track number, or the index of the MP4 track
+    id: u64,
+    codec: Codec,
+    /// Bytes Matroska header stripping removed from every frame
+    stripped_header: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Source {
+    Matroska(SegmentLayout),
+    /// Sample tables for each of `HlsPlan::tracks`, in the same order
+    Mp4(Vec<SampleTrack>),
+}
+
+/// How a session's file maps onto HLS segments. The whole playlist is known
+/// from the container's index before any media data has arrived, each
+/// segment is remuxed to MPEG-TS when asked for.
+#[derive(Debug)]
+pub struct HlsPlan {
+    tracks: Vec<HlsTrack>,
+    segments: Vec<HlsSegment>,
+    source: Source,
+}
+
+impl HlsPlan {
+    /// Plans segments from the Cues, which point at the Clusters starting
+    /// with a video keyframe
+    ///
+    /// # Arguments
+    /// - `header`: the start of the file, up to the first Cluster
+    /// - `clusters_end`: where the last Cluster ends, the Cues or the end of
+    ///   the file
+    pub fn from_matroska(
+        header: &[u8],
+        layout: SegmentLayout,
+        cues: &CueIndex,
+        clusters_end: u64,
+        duration_secs: Option<f64>,
+    ) -> Result<Self, MediaError> {
+        let tracks = ebml::track_configs(header)?
+            .into_iter()
+            .filter_map(|config| match config.codec() {
+                Ok(codec) => Some(HlsTrack {
+                    id: config.number,
+                    codec,
+                    stripped_header: config.stripped_header,
+                }),
+                Err(e) => {
+                    debug!("Leaving track {} out of HLS: {e}", config.number);
+                    None
+                }
+            })
+            .collect();
+        let tracks = keep_first_video(tracks, |track| &track.codec)?;
+
+        let mut boundaries: Vec<(f64, u64)> = Vec::new();
+        for point in cues.points() {
+            if boundaries
+                .last()
+                .is_none_or(|&(start, _)| point.time_secs - start >= TARGET_SEGMENT_SECS)
+            {
+                boundaries.push((point.time_secs, point.offset));
+            }
+        }
+
+        let segments = boundaries
+            .iter()
+            .enumerate()
+            .map(|(index, &(start_secs, offset))| {
+                let (end_secs, end) = boundaries.get(index + 1).copied().unwrap_or((
+                    duration_secs.unwrap_or(start_secs + TARGET_SEGMENT_SECS),
+                    clusters_end,
+                ));
+
+                HlsSegment {
+                    start_secs,
+                    duration_secs: (end_secs - start_secs).max(0.0),
+                    range: offset..end.max(offset),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            tracks,
+            segments,
+            source: Source::Matroska(layout),
+        })
+    }
+
+    /// Plans segments from the video track's sync samples (`stss`)
+    ///
+    /// # Arguments
+    /// - `moov`: the contents of the `moov` box
+    pub fn from_mp4(moov: &[u8]) -> Result<Self, MediaError> {
+        let tracks = mp4::sample_tracks(moov)?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, sample_track)| match &sample_track.codec {
+                Ok(codec) if sample_track.timescale > 0 => Some((
+                    HlsTrack {
+                        id: index as u64,
+                        codec: codec.clone(),
+                        stripped_header: Vec::new(),
+                    },
+                    sample_track,
+                )),
+                Ok(_) => None,
+                Err(e) => {
+                    debug!("Leaving track {index} out of HLS: {e}");
+                    None
+                }
+            })
+            .collect();
+        let (tracks, sample_tracks): (Vec<_>, Vec<_>) =
+            keep_first_video(tracks, |(track, _)| &track.codec)?
+                .into_iter()
+                .unzip();
+
+        let video = tracks
+            .iter()
+            .position(|track| track.codec.is_video())
+            .map(|index| &sample_tracks[index])
+            .ok_or(MediaError::NoVideoTrack)?;
+
+        let mut starts: Vec<f64> = Vec::new();
+        for sample in video.samples.iter().filter(|sample| sample.keyframe) {
+            let time = sample.dts as f64 / video.timescale as f64;
+            if starts
+                .last()
+                .is_none_or(|&start| time - start >= TARGET_SEGMENT_SECS)
+            {
+                starts.push(time);
+            }
+        }
+
+        let duration_secs = mp4::parse_moov(moov)
+            .ok()
+            .and_then(|metadata| metadata.duration_secs);
+
+        let segments = starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start_secs)| {
+                let end_secs = starts.get(index + 1).copied();
+
+                // samples of different tracks are interleaved, so the range
+                // spans from the first sample of any track to the last
+                let mut range: Option<Range<u64>> = None;
+                for track in &sample_tracks {
+                    for sample in samples_between(track, start_secs, end_secs) {
+                        let end = sample.offset + sample.size as u64;
+                        range = Some(match range {
+                            Some(range) => range.start.min(sample.offset)..range.end.max(end),
+                            None => sample.offset..end,
+                        });
+                    }
+                }
+
+                let end_secs = end_secs
+                    .or(duration_secs)
+                    .unwrap_or(start_secs + TARGET_SEGMENT_SECS);
+
+                HlsSegment {
+                    start_secs,
+                    duration_secs: (end_secs - start_secs).max(0.0),
+                    range: range.unwrap_or(0..0),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            tracks,
+            segments,
+            source: Source::Mp4(sample_tracks),
+        })
+    }
+
+    pub fn segments(&self) -> &[HlsSegment] {
+        &self.segments
+    }
+
+    /// Renders the VOD media playlist, with segments named by index
+    pub fn playlist(&self) -> String {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|segment| segment.duration_secs.ceil() as u64)
+            .max()
+            .unwrap_or_default();
+
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{target_duration}");
+        playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n");
+        playlist.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            let _ = writeln!(
+                playlist,
+                "#EXTINF:{:.3},\n{index}.ts",
+                segment.duration_secs
+            );
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+
+        playlist
+    }
+
+    /// Remuxes a segment to MPEG-TS
+    ///
+    /// # Arguments
+    /// - `data`: the bytes of the segment's `range`
+    pub fn remux(&self, index: usize, data: &[u8]) -> Result<Vec<u8>, MediaError> {
+        let frames = match &self.source {
+            Source::Matroska(layout) => {
+                let mut frames = ebml::read_frames(data, layout);
+                frames.retain(|frame| self.tracks.iter().any(|track| track.id == frame.track));
+                derive_decode_times(&mut frames, &self.tracks);
+                frames
+            }
+            Source::Mp4(sample_tracks) => self.mp4_frames(sample_tracks, index, data)?,
+        };
+
+        let mut muxer = TsMuxer::new(self.tracks.iter().map(|track| &track.codec));
+        muxer.write_tables();
+
+        let mut payload = Vec::new();
+        let mut joined = Vec::new();
+        for frame in &frames {
+            let Some(stream) = self.tracks.iter().position(|track| track.id == frame.track) else {
+                continue;
+            };
+            let track = &self.tracks[stream];
+
+            payload.clear();
+            for part in &frame.data {
+                let part = if track.stripped_header.is_empty() {
+                    *part
+                } else {
+                    joined.clear();
+                    joined.extend_from_slice(&track.stripped_header);
+                    joined.extend_from_slice(part);
+                    joined.as_slice()
+                };
+
+                track
+                    .codec
+                    .to_elementary(part, frame.keyframe, &mut payload)?;
+            }
+
+            muxer.write_pes(
+                stream,
+                frame.pts + TIMESTAMP_OFFSET,
+                frame.dts.map(|dts| dts + TIMESTAMP_OFFSET),
+                frame.keyframe && track.codec.is_video(),
+                &payload,
+            );
+        }
+
+        Ok(muxer.finish())
+    }
+
+    /// Takes each track's samples decoding within the segment's time span
+    /// from `data`, interleaved by decode time
+    fn mp4_frames<'a>(
+        &self,
+        sample_tracks: &[SampleTrack],
+        index: usize,
+        data: &'a [u8],
+    ) -> Result<Vec<Frame<'a>>, MediaError> {
+        let segment = self.segments.get(index).ok_or(MediaError::IncompleteData)?;
+        let end_secs = self.segments.get(index + 1).map(|next| next.start_secs);
+
+        let mut frames = Vec::new();
+        for (track, sample_track) in self.tracks.iter().zip(sample_tracks) {
+            for sample in samples_between(sample_track, segment.start_secs, end_secs) {
+                let start = sample
+                    .offset
+                    .checked_sub(segment.range.start)
+                    .ok_or(MediaError::IncompleteData)? as usize;
+                let sample_data = data
+                    .get(start..start + sample.size as usize)
+                    .ok_or(MediaError::IncompleteData)?;
+
+                // widened so long files at fine timescales don't overflow
+                let to_ticks = |time: u64| {
+                    (time as u128 * CLOCK_RATE as u128 / sample_track.timescale as u128) as u64
+                };
+                let pts = sample
+                    .dts
+                    .saturating_add_signed(sample.composition_offset as i64);
+
+                frames.push(Frame {
+                    track: track.id,
+                    pts: to_ticks(pts),
+                    dts: Some(to_ticks(sample.dts)),
+                    keyframe: sample.keyframe,
+                    duration: None,
+                    data: vec![sample_data],
+                });
+            }
+        }
+
+        frames.sort_by_key(|frame| frame.dts);
+        Ok(frames)
+    }
+}
+
+/// HLS players only play one video track from a segment, keep the first and
+/// make sure there is one
+fn keep_first_video<T>(tracks: Vec<T>, codec: impl Fn(&T) -> &Codec) -> Result<Vec<T>, MediaError> {
+    let mut seen_video = false;
+    let tracks: Vec<_> = tracks
+        .into_iter()
+        .filter(|track| !codec(track).is_video() || !std::mem::replace(&mut seen_video, true))
+        .collect();
+
+    if seen_video {
+        Ok(tracks)
+    } else {
+        Err(MediaError::NoVideoTrack)
+    }
+}
+
+/// The samples of a track decoding from `start_secs` up to `end_secs`, or to
+/// the end of the track
+fn samples_between(track: &SampleTrack, start_secs: f64, end_secs: Option<f64>) -> &[Sample] {
+    let to_time = |secs: f64| (secs * track.timescale as f64).round() as u64;
+    let first = track
+        .samples
+        .partition_point(|s| s.dts < to_time(start_secs));
+    let end = end_secs.map_or(track.samples.len(), |end_secs| {
+        track.samples.partition_point(|s| s.dts < to_time(end_secs))
+    });
+
+    &track.samples[first..end.max(first)]
+}
+
+/// Matroska video with B-frames stores frames in decode order but only their
+/// presentation times, while MPEG-TS needs both. The decode times are the
+/// same set of times in ascending order, pushed back by the largest reorder
+/// in the segment so no frame decodes after it's presented.
+fn derive_decode_times(frames: &mut [Frame], tracks: &[HlsTrack]) {
+    for track in tracks.iter().filter(|track| track.codec.is_video()) {
+        let mut times: Vec<u64> = frames
+            .iter()
+            .filter(|frame| frame.track == track.id)
+            .map(|frame| frame.pts)
+            .collect();
+        times.sort_unstable();
+
+        let video_frames = || frames.iter().filter(|frame| frame.track == track.id);
+        let delay = video_frames()
+            .zip(&times)
+            .map(|(frame, &time)| time.saturating_sub(frame.pts))
+            .max()
+            .unwrap_or_default();
+        if delay == 0 {
+            continue;
+        }
+
+        for (frame, time) in frames
+            .iter_mut()
+            .filter(|frame| frame.track == track.id)
+            .zip(times)
+        {
+            frame.dts = Some(time.saturating_sub(delay));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{cues::CuePoint, ebml::testing::element, ts::PACKET_SIZE};
+
+    fn track_entry(number: u8, codec: &str, private: &[u8]) -> Vec<u8> {
+        let mut entry = element(0xD7, &[number]);
+        entry.extend(element(0x86, codec.as_bytes()));
+        entry.extend(element(0x63A2, private));
+        element(0xAE, &entry)
+    }
+
+    fn matroska_plan() -> HlsPlan {
+        // one SPS, one PPS, 4 byte NAL lengths
+        let avcc = [
+            1, 0x64, 0, 0x1F, 0xFF, 0xE1, 0, 2, 0x67, 0x64, 1, 0, 2, 0x68, 0xEB,
+        ];
+
+        let mut tracks = track_entry(1, "V_MPEG4/ISO/AVC", &avcc);
+        tracks.extend(track_entry(2, "A_AC3", &[]));
+        tracks.extend(track_entry(3, "S_TEXT/UTF8", &[]));
+
+        let mut header = element(0x1A45_DFA3, &[]);
+        header.extend(element(0x1853_8067, &element(0x1654_AE6B, &tracks)));
+
+        let layout = SegmentLayout {
+            data_offset: 0,
+            timestamp_scale: 1_000_000,
+            cues_offset: None,
+            clusters_offset: None,
+        };
+        let cues = CueIndex::new(
+            [(0.0, 100), (2.0, 200), (6.5, 300), (8.0, 400)]
+                .into_iter()
+                .map(|(time_secs, offset)| CuePoint { time_secs, offset })
+                .collect(),
+        );
+
+        HlsPlan::from_matroska(&header, layout, &cues, 500, Some(10.0)).unwrap()
+    }
+
+    #[test]
+    fn test_playlist_from_cues() {
+        let plan = matroska_plan();
+
+        assert_eq!(
+            plan.segments(),
+            [
+                HlsSegment {
+                    start_secs: 0.0,
+                    duration_secs: 6.5,
+                    range: 100..300,
+                },
+                HlsSegment {
+                    start_secs: 6.5,
+                    duration_secs: 3.5,
+                    range: 300..500,
+                },
+            ]
+        );
+
+        let playlist = plan.playlist();
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:7\n"));
+        assert!(playlist.contains("#EXTINF:6.500,\n0.ts\n#EXTINF:3.500,\n1.ts\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    }
+
+    #[test]
+    fn test_remux_cluster() {
+        let plan = matroska_plan();
+
+        // a keyframe, an AC-3 frame and a subtitle, which is left out
+        let mut cluster = element(0xE7, &[0]);
+        cluster.extend(element(0xA3, &[0x81, 0, 0, 0x80, 0, 0, 0, 2, 0x65, 0x88]));
+        cluster.extend(element(0xA3, &[0x82, 0, 0, 0x80, 0x0B, 0x77]));
+        cluster.extend(element(0xA3, &[0x83, 0, 0, 0x80, b'h', b'i']));
+        let data = element(0x1F43_B675, &cluster);
+
+        let segment = plan.remux(0, &data).unwrap();
+        let pids: Vec<_> = segment
+            .chunks(PACKET_SIZE)
+            .map(|packet| u16::from_be_bytes([packet[1], packet[2]]) & 0x1FFF)
+            .collect();
+
+        assert_eq!(segment.len() % PACKET_SIZE, 0);
+        assert_eq!(pids, [0x0000, 0x1000, 0x0100, 0x0101]);
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use tracing::debug;
+
+use crate::media::container::Container;
+
+pub mod codec;
+pub mod container;
+pub mod cues;
+pub mod ebml;
+pub mod error;
+pub mod hls;
+pub mod mp4;
+pub mod subtitles;
+pub mod ts;
+
+/// What we know about the streamed file from its headers
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaInfo {
+    pub container: Container,
+    pub mime_type: &'static str,
+    pub duration_secs: Option<f64>,
+    /// Average bitrate in bits per second, from the file size and duration
+    pub bitrate: Option<u64>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Track {
+    /// Matroska's TrackNumber or the MP4 track ID, which blocks and samples
+    /// refer to
+    pub number: u64,
+    pub kind: TrackKind,
+    /// As the container names it, e.g. `V_MPEG4/ISO/AVC` for MKV or `avc1`
+    /// for MP4
+    pub codec: String,
+    pub language: Option<String>,
+    /// As the muxer labelled it, e.g. `SDH` or `Forced`
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+    Other,
+}
+
+/// Duration and tracks, as read by the container specific parsers
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub duration_secs: Option<f64>,
+    pub tracks: Vec<Track>,
+}
+
+/// A block of compressed frames as the container stores it. Matroska can
+/// lace several audio frames into one block, sharing its timestamp.
+#[derive(Debug, Clone)]
+pub struct Frame<'a> {
+    /// Matroska's track number, or the track's index in an MP4
+    pub track: u64,
+    /// Presentation time in 90kHz ticks
+    pub pts: u64,
+    /// Decode time in 90kHz ticks. Only MP4 stores these, Matroska blocks
+    /// are in decode order with just a presentation time.
+    pub dts: Option<u64>,
+    pub keyframe: bool,
+    /// How long the frame is shown for in 90kHz ticks, only Matroska
+    /// subtitle blocks store one
+    pub duration: Option<u64>,
+    pub data: Vec<&'a [u8]>,
+}
+
+/// Works out the container from the start of the file and reads whatever
+/// metadata is in `data`. Never fails, a file we can't parse still streams,
+/// it just has less to show.
+pub fn probe(data: &[u8], file_size: u64) -> MediaInfo {
+    let container = Container::sniff(data);
+
+    let metadata = match container {
+        Container::Matroska | Container::WebM => ebml::probe(data),
+        Container::Mp4 | Container::QuickTime => mp4::probe(data),
+        _ => Ok(Metadata::default()),
+    }
+    .unwrap_or_else(|e| {
+        debug!("Could not read {container:?} metadata: {e}");
+        Metadata::default()
+    });
+
+    MediaInfo {
+        container,
+        mime_type: container.mime_type(),
+        duration_secs: metadata.duration_secs,
+        bitrate: metadata
+            .duration_secs
+            .filter(|&duration| duration > 0.0)
+            .map(|duration| (file_size as f64 * 8.0 / duration) as u64),
+        tracks: metadata.tracks,
+    }
+}
@@ -0,0 +1,631 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::media::{
+    Metadata, Track, TrackKind,
+    codec::{AacConfig, Codec},
+    error::MediaError,
+};
+
+/// Size (4B) and type (4B)
+const BOX_HEADER_SIZE: usize = 8;
+/// Plus a 64 bit size (8B) for large boxes
+pub const MAX_BOX_HEADER_SIZE: u64 = 16;
+/// Version and flags at the start of a full box
+const FULL_BOX_HEADER_SIZE: usize = 4;
+
+/// Fields before a sample entry's child boxes
+const VISUAL_SAMPLE_ENTRY_SIZE: usize = 78;
+const AUDIO_SAMPLE_ENTRY_SIZE: usize = 28;
+/// QuickTime sound descriptions version 1 and 2 add fields
+const SOUND_V1_EXTRA_SIZE: usize = 16;
+const SOUND_V2_EXTRA_SIZE: usize = 36;
+
+/// MPEG-4 descriptor tags within `esds`
+const ES_DESCRIPTOR: u8 = 0x03;
+const DECODER_CONFIG_DESCRIPTOR: u8 = 0x04;
+const DECODER_SPECIFIC_INFO: u8 = 0x05;
+/// Fields of a DecoderConfigDescriptor before its DecoderSpecificInfo
+const DECODER_CONFIG_SIZE: usize = 13;
+
+/// A track's samples from its sample table
+#[derive(Debug)]
+pub struct SampleTrack {
+    pub timescale: u32,
+    /// The codec from the first sample entry, if we can remux it
+    pub codec: Result<Codec, MediaError>,
+    /// In decode order
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Offset in the file
+    pub offset: u64,
+    pub size: u32,
+    /// Decode time in the track's timescale
+    pub dts: u64,
+    /// Presentation time minus decode time, non-zero with B-frames
+    pub composition_offset: i32,
+    pub keyframe: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mp4Box<'a> {
+    pub kind: [u8; 4],
+    /// Offset of the box header within the buffer being iterated
+    pub offset: usize,
+    /// Declared size including the header
+    pub size: u64,
+    /// The box's data, cut short if the buffer ends first
+    pub data: &'a [u8],
+}
+
+/// Iterates the boxes at one level of an ISO BMFF buffer. Like EBML, a box
+/// running past the end of the buffer is yielded truncated and ends the
+/// iteration.
+pub struct Boxes<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+pub fn boxes(buffer: &[u8]) -> Boxes<'_> {
+    Boxes {
+        buffer,
+        position: 0,
+    }
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Mp4Box<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.buffer.get(self.position..)?;
+        let header = rest.get(..BOX_HEADER_SIZE)?;
+        let kind = header[4..8].try_into().ok()?;
+
+        // 1 means a 64 bit size follows the type, 0 means the box runs to
+        // the end of the file
+        let (size, header_len) = match BigEndian::read_u32(&header[..4]) {
+            0 => (rest.len() as u64, BOX_HEADER_SIZE),
+            1 => (rest.get(8..16).map(BigEndian::read_u64)?, 16),
+            size => (size as u64, BOX_HEADER_SIZE),
+        };
+        if size < header_len as u64 {
+            return None;
+        }
+
+        let end = size.min(rest.len() as u64) as usize;
+        let mp4_box = Mp4Box {
+            kind,
+            offset: self.position,
+            size,
+            data: &rest[header_len.min(end)..end],
+        };
+
+        self.position = match self.position.checked_add(size as usize) {
+            Some(next) if end as u64 == size => next,
+            _ => self.buffer.len(),
+        };
+
+        Some(mp4_box)
+    }
+}
+
+/// Reads the duration and tracks from the `moov` box. Files not prepared for
+/// streaming put it after the media data, in which case it's not here.
+pub fn probe(data: &[u8]) -> Result<Metadata, MediaError> {
+    let moov = find(data, b"moov").ok_or(MediaError::IncompleteData)?;
+    parse_moov(moov)
+}
+
+/// Where to look for `moov` when it isn't at the start of the file: the
+/// offset of the first top-level box past the end of `data`, typically
+/// straight after `mdat`.
+///
+/// # Returns
+/// - `None` if `moov` is in `data`, so players have it from the start
+pub fn moov_search_start(data: &[u8]) -> Option<u64> {
+    let mut next = 0;
+    for mp4_box in boxes(data) {
+        if &mp4_box.kind == b"moov" {
+            return None;
+        }
+        next = mp4_box.offset as u64 + mp4_box.size;
+    }
+
+    Some(next)
+}
+
+/// Parses the contents of a `moov` box
+pub fn parse_moov(moov: &[u8]) -> Result<Metadata, MediaError> {
+    let mvhd = find(moov, b"mvhd").ok_or(MediaError::Malformed {
+        container: "MP4",
+        reason: "moov has no mvhd",
+    })?;
+    let (timescale, duration) = read_timing(mvhd)?;
+
+    let tracks = boxes(moov)
+        .filter(|trak| &trak.kind == b"trak")
+        .filter_map(|trak| read_track(trak.data))
+        .collect();
+
+    Ok(Metadata {
+        duration_secs: (timescale > 0).then(|| duration as f64 / timescale as f64),
+        tracks,
+    })
+}
+
+/// Reads every track's sample table from the contents of a `moov` box. Edit
+/// lists are ignored, so tracks with one may be slightly out of sync.
+pub fn sample_tracks(moov: &[u8]) -> Result<Vec<SampleTrack>, MediaError> {
+    boxes(moov)
+        .filter(|trak| &trak.kind == b"trak")
+        .map(|trak| read_sample_track(trak.data))
+        .collect()
+}
+
+fn read_sample_track(trak: &[u8]) -> Result<SampleTrack, MediaError> {
+    let malformed = |reason| MediaError::Malformed {
+        container: "MP4",
+        reason,
+    };
+
+    let mdia = find(trak, b"mdia").ok_or(malformed("trak has no mdia"))?;
+    let (timescale, _) = read_timing(find(mdia, b"mdhd").ok_or(malformed("mdia has no mdhd"))?)?;
+    let stbl = find(mdia, b"minf")
+        .and_then(|minf| find(minf, b"stbl"))
+        .ok_or(malformed("trak has no sample table"))?;
+
+    let codec = find(stbl, b"stsd")
+        .and_then(|stsd| stsd.get(8..))
+        .and_then(|entries| boxes(entries).next())
+        .ok_or(malformed("stbl has no sample description"))
+        .and_then(read_sample_entry);
+
+    // sample sizes are either all the same or listed one by one
+    let stsz = find(stbl, b"stsz").ok_or(malformed("stbl has no stsz"))?;
+    let sizes: Vec<u32> = match stsz.get(4..12) {
+        Some(fields) if BigEndian::read_u32(&fields[..4]) != 0 => {
+            vec![BigEndian::read_u32(&fields[..4]); BigEndian::read_u32(&fields[4..]) as usize]
+        }
+        Some(_) => read_table(&stsz[4..], 4)?
+            .map(BigEndian::read_u32)
+            .collect(),
+        None => return Err(MediaError::IncompleteData),
+    };
+
+    let chunk_offsets: Vec<u64> = match (find(stbl, b"stco"), find(stbl, b"co64")) {
+        (Some(stco), _) => read_table(stco, 4)?
+            .map(|entry| BigEndian::read_u32(entry) as u64)
+            .collect(),
+        (_, Some(co64)) => read_table(co64, 8)?.map(BigEndian::read_u64).collect(),
+        _ => return Err(malformed("stbl has no chunk offsets")),
+    };
+
+    // runs of chunks with the same number of samples: first chunk (1-based)
+    // and samples per chunk
+    let chunk_runs: Vec<(u32, u32)> = read_table(
+        find(stbl, b"stsc").ok_or(malformed("stbl has no stsc"))?,
+        12,
+    )?
+    .map(|entry| {
+        (
+            BigEndian::read_u32(&entry[..4]),
+            BigEndian::read_u32(&entry[4..8]),
+        )
+    })
+    .collect();
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut sizes_left = sizes.iter();
+    'runs: for (index, &(first_chunk, per_chunk)) in chunk_runs.iter().enumerate() {
+        let end_chunk = chunk_runs
+            .get(index + 1)
+            .map_or(chunk_offsets.len() as u32 + 1, |&(next, _)| next);
+
+        for chunk in first_chunk..end_chunk {
+            let Some(&chunk_offset) = chunk_offsets.get(chunk.saturating_sub(1) as usize) else {
+                break 'runs;
+            };
+
+            let mut offset = chunk_offset;
+            for _ in 0..per_chunk {
+                let Some(&size) = sizes_left.next() else {
+                    break 'runs;
+                };
+
+                samples.push(Sample {
+                    offset,
+                    size,
+                    dts: 0,
+                    composition_offset: 0,
+                    keyframe: true,
+                });
+                offset += size as u64;
+            }
+        }
+    }
+
+    // decode times as runs of equal durations
+    let stts = find(stbl, b"stts").ok_or(malformed("stbl has no stts"))?;
+    let mut dts = 0;
+    let mut durations = read_table(stts, 8)?.flat_map(|entry| {
+        let count = BigEndian::read_u32(&entry[..4]);
+        std::iter::repeat_n(BigEndian::read_u32(&entry[4..]) as u64, count as usize)
+    });
+    for sample in &mut samples {
+        sample.dts = dts;
+        dts += durations.next().unwrap_or_default();
+    }
+
+    if let Some(ctts) = find(stbl, b"ctts") {
+        let offsets = read_table(ctts, 8)?.flat_map(|entry| {
+            let count = BigEndian::read_u32(&entry[..4]);
+            std::iter::repeat_n(BigEndian::read_i32(&entry[4..]), count as usize)
+        });
+        for (sample, offset) in samples.iter_mut().zip(offsets) {
+            sample.composition_offset = offset;
+        }
+    }
+
+    // without a sync sample table every sample is a keyframe
+    if let Some(stss) = find(stbl, b"stss") {
+        samples
+            .iter_mut()
+            .for_each(|sample| sample.keyframe = false);
+        for number in read_table(stss, 4)?.map(BigEndian::read_u32) {
+            if let Some(sample) = samples.get_mut(number.saturating_sub(1) as usize) {
+                sample.keyframe = true;
+            }
+        }
+    }
+
+    Ok(SampleTrack {
+        timescale,
+        codec,
+        samples,
+    })
+}
+
+/// Reads the codec config from a sample entry
+fn read_sample_entry(entry: Mp4Box) -> Result<Codec, MediaError> {
+    let config = |skip: usize, kind: &[u8; 4]| {
+        entry
+            .data
+            .get(skip..)
+            .and_then(|children| find(children, kind))
+            .ok_or(MediaError::IncompleteData)
+    };
+
+    match &entry.kind {
+        b"avc1" | b"avc3" => Codec::from_avcc(config(VISUAL_SAMPLE_ENTRY_SIZE, b"avcC")?),
+        b"hvc1" | b"hev1" => Codec::from_hvcc(config(VISUAL_SAMPLE_ENTRY_SIZE, b"hvcC")?),
+        b"mp4a" => {
+            let extra = match entry.data.get(8..10).map(BigEndian::read_u16) {
+                Some(1) => SOUND_V1_EXTRA_SIZE,
+                Some(2) => SOUND_V2_EXTRA_SIZE,
+                _ => 0,
+            };
+
+            read_esds(config(AUDIO_SAMPLE_ENTRY_SIZE + extra, b"esds")?)
+        }
+        b"ac-3" => Ok(Codec::Ac3),
+        b"ec-3" => Ok(Codec::Eac3),
+        b".mp3" => Ok(Codec::Mp3),
+        kind => Err(MediaError::UnsupportedCodec(
+            String::from_utf8_lossy(kind).into_owned(),
+        )),
+    }
+}
+
+/// Digs the AudioSpecificConfig out of an `esds` box's descriptors. MP3 is
+/// sometimes stored as `mp4a` too, which only the object type tells apart.
+fn read_esds(esds: &[u8]) -> Result<Codec, MediaError> {
+    let (_, es) = esds
+        .get(FULL_BOX_HEADER_SIZE..)
+        .and_then(read_descriptor)
+        .filter(|&(tag, _)| tag == ES_DESCRIPTOR)
+        .ok_or(MediaError::IncompleteData)?;
+
+    // ES ID then flags for optional fields: a dependency ID, a URL and an
+    // OCR stream ID
+    let flags = *es.get(2).ok_or(MediaError::IncompleteData)?;
+    let mut skip = 3;
+    if flags & 0x80 != 0 {
+        skip += 2;
+    }
+    if flags & 0x40 != 0 {
+        skip += 1 + *es.get(skip).ok_or(MediaError::IncompleteData)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        skip += 2;
+    }
+
+    let (_, decoder_config) = es
+        .get(skip..)
+        .and_then(read_descriptor)
+        .filter(|&(tag, _)| tag == DECODER_CONFIG_DESCRIPTOR)
+        .ok_or(MediaError::IncompleteData)?;
+
+    match decoder_config.first() {
+        Some(0x40 | 0x66..=0x68) => {}
+        Some(0x69 | 0x6B) => return Ok(Codec::Mp3),
+        Some(object_type) => {
+            return Err(MediaError::UnsupportedCodec(format!(
+                "mp4a object type {object_type:#04x}"
+            )));
+        }
+        None => return Err(MediaError::IncompleteData),
+    }
+
+    let (_, specific) = decoder_config
+        .get(DECODER_CONFIG_SIZE..)
+        .and_then(read_descriptor)
+        .filter(|&(tag, _)| tag == DECODER_SPECIFIC_INFO)
+        .ok_or(MediaError::IncompleteData)?;
+
+    AacConfig::parse(specific).map(Codec::Aac)
+}
+
+/// An MPEG-4 descriptor: a tag, then a size in up to four 7 bit groups
+fn read_descriptor(data: &[u8]) -> Option<(u8, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+
+    let mut size = 0;
+    let mut len = 0;
+    for &byte in rest.iter().take(4) {
+        size = (size << 7) | (byte & 0x7F) as usize;
+        len += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    Some((tag, rest.get(len..len + size)?))
+}
+
+/// The entries of a full box laid out as a 32 bit count then fixed-size
+/// entries, as most sample table boxes are
+fn read_table(
+    full_box: &[u8],
+    entry_size: usize,
+) -> Result<std::slice::ChunksExact<'_, u8>, MediaError> {
+    let count = full_box
+        .get(FULL_BOX_HEADER_SIZE..FULL_BOX_HEADER_SIZE + 4)
+        .map(BigEndian::read_u32)
+        .ok_or(MediaError::IncompleteData)? as usize;
+
+    let start = FULL_BOX_HEADER_SIZE + 4;
+    count
+        .checked_mul(entry_size)
+        .and_then(|len| full_box.get(start..start + len))
+        .map(|entries| entries.chunks_exact(entry_size))
+        .ok_or(MediaError::IncompleteData)
+}
+
+fn read_track(trak: &[u8]) -> Option<Track> {
+    let mdia = find(trak, b"mdia")?;
+
+    // the track ID follows the creation and modification times, which
+    // version 1 widens to 64 bits
+    let number = find(trak, b"tkhd")
+        .and_then(|tkhd| match tkhd.first() {
+            Some(1) => tkhd.get(20..24),
+            _ => tkhd.get(12..16),
+        })
+        .map_or(0, BigEndian::read_u32) as u64;
+
+    let kind = match find(mdia, b"hdlr")?.get(8..12)? {
+        b"vide" => TrackKind::Video,
+        b"soun" => TrackKind::Audio,
+        b"sbtl" | b"subt" | b"text" => TrackKind::Subtitle,
+        _ => TrackKind::Other,
+    };
+
+    // the first sample entry's type is the codec, e.g. avc1 or mp4a
+    let codec = find(mdia, b"minf")
+        .and_then(|minf| find(minf, b"stbl"))
+        .and_then(|stbl| find(stbl, b"stsd"))
+        .and_then(|stsd| stsd.get(8..))
+        .and_then(|entries| boxes(entries).next())
+        .map(|entry| String::from_utf8_lossy(&entry.kind).into_owned())
+        .unwrap_or_default();
+
+    let language = find(mdia, b"mdhd").and_then(read_language);
+
+    Some(Track {
+        number,
+        kind,
+        codec,
+        language,
+        name: None,
+    })
+}
+
+/// Timescale and duration from an `mvhd` or `mdhd`, which share the layout
+/// up to the duration. Version 1 widens the times to 64 bits.
+fn read_timing(header: &[u8]) -> Result<(u32, u64), MediaError> {
+    let timing = match header.first() {
+        Some(1) => header
+            .get(20..32)
+            .map(|t| (BigEndian::read_u32(&t[..4]), BigEndian::read_u64(&t[4..]))),
+        Some(_) => header.get(12..20).map(|t| {
+            (
+                BigEndian::read_u32(&t[..4]),
+                BigEndian::read_u32(&t[4..]) as u64,
+            )
+        }),
+        None => None,
+    };
+
+    timing.ok_or(MediaError::IncompleteData)
+}
+
+/// ISO 639-2/T code packed into 15 bits as three 5 bit letters
+fn read_language(mdhd: &[u8]) -> Option<String> {
+    let offset = if mdhd.first() == Some(&1) { 32 } else { 20 };
+    let packed = mdhd.get(offset..offset + 2).map(BigEndian::read_u16)?;
+
+    let language: String = [10, 5, 0]
+        .into_iter()
+        .map(|shift| (((packed >> shift) & 0x1F) as u8 + 0x60) as char)
+        .collect();
+
+    (language.chars().all(|c| c.is_ascii_lowercase()) && language != "und").then_some(language)
+}
+
+fn find<'a>(buffer: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(buffer)
+        .find(|mp4_box| &mp4_box.kind == kind)
+        .map(|mp4_box| mp4_box.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut mp4_box = ((data.len() + BOX_HEADER_SIZE) as u32)
+            .to_be_bytes()
+            .to_vec();
+        mp4_box.extend_from_slice(kind);
+        mp4_box.extend_from_slice(data);
+
+        mp4_box
+    }
+
+    fn full_box_v0(fields: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 4]; // version, flags
+        data.extend_from_slice(fields);
+        data
+    }
+
+    fn trak(id: u32, handler: &[u8; 4], codec: &[u8; 4], language: [u8; 2]) -> Vec<u8> {
+        let mut tkhd = vec![0; 8]; // creation, modification
+        tkhd.extend_from_slice(&id.to_be_bytes());
+
+        let mut mdhd = vec![0; 8]; // creation, modification
+        mdhd.extend_from_slice(&1000u32.to_be_bytes());
+        mdhd.extend_from_slice(&0u32.to_be_bytes());
+        mdhd.extend_from_slice(&language);
+
+        let mut hdlr = vec![0; 4]; // pre-defined
+        hdlr.extend_from_slice(handler);
+
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(mp4_box(codec, &[0; 8]));
+        let stbl = mp4_box(b"stsd", &full_box_v0(&stsd));
+        let minf = mp4_box(b"stbl", &stbl);
+
+        let mut mdia = mp4_box(b"mdhd", &full_box_v0(&mdhd));
+        mdia.extend(mp4_box(b"hdlr", &full_box_v0(&hdlr)));
+        mdia.extend(mp4_box(b"minf", &minf));
+
+        let mut trak = mp4_box(b"tkhd", &full_box_v0(&tkhd));
+        trak.extend(mp4_box(b"mdia", &mdia));
+        mp4_box(b"trak", &trak)
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut mvhd = vec![0; 8];
+        mvhd.extend_from_slice(&600u32.to_be_bytes());
+        mvhd.extend_from_slice(&(600u32 * 90).to_be_bytes());
+
+        let mut moov = mp4_box(b"mvhd", &full_box_v0(&mvhd));
+        moov.extend(trak(1, b"vide", b"avc1", [0x55, 0xC4])); // und
+        moov.extend(trak(2, b"soun", b"mp4a", [0x15, 0xC7])); // eng
+
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        file.extend(mp4_box(b"moov", &moov));
+        file.extend(mp4_box(b"mdat", &[0; 32]));
+
+        let metadata = probe(&file).unwrap();
+        assert_eq!(metadata.duration_secs, Some(90.0));
+        assert_eq!(
+            metadata.tracks,
+            vec![
+                Track {
+                    number: 1,
+                    kind: TrackKind::Video,
+                    codec: "avc1".to_string(),
+                    language: None,
+                    name: None,
+                },
+                Track {
+                    number: 2,
+                    kind: TrackKind::Audio,
+                    codec: "mp4a".to_string(),
+                    language: Some("eng".to_string()),
+                    name: None,
+                },
+            ]
+        );
+    }
+
+    fn table(entries: &[&[u32]]) -> Vec<u8> {
+        let mut table = full_box_v0(&(entries.len() as u32).to_be_bytes());
+        for field in entries.iter().flat_map(|entry| entry.iter()) {
+            table.extend_from_slice(&field.to_be_bytes());
+        }
+        table
+    }
+
+    #[test]
+    fn test_sample_tables() {
+        let mut mp4a = vec![0; AUDIO_SAMPLE_ENTRY_SIZE];
+        // ES_Descriptor, DecoderConfigDescriptor for AAC, then the
+        // AudioSpecificConfig for LC, 48kHz, stereo
+        let mut esds = vec![0; 4];
+        esds.extend([ES_DESCRIPTOR, 22, 0x00, 0x01, 0x00]);
+        esds.extend([DECODER_CONFIG_DESCRIPTOR, 17, 0x40, 0x15]);
+        esds.extend([0; DECODER_CONFIG_SIZE - 2]);
+        esds.extend([DECODER_SPECIFIC_INFO, 2, 0x11, 0x90]);
+        mp4a.extend(mp4_box(b"esds", &esds));
+
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(mp4_box(b"mp4a", &mp4a));
+        let mut stbl = mp4_box(b"stsd", &full_box_v0(&stsd));
+        // five 10 byte samples in chunks of three then two, 1024 ticks apart
+        stbl.extend(mp4_box(b"stsz", &full_box_v0(&[0, 0, 0, 10, 0, 0, 0, 5])));
+        stbl.extend(mp4_box(b"stco", &table(&[&[1000], &[5000]])));
+        stbl.extend(mp4_box(b"stsc", &table(&[&[1, 3, 1], &[2, 2, 1]])));
+        stbl.extend(mp4_box(b"stts", &table(&[&[5, 1024]])));
+        stbl.extend(mp4_box(b"stss", &table(&[&[1], &[4]])));
+
+        let mut mdhd = vec![0; 8];
+        mdhd.extend_from_slice(&48_000u32.to_be_bytes());
+        mdhd.extend_from_slice(&0u32.to_be_bytes());
+        let mut mdia = mp4_box(b"mdhd", &full_box_v0(&mdhd));
+        mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+        let moov = mp4_box(b"trak", &mp4_box(b"mdia", &mdia));
+
+        let tracks = sample_tracks(&moov).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].timescale, 48_000);
+        assert!(matches!(tracks[0].codec, Ok(Codec::Aac(_))));
+
+        let samples = &tracks[0].samples;
+        let offsets: Vec<_> = samples.iter().map(|sample| sample.offset).collect();
+        assert_eq!(offsets, [1000, 1010, 1020, 5000, 5010]);
+        assert_eq!(samples[4].dts, 4096);
+        let keyframes: Vec<_> = samples.iter().map(|sample| sample.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true, false]);
+    }
+
+    #[test]
+    fn test_moov_at_end() {
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        file.extend(mp4_box(b"mdat", &[0; 64]));
+
+        assert!(matches!(
+            probe(&file[..40]),
+            Err(MediaError::IncompleteData)
+        ));
+        assert_eq!(moov_search_start(&file[..40]), Some(88));
+
+        let mut faststart = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        faststart.extend(mp4_box(b"moov", &[]));
+        assert_eq!(moov_search_start(&faststart), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{fmt::Write, ops::Range};
+
+use serde::Serialize;
+
+use crate::media::{
+    ebml::{self, SegmentLayout},
+    ts::CLOCK_RATE,
+};
+
+/// How long to show a cue whose block doesn't say
+const DEFAULT_CUE_DURATION: u64 = 3 * CLOCK_RATE;
+
+/// ASS and SSA events in Matroska are the Dialogue line's fields from
+/// ReadOrder on, with the text after the eighth comma
+const ASS_FIELDS_BEFORE_TEXT: usize = 8;
+
+/// The text subtitle formats Matroska embeds that convert to WebVTT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    Srt,
+    Ass,
+    Ssa,
+    WebVtt,
+}
+
+/// One subtitle event, times in 90kHz ticks
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitleCue {
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+impl SubtitleFormat {
+    /// From a Matroska CodecID. Image-based subtitles (PGS, VobSub) aren't
+    /// text and can't be converted.
+    pub fn from_codec(codec: &str) -> Option<Self> {
+        match codec {
+            "S_TEXT/UTF8" | "S_TEXT/ASCII" => Some(Self::Srt),
+            "S_TEXT/ASS" | "S_ASS" => Some(Self::Ass),
+            "S_TEXT/SSA" | "S_SSA" => Some(Self::Ssa),
+            "S_TEXT/WEBVTT" => Some(Self::WebVtt),
+            _ => None,
+        }
+    }
+
+    /// Turns a block's text into WebVTT cue text
+    fn cue_text(self, block: &str) -> String {
+        let text = match self {
+            Self::Srt | Self::WebVtt => block.replace("\r\n", "\n"),
+            Self::Ass | Self::Ssa => {
+                let text = block
+                    .splitn(ASS_FIELDS_BEFORE_TEXT + 1, ',')
+                    .nth(ASS_FIELDS_BEFORE_TEXT)
+                    .unwrap_or_default();
+
+                escape(&strip_override_tags(text))
+                    .replace("\\N", "\n")
+                    .replace("\\n", "\n")
+                    .replace("\\h", " ")
+            }
+        };
+
+        // a blank line would end the cue and an arrow would start a new one
+        text.lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.replace("-->", "--&gt;"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The parts of the file to read cues from, each starting at a Cluster.
+/// Subtitles are interleaved with the video, so reading all of them only
+/// finds every cue once the whole file is in.
+///
+/// # Arguments
+/// - `ranges`: the downloaded parts of the file
+/// - `cluster_starts`: sorted offsets known to start a Cluster, each range
+///   is read from the first one in it
+/// - `max_len`: spans longer than this are split at the Clusters in them,
+///   where there are any
+pub fn cluster_spans(
+    ranges: &[Range<u64>],
+    cluster_starts: &[u64],
+    max_len: u64,
+) -> Vec<Range<u64>> {
+    let mut spans = Vec::new();
+
+    for range in ranges {
+        let first = cluster_starts.partition_point(|&start| start < range.start);
+        let mut starts = cluster_starts[first..]
+            .iter()
+            .copied()
+            .take_while(|&start| start < range.end);
+        let Some(mut span_start) = starts.next() else {
+            continue;
+        };
+
+        let mut last = span_start;
+        for start in starts.chain([range.end]) {
+            if start - span_start > max_len && last > span_start {
+                spans.push(span_start..last);
+                span_start = last;
+            }
+            last = start;
+        }
+        spans.push(span_start..range.end);
+    }
+
+    spans
+}
+
+/// Reads a subtitle track's cues from `data`, which starts at a Cluster
+pub fn read_cues(
+    data: &[u8],
+    layout: &SegmentLayout,
+    track: u64,
+    format: SubtitleFormat,
+) -> Vec<SubtitleCue> {
+    ebml::read_track_frames(data, layout, track)
+        .into_iter()
+        .filter_map(|frame| {
+            let text = format.cue_text(&String::from_utf8_lossy(&frame.data.concat()));
+            (!text.is_empty()).then(|| SubtitleCue {
+                start: frame.pts,
+                end: frame.pts + frame.duration.unwrap_or(DEFAULT_CUE_DURATION),
+                text,
+            })
+        })
+        .collect()
+}
+
+pub fn to_webvtt(cues: &[SubtitleCue]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for cue in cues {
+        let _ = write!(
+            vtt,
+            "\n{} --> {}\n{}\n",
+            timestamp(cue.start),
+            timestamp(cue.end),
+            cue.text
+        );
+    }
+
+    vtt
+}
+
+/// `hh:mm:ss.ttt` from 90kHz ticks
+fn timestamp(ticks: u64) -> String {
+    let millis = ticks * 1000 / CLOCK_RATE;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Drops ASS override blocks like `{\an8}` or `{\i1}`
+fn strip_override_tags(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut depth = 0usize;
+
+    for c in text.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => {}
+        }
+    }
+
+    stripped
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ass_to_webvtt() {
+        let text = SubtitleFormat::Ass
+            .cue_text("0,0,Default,,0,0,0,,{\\an8}Hello, world\\N{\\i1}second line{\\i0} & more");
+        assert_eq!(text, "Hello, world\nsecond line &amp; more");
+
+        let cues = [SubtitleCue {
+            start: 3_723_500 * 90,
+            end: 3_725_000 * 90,
+            text,
+        }];
+        assert_eq!(
+            to_webvtt(&cues),
+            "WEBVTT\n\n01:02:03.500 --> 01:02:05.000\nHello, world\nsecond line &amp; more\n"
+        );
+    }
+
+    #[test]
+    fn test_cluster_spans() {
+        let ranges = [0..100, 150..400, 500..600];
+        let cluster_starts = [10, 50, 90, 160, 250, 300, 520];
+
+        assert_eq!(
+            cluster_spans(&ranges, &cluster_starts, 300),
+            [10..100, 160..400, 520..600]
+        );
+        assert_eq!(
+            cluster_spans(&ranges, &cluster_starts, 100),
+            [10..100, 160..250, 250..300, 300..400, 520..600]
+        );
+    }
+
+    #[test]
+    fn test_srt_text() {
+        let text = SubtitleFormat::Srt.cue_text("<i>One</i>\r\n\r\nTwo --> three\r\n");
+        assert_eq!(text, "<i>One</i>\nTwo --&gt; three");
+
+        assert_eq!(SubtitleFormat::from_codec("S_HDMV/PGS"), None);
+    }
+}
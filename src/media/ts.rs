@@ -0,0 +1,329 @@
+use crate::media::codec::Codec;
+
+pub const PACKET_SIZE: usize = 188;
+const HEADER_SIZE: usize = 4;
+const PAYLOAD_SIZE: usize = PACKET_SIZE - HEADER_SIZE;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+/// Elementary streams get consecutive PIDs from here, in track order
+const FIRST_STREAM_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// Adaptation field flags
+const RANDOM_ACCESS: u8 = 0x40;
+const HAS_PCR: u8 = 0x10;
+
+/// Timestamps are in 90kHz ticks and wrap at 33 bits
+pub const CLOCK_RATE: u64 = 90_000;
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+/// How far the PCR trails the timestamps, callers offset timestamps by at
+/// least this much so the PCR never goes negative
+pub const PCR_DELAY: u64 = CLOCK_RATE * 7 / 10;
+
+/// Video PES packets may leave their length unset, audio ones are always
+/// small enough to fit
+const MAX_PES_LENGTH: usize = u16::MAX as usize;
+
+struct Stream {
+    pid: u16,
+    stream_type: u8,
+    stream_id: u8,
+    continuity: u8,
+}
+
+/// Writes one MPEG-TS program: a PAT and PMT up front, then PES packets for
+/// each track. The first video track carries the PCR.
+pub struct TsMuxer {
+    streams: Vec<Stream>,
+    pcr_stream: usize,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    out: Vec<u8>,
+}
+
+impl TsMuxer {
+    pub fn new<'a>(codecs: impl IntoIterator<Item = &'a Codec>) -> Self {
+        let mut pcr_stream = None;
+        let streams = codecs
+            .into_iter()
+            .enumerate()
+            .map(|(index, codec)| {
+                if codec.is_video() && pcr_stream.is_none() {
+                    pcr_stream = Some(index);
+                }
+
+                Stream {
+                    pid: FIRST_STREAM_PID + index as u16,
+                    stream_type: codec.stream_type(),
+                    stream_id: codec.stream_id(),
+                    continuity: 0,
+                }
+            })
+            .collect();
+
+        Self {
+            streams,
+            pcr_stream: pcr_stream.unwrap_or_default(),
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            out: Vec::new(),
+        }
+    }
+
+    /// Writes the PAT and PMT, which each segment starts with so players can
+    /// begin decoding from any of them
+    pub fn write_tables(&mut self) {
+        let mut pat = vec![0x00, 0xB0, 0x00];
+        pat.extend_from_slice(&1u16.to_be_bytes()); // transport stream ID
+        pat.extend_from_slice(&[0xC1, 0x00, 0x00]); // version 0, current, one section
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+
+        let mut pmt = vec![0x02, 0xB0, 0x00];
+        pmt.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pmt.extend_from_slice(&[0xC1, 0x00, 0x00]);
+        let pcr_pid = self.streams.get(self.pcr_stream).map_or(0x1FFF, |s| s.pid);
+        pmt.extend_from_slice(&(0xE000 | pcr_pid).to_be_bytes());
+        pmt.extend_from_slice(&[0xF0, 0x00]); // no program descriptors
+        for stream in &self.streams {
+            pmt.push(stream.stream_type);
+            pmt.extend_from_slice(&(0xE000 | stream.pid).to_be_bytes());
+            pmt.extend_from_slice(&[0xF0, 0x00]); // no ES descriptors
+        }
+
+        let mut continuity = self.pat_continuity;
+        self.write_section(PAT_PID, &mut continuity, pat);
+        self.pat_continuity = continuity;
+
+        let mut continuity = self.pmt_continuity;
+        self.write_section(PMT_PID, &mut continuity, pmt);
+        self.pmt_continuity = continuity;
+    }
+
+    /// Writes one frame as a PES packet. Timestamps are in 90kHz ticks; with
+    /// no DTS the decoder takes it to equal the PTS.
+    pub fn write_pes(
+        &mut self,
+        stream: usize,
+        pts: u64,
+        dts: Option<u64>,
+        keyframe: bool,
+        payload: &[u8],
+    ) {
+        let Stream { stream_id, .. } = self.streams[stream];
+        let dts = dts.filter(|&dts| dts != pts);
+
+        let header_data_len = if dts.is_some() { 10 } else { 5 };
+        let mut pes = Vec::with_capacity(9 + header_data_len + payload.len());
+        pes.extend_from_slice(&[0x00, 0x00, 0x01, stream_id]);
+
+        let length = 3 + header_data_len + payload.len();
+        let length = if length > MAX_PES_LENGTH { 0 } else { length };
+        pes.extend_from_slice(&(length as u16).to_be_bytes());
+
+        pes.push(0x84); // data aligned
+        match dts {
+            Some(dts) => {
+                pes.extend_from_slice(&[0xC0, header_data_len as u8]);
+                pes.extend_from_slice(&encode_timestamp(0x3, pts));
+                pes.extend_from_slice(&encode_timestamp(0x1, dts));
+            }
+            None => {
+                pes.extend_from_slice(&[0x80, header_data_len as u8]);
+                pes.extend_from_slice(&encode_timestamp(0x2, pts));
+            }
+        }
+        pes.extend_from_slice(payload);
+
+        // the PCR must not run ahead of the decoder, so it trails the DTS by
+        // the headroom the timestamps were given
+        let pcr = (stream == self.pcr_stream).then(|| dts.unwrap_or(pts).saturating_sub(PCR_DELAY));
+
+        let mut first = true;
+        let mut rest = pes.as_slice();
+        while !rest.is_empty() {
+            let mut adaptation = Vec::new();
+            if first {
+                let mut flags = if keyframe { RANDOM_ACCESS } else { 0 };
+                if let Some(pcr) = pcr {
+                    flags |= HAS_PCR;
+                    adaptation.push(flags);
+                    adaptation.extend_from_slice(&encode_pcr(pcr));
+                } else if flags != 0 {
+                    adaptation.push(flags);
+                }
+            }
+
+            let written = self.write_packet(stream, first, adaptation, rest);
+            rest = &rest[written..];
+            first = false;
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+
+    /// Writes one packet of `payload`, padding it out with adaptation field
+    /// stuffing if it's the last.
+    ///
+    /// # Returns
+    /// - The number of payload bytes written
+    fn write_packet(
+        &mut self,
+        stream: usize,
+        unit_start: bool,
+        mut adaptation: Vec<u8>,
+        payload: &[u8],
+    ) -> usize {
+        let Stream {
+            pid, continuity, ..
+        } = self.streams[stream];
+
+        let adaptation_len = if adaptation.is_empty() {
+            0
+        } else {
+            1 + adaptation.len()
+        };
+        let space = PAYLOAD_SIZE - adaptation_len;
+        let written = payload.len().min(space);
+        let stuffing = space - written;
+
+        let mut control = 0x10; // payload only
+        let mut field = Vec::new();
+        if adaptation_len + stuffing > 0 {
+            control = 0x30; // adaptation field and payload
+
+            // the field's length byte counts towards it, so a single byte of
+            // stuffing is a zero-length field
+            let total = adaptation_len + stuffing;
+            if adaptation.is_empty() && total >= 2 {
+                adaptation.push(0x00);
+            }
+            adaptation.resize(total - 1, 0xFF);
+
+            field.push(adaptation.len() as u8);
+            field.extend(adaptation);
+        }
+
+        self.out.push(SYNC_BYTE);
+        self.out
+            .extend_from_slice(&((unit_start as u16) << 14 | pid).to_be_bytes());
+        self.out.push(control | continuity);
+        self.out.extend(field);
+        self.out.extend_from_slice(&payload[..written]);
+
+        self.streams[stream].continuity = (continuity + 1) & 0x0F;
+
+        written
+    }
+
+    /// Writes a PSI section in a single packet, with the pointer field
+    /// in front and CRC behind
+    fn write_section(&mut self, pid: u16, continuity: &mut u8, mut section: Vec<u8>) {
+        // section length counts everything after it, CRC included
+        let length = section.len() - 3 + 4;
+        section[1] |= (length >> 8) as u8;
+        section[2] = length as u8;
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let start = self.out.len();
+        self.out.push(SYNC_BYTE);
+        self.out.extend_from_slice(&(0x4000 | pid).to_be_bytes());
+        self.out.push(0x10 | *continuity);
+        self.out.push(0x00); // pointer field
+        self.out.extend(section);
+        self.out.resize(start + PACKET_SIZE, 0xFF);
+
+        *continuity = (*continuity + 1) & 0x0F;
+    }
+}
+
+/// 33 bit PTS/DTS spread over 5 bytes with marker bits, `prefix` tells which
+/// it is
+fn encode_timestamp(prefix: u8, timestamp: u64) -> [u8; 5] {
+    let ts = timestamp & TIMESTAMP_MASK;
+
+    [
+        (prefix << 4) | ((ts >> 29) as u8 & 0x0E) | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xFE) | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xFE) | 1,
+    ]
+}
+
+/// 33 bit PCR base, 6 reserved bits and a zero 9 bit extension
+fn encode_pcr(pcr: u64) -> [u8; 6] {
+    let base = pcr & TIMESTAMP_MASK;
+
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base & 1) << 7) as u8 | 0x7E,
+        0x00,
+    ]
+}
+
+/// The CRC PSI sections end with: polynomial 0x04C11DB7, not reflected, no
+/// final XOR
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_mpeg2() {
+        // the check value for CRC-32/MPEG-2
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+    }
+
+    #[test]
+    fn test_timestamp_encoding() {
+        assert_eq!(
+            encode_timestamp(0x2, 90_000),
+            [0x21, 0x00, 0x05, 0xBF, 0x21]
+        );
+    }
+
+    #[test]
+    fn test_packetisation() {
+        let codecs = [Codec::Ac3, Codec::Mp3];
+        let mut muxer = TsMuxer::new(&codecs);
+        muxer.write_tables();
+        muxer.write_pes(0, 1000, None, true, &[0xAB; 400]);
+        let out = muxer.finish();
+
+        assert_eq!(out.len() % PACKET_SIZE, 0);
+        assert!(out.chunks(PACKET_SIZE).all(|packet| packet[0] == SYNC_BYTE));
+
+        let packets: Vec<_> = out.chunks(PACKET_SIZE).skip(2).collect();
+        assert_eq!(packets.len(), 3);
+
+        // no video, so the first stream carries the PCR
+        assert_eq!(packets[0][1..3], [0x41, 0x00]);
+        assert_eq!(packets[0][3], 0x30);
+        assert_eq!(packets[0][5], RANDOM_ACCESS | HAS_PCR);
+
+        // continuity counts up and the last packet is stuffed
+        assert_eq!(packets[1][3], 0x11);
+        assert_eq!(packets[2][3], 0x32);
+        assert_eq!(packets[2][PACKET_SIZE - 1], 0xAB);
+    }
+}
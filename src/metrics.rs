@@ -0,0 +1,190 @@
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::nntp::error::NntpError;
+use crate::stream::orchestrator::BufferHealth;
+
+/// Every metric the service exports at `/metrics`. Counters are recorded
+/// where things happen, gauges are set just before each scrape.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Raw article bytes, by server
+    pub downloaded_bytes: IntCounterVec,
+    /// Article fetches by server and outcome, see [`outcome`]
+    pub articles: IntCounterVec,
+    pub pool_size: IntGaugeVec,
+    pub pool_idle: IntGaugeVec,
+    /// From asking for a connection to having one, turn in the share included
+    pub pool_wait_seconds: HistogramVec,
+    pub decode_seconds: Histogram,
+    /// Requests waiting their turn at a connection, by server
+    pub queue_depth: IntGaugeVec,
+    /// 0 for critical up to 3 for excellent, by session
+    pub buffer_health: IntGaugeVec,
+    pub active_streams: IntGauge,
+    pub served_bytes: IntCounter,
+    /// Bytes on disk under the session directories
+    pub cache_bytes: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("nzb_streamer".to_string()), None)
+            .expect("the prefix is valid");
+
+        let metrics = Self {
+            downloaded_bytes: IntCounterVec::new(
+                Opts::new("downloaded_bytes_total", "Raw article bytes downloaded"),
+                &["server"],
+            )
+            .expect("valid metric"),
+            articles: IntCounterVec::new(
+                Opts::new("articles_total", "Article fetches by outcome"),
+                &["server", "outcome"],
+            )
+            .expect("valid metric"),
+            pool_size: IntGaugeVec::new(
+                Opts::new("pool_connections", "Connections open"),
+                &["server"],
+            )
+            .expect("valid metric"),
+            pool_idle: IntGaugeVec::new(
+                Opts::new("pool_idle_connections", "Connections open and idle"),
+                &["server"],
+            )
+            .expect("valid metric"),
+            pool_wait_seconds: HistogramVec::new(
+                HistogramOpts::new("pool_wait_seconds", "Time waiting for a connection"),
+                &["server"],
+            )
+            .expect("valid metric"),
+            decode_seconds: Histogram::with_opts(HistogramOpts::new(
+                "decode_seconds",
+                "Time decoding an article's yEnc",
+            ))
+            .expect("valid metric"),
+            queue_depth: IntGaugeVec::new(
+                Opts::new("queue_depth", "Requests waiting their turn at a connection"),
+                &["server"],
+            )
+            .expect("valid metric"),
+            buffer_health: IntGaugeVec::new(
+                Opts::new(
+                    "buffer_health",
+                    "Buffer ahead of the player, 0 critical to 3 excellent",
+                ),
+                &["session"],
+            )
+            .expect("valid metric"),
+            active_streams: IntGauge::new("active_streams", "HTTP streams being sent")
+                .expect("valid metric"),
+            served_bytes: IntCounter::new("served_bytes_total", "Bytes sent to players")
+                .expect("valid metric"),
+            cache_bytes: IntGauge::new("cache_bytes", "Disk used by session files")
+                .expect("valid metric"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(metrics.downloaded_bytes.clone()),
+            Box::new(metrics.articles.clone()),
+            Box::new(metrics.pool_size.clone()),
+            Box::new(metrics.pool_idle.clone()),
+            Box::new(metrics.pool_wait_seconds.clone()),
+            Box::new(metrics.decode_seconds.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.buffer_health.clone()),
+            Box::new(metrics.active_streams.clone()),
+            Box::new(metrics.served_bytes.clone()),
+            Box::new(metrics.cache_bytes.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .expect("metrics encode as text")
+    }
+}
+
+/// The `outcome` label of an article fetch
+pub fn outcome<T>(result: &Result<T, NntpError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(NntpError::ArticleNotFound(_)) => "430",
+        Err(NntpError::Crc(_) | NntpError::Decode(_)) => "crc",
+        Err(NntpError::Timeout) => "timeout",
+        Err(_) => "error",
+    }
+}
+
+pub fn health_value(health: BufferHealth) -> i64 {
+    match health {
+        BufferHealth::Critical => 0,
+        BufferHealth::Poor => 1,
+        BufferHealth::Good => 2,
+        BufferHealth::Excellent => 3,
+    }
+}
+
+/// Bytes allocated on disk under `path`, so sparse session files only count
+/// what's been downloaded
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.blocks() * 512);
+    }
+
+    std::fs::read_dir(path)?.try_fold(0, |total, entry| Ok(total + disk_usage(&entry?.path())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        METRICS
+            .articles
+            .with_label_values(&["news.example.com", outcome::<()>(&Err(NntpError::Timeout))])
+            .inc();
+
+        let text = METRICS.encode();
+        assert!(text.contains(
+            r#"nzb_streamer_articles_total{outcome="timeout",server="news.example.com"} 1"#
+        ));
+        assert!(text.contains("# TYPE nzb_streamer_decode_seconds histogram"));
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("session")).unwrap();
+        std::fs::write(dir.path().join("session/stream.mkv"), vec![1; 64 * 1024]).unwrap();
+
+        assert!(disk_usage(dir.path()).unwrap() >= 64 * 1024);
+        assert_eq!(disk_usage(&dir.path().join("missing")).unwrap(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::{
+    newznab::{
+        SearchQuery, SearchResult, api_error, config::NewznabConfig, error::NewznabError,
+        parse_results,
+    },
+    nzb::source,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Searches a Newznab-compatible indexer and downloads NZBs from it
+#[derive(Debug, Clone)]
+pub struct NewznabClient {
+    http: reqwest::Client,
+    api_url: String,
+    apikey: String,
+}
+
+impl NewznabClient {
+    pub fn new(config: NewznabConfig) -> Result<Self, NewznabError> {
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("nzb-streamer/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        Ok(Self {
+            http,
+            api_url: format!("{}/api", config.url.trim_end_matches('/')),
+            apikey: config.apikey,
+        })
+    }
+
+    pub async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>, NewznabError> {
+        let mut params = query.params()?;
+        params.push(("apikey", self.apikey.clone()));
+
+        let body = self
+            .http
+            .get(&self.api_url)
+            .query(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let results = parse_results(&body)?;
+        info!("Indexer returned {} results for {:?}", results.len(), query);
+
+        Ok(results)
+    }
+
+    /// Downloads a result's NZB, gunzipping it if the indexer sent it
+    /// compressed
+    pub async fn fetch_nzb(&self, result: &SearchResult) -> Result<String, NewznabError> {
+        let response = self
+            .http
+            .get(&result.link)
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = source::decode(&source::read_body(response).await?)?;
+
+        // a rejected grab still comes back 200, as an error document
+        if let Some(error) = roxmltree::Document::parse(&body)
+            .ok()
+            .and_then(|document| api_error(&document))
+        {
+            return Err(error);
+        }
+
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+    use mockito::Matcher;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_search_and_fetch() {
+        let mut server = mockito::Server::new_async().await;
+        let feed = format!(
+            r#"<rss xmlns:newznab="http://www.newznab.com/DTD/2010/feeds/attributes/"><channel>
+                <item><title>Movie.2020.1080p</title><guid>abc</guid>
+                <enclosure url="{}/getnzb/abc.nzb" length="10"/></item>
+            </channel></rss>"#,
+            server.url()
+        );
+
+        let search = server
+            .mock("GET", "/api")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("t".into(), "movie".into()),
+                Matcher::UrlEncoded("imdbid".into(), "0111161".into()),
+                Matcher::UrlEncoded("apikey".into(), "secret".into()),
+            ]))
+            .with_body(feed)
+            .create_async()
+            .await;
+        let grab = server
+            .mock("GET", "/getnzb/abc.nzb")
+            .with_body("<nzb/>")
+            .create_async()
+            .await;
+
+        let client = NewznabClient::new(NewznabConfig {
+            url: format!("{}/", server.url()),
+            apikey: "secret".to_string(),
+        })
+        .unwrap();
+        let query = SearchQuery::Movie {
+            query: None,
+            imdb_id: Some("tt0111161".to_string()),
+        };
+
+        let results = client.search(&query).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].title, "Movie.2020.1080p");
+        assert_eq!(client.fetch_nzb(&results[0]).await.unwrap(), "<nzb/>");
+
+        search.assert_async().await;
+        grab.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_gzipped_grab() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"<nzb/>").unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/getnzb/abc.nzb")
+            .with_body(encoder.finish().unwrap())
+            .create_async()
+            .await;
+
+        let client = NewznabClient::new(NewznabConfig {
+            url: server.url(),
+            apikey: "secret".to_string(),
+        })
+        .unwrap();
+        let result = SearchResult {
+            title: "Movie".to_string(),
+            guid: "abc".to_string(),
+            link: format!("{}/getnzb/abc.nzb", server.url()),
+            size: None,
+            category: None,
+            pub_date: None,
+        };
+
+        assert_eq!(client.fetch_nzb(&result).await.unwrap(), "<nzb/>");
+    }
+
+    #[tokio::test]
+    async fn test_rejected_grab() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/getnzb/abc.nzb")
+            .with_body(r#"<error code="429" description="Request limit reached"/>"#)
+            .create_async()
+            .await;
+
+        let client = NewznabClient::new(NewznabConfig {
+            url: server.url(),
+            apikey: "secret".to_string(),
+        })
+        .unwrap();
+        let result = SearchResult {
+            title: "Movie".to_string(),
+            guid: "abc".to_string(),
+            link: format!("{}/getnzb/abc.nzb", server.url()),
+            size: None,
+            category: None,
+            pub_date: None,
+        };
+
+        let error = client.fetch_nzb(&result).await.unwrap_err();
+        assert!(matches!(error, NewznabError::Api { code, .. } if code == "429"));
+    }
+}
@@ -0,0 +1,23 @@
+use config::{Config, Environment};
+use serde::Deserialize;
+
+use crate::newznab::error::NewznabError;
+
+/// The indexer to search, from `NEWZNAB_URL` and `NEWZNAB_APIKEY`
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewznabConfig {
+    /// Base URL of the indexer, the API lives at `{url}/api`
+    pub url: String,
+    pub apikey: String,
+}
+
+impl NewznabConfig {
+    pub fn from_env() -> Result<Self, NewznabError> {
+        let config: NewznabConfig = Config::builder()
+            .add_source(Environment::with_prefix("NEWZNAB").separator("_"))
+            .build()?
+            .try_deserialize()?;
+
+        Ok(config)
+    }
+}
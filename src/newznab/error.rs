@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum NewznabError {
+    #[error("Error reading indexer config from environment")]
+    Config(#[from] config::ConfigError),
+
+    #[error("Error talking to indexer")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Indexer returned an unreadable NZB: {0}")]
+    Nzb(#[from] crate::nzb::error::NzbError),
+
+    #[error("Indexer returned malformed XML")]
+    Xml(#[from] roxmltree::Error),
+
+    #[error("Indexer returned error {code}: {description}")]
+    Api { code: String, description: String },
+
+    #[error("Search type '{0}' needs a query or an IMDb ID")]
+    MissingQuery(&'static str),
+
+    #[error("No search results")]
+    NoResults,
+}
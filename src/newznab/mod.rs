@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+
+use crate::newznab::error::NewznabError;
+
+pub mod client;
+pub mod config;
+pub mod error;
+
+/// Newznab extends RSS items with `<newznab:attr name=".." value=".."/>`
+const NEWZNAB_NS: &str = "http://www.newznab.com/DTD/2010/feeds/attributes/";
+
+/// The search functions we use, with the parameters each one takes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SearchQuery {
+    /// `t=search`: free text across every category
+    Search { query: String },
+    /// `t=tvsearch`: by IMDb ID where the indexer supports it, or name, and
+    /// optionally season and episode
+    TvSearch {
+        query: Option<String>,
+        imdb_id: Option<String>,
+        season: Option<u32>,
+        episode: Option<u32>,
+    },
+    /// `t=movie`: by IMDb ID or name
+    Movie {
+        query: Option<String>,
+        imdb_id: Option<String>,
+    },
+}
+
+/// One item from a search's RSS feed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub guid: String,
+    /// Where to download the NZB, already carrying the API key
+    pub link: String,
+    pub size: Option<u64>,
+    pub category: Option<String>,
+    pub pub_date: Option<String>,
+}
+
+impl SearchQuery {
+    /// The query string parameters, less the API key
+    pub fn params(&self) -> Result<Vec<(&'static str, String)>, NewznabError> {
+        let mut params = Vec::new();
+
+        let (function, query, imdb_id) = match self {
+            SearchQuery::Search { query } => ("search", Some(query), None),
+            SearchQuery::TvSearch {
+                query,
+                imdb_id,
+                season,
+                episode,
+            } => {
+                params.extend(season.map(|season| ("season", season.to_string())));
+                params.extend(episode.map(|episode| ("ep", episode.to_string())));
+                ("tvsearch", query.as_ref(), imdb_id.as_ref())
+            }
+            SearchQuery::Movie { query, imdb_id } => ("movie", query.as_ref(), imdb_id.as_ref()),
+        };
+
+        if query.is_none_or(|query| query.is_empty()) && imdb_id.is_none() {
+            return Err(NewznabError::MissingQuery(function));
+        }
+
+        params.insert(0, ("t", function.to_string()));
+        params.extend(query.map(|query| ("q", query.clone())));
+        // indexers want the bare number, without the `tt`
+        params.extend(imdb_id.map(|id| ("imdbid", id.trim_start_matches("tt").to_string())));
+
+        Ok(params)
+    }
+}
+
+/// Parses a search's RSS feed, or the `<error>` document indexers answer
+/// with instead
+pub fn parse_results(xml: &str) -> Result<Vec<SearchResult>, NewznabError> {
+    let document = roxmltree::Document::parse(xml)?;
+    if let Some(error) = api_error(&document) {
+        return Err(error);
+    }
+
+    let results = document
+        .root_element()
+        .descendants()
+        .filter(|node| node.has_tag_name("item"))
+        .filter_map(|item| {
+            let child_text = |name: &str| {
+                item.children()
+                    .find(|child| child.has_tag_name(name))
+                    .and_then(|child| child.text())
+                    .map(|text| text.trim().to_string())
+            };
+            let attr = |name: &str| {
+                item.children()
+                    .filter(|child| child.has_tag_name((NEWZNAB_NS, "attr")))
+                    .find(|child| child.attribute("name") == Some(name))
+                    .and_then(|child| child.attribute("value"))
+            };
+            let enclosure = item
+                .children()
+                .find(|child| child.has_tag_name("enclosure"));
+
+            // the enclosure is the NZB itself, link is sometimes a details page
+            let link = enclosure
+                .and_then(|enclosure| enclosure.attribute("url"))
+                .map(str::to_string)
+                .or_else(|| child_text("link"))?;
+            let size = attr("size")
+                .or_else(|| enclosure.and_then(|enclosure| enclosure.attribute("length")))
+                .and_then(|size| size.parse().ok());
+
+            Some(SearchResult {
+                title: child_text("title")?,
+                guid: child_text("guid").unwrap_or_else(|| link.clone()),
+                link,
+                size,
+                category: attr("category").map(str::to_string),
+                pub_date: child_text("pubDate"),
+            })
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Indexers answer failed requests, including NZB downloads, with a bare
+/// `<error code=".." description=".."/>`
+pub fn api_error(document: &roxmltree::Document) -> Option<NewznabError> {
+    let root = document.root_element();
+
+    root.has_tag_name("error").then(|| NewznabError::Api {
+        code: root.attribute("code").unwrap_or_default().to_string(),
+        description: root
+            .attribute("description")
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESULTS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:newznab="http://www.newznab.com/DTD/2010/feeds/attributes/">
+  <channel>
+    <title>indexer</title>
+    <item>
+      <title>Show.S01E02.1080p.WEB.H264</title>
+      <guid isPermaLink="false">abc123</guid>
+      <link>http://indexer/getnzb/abc123</link>
+      <pubDate>Sat, 04 Oct 2025 10:00:00 +0000</pubDate>
+      <enclosure url="http://indexer/getnzb/abc123.nzb" length="100" type="application/x-nzb"/>
+      <newznab:attr name="category" value="5040"/>
+      <newznab:attr name="size" value="2147483648"/>
+    </item>
+    <item>
+      <title>Show.S01E02.720p.WEB.H264</title>
+      <link>http://indexer/getnzb/def456</link>
+    </item>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn test_parse_results() {
+        let results = parse_results(RESULTS).unwrap();
+        assert_eq!(results.len(), 2);
+
+        assert_eq!(
+            results[0],
+            SearchResult {
+                title: "Show.S01E02.1080p.WEB.H264".to_string(),
+                guid: "abc123".to_string(),
+                link: "http://indexer/getnzb/abc123.nzb".to_string(),
+                size: Some(2_147_483_648),
+                category: Some("5040".to_string()),
+                pub_date: Some("Sat, 04 Oct 2025 10:00:00 +0000".to_string()),
+            }
+        );
+        assert_eq!(results[1].guid, "http://indexer/getnzb/def456");
+        assert_eq!(results[1].size, None);
+
+        let error =
+            parse_results(r#"<error code="100" description="Incorrect user credentials"/>"#);
+        assert!(matches!(error, Err(NewznabError::Api { code, .. }) if code == "100"));
+    }
+
+    #[test]
+    fn test_query_params() {
+        let query = SearchQuery::TvSearch {
+            query: None,
+            imdb_id: Some("tt0944947".to_string()),
+            season: Some(1),
+            episode: Some(2),
+        };
+        assert_eq!(
+            query.params().unwrap(),
+            vec![
+                ("t", "tvsearch".to_string()),
+                ("season", "1".to_string()),
+                ("ep", "2".to_string()),
+                ("imdbid", "0944947".to_string()),
+            ]
+        );
+
+        let empty = SearchQuery::Movie {
+            query: Some(String::new()),
+            imdb_id: None,
+        };
+        assert!(matches!(
+            empty.params(),
+            Err(NewznabError::MissingQuery("movie"))
+        ));
+    }
+}
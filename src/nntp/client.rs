@@ -1,30 +1,90 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::nntp::pool::NntpPool;
-use crate::nntp::yenc::extract_yenc_data;
+use crate::metrics::{METRICS, outcome};
+use crate::nntp::connection::{Request, Response};
+use crate::nntp::pool::{Connection, NntpPool};
+use crate::nntp::yenc::{
+    YencArticle, extract_file_size, extract_part_crc, extract_part_offset, extract_yenc_data,
+};
 use crate::nntp::{config::NntpConfig, error::NntpError};
+use crate::scheduler::bandwidth::RateLimiter;
+use crate::scheduler::share::{ConnectionShare, Demand, Permit, Tenant};
 use backoff::ExponentialBackoff;
 use backoff::exponential::ExponentialBackoffBuilder;
 use backoff::future::retry;
 use bytes::Bytes;
+use deadpool::managed::Object;
+use futures::future::join_all;
 use nzb_rs::Segment;
-use rek2_nntp::body_bytes;
 use tokio::time;
 use tracing::{debug, info, warn};
 
+/// How long the server gets to answer a command. A window of pipelined
+/// commands gets this for each.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct NntpClient {
+    /// The host, to label metrics with
+    server: String,
     pool: NntpPool,
+    pipeline_depth: usize,
+    /// Covers everything downloaded from the server, sessions can have
+    /// their own limit on top
+    bandwidth: Arc<RateLimiter>,
+    /// Who gets the next connection when every one is busy
+    share: ConnectionShare,
+    /// Stands in for requests made outside a session's background download,
+    /// which someone is usually waiting on
+    direct: Arc<Tenant>,
+    /// How long a failing article keeps being retried
+    retry_budget: Duration,
 }
 
 impl NntpClient {
-    pub fn new(config: NntpConfig) -> Result<Self, NntpError> {
+    pub fn new(config: NntpConfig, retry_budget: Duration) -> Result<Self, NntpError> {
         Ok(Self {
+            server: config.host.clone(),
+            pipeline_depth: (*config.pipeline_depth).max(1),
+            share: ConnectionShare::new(*config.max_connections),
+            direct: Arc::new(Tenant::new(|| Demand::Streaming, None)),
             pool: NntpPool::new(config)?,
+            bandwidth: Arc::default(),
+            retry_budget,
         })
     }
 
-    pub async fn warm_pool(&self) {
-        let target = 50; // TODO: hardcoded for now
+    /// How many commands this server takes down a connection at once
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    pub fn bandwidth(&self) -> &Arc<RateLimiter> {
+        &self.bandwidth
+    }
+
+    /// Sets the pool and queue gauges, just before a scrape
+    pub fn record_metrics(&self) {
+        let status = self.pool.status();
+        let server = [self.server.as_str()];
+
+        METRICS
+            .pool_size
+            .with_label_values(&server)
+            .set(status.size as i64);
+        METRICS
+            .pool_idle
+            .with_label_values(&server)
+            .set(status.available as i64);
+        METRICS
+            .queue_depth
+            .with_label_values(&server)
+            .set(self.share.waiting() as i64);
+    }
+
+    /// Opens up to `connections` ahead of the first request
+    pub async fn warm_pool(&self, connections: usize) {
+        let target = connections.min(self.pool.status().max_size);
         info!("Pre-warming connection pool with {} connections", target);
 
         for i in 0..target {
@@ -49,13 +109,30 @@ impl NntpClient {
     }
 
     pub async fn download(&self, segment: &Segment) -> Result<Bytes, NntpError> {
+        Ok(self.download_article(segment).await?.data)
+    }
+
+    pub async fn download_article(&self, segment: &Segment) -> Result<YencArticle, NntpError> {
+        self.retry_download(segment, &self.direct).await
+    }
+
+    async fn retry_download(
+        &self,
+        segment: &Segment,
+        tenant: &Arc<Tenant>,
+    ) -> Result<YencArticle, NntpError> {
         let backoff: ExponentialBackoff = ExponentialBackoffBuilder::new()
-            .with_max_elapsed_time(Some(Duration::from_secs(30)))
+            .with_max_elapsed_time(Some(self.retry_budget))
             .build();
 
         retry(backoff, || async {
-            match self.download_segment(segment).await {
+            match self.download_segment(segment, tenant).await {
                 Ok(data) => Ok(data),
+                // neither a missing article nor a corrupt one gets better
+                // by asking again
+                Err(e @ (NntpError::ArticleNotFound(_) | NntpError::Crc(_))) => {
+                    Err(backoff::Error::permanent(e))
+                }
                 Err(e) => {
                     warn!("Download attempt failed: {}", e);
                     Err(backoff::Error::transient(e)) // TODO: for now, some errors ARE permenant
@@ -65,26 +142,209 @@ impl NntpClient {
         .await
     }
 
-    async fn download_segment(&self, segment: &Segment) -> Result<Bytes, NntpError> {
-        let mut conn = self.pool.get().await?;
+    /// Downloads the segments over one connection, pipelining the `BODY`s
+    /// up to the server's depth at a time. Any that fail for a reason other
+    /// than being gone or corrupt are retried on their own with backoff, so
+    /// a dropped connection doesn't sink the rest.
+    ///
+    /// The connection is taken in the `tenant`'s turn, and the whole batch
+    /// waits for bandwidth under its limit as well as the global one. Without
+    /// a tenant the batch is taken to be for someone watching.
+    ///
+    /// # Returns
+    /// - Each segment's article, in order
+    pub async fn download_articles(
+        &self,
+        segments: &[Segment],
+        tenant: Option<&Arc<Tenant>>,
+    ) -> Vec<Result<YencArticle, NntpError>> {
+        let tenant = tenant.unwrap_or(&self.direct);
+        let bytes = segments.iter().map(|segment| segment.size as u64).sum();
+        self.acquire_bandwidth(tenant, bytes).await;
+
+        let message_ids = message_ids(segments);
+        let requests: Vec<_> = message_ids.iter().map(|id| Request::Body(id)).collect();
+        let bodies = self
+            .pipeline(&requests, tenant)
+            .await
+            .into_iter()
+            .map(|outcome| {
+                outcome.map(|response| match response {
+                    Response::Body(body) => body,
+                    Response::Stat(_) => unreachable!("BODY is answered with a body"),
+                })
+            });
+
+        join_all(
+            segments
+                .iter()
+                .zip(bodies)
+                .map(|(segment, body)| async move {
+                    match self.decode(segment, body) {
+                        Err(e @ (NntpError::ArticleNotFound(_) | NntpError::Crc(_))) => Err(e),
+                        Err(e) => {
+                            debug!("Retrying {} on its own: {e}", segment.message_id);
+                            self.retry_download(segment, tenant).await
+                        }
+                        article => article,
+                    }
+                }),
+        )
+        .await
+    }
+
+    /// Asks the server whether it still has each segment, without downloading
+    /// any, pipelining the `STAT`s down one connection
+    pub async fn stat_all(&self, segments: &[&Segment]) -> Result<Vec<bool>, NntpError> {
+        let message_ids = message_ids(segments.iter().copied());
+        let requests: Vec<_> = message_ids.iter().map(|id| Request::Stat(id)).collect();
+
+        self.pipeline(&requests, &self.direct)
+            .await
+            .into_iter()
+            .map(|outcome| {
+                outcome.map(|response| match response {
+                    Response::Stat(exists) => exists,
+                    Response::Body(_) => unreachable!("STAT is answered with a status"),
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the requests down one connection in windows of the pipeline
+    /// depth, each window sent before any of its answers are read
+    async fn pipeline(
+        &self,
+        requests: &[Request<'_>],
+        tenant: &Arc<Tenant>,
+    ) -> Vec<Result<Response, NntpError>> {
+        let (_permit, mut conn) = match self.connection(tenant).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(
+                    "No connection for {} pipelined requests: {e}",
+                    requests.len()
+                );
+                return requests
+                    .iter()
+                    .map(|_| Err(NntpError::Unanswered))
+                    .collect();
+            }
+        };
+
+        let mut outcomes = Vec::with_capacity(requests.len());
+        for window in requests.chunks(self.pipeline_depth) {
+            if conn.is_broken() {
+                break;
+            }
+            match time::timeout(COMMAND_TIMEOUT * window.len() as u32, conn.pipeline(window)).await
+            {
+                Ok(window_outcomes) => outcomes.extend(window_outcomes),
+                // the rest go unanswered, the connection is left mid-response
+                Err(_) => {
+                    outcomes.push(Err(NntpError::Timeout));
+                    break;
+                }
+            }
+        }
+
+        outcomes.resize_with(requests.len(), || Err(NntpError::Unanswered));
+        outcomes
+    }
+
+    async fn download_segment(
+        &self,
+        segment: &Segment,
+        tenant: &Arc<Tenant>,
+    ) -> Result<YencArticle, NntpError> {
+        self.acquire_bandwidth(tenant, segment.size as u64).await;
+        let (_permit, mut conn) = self.connection(tenant).await?;
 
         let message_id = format!("<{}>", segment.message_id);
-        let raw_data = body_bytes(&mut conn, &message_id)
+        let body = time::timeout(COMMAND_TIMEOUT, conn.body(&message_id))
             .await
-            .map_err(|e| NntpError::Read(e.to_string()))?; // TODO: permenant error
+            .unwrap_or(Err(NntpError::Timeout)); // TODO: permenant error
 
         //drop(conn); // TODO: quit logic when dropping connection (this is recycle though)
 
-        let yenc_data = extract_yenc_data(&raw_data);
-        let decoded = yenc::decode_buffer(&yenc_data).unwrap(); // TODO: permenant error
+        self.decode(segment, body)
+    }
+
+    /// Waits for the tenant's turn, then takes a connection from the pool
+    async fn connection(
+        &self,
+        tenant: &Arc<Tenant>,
+    ) -> Result<(Permit<'_>, Object<Connection>), NntpError> {
+        let started = Instant::now();
+        let permit = self.share.acquire(tenant).await;
+        let conn = self.pool.get().await?;
 
-        debug!(
-            "Downloaded segment {} ({} bytes raw, {} decoded)",
-            segment.message_id,
-            raw_data.len(),
-            decoded.len()
-        );
+        METRICS
+            .pool_wait_seconds
+            .with_label_values(&[self.server.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+        Ok((permit, conn))
+    }
+
+    /// Decodes a fetched body, counting the fetch and its outcome
+    fn decode(
+        &self,
+        segment: &Segment,
+        body: Result<Bytes, NntpError>,
+    ) -> Result<YencArticle, NntpError> {
+        let article = body.and_then(|body| {
+            METRICS
+                .downloaded_bytes
+                .with_label_values(&[self.server.as_str()])
+                .inc_by(body.len() as u64);
+            let _timer = METRICS.decode_seconds.start_timer();
+            decode_article(segment, &body)
+        });
+
+        METRICS
+            .articles
+            .with_label_values(&[self.server.as_str(), outcome(&article)])
+            .inc();
+        article
+    }
+
+    /// Waits until `bytes` fit under the tenant's own limit, then the global
+    /// one
+    async fn acquire_bandwidth(&self, tenant: &Tenant, bytes: u64) {
+        if let Some(bandwidth) = tenant.bandwidth() {
+            bandwidth.acquire(bytes).await;
+        }
+        self.bandwidth.acquire(bytes).await;
+    }
+}
+
+fn message_ids<'a>(segments: impl IntoIterator<Item = &'a Segment>) -> Vec<String> {
+    segments
+        .into_iter()
+        .map(|segment| format!("<{}>", segment.message_id))
+        .collect()
+}
 
-        Ok(decoded.into()) // TODO: fix type constraints
+fn decode_article(segment: &Segment, raw_data: &[u8]) -> Result<YencArticle, NntpError> {
+    let yenc_data = extract_yenc_data(raw_data);
+    // TODO: permenant error
+    let decoded = yenc::decode_buffer(&yenc_data).map_err(|e| NntpError::Decode(e.to_string()))?;
+    if let Some(crc) = extract_part_crc(raw_data)
+        && crc32fast::hash(&decoded) != crc
+    {
+        return Err(NntpError::Crc(segment.message_id.clone()));
     }
+
+    debug!(
+        "Downloaded segment {} ({} bytes raw, {} decoded)",
+        segment.message_id,
+        raw_data.len(),
+        decoded.len()
+    );
+
+    Ok(YencArticle {
+        data: decoded.into(), // TODO: fix type constraints
+        file_size: extract_file_size(raw_data),
+        part_offset: extract_part_offset(raw_data),
+    })
 }
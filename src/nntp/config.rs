@@ -1,24 +1,45 @@
 use std::time::Duration;
 
 use config::{Config, Environment};
-use derive_more::Constructor;
 use serde::Deserialize;
 use shrinkwraprs::Shrinkwrap;
 use tracing::warn;
 
 use crate::nntp::error::NntpError;
 
-#[derive(Debug, Clone, Deserialize, Constructor)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct NntpConfig {
     pub host: String,
     pub username: String,
     pub password: String,
 
-    #[serde(default)] // TODO: not working?
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    /// Plain NNTP is only for servers on a trusted network
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+
+    #[serde(default)]
     pub max_connections: MaxConnections,
 
+    /// Seconds a pooled connection may sit unused
     #[serde(default)]
     pub idle_timeout: IdleTimeout,
+
+    /// Commands sent down a connection before waiting on the first answer,
+    /// 1 for servers that don't cope with pipelining
+    #[serde(default)]
+    pub pipeline_depth: PipelineDepth,
+}
+
+/// NNTP over TLS
+fn default_port() -> u16 {
+    563
+}
+
+fn default_tls() -> bool {
+    true
 }
 
 #[derive(Deserialize, Debug, Clone, Shrinkwrap)]
@@ -33,8 +54,15 @@ impl Default for MaxConnections {
 }
 
 #[derive(Deserialize, Debug, Clone, Shrinkwrap)]
+#[serde(from = "u64")]
 pub struct IdleTimeout(Duration);
 
+impl From<u64> for IdleTimeout {
+    fn from(secs: u64) -> Self {
+        IdleTimeout(Duration::from_secs(secs))
+    }
+}
+
 impl Default for IdleTimeout {
     fn default() -> Self {
         const DEFAULT: usize = 10;
@@ -46,14 +74,20 @@ impl Default for IdleTimeout {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Shrinkwrap)]
+pub struct PipelineDepth(usize);
+
+impl Default for PipelineDepth {
+    fn default() -> Self {
+        PipelineDepth(8)
+    }
+}
+
 impl NntpConfig {
     pub fn from_env() -> Result<Self, NntpError> {
         let config: NntpConfig = Config::builder()
-            .add_source(
-                Environment::with_prefix("NNTP")
-                    .separator("_")
-                    .list_separator(" "),
-            )
+            // no key separator, it would split `max_connections` into a table
+            .add_source(Environment::with_prefix("NNTP").list_separator(" "))
             .build()?
             .try_deserialize()?;
 
@@ -0,0 +1,400 @@
+use std::{fmt, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{self, ClientConfig, RootCertStore, pki_types::ServerName},
+};
+use tracing::debug;
+
+use crate::nntp::{config::NntpConfig, error::NntpError};
+
+/// Greeting codes: posting allowed or not, we only read either way
+const SERVICE_AVAILABLE: [u16; 2] = [200, 201];
+const AUTH_ACCEPTED: u16 = 281;
+const PASSWORD_REQUIRED: u16 = 381;
+const BODY_FOLLOWS: u16 = 222;
+const ARTICLE_EXISTS: u16 = 223;
+const NO_SUCH_ARTICLE: u16 = 430;
+
+/// Anything we can speak NNTP over, TLS or plain TCP
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// An article command, by message ID in angle brackets. Each is answered on
+/// its own, so several can be in flight on a connection at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Body(&'a str),
+    Stat(&'a str),
+}
+
+impl Request<'_> {
+    fn command(&self) -> String {
+        match self {
+            Request::Body(message_id) => format!("BODY {message_id}"),
+            Request::Stat(message_id) => format!("STAT {message_id}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Dot-unstuffed, with its line endings kept
+    Body(Bytes),
+    /// Whether the server has the article
+    Stat(bool),
+}
+
+/// The status line every NNTP response starts with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub code: u16,
+    pub message: String,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.message)
+    }
+}
+
+/// A connection speaking just the parts of NNTP (RFC 3977) we download with:
+/// the greeting, `AUTHINFO`, `BODY` and `STAT`
+pub struct NntpConnection {
+    stream: BufStream<Box<dyn Io>>,
+    /// Set while a command is in flight. Still set afterwards means it failed
+    /// or was cancelled partway, and the stream can't be trusted.
+    in_command: bool,
+}
+
+impl NntpConnection {
+    /// Connects, over TLS unless the config turns it off, and logs in
+    pub async fn connect(config: &NntpConfig) -> Result<Self, NntpError> {
+        debug!("Connecting to {}:{}", config.host, config.port);
+        let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+
+        let stream: Box<dyn Io> = if config.tls {
+            let server_name = ServerName::try_from(config.host.clone())
+                .map_err(|_| NntpError::InvalidHost(config.host.clone()))?;
+            Box::new(tls_connector().connect(server_name, tcp).await?)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut connection = Self::new(stream).await?;
+        connection
+            .authenticate(&config.username, &config.password)
+            .await?;
+
+        Ok(connection)
+    }
+
+    /// Reads the greeting off a freshly opened stream
+    async fn new(stream: Box<dyn Io>) -> Result<Self, NntpError> {
+        let mut connection = Self {
+            stream: BufStream::new(stream),
+            in_command: false,
+        };
+
+        let greeting = connection.read_status().await?;
+        if !SERVICE_AVAILABLE.contains(&greeting.code) {
+            return Err(NntpError::UnexpectedResponse(greeting));
+        }
+
+        Ok(connection)
+    }
+
+    async fn authenticate(&mut self, username: &str, password: &str) -> Result<(), NntpError> {
+        self.send(&format!("AUTHINFO USER {username}")).await?;
+        match self.read_status().await? {
+            // some servers take the user alone
+            status if status.code == AUTH_ACCEPTED => return Ok(()),
+            status if status.code == PASSWORD_REQUIRED => {}
+            status => return Err(NntpError::Authentication(status.to_string())),
+        }
+
+        self.send(&format!("AUTHINFO PASS {password}")).await?;
+        match self.read_status().await? {
+            status if status.code == AUTH_ACCEPTED => Ok(()),
+            status => Err(NntpError::Authentication(status.to_string())),
+        }
+    }
+
+    /// Whether a command was left half done, out of step with the server
+    pub fn is_broken(&self) -> bool {
+        self.in_command
+    }
+
+    /// The article's body, dot-unstuffed, with its line endings kept
+    pub async fn body(&mut self, message_id: &str) -> Result<Bytes, NntpError> {
+        self.request(Request::Body(message_id))
+            .await?
+            .map(|response| match response {
+                Response::Body(body) => body,
+                Response::Stat(_) => unreachable!("BODY is answered with a body"),
+            })
+    }
+
+    /// Whether the server still has the article, without fetching it
+    pub async fn stat(&mut self, message_id: &str) -> Result<bool, NntpError> {
+        self.request(Request::Stat(message_id))
+            .await?
+            .map(|response| match response {
+                Response::Stat(exists) => exists,
+                Response::Body(_) => unreachable!("STAT is answered with a status"),
+            })
+    }
+
+    /// Sends every request before reading any of the responses, so a batch
+    /// costs one round trip rather than one per article. The server answers
+    /// in the order the requests were sent.
+    ///
+    /// # Returns
+    /// - The outcome of each request, in order. An article the server
+    ///   doesn't have fails on its own. If the connection fails, the request
+    ///   it failed on gets the error and the ones after it
+    ///   [`NntpError::Unanswered`].
+    pub async fn pipeline(&mut self, requests: &[Request<'_>]) -> Vec<Result<Response, NntpError>> {
+        let mut outcomes = Vec::with_capacity(requests.len());
+        if let Err(e) = self.run_pipeline(requests, &mut outcomes).await {
+            outcomes.push(Err(e));
+            outcomes.resize_with(requests.len(), || Err(NntpError::Unanswered));
+        }
+
+        outcomes
+    }
+
+    async fn run_pipeline(
+        &mut self,
+        requests: &[Request<'_>],
+        outcomes: &mut Vec<Result<Response, NntpError>>,
+    ) -> Result<(), NntpError> {
+        for request in requests {
+            self.write_command(&request.command()).await?;
+        }
+        self.stream.flush().await?;
+
+        for request in requests {
+            outcomes.push(self.read_response(*request).await?);
+        }
+
+        self.in_command = false;
+        Ok(())
+    }
+
+    async fn request(
+        &mut self,
+        request: Request<'_>,
+    ) -> Result<Result<Response, NntpError>, NntpError> {
+        self.send(&request.command()).await?;
+        let outcome = self.read_response(request).await?;
+
+        self.in_command = false;
+        Ok(outcome)
+    }
+
+    /// # Returns
+    /// - The outcome for the article. The whole response has been read
+    ///   either way, so the connection is still in step with the server.
+    /// - An error reading the response, after which it isn't
+    async fn read_response(
+        &mut self,
+        request: Request<'_>,
+    ) -> Result<Result<Response, NntpError>, NntpError> {
+        let status = self.read_status().await?;
+
+        Ok(match (request, status.code) {
+            (Request::Body(_), BODY_FOLLOWS) => Ok(Response::Body(self.read_multiline().await?)),
+            (Request::Body(message_id), NO_SUCH_ARTICLE) => {
+                Err(NntpError::ArticleNotFound(message_id.to_string()))
+            }
+            (Request::Stat(_), ARTICLE_EXISTS) => Ok(Response::Stat(true)),
+            (Request::Stat(_), NO_SUCH_ARTICLE) => Ok(Response::Stat(false)),
+            _ => Err(NntpError::UnexpectedResponse(status)),
+        })
+    }
+
+    async fn send(&mut self, command: &str) -> Result<(), NntpError> {
+        self.write_command(command).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Buffers a command without flushing it
+    async fn write_command(&mut self, command: &str) -> Result<(), NntpError> {
+        self.in_command = true;
+        self.stream.write_all(command.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+
+        Ok(())
+    }
+
+    async fn read_status(&mut self) -> Result<Status, NntpError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(NntpError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let line = line.trim_end();
+        let (code, message) = line.split_once(' ').unwrap_or((line, ""));
+        let code = code
+            .parse()
+            .map_err(|_| NntpError::Read(format!("malformed status line '{line}'")))?;
+
+        Ok(Status {
+            code,
+            message: message.to_string(),
+        })
+    }
+
+    /// Reads up to the lone `.` that ends a multi-line response, undoing the
+    /// dot-stuffing of lines that start with one
+    async fn read_multiline(&mut self) -> Result<Bytes, NntpError> {
+        let mut body = BytesMut::new();
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            if self.stream.read_until(b'\n', &mut line).await? == 0 {
+                return Err(NntpError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            match line.as_slice() {
+                b".\r\n" | b".\n" => return Ok(body.freeze()),
+                [b'.', rest @ ..] => body.extend_from_slice(rest),
+                _ => body.extend_from_slice(&line),
+            }
+        }
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Plays the server side of a conversation: the greeting, then a response
+    /// to each command the client sends
+    async fn connection(responses: &'static [&'static str]) -> NntpConnection {
+        let (client, server) = duplex(64 * 1024);
+
+        tokio::spawn(async move {
+            let mut server = BufStream::new(server);
+            server
+                .write_all(b"200 news.example.com ready\r\n")
+                .await
+                .unwrap();
+            server.flush().await.unwrap();
+
+            let mut command = String::new();
+            for response in responses {
+                server.read_line(&mut command).await.unwrap();
+                server.write_all(response.as_bytes()).await.unwrap();
+                server.flush().await.unwrap();
+            }
+        });
+
+        NntpConnection::new(Box::new(client)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_body_unstuffs_dots() {
+        let mut connection =
+            connection(&["222 0 <a@b> body\r\n=ybegin line\r\n..leading dot\r\n.\r\n"]).await;
+
+        let body = connection.body("<a@b>").await.unwrap();
+        assert_eq!(&body[..], b"=ybegin line\r\n.leading dot\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_stat_and_missing_articles() {
+        let mut connection = connection(&[
+            "223 0 <a@b>\r\n",
+            "430 No such article\r\n",
+            "430 No such article\r\n",
+        ])
+        .await;
+
+        assert!(connection.stat("<a@b>").await.unwrap());
+        assert!(!connection.stat("<c@d>").await.unwrap());
+        assert!(matches!(
+            connection.body("<c@d>").await,
+            Err(NntpError::ArticleNotFound(id)) if id == "<c@d>"
+        ));
+        assert!(!connection.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let mut connection = connection(&[concat!(
+            "222 0 <a@b>\r\nfirst\r\n.\r\n",
+            "430 No such article\r\n",
+            "223 0 <e@f>\r\n",
+            "222 0 <g@h>\r\nsecond\r\n.\r\n",
+        )])
+        .await;
+
+        let outcomes = connection
+            .pipeline(&[
+                Request::Body("<a@b>"),
+                Request::Body("<c@d>"),
+                Request::Stat("<e@f>"),
+                Request::Body("<g@h>"),
+            ])
+            .await;
+
+        // the missing article fails without taking the rest with it
+        assert!(matches!(&outcomes[0], Ok(Response::Body(body)) if &body[..] == b"first\r\n"));
+        assert!(matches!(&outcomes[1], Err(NntpError::ArticleNotFound(id)) if id == "<c@d>"));
+        assert!(matches!(outcomes[2], Ok(Response::Stat(true))));
+        assert!(matches!(&outcomes[3], Ok(Response::Body(body)) if &body[..] == b"second\r\n"));
+        assert!(!connection.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_connection_lost() {
+        let mut connection = connection(&["223 0 <a@b>\r\n222 0 <c@d>\r\ntrunc"]).await;
+
+        let outcomes = connection
+            .pipeline(&[
+                Request::Stat("<a@b>"),
+                Request::Body("<c@d>"),
+                Request::Body("<e@f>"),
+            ])
+            .await;
+
+        assert!(matches!(outcomes[0], Ok(Response::Stat(true))));
+        assert!(matches!(outcomes[1], Err(NntpError::Io(_))));
+        assert!(matches!(outcomes[2], Err(NntpError::Unanswered)));
+        assert!(connection.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_authentication() {
+        let mut connection = connection(&["381 Password required\r\n", "481 Rejected\r\n"]).await;
+
+        assert!(matches!(
+            connection.authenticate("user", "wrong").await,
+            Err(NntpError::Authentication(status)) if status.starts_with("481")
+        ));
+    }
+}
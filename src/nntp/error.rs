@@ -2,6 +2,8 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::nntp::connection::Status;
+
 #[derive(Error, Debug)]
 pub enum NntpError {
     #[error("Error reading config from environment")]
@@ -10,6 +12,30 @@ pub enum NntpError {
     #[error("Error reading body: {0}")]
     Read(String),
 
+    #[error("Error decoding yEnc body: {0}")]
+    Decode(String),
+
+    #[error("Article {0} failed its CRC check")]
+    Crc(String),
+
+    #[error("Article {0} not found")]
+    ArticleNotFound(String),
+
+    #[error("Server took too long to answer")]
+    Timeout,
+
+    #[error("Server rejected login: {0}")]
+    Authentication(String),
+
+    #[error("Unexpected server response: {0}")]
+    UnexpectedResponse(Status),
+
+    #[error("Connection failed before the server answered")]
+    Unanswered,
+
+    #[error("'{0}' is not a valid TLS server name")]
+    InvalidHost(String),
+
     #[error("I/O error")]
     Io(#[from] io::Error),
 
@@ -24,4 +50,7 @@ pub enum NntpError {
 pub enum NntpPoolError {
     #[error("Authentication error: {0}")]
     Authentication(String),
+
+    #[error("Error connecting: {0}")]
+    Connection(String),
 }
@@ -1,8 +1,10 @@
+use crate::nntp::connection::NntpConnection;
 use crate::nntp::error::NntpPoolError;
 use crate::nntp::{config::NntpConfig, error::NntpError};
 use deadpool::Runtime;
-use deadpool::managed::{Manager, Metrics, Pool, PoolConfig, QueueMode, RecycleResult, Timeouts};
-use rek2_nntp::{AuthenticatedConnection, authenticate};
+use deadpool::managed::{
+    Manager, Metrics, Pool, PoolConfig, QueueMode, RecycleError, RecycleResult, Timeouts,
+};
 use shrinkwraprs::Shrinkwrap;
 use std::time::Duration;
 use tracing::debug;
@@ -12,29 +14,30 @@ pub struct Connection {
 }
 
 impl Manager for Connection {
-    type Type = AuthenticatedConnection;
+    type Type = NntpConnection;
     type Error = NntpPoolError;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
         debug!("Creating new NNTP connection to {}", self.config.host);
 
-        let conn = authenticate(
-            &self.config.host,
-            &self.config.username,
-            &self.config.password,
-        )
-        .await
-        .map_err(|e| NntpPoolError::Authentication(e.to_string()))?;
-
-        Ok(conn)
+        NntpConnection::connect(&self.config)
+            .await
+            .map_err(|e| match e {
+                NntpError::Authentication(reason) => NntpPoolError::Authentication(reason),
+                e => NntpPoolError::Connection(e.to_string()),
+            })
     }
 
     async fn recycle(
         &self,
-        _conn: &mut Self::Type,
+        conn: &mut Self::Type,
         _metrics: &Metrics,
     ) -> RecycleResult<Self::Error> {
         // Could send NOOP here to check health
+        if conn.is_broken() {
+            return Err(RecycleError::Message("left mid-response".into()));
+        }
+
         Ok(())
     }
 }
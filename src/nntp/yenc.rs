@@ -2,15 +2,74 @@ use bytes::Bytes;
 use md5::{Digest, Md5};
 
 pub fn extract_filename(subject: &str) -> Option<&str> {
-    if let Some(start) = subject.find('"') {
-        if let Some(end) = subject[start + 1..].find('"') {
-            return Some(&subject[start + 1..start + 1 + end]);
-        }
+    if let Some(start) = subject.find('"')
+        && let Some(end) = subject[start + 1..].find('"')
+    {
+        return Some(&subject[start + 1..start + 1 + end]);
     }
 
     subject.split_whitespace().next()
 }
 
+/// Decoded body of a single article, along with the total size of the file
+/// it belongs to, as declared by the `=ybegin` line
+#[derive(Debug, Clone)]
+pub struct YencArticle {
+    pub data: Bytes,
+    pub file_size: Option<u64>,
+    /// Zero-based offset of this part within the file, from `=ypart begin=`
+    pub part_offset: Option<u64>,
+}
+
+/// Reads `size=` from the `=ybegin` line. For multi-part posts this is the
+/// size of the whole file, not the part.
+pub fn extract_file_size(article: &[u8]) -> Option<u64> {
+    let line = article
+        .split(|&b| b == b'\n')
+        .find(|line| line.starts_with(b"=ybegin"))?;
+
+    std::str::from_utf8(line)
+        .ok()?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("size="))?
+        .parse()
+        .ok()
+}
+
+/// Reads `begin=` from the `=ypart` line, converted to a zero-based offset.
+/// Single-part posts have no `=ypart` line, so their data starts at 0.
+pub fn extract_part_offset(article: &[u8]) -> Option<u64> {
+    let mut lines = article.split(|&b| b == b'\n');
+    lines.find(|line| line.starts_with(b"=ybegin"))?;
+
+    match lines.next() {
+        Some(line) if line.starts_with(b"=ypart") => std::str::from_utf8(line)
+            .ok()?
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("begin="))?
+            .parse::<u64>()
+            .ok()?
+            .checked_sub(1),
+        _ => Some(0),
+    }
+}
+
+/// Reads the checksum of the part's decoded data from the `=yend` line,
+/// `pcrc32=`, or `crc32=` for single-part posts where it's the same thing
+pub fn extract_part_crc(article: &[u8]) -> Option<u32> {
+    let line = article
+        .split(|&b| b == b'\n')
+        .find(|line| line.starts_with(b"=yend"))?;
+    let fields: Vec<_> = std::str::from_utf8(line).ok()?.split_whitespace().collect();
+    let field = |prefix: &str| fields.iter().find_map(|field| field.strip_prefix(prefix));
+
+    let crc = match field("part=") {
+        Some(_) => field("pcrc32=")?,
+        None => field("pcrc32=").or_else(|| field("crc32="))?,
+    };
+    u32::from_str_radix(crc, 16).ok()
+}
+
 pub fn extract_yenc_data(article: &[u8]) -> Bytes {
     let mut in_body = false;
     article
@@ -50,3 +109,120 @@ pub fn compute_hash16k(bytes: &[u8]) -> Vec<u8> {
 
     Md5::new().chain_update(&bytes[..len]).finalize().to_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const LINE_LENGTH: usize = 128;
+
+    /// Wraps `data` in a single-part yEnc article, escaping critical
+    /// characters and splitting lines like a posting client would
+    fn encode_yenc(data: &[u8]) -> Vec<u8> {
+        let mut article = format!(
+            "=ybegin part=1 line={LINE_LENGTH} size={0} name=test.bin\r\n=ypart begin=1 end={0}\r\n",
+            data.len()
+        )
+        .into_bytes();
+
+        let mut column = 0;
+        for &byte in data {
+            let c = byte.wrapping_add(42);
+            // a leading dot would be taken for NNTP dot-stuffing
+            if matches!(c, 0x00 | b'\n' | b'\r' | b'=') || (c == b'.' && column == 0) {
+                article.extend_from_slice(&[b'=', c.wrapping_add(64)]);
+                column += 2;
+            } else {
+                article.push(c);
+                column += 1;
+            }
+
+            if column >= LINE_LENGTH {
+                article.extend_from_slice(b"\r\n");
+                column = 0;
+            }
+        }
+
+        article.extend_from_slice(format!("\r\n=yend size={}\r\n", data.len()).as_bytes());
+        article
+    }
+
+    #[test]
+    fn test_yenc_leading_dot() {
+        // 4 encodes to `.`, and `..` opening a line reads as dot-stuffing
+        let article = encode_yenc(&[4, 4]);
+        let decoded = yenc::decode_buffer(&extract_yenc_data(&article)).unwrap();
+
+        assert_eq!(decoded, [4, 4]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_yenc_roundtrip(data in prop::collection::vec(any::<u8>(), 0..2048)) {
+            let article = encode_yenc(&data);
+            let decoded = yenc::decode_buffer(&extract_yenc_data(&article)).unwrap();
+
+            prop_assert_eq!(decoded, data);
+        }
+
+        #[test]
+        fn test_extract_yenc_never_panics(article in prop::collection::vec(any::<u8>(), 0..1024)) {
+            let _ = extract_yenc_data(&article);
+        }
+
+        #[test]
+        fn test_extract_filename_never_panics(subject in ".*") {
+            let _ = extract_filename(&subject);
+        }
+    }
+
+    #[test]
+    fn test_extract_file_size() {
+        let article = b"=ybegin part=1 total=10 line=128 size=123456 name=movie mkv.mkv\r\n\
+                        =ypart begin=1 end=716800\r\ndata\r\n=yend size=716800\r\n";
+
+        assert_eq!(extract_file_size(article), Some(123456));
+        assert_eq!(extract_file_size(b"no yenc here"), None);
+    }
+
+    #[test]
+    fn test_extract_part_offset() {
+        let multi_part = b"=ybegin part=2 line=128 size=1000 name=a.mkv\r\n\
+                           =ypart begin=501 end=1000\r\ndata\r\n=yend size=500\r\n";
+        let single_part = b"=ybegin line=128 size=4 name=a.mkv\r\ndata\r\n=yend size=4\r\n";
+
+        assert_eq!(extract_part_offset(multi_part), Some(500));
+        assert_eq!(extract_part_offset(single_part), Some(0));
+        assert_eq!(extract_part_offset(b"no yenc here"), None);
+    }
+
+    #[test]
+    fn test_extract_part_crc() {
+        let multi_part = b"=ybegin part=2 line=128 size=1000 name=a.mkv\r\n\
+                           =ypart begin=501 end=1000\r\ndata\r\n\
+                           =yend size=500 part=2 pcrc32=0000abcd crc32=ffffffff\r\n";
+        let single_part =
+            b"=ybegin line=128 size=4 name=a.mkv\r\ndata\r\n=yend size=4 crc32=DEADBEEF\r\n";
+
+        assert_eq!(extract_part_crc(multi_part), Some(0xabcd));
+        assert_eq!(extract_part_crc(single_part), Some(0xdeadbeef));
+        assert_eq!(
+            extract_part_crc(b"=ybegin size=4\r\ndata\r\n=yend size=4\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_filename() {
+        assert_eq!(
+            extract_filename(r#"[01/10] - "movie.part01.rar" yEnc (1/200)"#),
+            Some("movie.part01.rar")
+        );
+        assert_eq!(
+            extract_filename("movie.r00 yEnc (1/200)"),
+            Some("movie.r00")
+        );
+        assert_eq!(extract_filename(""), None);
+    }
+}
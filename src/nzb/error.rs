@@ -1,7 +1,27 @@
+use std::{io, path::PathBuf, string::FromUtf8Error};
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum NzbError {
     #[error("Error parsing NZB file")]
     Parsing(#[from] nzb_rs::ParseNzbError),
+
+    #[error("Error downloading NZB")]
+    Fetch(#[from] reqwest::Error),
+
+    #[error("Error reading NZB file")]
+    Read(#[from] io::Error),
+
+    #[error("NZB is not valid UTF-8")]
+    Utf8(#[from] FromUtf8Error),
+
+    #[error("'{0}' is outside the NZB directory")]
+    PathNotAllowed(PathBuf),
+
+    #[error("Reading NZBs from the server is disabled, set --nzb-dir to allow it")]
+    PathsDisabled,
+
+    #[error("NZB is bigger than the {0} byte limit")]
+    TooLarge(u64),
 }
@@ -1,4 +1,5 @@
 pub mod error;
 pub mod parser;
+pub mod source;
 
-pub use parser::{Nzb, parse};
+pub use parser::{Nzb, NzbMeta, parse};
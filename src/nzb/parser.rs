@@ -1,45 +1,258 @@
+use crate::archive::kind::ContentKind;
+use crate::nntp::yenc::extract_filename;
 use crate::nzb::error::NzbError;
+use itertools::Itertools;
 use nzb_rs::{File, Nzb as RawNzb};
 use tracing::{debug, info};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Nzb {
     pub par2: Vec<File>,
     pub rar: Vec<File>,
+    pub sevenzip: Vec<File>,
+    pub zip: Vec<File>,
+    pub split: Vec<File>,
+    pub video: Vec<File>,
     pub obfuscated: Vec<File>,
+    pub meta: NzbMeta,
+}
+
+/// Release metadata from the NZB `<head>` and file groups
+#[derive(Debug, Clone, Default)]
+pub struct NzbMeta {
+    pub title: Option<String>,
+    pub password: Option<String>,
+    pub category: Option<String>,
+    pub tags: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+impl Nzb {
+    /// The main video of a direct post. Releases often include a sample,
+    /// so pick the biggest.
+    pub fn main_video(&self) -> Option<&File> {
+        self.video.iter().max_by_key(|file| file_size(file))
+    }
+
+    /// Files that make up the stream when names aren't obfuscated, preferring
+    /// RAR, then split files, then a bare video post. 7z and ZIP volumes need
+    /// their end headers read first, so they're handled separately.
+    pub fn plain_stream_files(&self) -> Option<Vec<File>> {
+        [&self.rar, &self.split]
+            .into_iter()
+            .find(|files| !files.is_empty())
+            .cloned()
+            .or_else(|| self.main_video().map(|video| vec![video.clone()]))
+    }
+
+    /// PAR2 files in the order we should try them for filename recovery.
+    ///
+    /// The index file (no `.volXX+YY`) carries only metadata packets, so the
+    /// smallest of those comes first. Volume files repeat the FileDesc
+    /// packets, so they follow, smallest first, as a fallback.
+    pub fn par2_candidates(&self) -> Vec<&File> {
+        let (mut index, mut volumes): (Vec<_>, Vec<_>) =
+            self.par2.iter().partition(|file| !is_par2_volume(file));
+
+        index.sort_by_key(|file| file_size(file));
+        volumes.sort_by_key(|file| file_size(file));
+
+        index.into_iter().chain(volumes).collect()
+    }
+
+    /// Everything but PAR2, the files the recovery data has to make up for
+    pub fn content_files(&self) -> impl Iterator<Item = &File> {
+        [
+            &self.rar,
+            &self.sevenzip,
+            &self.zip,
+            &self.split,
+            &self.video,
+            &self.obfuscated,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// The PAR2 volumes, which hold the recovery blocks. The index file only
+    /// describes the set.
+    pub fn recovery_files(&self) -> impl Iterator<Item = &File> {
+        self.par2.iter().filter(|file| is_par2_volume(file))
+    }
+}
+
+fn is_par2_volume(file: &File) -> bool {
+    extract_filename(&file.subject).is_some_and(|name| name.to_ascii_lowercase().contains(".vol"))
+}
+
+fn file_size(file: &File) -> u64 {
+    file.segments
+        .iter()
+        .map(|segment| segment.size as u64)
+        .sum()
 }
 
 pub fn parse(content: &str) -> Result<Nzb, NzbError> {
     debug!("Parsing NZB");
 
     let raw_nzb = RawNzb::parse(content)?;
-    let (par2, rar, obfuscated) = raw_nzb.files.into_iter().fold(
-        (Vec::new(), Vec::new(), Vec::new()),
-        |(mut par2, mut rar, mut obf), file| {
-            if file.is_par2() {
-                par2.push(file);
-            } else if file.is_rar() {
-                rar.push(file);
-            } else if file.is_obfuscated() {
-                obf.push(file);
-            }
-
-            (par2, rar, obf)
-        },
-    );
 
-    info!(
-        "Successfully parsed NZB (PAR2: {}, RAR: {}, obfuscated: {})",
-        par2.len(),
-        rar.len(),
-        obfuscated.len(),
-    );
+    let meta = NzbMeta {
+        title: raw_nzb.meta.title,
+        password: raw_nzb.meta.passwords.into_iter().next(),
+        category: raw_nzb.meta.category,
+        tags: raw_nzb.meta.tags,
+        groups: raw_nzb
+            .files
+            .iter()
+            .flat_map(|file| file.groups.iter().cloned())
+            .sorted()
+            .dedup()
+            .collect(),
+    };
 
-    let nzb = Nzb {
-        par2,
-        rar,
-        obfuscated,
+    let mut nzb = Nzb {
+        meta,
+        ..Default::default()
     };
 
+    for file in raw_nzb.files {
+        let kind = extract_filename(&file.subject).and_then(ContentKind::from_filename);
+
+        match kind {
+            _ if file.is_par2() => nzb.par2.push(file),
+            _ if file.is_rar() => nzb.rar.push(file),
+            Some(ContentKind::Par2) => nzb.par2.push(file),
+            Some(ContentKind::Rar) => nzb.rar.push(file),
+            Some(ContentKind::SevenZip) => nzb.sevenzip.push(file),
+            Some(ContentKind::Zip) => nzb.zip.push(file),
+            Some(ContentKind::Split) => nzb.split.push(file),
+            Some(ContentKind::Video) => nzb.video.push(file),
+            None if file.is_obfuscated() => nzb.obfuscated.push(file),
+            None => debug!("Ignoring file {}", file.subject),
+        }
+    }
+
+    info!(
+        "Successfully parsed NZB '{}' (PAR2: {}, RAR: {}, 7z: {}, ZIP: {}, split: {}, video: {}, obfuscated: {})",
+        nzb.meta.title.as_deref().unwrap_or("untitled"),
+        nzb.par2.len(),
+        nzb.rar.len(),
+        nzb.sevenzip.len(),
+        nzb.zip.len(),
+        nzb.split.len(),
+        nzb.video.len(),
+        nzb.obfuscated.len(),
+    );
+
     Ok(nzb)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nzb_file(name: &str, segment_sizes: &[u64]) -> String {
+        let segments: String = segment_sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                format!(
+                    r#"<segment bytes="{size}" number="{}">{name}.{i}@example</segment>"#,
+                    i + 1
+                )
+            })
+            .collect();
+
+        format!(
+            r#"<file poster="poster" date="1700000000" subject="[1/1] - &quot;{name}&quot; yEnc (1/{})">
+                <groups><group>alt.binaries.test</group></groups>
+                <segments>{segments}</segments>
+            </file>"#,
+            segment_sizes.len()
+        )
+    }
+
+    fn nzb_with_head(head: &str, files: &[(&str, &[u64])]) -> String {
+        let files: String = files
+            .iter()
+            .map(|(name, sizes)| nzb_file(name, sizes))
+            .collect();
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <nzb xmlns="http://www.newzbin.com/DTD/2003/nzb">{head}{files}</nzb>"#
+        )
+    }
+
+    fn nzb(files: &[(&str, &[u64])]) -> String {
+        nzb_with_head("", files)
+    }
+
+    #[test]
+    fn test_classifies_direct_posts() {
+        let content = nzb(&[
+            ("movie.mkv", &[700_000, 700_000]),
+            ("movie-sample.mkv", &[100_000]),
+            ("other.7z.001", &[1000]),
+            ("other.mkv.001", &[1000]),
+            ("movie.nfo", &[10]),
+        ]);
+        let nzb = parse(&content).unwrap();
+
+        assert_eq!(nzb.video.len(), 2);
+        assert_eq!(nzb.sevenzip.len(), 1);
+        assert_eq!(nzb.split.len(), 1);
+        assert!(nzb.rar.is_empty());
+
+        let main = nzb.main_video().unwrap();
+        assert_eq!(extract_filename(&main.subject), Some("movie.mkv"));
+    }
+
+    #[test]
+    fn test_parse_meta() {
+        let content = nzb_with_head(
+            r#"<head>
+                <meta type="title">Some.Release.2024.1080p</meta>
+                <meta type="password">hunter2</meta>
+                <meta type="category">Movies</meta>
+                <meta type="tag">HD</meta>
+            </head>"#,
+            &[("release.rar", &[1000])],
+        );
+        let nzb = parse(&content).unwrap();
+
+        assert_eq!(nzb.meta.title.as_deref(), Some("Some.Release.2024.1080p"));
+        assert_eq!(nzb.meta.password.as_deref(), Some("hunter2"));
+        assert_eq!(nzb.meta.category.as_deref(), Some("Movies"));
+        assert_eq!(nzb.meta.tags, vec!["HD"]);
+        assert_eq!(nzb.meta.groups, vec!["alt.binaries.test"]);
+    }
+
+    #[test]
+    fn test_par2_candidates_prefers_smallest_index() {
+        let content = nzb(&[
+            ("release.vol00+01.par2", &[1000]),
+            ("release.par2", &[700_000, 300_000]),
+            ("release.vol01+02.par2", &[2000]),
+            ("release-alt.par2", &[500]),
+        ]);
+        let nzb = parse(&content).unwrap();
+
+        let names: Vec<_> = nzb
+            .par2_candidates()
+            .into_iter()
+            .filter_map(|file| extract_filename(&file.subject))
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "release-alt.par2",
+                "release.par2",
+                "release.vol00+01.par2",
+                "release.vol01+02.par2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::nzb::error::NzbError;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Far more than the NZB of any real release, which runs to a few MB
+pub const MAX_NZB_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How long fetching an NZB from a URL may take altogether
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where to get an NZB from, as `{"url": ..}`, `{"path": ..}` or
+/// `{"nzb": "<?xml ..."}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NzbSource {
+    Url(String),
+    /// Relative paths are taken from the NZB directory
+    Path(PathBuf),
+    Nzb(String),
+}
+
+/// An NZB's contents and the file name it came with, if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedNzb {
+    pub content: String,
+    pub name: Option<String>,
+}
+
+impl NzbSource {
+    /// Fetches or reads the NZB
+    ///
+    /// # Arguments
+    /// - `nzb_dir`: the only directory paths may point into, with no
+    ///   directory paths aren't accepted at all
+    pub async fn load(
+        &self,
+        http: &reqwest::Client,
+        nzb_dir: Option<&Path>,
+    ) -> Result<LoadedNzb, NzbError> {
+        match self {
+            NzbSource::Url(url) => {
+                info!("Fetching NZB from {url}");
+                let response = http.get(url).send().await?.error_for_status()?;
+                let bytes = read_body(response).await?;
+
+                Ok(LoadedNzb {
+                    content: decode(&bytes)?,
+                    name: url
+                        .split(['?', '#'])
+                        .next()
+                        .and_then(|path| path.rsplit('/').next())
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string),
+                })
+            }
+            NzbSource::Path(path) => {
+                let path = allowed_path(path, nzb_dir.ok_or(NzbError::PathsDisabled)?).await?;
+                info!("Reading NZB from {}", path.display());
+
+                Ok(LoadedNzb {
+                    content: decode(&tokio::fs::read(&path).await?)?,
+                    name: path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned()),
+                })
+            }
+            NzbSource::Nzb(content) => Ok(LoadedNzb {
+                content: content.clone(),
+                name: None,
+            }),
+        }
+    }
+}
+
+/// A client for fetching NZBs, which gives up on servers that stall
+pub fn http_client() -> Result<reqwest::Client, NzbError> {
+    Ok(reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .user_agent(concat!("nzb-streamer/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// Reads a response's body, up to [`MAX_NZB_SIZE`]
+pub async fn read_body(response: reqwest::Response) -> Result<Vec<u8>, NzbError> {
+    read_body_up_to(response, MAX_NZB_SIZE).await
+}
+
+async fn read_body_up_to(mut response: reqwest::Response, limit: u64) -> Result<Vec<u8>, NzbError> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(NzbError::TooLarge(limit));
+    }
+
+    // the length is only the server's word, so the body is counted too
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(NzbError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// NZB bytes as text, decompressing them first if they're gzipped, as
+/// indexers and `.nzb.gz` files often are. Either way they're held to
+/// [`MAX_NZB_SIZE`], a small gzip can inflate to gigabytes.
+pub fn decode(bytes: &[u8]) -> Result<String, NzbError> {
+    decode_up_to(bytes, MAX_NZB_SIZE)
+}
+
+fn decode_up_to(bytes: &[u8], limit: u64) -> Result<String, NzbError> {
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        if bytes.len() as u64 > limit {
+            return Err(NzbError::TooLarge(limit));
+        }
+        return Ok(String::from_utf8(bytes.to_vec())?);
+    }
+
+    let mut content = Vec::new();
+    GzDecoder::new(bytes)
+        .take(limit + 1)
+        .read_to_end(&mut content)?;
+    if content.len() as u64 > limit {
+        return Err(NzbError::TooLarge(limit));
+    }
+
+    Ok(String::from_utf8(content)?)
+}
+
+/// Resolves `path` within `nzb_dir`, following symlinks and `..` so nothing
+/// outside it can be read
+async fn allowed_path(path: &Path, nzb_dir: &Path) -> Result<PathBuf, NzbError> {
+    let nzb_dir = tokio::fs::canonicalize(nzb_dir).await?;
+    let path = tokio::fs::canonicalize(nzb_dir.join(path)).await?;
+
+    if !path.starts_with(&nzb_dir) {
+        return Err(NzbError::PathNotAllowed(path));
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+
+    use super::*;
+
+    #[test]
+    fn test_decode_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"<nzb/>").unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(decode(&gzipped).unwrap(), "<nzb/>");
+        assert_eq!(decode(b"<nzb/>").unwrap(), "<nzb/>");
+    }
+
+    #[test]
+    fn test_decode_size_limit() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&[b' '; 1000]).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(decode_up_to(&gzipped, 1000).unwrap().len(), 1000);
+        assert!(matches!(
+            decode_up_to(&gzipped, 999),
+            Err(NzbError::TooLarge(999))
+        ));
+        assert!(matches!(
+            decode_up_to(&[b' '; 1000], 999),
+            Err(NzbError::TooLarge(999))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limit() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/movie.nzb")
+            .with_body("<nzb/>")
+            .create_async()
+            .await;
+
+        let http = http_client().unwrap();
+        let get = || http.get(format!("{}/movie.nzb", server.url())).send();
+        assert_eq!(
+            read_body_up_to(get().await.unwrap(), 6).await.unwrap(),
+            b"<nzb/>"
+        );
+        assert!(matches!(
+            read_body_up_to(get().await.unwrap(), 5).await,
+            Err(NzbError::TooLarge(5))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_path_allow_list() {
+        let root = tempfile::tempdir().unwrap();
+        let nzb_dir = root.path().join("nzbs");
+        std::fs::create_dir(&nzb_dir).unwrap();
+        std::fs::write(nzb_dir.join("movie.nzb"), "<nzb/>").unwrap();
+        std::fs::write(root.path().join("secret.nzb"), "<nzb/>").unwrap();
+
+        let http = reqwest::Client::new();
+        let source = NzbSource::Path("movie.nzb".into());
+        let loaded = source.load(&http, Some(&nzb_dir)).await.unwrap();
+        assert_eq!(loaded.name.as_deref(), Some("movie.nzb"));
+
+        let escape = NzbSource::Path("../secret.nzb".into());
+        assert!(matches!(
+            escape.load(&http, Some(&nzb_dir)).await,
+            Err(NzbError::PathNotAllowed(_))
+        ));
+        assert!(matches!(
+            source.load(&http, None).await,
+            Err(NzbError::PathsDisabled)
+        ));
+    }
+}
@@ -0,0 +1,463 @@
+use axum::{
+    extract::{Multipart, Query, State},
+    response::Json,
+};
+use serde_json::{Value, json};
+
+use crate::{
+    error::RestError,
+    nzb::{
+        self,
+        source::{LoadedNzb, NzbSource},
+    },
+    sabnzbd::{self, ApiRequest, Outcome, Progress, SabnzbdConfig},
+    server::AppState,
+    session::{SessionMode, create::create_session},
+};
+
+/// SABnzbd's API, enough of it for Sonarr and Radarr to send grabs, follow
+/// them in the queue and import them from the history. Errors are reported
+/// the way SABnzbd does, in the body.
+pub async fn api(State(state): State<AppState>, Query(request): Query<ApiRequest>) -> Json<Value> {
+    Json(
+        response(&state, request, None)
+            .await
+            .unwrap_or_else(|e| sabnzbd::error(&e.to_string())),
+    )
+}
+
+/// `mode=addfile` posts the NZB as a form upload, other modes are answered
+/// as if they were a GET
+pub async fn addfile(
+    State(state): State<AppState>,
+    Query(request): Query<ApiRequest>,
+    mut multipart: Multipart,
+) -> Json<Value> {
+    let upload = async {
+        while let Some(field) = multipart.next_field().await? {
+            if field.file_name().is_some() {
+                let name = field.file_name().map(str::to_string);
+                let bytes = field.bytes().await?;
+
+                return Ok(Some(LoadedNzb {
+                    content: nzb::source::decode(&bytes)?,
+                    name,
+                }));
+            }
+        }
+
+        Ok::<_, RestError>(None)
+    };
+
+    let response = match upload.await {
+        Ok(upload) => response(&state, request, upload).await,
+        Err(e) => Err(e),
+    };
+
+    Json(response.unwrap_or_else(|e| sabnzbd::error(&e.to_string())))
+}
+
+async fn response(
+    state: &AppState,
+    request: ApiRequest,
+    upload: Option<LoadedNzb>,
+) -> Result<Value, RestError> {
+    // anyone who can reach us could queue downloads, so there's no API
+    // without a key
+    let Some(sab) = state.sab.as_deref() else {
+        return Ok(sabnzbd::error(
+            "SABnzbd API is off, set sab.api_key or start with --sab-api-key",
+        ));
+    };
+
+    // SABnzbd answers version without a key, clients check it first
+    if request.mode == "version" {
+        return Ok(json!({ "version": sabnzbd::VERSION }));
+    }
+
+    if request.apikey.as_ref() != Some(&sab.api_key) {
+        return Ok(sabnzbd::error("API Key Incorrect"));
+    }
+
+    let delete = request.name.as_deref() == Some("delete");
+    match request.mode.as_str() {
+        "get_config" => Ok(sabnzbd::config(&state.complete_dir, &sab.categories)),
+        "addurl" => {
+            let url = request.name.clone().ok_or(RestError::MissingNzb)?;
+            let nzb = NzbSource::Url(url).load(&state.http, None).await?;
+            add(state, sab, &request, nzb).await
+        }
+        "addfile" => {
+            let nzb = upload.ok_or(RestError::MissingNzb)?;
+            add(state, sab, &request, nzb).await
+        }
+        "queue" | "history" if delete => {
+            let removed = sab
+                .jobs
+                .remove(request.value.as_deref().unwrap_or_default());
+            let mut nzo_ids = Vec::with_capacity(removed.len());
+
+            for job in removed {
+                nzo_ids.push(sabnzbd::nzo_id(&job));
+                let Some(session) = state.sessions.write().await.remove(&job.session_id) else {
+                    continue;
+                };
+
+                if let Some(download) = session.download.get() {
+                    download.abort();
+                }
+                if let Some(dir) = session.orchestrator.path().parent() {
+                    let _ = tokio::fs::remove_dir_all(dir).await;
+                }
+                if let Some(output) = session.output.get()
+                    && request.del_files == 1
+                {
+                    let _ = tokio::fs::remove_dir_all(&output.path).await;
+                }
+            }
+
+            Ok(json!({ "status": true, "nzo_ids": nzo_ids }))
+        }
+        "queue" | "history" => {
+            let sessions = state.sessions.read().await;
+            let jobs = sab.jobs.all();
+            let jobs = jobs
+                .iter()
+                .filter_map(|job| Some((job, sessions.get(&job.session_id)?)))
+                .map(|(job, session)| {
+                    let progress = Progress {
+                        total_bytes: session.orchestrator.total_size(),
+                        downloaded_bytes: session.orchestrator.downloaded_bytes(),
+                    };
+                    let outcome = match (session.output.get(), session.failure.get()) {
+                        (Some(output), _) => Some(Outcome::Completed(output)),
+                        (None, Some(failure)) => Some(Outcome::Failed(failure)),
+                        (None, None) => None,
+                    };
+                    (job, progress, outcome)
+                });
+
+            Ok(if request.mode == "queue" {
+                sabnzbd::queue(
+                    jobs.filter(|(_, _, outcome)| outcome.is_none())
+                        .enumerate()
+                        .map(|(index, (job, progress, _))| {
+                            sabnzbd::queue_slot(index, job, &progress)
+                        })
+                        .collect(),
+                )
+            } else {
+                sabnzbd::history(
+                    jobs.filter_map(|(job, progress, outcome)| {
+                        Some(sabnzbd::history_slot(job, &progress, outcome?))
+                    })
+                    .collect(),
+                )
+            })
+        }
+        mode => Ok(sabnzbd::error(&format!("Mode '{mode}' not supported"))),
+    }
+}
+
+/// Starts a session for a grab and queues it under the name and category
+/// the client gave
+async fn add(
+    state: &AppState,
+    sab: &SabnzbdConfig,
+    request: &ApiRequest,
+    nzb: LoadedNzb,
+) -> Result<Value, RestError> {
+    let name = request.nzbname.clone().or_else(|| {
+        nzb.name.as_deref().map(|name| {
+            name.trim_end_matches(".gz")
+                .trim_end_matches(".nzb")
+                .to_string()
+        })
+    });
+    // the *arrs import the file, so it has to be all there
+    let session =
+        create_session(state, &nzb.content, name.as_deref(), SessionMode::Download).await?;
+
+    let job = sabnzbd::Job {
+        session_id: session.id,
+        name: name.unwrap_or_else(|| session.title.clone()),
+        category: request.cat.clone().unwrap_or_else(|| "*".to_string()),
+    };
+    let nzo_id = sabnzbd::nzo_id(&job);
+    sab.jobs.add(job);
+
+    Ok(json!({ "status": true, "nzo_ids": [nzo_id] }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path, sync::Arc};
+
+    use axum_test::{
+        TestServer,
+        multipart::{MultipartForm, Part},
+    };
+    use tokio::sync::{RwLock, watch};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        media,
+        nntp::config::NntpConfig,
+        nzb::parser::NzbMeta,
+        sabnzbd::{Job, Jobs},
+        scheduler::adaptive::AdaptiveScheduler,
+        server,
+        session::{Session, SessionOutput},
+        settings::Settings,
+        stream::orchestrator::{BufferHealth, StreamOrchestrator},
+        stremio::{StremioAddon, resolver::DirectoryResolver},
+    };
+
+    const API_KEY: &str = "secret";
+
+    const NZB: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <nzb xmlns="http://www.newzbin.com/DTD/2003/nzb">
+            <file poster="p" date="1700000000" subject="&quot;movie.mkv&quot; yEnc (1/1)">
+                <groups><group>alt.binaries.test</group></groups>
+                <segments><segment bytes="1000" number="1">movie.mkv.0@x</segment></segments>
+            </file>
+        </nzb>"#;
+
+    /// The API against a server nothing listens on, so grabs fail once
+    /// they're loaded
+    fn server(dir: &Path) -> (TestServer, AppState) {
+        let mut settings = Settings::default();
+        settings.cache.dir = dir.join("cache");
+        settings.scheduler.retry_budget_secs = 0;
+        let config = NntpConfig {
+            host: "127.0.0.1".to_string(),
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            port: 1,
+            tls: false,
+            max_connections: Default::default(),
+            idle_timeout: Default::default(),
+            pipeline_depth: Default::default(),
+        };
+
+        let state = AppState {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            scheduler: Arc::new(
+                AdaptiveScheduler::new(config, settings.scheduler.clone()).unwrap(),
+            ),
+            stremio: Arc::new(StremioAddon::new(Arc::new(DirectoryResolver::new(dir)))),
+            http: reqwest::Client::new(),
+            nzb_dir: None,
+            complete_dir: dir.join("complete"),
+            settings: Arc::new(settings),
+            sab: Some(Arc::new(SabnzbdConfig {
+                jobs: Jobs::default(),
+                api_key: API_KEY.to_string(),
+                categories: vec!["tv".to_string()],
+            })),
+            newznab: None,
+            mock_mode: false,
+        };
+
+        (
+            TestServer::new(server::router(state.clone())).unwrap(),
+            state,
+        )
+    }
+
+    /// Queues a job for a session that's finished if `output` is given
+    async fn add_job(state: &AppState, dir: &Path, name: &str, output: Option<&Path>) -> String {
+        let id = Uuid::new_v4();
+        let session_dir = dir.join(id.to_string());
+        std::fs::create_dir_all(&session_dir).unwrap();
+
+        let orchestrator = StreamOrchestrator::new(
+            Vec::new(),
+            session_dir.join("stream"),
+            watch::channel(BufferHealth::Critical).0,
+            watch::channel(0).0,
+            state.settings.health,
+        );
+        let session = Session::new(
+            id,
+            NzbMeta::default(),
+            media::probe(&[], 0),
+            Some(name),
+            None,
+            SessionMode::Download,
+            orchestrator,
+        );
+        if let Some(path) = output {
+            std::fs::create_dir_all(path).unwrap();
+            let _ = session.output.set(SessionOutput {
+                path: path.to_path_buf(),
+                completed_at: chrono::Utc::now(),
+            });
+        }
+        state.sessions.write().await.insert(id, Arc::new(session));
+
+        let job = Job {
+            session_id: id,
+            name: name.to_string(),
+            category: "tv".to_string(),
+        };
+        let nzo_id = sabnzbd::nzo_id(&job);
+        state.sab.as_ref().unwrap().jobs.add(job);
+        nzo_id
+    }
+
+    async fn get(server: &TestServer, query: &[(&str, &str)]) -> Value {
+        let mut request = server.get("/sabnzbd/api");
+        for (key, value) in query {
+            request = request.add_query_param(key, value);
+        }
+        request.await.json()
+    }
+
+    #[tokio::test]
+    async fn test_api_key_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, _) = server(dir.path());
+
+        let version = get(&server, &[("mode", "version")]).await;
+        assert_eq!(version["version"], sabnzbd::VERSION);
+
+        for apikey in [None, Some("wrong")] {
+            let mut query = vec![("mode", "queue")];
+            query.extend(apikey.map(|key| ("apikey", key)));
+            let response = get(&server, &query).await;
+            assert_eq!(response["status"], false);
+            assert_eq!(response["error"], "API Key Incorrect");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_off_without_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, mut state) = server(dir.path());
+        state.sab = None;
+        let server = TestServer::new(server::router(state)).unwrap();
+
+        let response = get(&server, &[("mode", "version")]).await;
+        assert_eq!(response["status"], false);
+    }
+
+    #[tokio::test]
+    async fn test_queue_and_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, state) = server(dir.path());
+        let queued = add_job(&state, dir.path(), "Show.S01E01", None).await;
+        let done = dir.path().join("complete/Show.S01E02");
+        let finished = add_job(&state, dir.path(), "Show.S01E02", Some(&done)).await;
+
+        let queue = get(&server, &[("mode", "queue"), ("apikey", API_KEY)]).await;
+        let slots = queue["queue"]["slots"].as_array().unwrap();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0]["nzo_id"], queued);
+        assert_eq!(slots[0]["filename"], "Show.S01E01");
+
+        let history = get(&server, &[("mode", "history"), ("apikey", API_KEY)]).await;
+        let slots = history["history"]["slots"].as_array().unwrap();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0]["nzo_id"], finished);
+        assert_eq!(slots[0]["status"], "Completed");
+        assert_eq!(slots[0]["storage"], done.display().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, state) = server(dir.path());
+        let done = dir.path().join("complete/Show.S01E01");
+        let kept = dir.path().join("complete/Show.S01E02");
+        let deleted = add_job(&state, dir.path(), "Show.S01E01", Some(&done)).await;
+        let other = add_job(&state, dir.path(), "Show.S01E02", Some(&kept)).await;
+
+        let response = get(
+            &server,
+            &[
+                ("mode", "history"),
+                ("name", "delete"),
+                ("value", &deleted),
+                ("del_files", "1"),
+                ("apikey", API_KEY),
+            ],
+        )
+        .await;
+        assert_eq!(response["status"], true);
+        assert_eq!(response["nzo_ids"], json!([deleted]));
+        assert!(!done.exists());
+        assert!(kept.exists());
+        assert_eq!(state.sessions.read().await.len(), 1);
+
+        let history = get(&server, &[("mode", "history"), ("apikey", API_KEY)]).await;
+        let slots = history["history"]["slots"].as_array().unwrap();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0]["nzo_id"], other);
+    }
+
+    #[tokio::test]
+    async fn test_addurl() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, state) = server(dir.path());
+        let mut indexer = mockito::Server::new_async().await;
+        let nzb = indexer
+            .mock("GET", "/movie.nzb")
+            .with_body(NZB)
+            .create_async()
+            .await;
+
+        let missing = get(&server, &[("mode", "addurl"), ("apikey", API_KEY)]).await;
+        assert_eq!(missing["status"], false);
+
+        let url = format!("{}/movie.nzb", indexer.url());
+        let response = get(
+            &server,
+            &[("mode", "addurl"), ("name", &url), ("apikey", API_KEY)],
+        )
+        .await;
+        nzb.assert_async().await;
+
+        // the NZB was fetched, but there's no server to download it from
+        assert_eq!(response["status"], false);
+        assert!(state.sab.as_ref().unwrap().jobs.all().is_empty());
+        assert!(state.sessions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_addfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, state) = server(dir.path());
+        let post = |form| {
+            server
+                .post("/sabnzbd/api")
+                .add_query_param("mode", "addfile")
+                .add_query_param("apikey", API_KEY)
+                .multipart(form)
+        };
+
+        let missing: Value = post(MultipartForm::new().add_text("nzbname", "Movie"))
+            .await
+            .json();
+        assert_eq!(missing["status"], false);
+
+        let garbage: Value = post(MultipartForm::new().add_part(
+            "name",
+            Part::bytes(b"not an nzb".to_vec()).file_name("movie.nzb"),
+        ))
+        .await
+        .json();
+        assert_eq!(garbage["status"], false);
+
+        let upload = Part::bytes(NZB.as_bytes().to_vec()).file_name("movie.nzb");
+        let response: Value = post(MultipartForm::new().add_part("name", upload))
+            .await
+            .json();
+        assert_eq!(response["status"], false);
+        assert!(state.sab.as_ref().unwrap().jobs.all().is_empty());
+        // nothing is left behind in the cache by the failed grab
+        let cache = &state.settings.cache.dir;
+        assert!(std::fs::read_dir(cache).unwrap().next().is_none());
+    }
+}
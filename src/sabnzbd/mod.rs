@@ -0,0 +1,317 @@
+use std::path::Path;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::session::{SessionFailure, SessionOutput};
+
+pub mod handlers;
+
+/// What we tell clients we are. Sonarr and Radarr refuse anything older
+/// than 0.7, and read newer fields from 3.x on.
+pub const VERSION: &str = "4.3.3";
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+/// The query parameters SABnzbd's API takes, of which each mode uses a few
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiRequest {
+    pub mode: String,
+    pub apikey: Option<String>,
+    /// The URL for `addurl`, or a sub-command like `delete` for `queue` and
+    /// `history`
+    pub name: Option<String>,
+    /// Comma separated job IDs for deletes
+    pub value: Option<String>,
+    pub cat: Option<String>,
+    pub nzbname: Option<String>,
+    #[serde(default)]
+    pub del_files: u8,
+}
+
+/// Grabs sent through the API, and what it offers
+#[derive(Debug)]
+pub struct SabnzbdConfig {
+    pub jobs: Jobs,
+    pub api_key: String,
+    pub categories: Vec<String>,
+}
+
+/// A grab sent through the API, tracked so the queue and history can show
+/// the name and category the client gave it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub session_id: Uuid,
+    pub name: String,
+    pub category: String,
+}
+
+/// How far along a job's session is
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub total_bytes: u64,
+    pub downloaded_bytes: u64,
+}
+
+/// How a job's session ended, for its history slot
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome<'a> {
+    Completed(&'a SessionOutput),
+    Failed(&'a SessionFailure),
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueSlot {
+    pub index: usize,
+    pub nzo_id: String,
+    pub filename: String,
+    pub cat: String,
+    pub status: &'static str,
+    pub priority: &'static str,
+    pub mb: String,
+    pub mbleft: String,
+    pub percentage: String,
+    pub timeleft: &'static str,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistorySlot {
+    pub nzo_id: String,
+    pub name: String,
+    pub nzb_name: String,
+    pub category: String,
+    pub status: &'static str,
+    pub bytes: u64,
+    /// The folder the client imports from
+    pub storage: String,
+    /// Unix time
+    pub completed: i64,
+    pub fail_message: String,
+}
+
+/// Jobs in the order they were added, which is the queue order
+#[derive(Debug, Default)]
+pub struct Jobs(RwLock<Vec<Job>>);
+
+impl Jobs {
+    pub fn add(&self, job: Job) {
+        self.0.write().push(job);
+    }
+
+    pub fn all(&self) -> Vec<Job> {
+        self.0.read().clone()
+    }
+
+    /// Removes the jobs with the given IDs, or every job with `all`
+    ///
+    /// # Returns
+    /// - The jobs that were removed
+    pub fn remove(&self, ids: &str) -> Vec<Job> {
+        let ids: Vec<_> = ids.split(',').map(str::trim).collect();
+        let mut jobs = self.0.write();
+
+        let (removed, kept) = jobs
+            .drain(..)
+            .partition(|job| ids.contains(&"all") || ids.contains(&nzo_id(job).as_str()));
+        *jobs = kept;
+
+        removed
+    }
+}
+
+/// Our job IDs are the session IDs
+pub fn nzo_id(job: &Job) -> String {
+    job.session_id.to_string()
+}
+
+pub fn queue_slot(index: usize, job: &Job, progress: &Progress) -> QueueSlot {
+    let left = progress
+        .total_bytes
+        .saturating_sub(progress.downloaded_bytes);
+    let percentage = match progress.total_bytes {
+        0 => 0,
+        total => progress.downloaded_bytes * 100 / total,
+    };
+
+    QueueSlot {
+        index,
+        nzo_id: nzo_id(job),
+        filename: job.name.clone(),
+        cat: job.category.clone(),
+        status: "Downloading",
+        priority: "Normal",
+        mb: megabytes(progress.total_bytes),
+        mbleft: megabytes(left),
+        percentage: percentage.to_string(),
+        timeleft: "0:00:00",
+        labels: Vec::new(),
+    }
+}
+
+/// The *arrs import completed jobs from `storage` and look for another
+/// release for failed ones
+pub fn history_slot(job: &Job, progress: &Progress, outcome: Outcome) -> HistorySlot {
+    let (status, storage, completed, fail_message) = match outcome {
+        Outcome::Completed(output) => (
+            "Completed",
+            output.path.display().to_string(),
+            output.completed_at,
+            String::new(),
+        ),
+        Outcome::Failed(failure) => (
+            "Failed",
+            String::new(),
+            failure.failed_at,
+            failure.message.clone(),
+        ),
+    };
+
+    HistorySlot {
+        nzo_id: nzo_id(job),
+        name: job.name.clone(),
+        nzb_name: format!("{}.nzb", job.name),
+        category: job.category.clone(),
+        status,
+        bytes: progress.total_bytes,
+        storage,
+        completed: completed.timestamp(),
+        fail_message,
+    }
+}
+
+pub fn queue(slots: Vec<QueueSlot>) -> Value {
+    json!({
+        "queue": {
+            "status": if slots.is_empty() { "Idle" } else { "Downloading" },
+            "paused": false,
+            "speedlimit": "100",
+            "kbpersec": "0",
+            "noofslots": slots.len(),
+            "noofslots_total": slots.len(),
+            "slots": slots,
+        }
+    })
+}
+
+pub fn history(slots: Vec<HistorySlot>) -> Value {
+    json!({
+        "history": {
+            "noofslots": slots.len(),
+            "slots": slots,
+        }
+    })
+}
+
+/// The parts of the config the *arrs check: where completed downloads go
+/// and which categories exist
+pub fn config(complete_dir: &Path, categories: &[String]) -> Value {
+    let categories: Vec<_> = std::iter::once("*")
+        .chain(categories.iter().map(String::as_str))
+        .enumerate()
+        .map(|(order, name)| {
+            json!({
+                "name": name,
+                "order": order,
+                "pp": "3",
+                "script": "None",
+                "dir": "",
+                "priority": -100,
+            })
+        })
+        .collect();
+
+    json!({
+        "config": {
+            "misc": {
+                "complete_dir": complete_dir.display().to_string(),
+                "enable_tv_sorting": false,
+                "enable_movie_sorting": false,
+                "enable_date_sorting": false,
+                "pre_check": false,
+                "history_retention": "",
+            },
+            "categories": categories,
+            "sorters": [],
+        }
+    })
+}
+
+pub fn error(message: &str) -> Value {
+    json!({ "status": false, "error": message })
+}
+
+fn megabytes(bytes: u64) -> String {
+    format!("{:.2}", bytes as f64 / BYTES_PER_MB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str) -> Job {
+        Job {
+            session_id: Uuid::new_v4(),
+            name: name.to_string(),
+            category: "tv".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queue_slot() {
+        let progress = Progress {
+            total_bytes: 4 * 1024 * 1024,
+            downloaded_bytes: 1024 * 1024,
+        };
+        let slot = queue_slot(0, &job("Show.S01E01"), &progress);
+
+        assert_eq!(slot.mb, "4.00");
+        assert_eq!(slot.mbleft, "3.00");
+        assert_eq!(slot.percentage, "25");
+        assert_eq!(slot.cat, "tv");
+    }
+
+    #[test]
+    fn test_history_slot() {
+        let progress = Progress {
+            total_bytes: 1024,
+            downloaded_bytes: 512,
+        };
+        let output = SessionOutput {
+            path: "/data/complete/Show.S01E01".into(),
+            completed_at: chrono::Utc::now(),
+        };
+        let failure = SessionFailure {
+            message: "Volumes still damaged after PAR2 repair".to_string(),
+            failed_at: chrono::Utc::now(),
+        };
+
+        let slot = history_slot(&job("Show.S01E01"), &progress, Outcome::Completed(&output));
+        assert_eq!(slot.status, "Completed");
+        assert_eq!(slot.storage, "/data/complete/Show.S01E01");
+        assert!(slot.fail_message.is_empty());
+
+        let slot = history_slot(&job("Show.S01E01"), &progress, Outcome::Failed(&failure));
+        assert_eq!(slot.status, "Failed");
+        assert!(slot.storage.is_empty());
+        assert_eq!(slot.fail_message, failure.message);
+    }
+
+    #[test]
+    fn test_remove_jobs() {
+        let jobs = Jobs::default();
+        let (first, second, third) = (job("one"), job("two"), job("three"));
+        for job in [&first, &second, &third] {
+            jobs.add(job.clone());
+        }
+
+        let removed = jobs.remove(&format!("{}, {}", nzo_id(&first), nzo_id(&third)));
+        assert_eq!(removed, vec![first, third]);
+        assert_eq!(jobs.all(), vec![second]);
+
+        jobs.remove("all");
+        assert!(jobs.all().is_empty());
+    }
+}
@@ -1,24 +1,36 @@
+use crate::archive::MAX_HEADER_SIZE;
+use crate::archive::error::ArchiveError;
 use crate::archive::par2::DownloadTask;
 use crate::nntp::client::NntpClient;
 use crate::nntp::config::NntpConfig;
 use crate::nntp::yenc::compute_hash16k;
+use crate::nzb::Nzb;
+use crate::scheduler::availability::{self, AvailabilityReport};
+use crate::scheduler::bandwidth::RateLimiter;
 use crate::scheduler::batch::BatchGenerator;
 use crate::scheduler::error::SchedulerError;
-use crate::scheduler::job_processor::process_job;
+use crate::scheduler::job_processor::{process_job, write_to_mmap};
+use crate::scheduler::share::Tenant;
+use crate::settings::SchedulerSettings;
 use crate::stream::orchestrator::BufferHealth;
-use bytes::Bytes;
-use futures::TryStreamExt;
+use bytes::{Bytes, BytesMut};
 use futures::stream::{self, StreamExt};
+use futures::{TryStreamExt, future};
 use memmap2::MmapMut;
 use parking_lot::RwLock;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::watch;
 
-use tracing::info;
+use tracing::{info, warn};
 
 pub struct AdaptiveScheduler {
     client: Arc<NntpClient>,
     max_workers: usize,
+    tuning: SchedulerSettings,
 }
 
 #[derive(Debug)]
@@ -26,20 +38,39 @@ pub struct FirstSegment {
     pub nzb: nzb_rs::File,
     pub hash16k: Bytes,
     pub bytes: Bytes,
+    /// Size of the whole file according to the yEnc header
+    pub file_size: Option<u64>,
 }
 
 impl AdaptiveScheduler {
-    pub fn new(config: NntpConfig) -> Result<Self, SchedulerError> {
-        let client = NntpClient::new(config.clone())?; // TODO: don't clone here
+    pub fn new(config: NntpConfig, tuning: SchedulerSettings) -> Result<Self, SchedulerError> {
+        let max_workers = *config.max_connections;
+        let client = NntpClient::new(config, Duration::from_secs(tuning.retry_budget_secs))?;
 
         Ok(Self {
             client: Arc::new(client),
-            max_workers: *config.max_connections,
+            max_workers,
+            tuning,
         })
     }
 
+    /// The global bandwidth limit
+    pub fn bandwidth(&self) -> &Arc<RateLimiter> {
+        self.client.bandwidth()
+    }
+
+    /// Sets the connection gauges, just before a scrape
+    pub fn record_metrics(&self) {
+        self.client.record_metrics()
+    }
+
     pub async fn warm_pool(&self) {
-        self.client.warm_pool().await
+        self.client.warm_pool(self.tuning.warm_connections).await
+    }
+
+    /// Articles fetched at once outside the background download
+    fn prefetch_parallelism(&self) -> usize {
+        self.max_workers.min(self.tuning.prefetch_parallelism)
     }
 
     pub async fn download_first_segments(
@@ -50,7 +81,7 @@ impl AdaptiveScheduler {
 
         stream::iter(files.iter().cloned())
             .map(|file| self.download_first_segment(file))
-            .buffer_unordered(self.max_workers.min(20)) // TODO: workers
+            .buffer_unordered(self.prefetch_parallelism())
             .collect::<Vec<_>>()
             .await
             .into_iter()
@@ -66,27 +97,340 @@ impl AdaptiveScheduler {
             .first()
             .ok_or_else(|| SchedulerError::EmptyFile(file.subject.clone()))?;
 
-        let data = self.client.download(first_segment).await?;
-        let hash16k = compute_hash16k(&data);
+        let article = self.client.download_article(first_segment).await?;
+        let hash16k = compute_hash16k(&article.data);
 
         Ok(FirstSegment {
             nzb: file,
             hash16k: hash16k.into(),
-            bytes: data,
+            bytes: article.data,
+            file_size: article.file_size,
         })
     }
 
+    /// Downloads every segment of a file and stitches them together in order.
+    /// Only meant for small auxiliary files (e.g. PAR2 index files); large
+    /// files go through [`Self::schedule_downloads`].
+    pub async fn download_file(&self, file: &nzb_rs::File) -> Result<Bytes, SchedulerError> {
+        if file.segments.is_empty() {
+            return Err(SchedulerError::EmptyFile(file.subject.clone()));
+        }
+
+        let client = &self.client;
+        let segments = stream::iter(file.segments.iter().cloned())
+            .map(|segment| async move { client.download(&segment).await })
+            .buffered(self.prefetch_parallelism())
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut buffer = BytesMut::with_capacity(segments.iter().map(Bytes::len).sum());
+        for segment in segments {
+            buffer.extend_from_slice(&segment);
+        }
+
+        Ok(buffer.freeze())
+    }
+
+    /// Downloads the end of a volume from byte `from` onwards, given its
+    /// first segment and `size`. Archive end headers live there, so this
+    /// avoids fetching a whole volume just to read them.
+    pub async fn download_tail(
+        &self,
+        volume: &FirstSegment,
+        size: u64,
+        from: u64,
+    ) -> Result<Bytes, SchedulerError> {
+        header_end(from, size.saturating_sub(from), size)?;
+
+        self.download_file_range(&volume.nzb, &volume.bytes, from, size)
+            .await
+    }
+
+    /// Downloads `length` bytes at absolute offset `start` of a multi-volume
+    /// archive, given each volume's first segment and size in order
+    pub async fn download_range(
+        &self,
+        volumes: &[(&FirstSegment, u64)],
+        start: u64,
+        length: u64,
+    ) -> Result<Bytes, SchedulerError> {
+        let size = volumes.iter().map(|(_, size)| size).sum();
+        let end = header_end(start, length, size)?;
+        let mut buffer = BytesMut::with_capacity(length as usize);
+        let mut volume_start = 0;
+
+        for (volume, size) in volumes {
+            let volume_end = volume_start + size;
+
+            if start < volume_end && end > volume_start {
+                let from = start.saturating_sub(volume_start);
+                let to = end.min(volume_end) - volume_start;
+                let data = self
+                    .download_file_range(&volume.nzb, &volume.bytes, from, to)
+                    .await?;
+
+                buffer.extend_from_slice(&data);
+            }
+
+            volume_start = volume_end;
+        }
+
+        if buffer.len() as u64 != length {
+            return Err(SchedulerError::Archive(ArchiveError::IncompleteData));
+        }
+
+        Ok(buffer.freeze())
+    }
+
+    /// Downloads `length` bytes at `start` in the session file ahead of the
+    /// background downloads and writes them into `mmap`. Players read index
+    /// data like the MKV Cues before seeking, so it can't wait its turn.
+    pub async fn prefetch(
+        &self,
+        tasks: &[DownloadTask],
+        mmap: &Arc<RwLock<MmapMut>>,
+        start: u64,
+        length: u64,
+    ) -> Result<Bytes, SchedulerError> {
+        let size = tasks.iter().map(|task| *task.length()).sum();
+        let end = range_end(start, length, size)?;
+        let mut buffer = BytesMut::zeroed(length as usize);
+        let mut task_start = 0;
+
+        for task in tasks {
+            let task_end = task_start + task.length();
+            let from = start.max(task_start);
+            let to = end.min(task_end);
+
+            if from < to {
+                let data = self
+                    .download_file_range(
+                        task.nzb(),
+                        task.bytes(),
+                        task.offset() + (from - task_start),
+                        task.offset() + (to - task_start),
+                    )
+                    .await?;
+
+                buffer[(from - start) as usize..(to - start) as usize].copy_from_slice(&data);
+                write_to_mmap(mmap, from, &data);
+            }
+
+            task_start = task_end;
+        }
+
+        Ok(buffer.freeze())
+    }
+
+    /// Downloads `from..to` of a file, fetching only the segments covering
+    /// it, given the `first` segment we already have. yEnc splits files into
+    /// equal parts, so its size tells us where every other one starts.
+    pub async fn download_file_range(
+        &self,
+        file: &nzb_rs::File,
+        first: &Bytes,
+        from: u64,
+        to: u64,
+    ) -> Result<Bytes, SchedulerError> {
+        let part_size = first.len() as u64;
+        if part_size == 0 || from >= to {
+            return Err(SchedulerError::EmptyFile(file.subject.clone()));
+        }
+
+        let segments = &file.segments;
+        let parts = stream::iter((from / part_size) as usize..=((to - 1) / part_size) as usize)
+            .map(|index| async move {
+                // we already have the first segment
+                if index == 0 {
+                    return Ok((0, first.clone()));
+                }
+
+                let segment = segments
+                    .get(index)
+                    .ok_or(SchedulerError::Archive(ArchiveError::IncompleteData))?;
+                let article = self.client.download_article(segment).await?;
+                let offset = article.part_offset.unwrap_or(index as u64 * part_size);
+
+                Ok::<_, SchedulerError>((offset, article.data))
+            })
+            .buffered(self.prefetch_parallelism())
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut buffer = BytesMut::zeroed((to - from) as usize);
+        let mut filled = 0;
+        for (offset, data) in parts {
+            let start = offset.max(from);
+            let end = (offset + data.len() as u64).min(to);
+
+            if start < end {
+                buffer[(start - from) as usize..(end - from) as usize]
+                    .copy_from_slice(&data[(start - offset) as usize..(end - offset) as usize]);
+                filled += end - start;
+            }
+        }
+
+        if filled != to - from {
+            return Err(SchedulerError::Archive(ArchiveError::IncompleteData));
+        }
+
+        Ok(buffer.freeze())
+    }
+
+    /// Downloads every segment of each file to its path, all at once with no
+    /// regard for playback. Segments are written at their yEnc part offsets,
+    /// so one that fails leaves a hole for PAR2 to repair rather than
+    /// shifting the rest of the file.
+    ///
+    /// # Returns
+    /// - The number of segments of each file that couldn't be downloaded
+    pub async fn download_files(
+        &self,
+        files: &[(&nzb_rs::File, PathBuf)],
+        tenant: &Arc<Tenant>,
+    ) -> Result<Vec<usize>, SchedulerError> {
+        info!("Downloading {} files in full", files.len());
+
+        let outputs = files
+            .iter()
+            .map(|(_, path)| std::fs::File::create(path).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut sized = vec![false; files.len()];
+
+        // owned, so the future doesn't borrow from `files` across the stream
+        let depth = self.client.pipeline_depth();
+        let batches = files
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (file, _))| {
+                file.segments
+                    .chunks(depth)
+                    .map(move |batch| (index, batch.to_vec()))
+            })
+            .collect::<Vec<_>>();
+        let client = &self.client;
+        let mut downloads = stream::iter(batches)
+            .map(|(index, batch)| async move {
+                let articles = client.download_articles(&batch, Some(tenant)).await;
+                stream::iter(articles.into_iter().map(move |article| (index, article)))
+            })
+            .buffer_unordered(self.max_workers)
+            .flatten();
+
+        let mut missing = vec![0; files.len()];
+        while let Some((index, download)) = downloads.next().await {
+            let subject = &files[index].0.subject;
+            let article = match download {
+                Ok(article) => article,
+                Err(e) => {
+                    warn!("Segment of '{subject}' failed: {e}");
+                    missing[index] += 1;
+                    continue;
+                }
+            };
+            let Some(offset) = article.part_offset else {
+                warn!("{}", SchedulerError::MissingPartOffset(subject.clone()));
+                missing[index] += 1;
+                continue;
+            };
+
+            // size the file up front so missing segments at the end still
+            // leave it the length PAR2 expects
+            let file_size = article.file_size.filter(|_| !sized[index]);
+            sized[index] |= file_size.is_some();
+
+            let output = Arc::clone(&outputs[index]);
+            tokio::task::spawn_blocking(move || {
+                if let Some(file_size) = file_size {
+                    output.set_len(file_size)?;
+                }
+                output.write_all_at(&article.data, offset)
+            })
+            .await??;
+        }
+
+        info!(
+            "Downloaded {} files, {} segments missing",
+            files.len(),
+            missing.iter().sum::<usize>()
+        );
+        Ok(missing)
+    }
+
+    /// Writes the stream data of each task to `out`, in order. Segments are
+    /// fetched ahead of the writer, but a missing one fails the whole stream,
+    /// there's no file to leave a hole in.
+    ///
+    /// # Returns
+    /// - The number of bytes written
+    pub async fn write_stream(
+        &self,
+        tasks: &[DownloadTask],
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<u64, SchedulerError> {
+        let mut written = 0;
+
+        for task in tasks {
+            let (start, end) = (*task.offset(), task.offset() + task.length());
+            let client = &self.client;
+            // we already have the first segment
+            let rest = task.nzb().segments.get(1..).unwrap_or_default();
+            let mut segments = stream::once(future::ready(Ok(task.bytes().clone()))).chain(
+                stream::iter(rest.chunks(client.pipeline_depth()))
+                    .map(|batch| async move {
+                        stream::iter(client.download_articles(batch, None).await)
+                    })
+                    .buffered(self.max_workers)
+                    .flatten()
+                    .map_ok(|article| article.data),
+            );
+
+            let mut position = 0;
+            while position < end
+                && let Some(data) = segments.try_next().await?
+            {
+                let from = start.clamp(position, position + data.len() as u64);
+                let to = end.clamp(position, position + data.len() as u64);
+                out.write_all(&data[(from - position) as usize..(to - position) as usize])
+                    .await?;
+
+                written += to - from;
+                position += data.len() as u64;
+            }
+
+            if position < end {
+                return Err(SchedulerError::Archive(ArchiveError::IncompleteData));
+            }
+        }
+
+        out.flush().await?;
+        Ok(written)
+    }
+
+    /// Asks about every segment of the release with pipelined `STAT`s, or an
+    /// evenly spread `sample` of them, and weighs what's missing against the
+    /// PAR2 recovery data
+    pub async fn check_availability(
+        &self,
+        nzb: &Nzb,
+        sample: Option<usize>,
+    ) -> Result<AvailabilityReport, SchedulerError> {
+        Ok(availability::check(&self.client, nzb, sample, self.max_workers).await?)
+    }
+
     pub async fn schedule_downloads(
         &self,
         tasks: Vec<DownloadTask>,
         mmap: Arc<RwLock<MmapMut>>,
         health_rx: watch::Receiver<BufferHealth>,
+        position_rx: watch::Receiver<u64>,
+        tenant: Arc<Tenant>,
     ) -> Result<(), SchedulerError> {
         info!("Starting adaptive scheduling for {} tasks", tasks.len());
 
         let total_tasks = tasks.len();
 
-        let generator = BatchGenerator::new(tasks, health_rx);
+        let generator = BatchGenerator::new(tasks, health_rx, position_rx);
         let client = self.client.clone();
         let max_workers = self.max_workers;
 
@@ -94,6 +438,7 @@ impl AdaptiveScheduler {
             .then(|batch| {
                 let client = client.clone();
                 let mmap = mmap.clone();
+                let tenant = tenant.clone();
                 let job_parallelism = batch.health.concurrent_jobs(total_tasks);
                 let segment_parallelism = batch
                     .health
@@ -102,7 +447,13 @@ impl AdaptiveScheduler {
                 async move {
                     stream::iter(batch.jobs)
                         .map(|job| {
-                            process_job(job, client.clone(), mmap.clone(), segment_parallelism)
+                            process_job(
+                                job,
+                                client.clone(),
+                                mmap.clone(),
+                                tenant.clone(),
+                                segment_parallelism,
+                            )
                         })
                         .buffer_unordered(job_parallelism)
                         .try_collect::<Vec<_>>()
@@ -117,3 +468,46 @@ impl AdaptiveScheduler {
         Ok(())
     }
 }
+
+/// The end of `length` bytes of archive header at `start`, checked like
+/// [`range_end`] and against [`MAX_HEADER_SIZE`], which is far below what a
+/// corrupt header can claim in a multi-gigabyte archive
+fn header_end(start: u64, length: u64, size: u64) -> Result<u64, SchedulerError> {
+    if length > MAX_HEADER_SIZE {
+        return Err(SchedulerError::HeaderTooLarge(length));
+    }
+
+    range_end(start, length, size)
+}
+
+/// The end of `length` bytes at `start`, checked against the `size` of the
+/// file before anything is allocated for them, since archive headers can
+/// claim any range they like
+fn range_end(start: u64, length: u64, size: u64) -> Result<u64, SchedulerError> {
+    start
+        .checked_add(length)
+        .filter(|&end| end <= size)
+        .ok_or(SchedulerError::OutOfRange {
+            start,
+            length,
+            size,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_range_end() {
+        assert_eq!(range_end(10, 20, 30).unwrap(), 30);
+        assert!(range_end(10, 21, 30).is_err());
+        assert!(range_end(u64::MAX, 2, u64::MAX).is_err());
+
+        assert_eq!(header_end(10, 20, 30).unwrap(), 30);
+        assert!(matches!(
+            header_end(0, MAX_HEADER_SIZE + 1, u64::MAX),
+            Err(SchedulerError::HeaderTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+use futures::{StreamExt, TryStreamExt, stream};
+use nzb_rs::{File, Segment};
+use serde::Serialize;
+
+use crate::{
+    nntp::{client::NntpClient, error::NntpError, yenc::extract_filename},
+    nzb::Nzb,
+};
+
+/// How much of a release is still on the server, and whether PAR2 can make
+/// up for the rest
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AvailabilityReport {
+    /// Articles in the content files and PAR2 volumes
+    pub articles: usize,
+    /// Articles asked about, fewer than `articles` for a sample
+    pub checked: usize,
+    pub available: usize,
+    pub available_percent: f64,
+    /// Content bytes that are gone, scaled up from the sample
+    pub missing_bytes: u64,
+    /// Recovery bytes left in the PAR2 volumes, scaled up from the sample
+    pub recovery_bytes: u64,
+    /// Whether there's at least as much recovery data as there is missing.
+    /// PAR2 repairs whole slices, so a release right at the limit may still
+    /// come up short.
+    pub repairable: bool,
+    /// Files with articles missing, and how many of those checked are gone
+    pub damaged: Vec<DamagedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DamagedFile {
+    pub name: String,
+    pub missing: usize,
+}
+
+/// Bytes of one kind of file, checked and found missing
+#[derive(Debug, Default)]
+struct Tally {
+    total: u64,
+    checked: u64,
+    missing: u64,
+}
+
+impl Tally {
+    /// What the sample found missing, scaled up to all of the files
+    fn estimated_missing(&self) -> u64 {
+        if self.checked == 0 {
+            return 0;
+        }
+
+        (self.missing as f64 / self.checked as f64 * self.total as f64).round() as u64
+    }
+}
+
+/// The content files then the PAR2 volumes of a release
+struct Articles<'a> {
+    /// Each file, and whether it's a PAR2 volume
+    files: Vec<(&'a File, bool)>,
+    /// Every segment of the files, with the index into `files` of its file
+    segments: Vec<(usize, &'a Segment)>,
+}
+
+impl<'a> Articles<'a> {
+    fn of(nzb: &'a Nzb) -> Self {
+        let files: Vec<_> = nzb
+            .content_files()
+            .map(|file| (file, false))
+            .chain(nzb.recovery_files().map(|file| (file, true)))
+            .collect();
+        let segments = files
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (file, _))| {
+                file.segments.iter().map(move |segment| (index, segment))
+            })
+            .collect();
+
+        Self { files, segments }
+    }
+}
+
+/// Asks the server about every segment of the content files and PAR2
+/// volumes, or an evenly spread `sample` of them, without downloading any
+pub async fn check(
+    client: &NntpClient,
+    nzb: &Nzb,
+    sample: Option<usize>,
+    concurrency: usize,
+) -> Result<AvailabilityReport, NntpError> {
+    let Articles { files, segments } = Articles::of(nzb);
+
+    let checked = sample_evenly(&segments, sample);
+    let checked_segments: Vec<_> = checked.iter().map(|(_, segment)| *segment).collect();
+    let exists = stat(client, &checked_segments, concurrency).await?;
+
+    Ok(report(&files, segments.len(), &checked, &exists))
+}
+
+/// Asks about the segments in batches of the server's pipeline depth, spread
+/// over `concurrency` connections
+///
+/// # Returns
+/// - Whether the server has each segment, in order
+pub async fn stat(
+    client: &NntpClient,
+    segments: &[&Segment],
+    concurrency: usize,
+) -> Result<Vec<bool>, NntpError> {
+    // owned indices, futures holding borrowed segment batches aren't `Send`
+    let indices: Vec<_> = (0..segments.len()).collect();
+    let batches = indices
+        .chunks(client.pipeline_depth())
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+    let batches = stream::iter(batches)
+        .map(|batch| async move {
+            let batch_segments: Vec<_> = batch.iter().map(|&i| segments[i]).collect();
+            client.stat_all(&batch_segments).await
+        })
+        .buffered(concurrency)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(batches.into_iter().flatten().collect())
+}
+
+/// Every `len / sample`th item, or all of them without a sample
+fn sample_evenly<T: Copy>(items: &[T], sample: Option<usize>) -> Vec<T> {
+    match sample {
+        Some(sample) if (1..items.len()).contains(&sample) => (0..sample)
+            .map(|i| items[i * items.len() / sample])
+            .collect(),
+        _ => items.to_vec(),
+    }
+}
+
+/// # Arguments
+/// - `files`: each file, and whether it's a PAR2 volume
+/// - `checked`: the index into `files` and the segment of each article asked
+///   about
+/// - `exists`: whether each checked article is still there
+fn report(
+    files: &[(&File, bool)],
+    articles: usize,
+    checked: &[(usize, &Segment)],
+    exists: &[bool],
+) -> AvailabilityReport {
+    let (mut content, mut recovery) = (Tally::default(), Tally::default());
+    for (file, is_recovery) in files {
+        let tally = if *is_recovery {
+            &mut recovery
+        } else {
+            &mut content
+        };
+        tally.total += file
+            .segments
+            .iter()
+            .map(|segment| segment.size as u64)
+            .sum::<u64>();
+    }
+
+    let mut missing = vec![0; files.len()];
+    for (&(index, segment), &exists) in checked.iter().zip(exists) {
+        let tally = if files[index].1 {
+            &mut recovery
+        } else {
+            &mut content
+        };
+        tally.checked += segment.size as u64;
+        if !exists {
+            tally.missing += segment.size as u64;
+            missing[index] += 1;
+        }
+    }
+
+    let available = exists.iter().filter(|&&exists| exists).count();
+    let available_percent = match checked.len() {
+        0 => 100.0,
+        checked => available as f64 * 100.0 / checked as f64,
+    };
+    let missing_bytes = content.estimated_missing();
+    let recovery_bytes = recovery.total - recovery.estimated_missing();
+
+    AvailabilityReport {
+        articles,
+        checked: checked.len(),
+        available,
+        available_percent,
+        missing_bytes,
+        recovery_bytes,
+        repairable: missing_bytes <= recovery_bytes,
+        damaged: files
+            .iter()
+            .zip(missing)
+            .filter(|(_, missing)| *missing > 0)
+            .map(|((file, _), missing)| DamagedFile {
+                name: extract_filename(&file.subject)
+                    .unwrap_or(&file.subject)
+                    .to_string(),
+                missing,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nzb;
+
+    fn release() -> Nzb {
+        let files: String = [("movie.mkv", 4), ("movie.vol0+1.par2", 1)]
+            .iter()
+            .map(|(name, segments)| {
+                let segments: String = (0..*segments)
+                    .map(|i| {
+                        format!(
+                            r#"<segment bytes="1000" number="{}">{name}.{i}@x</segment>"#,
+                            i + 1
+                        )
+                    })
+                    .collect();
+                format!(
+                    r#"<file poster="p" date="1700000000" subject="&quot;{name}&quot; yEnc (1/1)">
+                        <groups><group>alt.binaries.test</group></groups>
+                        <segments>{segments}</segments>
+                    </file>"#
+                )
+            })
+            .collect();
+
+        nzb::parse(&format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <nzb xmlns="http://www.newzbin.com/DTD/2003/nzb">{files}</nzb>"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sample_evenly() {
+        let items: Vec<_> = (0..10).collect();
+
+        assert_eq!(sample_evenly(&items, Some(5)), [0, 2, 4, 6, 8]);
+        assert_eq!(sample_evenly(&items, Some(20)), items);
+        assert_eq!(sample_evenly(&items, None), items);
+    }
+
+    #[test]
+    fn test_report_against_recovery() {
+        let nzb = release();
+        let Articles { files, segments } = Articles::of(&nzb);
+
+        let partial = report(
+            &files,
+            segments.len(),
+            &segments,
+            &[true, false, true, true, true],
+        );
+        assert_eq!(partial.available_percent, 80.0);
+        assert_eq!(partial.missing_bytes, 1000);
+        assert_eq!(partial.recovery_bytes, 1000);
+        assert!(partial.repairable);
+        assert_eq!(
+            partial.damaged,
+            [DamagedFile {
+                name: "movie.mkv".to_string(),
+                missing: 1
+            }]
+        );
+
+        // the recovery volume going too leaves nothing to repair with
+        let unrecoverable = report(
+            &files,
+            segments.len(),
+            &segments,
+            &[true, false, true, true, false],
+        );
+        assert!(!unrecoverable.repairable);
+    }
+
+    #[test]
+    fn test_report_scales_sample() {
+        let nzb = release();
+        let Articles { files, segments } = Articles::of(&nzb);
+        let sampled = sample_evenly(&segments, Some(2));
+
+        // one of the two content articles asked about is gone, so half the file
+        let sampled = report(&files, segments.len(), &sampled, &[false, true]);
+        assert_eq!(sampled.checked, 2);
+        assert_eq!(sampled.missing_bytes, 2000);
+        assert!(!sampled.repairable);
+    }
+}
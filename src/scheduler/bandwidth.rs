@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+
+/// How much a bucket holds, in seconds of its rate. Lets a download that's
+/// been waiting go at full speed briefly without breaking the average.
+const BURST_SECS: f64 = 1.0;
+
+/// Longest a download sleeps before checking the limit again, so a change
+/// through the API applies to downloads already waiting
+const MAX_WAIT: Duration = Duration::from_millis(250);
+
+/// A bandwidth limit and the times of day it's different
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthSettings {
+    /// Bytes per second, unlimited when absent or 0
+    pub limit: Option<u64>,
+    /// Windows with a limit of their own, e.g. unthrottled at night. The
+    /// first that covers the time wins.
+    #[serde(default)]
+    pub schedule: Vec<ScheduledLimit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledLimit {
+    /// Local time, e.g. `23:00`
+    pub from: NaiveTime,
+    /// Before `from` for windows that run past midnight
+    pub to: NaiveTime,
+    /// Bytes per second, unlimited when absent or 0
+    pub limit: Option<u64>,
+}
+
+impl ScheduledLimit {
+    fn covers(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+impl BandwidthSettings {
+    /// The limit at `time`, `None` if there isn't one
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.schedule
+            .iter()
+            .find(|window| window.covers(time))
+            .map_or(self.limit, |window| window.limit)
+            .filter(|&limit| limit > 0)
+    }
+}
+
+/// Holds downloads to a limit that can be changed while they're running.
+/// One covers everything the client downloads, and each session can have
+/// its own on top.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    settings: RwLock<BandwidthSettings>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn settings(&self) -> BandwidthSettings {
+        self.settings.read().clone()
+    }
+
+    pub fn set(&self, settings: BandwidthSettings) {
+        *self.settings.write() = settings;
+    }
+
+    /// The limit right now, `None` if there isn't one
+    pub fn current_limit(&self) -> Option<u64> {
+        self.settings.read().limit_at(Local::now().time())
+    }
+
+    /// Waits until `bytes` more can be downloaded without going over the
+    /// limit
+    pub async fn acquire(&self, bytes: u64) {
+        while let Some(rate) = self.current_limit() {
+            let wait = match self.bucket.lock().take(bytes, rate, Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            };
+            time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+/// Tokens are bytes, refilled at whatever rate the limit is when they're
+/// taken
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    /// Takes `bytes` if there are enough tokens. More than the bucket holds
+    /// goes once it's full, leaving it in debt that later takes wait out.
+    ///
+    /// # Returns
+    /// - How long until there will be enough, if there aren't now
+    fn take(&mut self, bytes: u64, rate: u64, now: Instant) -> Result<(), Duration> {
+        let rate = rate as f64;
+        let capacity = rate * BURST_SECS;
+
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.refilled = now;
+
+        let needed = (bytes as f64).min(capacity);
+        if self.tokens >= needed {
+            self.tokens -= bytes as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - self.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let settings: BandwidthSettings = serde_json::from_str(
+            r#"{"limit": 1000, "schedule": [{"from": "23:00", "to": "07:00", "limit": null}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.limit_at(time("12:00")), Some(1000));
+        assert_eq!(settings.limit_at(time("23:30")), None);
+        assert_eq!(settings.limit_at(time("06:59")), None);
+        assert_eq!(settings.limit_at(time("07:00")), Some(1000));
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            refilled: start,
+        };
+
+        // half a second at 1000 B/s buys 500 bytes
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            bucket.take(1000, 1000, later),
+            Err(Duration::from_millis(500))
+        );
+        assert_eq!(bucket.take(500, 1000, later), Ok(()));
+
+        // more than the bucket holds goes once it's full, then is paid back
+        let full = later + Duration::from_secs(5);
+        assert_eq!(bucket.take(3000, 1000, full), Ok(()));
+        assert_eq!(bucket.take(1, 1000, full), Err(Duration::from_millis(2001)));
+    }
+}
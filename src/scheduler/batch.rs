@@ -1,6 +1,7 @@
 use crate::archive::par2::DownloadTask;
 use crate::stream::orchestrator::BufferHealth;
 use derive_more::Constructor;
+use itertools::Itertools;
 use std::sync::Arc;
 use tokio::sync::watch;
 use tracing::debug;
@@ -25,6 +26,7 @@ impl From<BufferHealth> for Priority {
 #[derive(Debug, Clone, Constructor)]
 pub struct Job {
     pub task: Arc<DownloadTask>,
+    /// Where this volume's stream data starts in the session file
     pub offset: u64,
 }
 
@@ -39,20 +41,26 @@ pub struct BatchGenerator {
     jobs: Vec<Job>,
     consumed: Vec<bool>,
     health_rx: watch::Receiver<BufferHealth>,
+    /// Where the player is reading, in bytes into the session file
+    position_rx: watch::Receiver<u64>,
 }
 
 impl BatchGenerator {
-    pub fn new(tasks: Vec<DownloadTask>, health_rx: watch::Receiver<BufferHealth>) -> Self {
-        let mut offset = tasks.first().unwrap().bytes().len();
+    pub fn new(
+        tasks: Vec<DownloadTask>,
+        health_rx: watch::Receiver<BufferHealth>,
+        position_rx: watch::Receiver<u64>,
+    ) -> Self {
+        // each volume's stream data follows on directly from the previous one's
+        let mut offset = 0;
         let mut jobs = Vec::new();
-        for task in tasks.into_iter().skip(1) {
-            // skip first
-            let length = task.bytes().len();
-            println!("Writing {} bytes at offset {}", task.bytes().len(), offset);
+        for task in tasks {
+            let length = *task.length();
+            debug!("Volume {:?} starts at offset {}", task.path(), offset);
 
             let job = Job {
                 task: task.into(),
-                offset: offset as u64,
+                offset,
             };
             jobs.push(job);
             offset += length;
@@ -64,15 +72,24 @@ impl BatchGenerator {
             jobs,
             consumed,
             health_rx,
+            position_rx,
         }
     }
 
-    /// Get indices of available (not consumed) jobs
+    /// Get indices of available (not consumed) jobs. Volumes from the
+    /// playback position onwards come first, so a seek doesn't wait behind
+    /// the volumes before it.
     fn available_indices(&self) -> Vec<usize> {
+        let position = *self.position_rx.borrow();
+
         self.consumed
             .iter()
             .enumerate()
             .filter_map(|(i, &consumed)| (!consumed).then_some(i))
+            .sorted_by_key(|&i| {
+                let job = &self.jobs[i];
+                job.offset + job.task.length() <= position
+            })
             .collect()
     }
 }
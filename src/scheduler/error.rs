@@ -14,12 +14,24 @@ pub enum SchedulerError {
     #[error("IO error")]
     Io(#[from] std::io::Error),
 
+    #[error("Error in background write")]
+    Join(#[from] tokio::task::JoinError),
+
     #[error("Error in channel communication on write")]
     Write(#[from] mpsc::error::SendError<(usize, Bytes)>),
 
     #[error("File '{0}' contained no subjects, source NZB may be malformed")]
     EmptyFile(String),
 
+    #[error("Segment of '{0}' has no yEnc part offset, can't place it in the file")]
+    MissingPartOffset(String),
+
+    #[error("{length} bytes at offset {start} run past the end of the {size} byte file")]
+    OutOfRange { start: u64, length: u64, size: u64 },
+
+    #[error("Archive header of {0} bytes is too big to read")]
+    HeaderTooLarge(u64),
+
     #[error("Tried to write file to path '{0}', but does not exist")]
     FileNotFound(PathBuf),
 
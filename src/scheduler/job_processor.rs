@@ -2,7 +2,6 @@
 use bytes::Bytes;
 use futures::{StreamExt, stream};
 use memmap2::MmapMut;
-use ordered_stream::OrderedStreamExt;
 use parking_lot::RwLock;
 use std::sync::Arc;
 use tracing::{debug, error};
@@ -10,14 +9,17 @@ use tracing::{debug, error};
 use crate::nntp::client::NntpClient;
 use crate::scheduler::batch::Job;
 use crate::scheduler::error::SchedulerError;
+use crate::scheduler::share::Tenant;
 
 pub async fn process_job(
     job: Job,
     client: Arc<NntpClient>,
     mmap: Arc<RwLock<MmapMut>>,
+    tenant: Arc<Tenant>,
     segment_parallelism: usize,
 ) -> Result<(), SchedulerError> {
-    let segments = job.task.nzb().segments.iter().cloned();
+    // the first segment was written when the session was created
+    let segments = job.task.nzb().segments.get(1..).unwrap_or_default();
 
     debug!(
         "Processing job at offset {} with {} segments",
@@ -25,24 +27,48 @@ pub async fn process_job(
         segments.len()
     );
 
-    let mut downloads = stream::iter(segments)
-        .map(|segment| {
+    // each batch is pipelined down a connection of its own, taken in the
+    // session's turn against every other session. The batches are owned so
+    // the job's future stays `Send` for any lifetime.
+    let batches = segments
+        .chunks(client.pipeline_depth())
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+    let mut downloads = stream::iter(batches)
+        .map(|batch| {
             let client = client.clone();
-            async move { client.download(&segment).await }
+            let tenant = tenant.clone();
+            async move { stream::iter(client.download_articles(&batch, Some(&tenant)).await) }
         })
-        .buffered(segment_parallelism);
+        .buffered(segment_parallelism)
+        .flatten();
+
+    let data_start = *job.task.offset();
+    let data_end = data_start + *job.task.length();
+    let mut file_pos = job.task.bytes().len() as u64;
 
-    let mut write_offset = job.offset;
     while let Some(download) = downloads.next().await {
         match download {
-            Ok(data) => {
+            Ok(article) => {
+                // prefer the yEnc part offset so a failed segment doesn't shift
+                // everything after it
+                let segment_start = article.part_offset.unwrap_or(file_pos);
+                file_pos = segment_start + article.data.len() as u64;
+
+                // only the part of the volume that holds stream data is written
+                let start = segment_start.max(data_start);
+                let end = file_pos.min(data_end);
+                if start >= end {
+                    continue;
+                }
+
+                let data = article
+                    .data
+                    .slice((start - segment_start) as usize..(end - segment_start) as usize);
+                let write_offset = job.offset + (start - data_start);
                 write_to_mmap(&mmap, write_offset, &data);
-                write_offset += data.len() as u64;
 
-                debug!(
-                    "Wrote segment at offset {}",
-                    write_offset - data.len() as u64
-                );
+                debug!("Wrote segment at offset {}", write_offset);
             }
             Err(e) => error!("Encountered error during download: {}", e),
         }
@@ -51,7 +77,7 @@ pub async fn process_job(
     Ok(())
 }
 
-fn write_to_mmap(mmap: &Arc<RwLock<MmapMut>>, offset: u64, data: &Bytes) {
+pub(crate) fn write_to_mmap(mmap: &Arc<RwLock<MmapMut>>, offset: u64, data: &Bytes) {
     let mut guard = mmap.write();
     let start = offset as usize;
     let end = (start + data.len()).min(guard.len());
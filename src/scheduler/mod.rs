@@ -1,4 +1,7 @@
 pub mod adaptive;
+pub mod availability;
+pub mod bandwidth;
 pub mod batch;
 pub mod error;
 pub mod job_processor;
+pub mod share;
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use itertools::Itertools;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::scheduler::bandwidth::RateLimiter;
+
+/// How urgently a session needs connections, most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Demand {
+    /// Being played with the buffer running out
+    Starving,
+    /// Being played
+    Streaming,
+    /// Nobody's watching, e.g. prefetching the rest or a download session
+    Background,
+}
+
+/// Something downloading over the shared connections, usually a session
+pub struct Tenant {
+    id: u64,
+    demand: Box<dyn Fn() -> Demand + Send + Sync>,
+    /// What `demand` last said, so waiters are ranked under the share's lock
+    /// without reading their sessions
+    cached_demand: AtomicU8,
+    /// Its own limit, on top of the global one
+    bandwidth: Option<Arc<RateLimiter>>,
+}
+
+impl Tenant {
+    /// `demand` is asked again each time a connection frees up while the
+    /// tenant waits, so it should be cheap
+    pub fn new(
+        demand: impl Fn() -> Demand + Send + Sync + 'static,
+        bandwidth: Option<Arc<RateLimiter>>,
+    ) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            cached_demand: AtomicU8::new(demand() as u8),
+            demand: Box::new(demand),
+            bandwidth,
+        }
+    }
+
+    pub fn demand(&self) -> Demand {
+        (self.demand)()
+    }
+
+    fn refresh_demand(&self) {
+        self.cached_demand
+            .store(self.demand() as u8, Ordering::Relaxed);
+    }
+
+    fn cached_demand(&self) -> Demand {
+        match self.cached_demand.load(Ordering::Relaxed) {
+            0 => Demand::Starving,
+            1 => Demand::Streaming,
+            _ => Demand::Background,
+        }
+    }
+
+    pub fn bandwidth(&self) -> Option<&RateLimiter> {
+        self.bandwidth.as_deref()
+    }
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tenant")
+            .field("id", &self.id)
+            .field("demand", &self.demand())
+            .finish()
+    }
+}
+
+/// Hands out a server's connections across every session. A free connection
+/// goes to the most urgent demand, and between tenants with the same demand
+/// to the one holding the fewest, so a big background download can't starve
+/// someone else's playback.
+#[derive(Debug)]
+pub struct ConnectionShare {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    free: usize,
+    /// Connections each tenant holds
+    held: HashMap<u64, usize>,
+    /// In arrival order. Only non-empty while there's nothing free.
+    waiting: Vec<Waiter>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    ticket: u64,
+    tenant: Arc<Tenant>,
+    granted: oneshot::Sender<()>,
+}
+
+impl ConnectionShare {
+    pub fn new(connections: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                free: connections,
+                held: HashMap::new(),
+                waiting: Vec::new(),
+                next_ticket: 0,
+            }),
+        }
+    }
+
+    /// Requests waiting on a connection
+    pub fn waiting(&self) -> usize {
+        self.state.lock().waiting.len()
+    }
+
+    /// Waits for the tenant's turn at a connection, held until the permit is
+    /// dropped
+    pub async fn acquire(&self, tenant: &Arc<Tenant>) -> Permit<'_> {
+        tenant.refresh_demand();
+        let (ticket, granted) = {
+            let mut state = self.state.lock();
+            if state.free > 0 {
+                state.grant(tenant.id);
+                return Permit {
+                    share: self,
+                    tenant: tenant.id,
+                };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiting.push(Waiter {
+                ticket,
+                tenant: Arc::clone(tenant),
+                granted: tx,
+            });
+            (ticket, rx)
+        };
+
+        let mut pending = Pending {
+            share: self,
+            tenant: tenant.id,
+            ticket,
+            granted,
+            done: false,
+        };
+        (&mut pending.granted)
+            .await
+            .expect("waiters are only dropped once granted");
+        pending.done = true;
+
+        Permit {
+            share: self,
+            tenant: tenant.id,
+        }
+    }
+
+    fn release(&self, tenant: u64) {
+        // a waiter's demand changes as it waits, e.g. its buffer running low,
+        // so it's asked again, outside the lock since that reads the session
+        let waiting: Vec<_> = self
+            .state
+            .lock()
+            .waiting
+            .iter()
+            .map(|waiter| Arc::clone(&waiter.tenant))
+            .unique_by(|tenant| tenant.id)
+            .collect();
+        for waiter in &waiting {
+            waiter.refresh_demand();
+        }
+
+        let mut state = self.state.lock();
+        if let Some(held) = state.held.get_mut(&tenant) {
+            *held -= 1;
+            if *held == 0 {
+                state.held.remove(&tenant);
+            }
+        }
+        state.free += 1;
+        state.dispatch();
+    }
+}
+
+impl State {
+    fn grant(&mut self, tenant: u64) {
+        self.free -= 1;
+        *self.held.entry(tenant).or_default() += 1;
+    }
+
+    /// Gives free connections to the waiters that should go next
+    fn dispatch(&mut self) {
+        while self.free > 0 && !self.waiting.is_empty() {
+            let (next, _) = self
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, waiter)| {
+                    let held = self.held.get(&waiter.tenant.id).copied();
+                    (
+                        waiter.tenant.cached_demand(),
+                        held.unwrap_or_default(),
+                        waiter.ticket,
+                    )
+                })
+                .expect("there's a waiter");
+
+            let waiter = self.waiting.remove(next);
+            if waiter.granted.send(()).is_ok() {
+                self.grant(waiter.tenant.id);
+            }
+        }
+    }
+}
+
+/// A turn at a connection
+#[derive(Debug)]
+pub struct Permit<'a> {
+    share: &'a ConnectionShare,
+    tenant: u64,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.share.release(self.tenant);
+    }
+}
+
+/// Leaves the queue if the wait is given up, handing the connection back if
+/// it was granted in the meantime
+struct Pending<'a> {
+    share: &'a ConnectionShare,
+    tenant: u64,
+    ticket: u64,
+    granted: oneshot::Receiver<()>,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut state = self.share.state.lock();
+        if let Some(index) = state
+            .waiting
+            .iter()
+            .position(|waiter| waiter.ticket == self.ticket)
+        {
+            state.waiting.remove(index);
+            return;
+        }
+        drop(state);
+
+        if self.granted.try_recv().is_ok() {
+            self.share.release(self.tenant);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn tenant(demand: Demand) -> Arc<Tenant> {
+        Arc::new(Tenant::new(move || demand, None))
+    }
+
+    /// Queues an acquire for each tenant behind a full share, then frees the
+    /// connection and records who got it in turn
+    async fn order(
+        share: Arc<ConnectionShare>,
+        tenants: &[(&'static str, Arc<Tenant>)],
+    ) -> Vec<&'static str> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let blocker = tenant(Demand::Starving);
+        let held = share.acquire(&blocker).await;
+
+        let mut handles = Vec::new();
+        for (name, tenant) in tenants {
+            handles.push(tokio::spawn({
+                let (share, tenant, order) =
+                    (Arc::clone(&share), Arc::clone(tenant), Arc::clone(&order));
+                let name = *name;
+                async move {
+                    let _permit = share.acquire(&tenant).await;
+                    order.lock().push(name);
+                }
+            }));
+            while share.waiting() < handles.len() {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        drop(held);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        Arc::try_unwrap(order).unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_most_urgent_first() {
+        let share = Arc::new(ConnectionShare::new(1));
+        let order = order(
+            share,
+            &[
+                ("background", tenant(Demand::Background)),
+                ("streaming", tenant(Demand::Streaming)),
+                ("starving", tenant(Demand::Starving)),
+            ],
+        )
+        .await;
+
+        assert_eq!(order, ["starving", "streaming", "background"]);
+    }
+
+    #[tokio::test]
+    async fn test_fair_between_equals() {
+        let share = Arc::new(ConnectionShare::new(2));
+        let (greedy, other) = (tenant(Demand::Background), tenant(Demand::Background));
+
+        // the greedy tenant already holds the other connection
+        let _held = share.acquire(&greedy).await;
+        let order = order(
+            Arc::clone(&share),
+            &[("greedy", greedy.clone()), ("other", other)],
+        )
+        .await;
+
+        assert_eq!(order, ["other", "greedy"]);
+    }
+
+    #[tokio::test]
+    async fn test_demand_changes_while_waiting() {
+        let share = Arc::new(ConnectionShare::new(1));
+        let held = share.acquire(&tenant(Demand::Starving)).await;
+
+        let starving = Arc::new(AtomicBool::new(false));
+        let late = Arc::new(Tenant::new(
+            {
+                let starving = Arc::clone(&starving);
+                move || {
+                    if starving.load(Ordering::SeqCst) {
+                        Demand::Starving
+                    } else {
+                        Demand::Background
+                    }
+                }
+            },
+            None,
+        ));
+        let first = Arc::new(Mutex::new(None));
+        for (name, tenant) in [("streaming", tenant(Demand::Streaming)), ("late", late)] {
+            tokio::spawn({
+                let (share, first) = (Arc::clone(&share), Arc::clone(&first));
+                async move {
+                    let _permit = share.acquire(&tenant).await;
+                    first.lock().get_or_insert(name);
+                }
+            });
+        }
+        while share.waiting() < 2 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // the player started struggling after it queued
+        starving.store(true, Ordering::SeqCst);
+        drop(held);
+        while first.lock().is_none() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(*first.lock(), Some("late"));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_wait_leaves_queue() {
+        let share = ConnectionShare::new(1);
+        let background = tenant(Demand::Background);
+        let held = share.acquire(&background).await;
+
+        let wait = time::timeout(Duration::from_millis(10), share.acquire(&background)).await;
+        assert!(wait.is_err());
+        assert_eq!(share.waiting(), 0);
+
+        drop(held);
+        let _permit = share.acquire(&background).await;
+    }
+}
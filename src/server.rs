@@ -0,0 +1,223 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::{
+    Router,
+    extract::State,
+    response::{IntoResponse, Json},
+    routing::{get, post},
+};
+use http::header;
+use serde_json::json;
+use tokio::{net::TcpListener, sync::RwLock, task};
+use tower_http::cors::CorsLayer;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    metrics::{self, METRICS},
+    newznab::client::NewznabClient,
+    sabnzbd::{self, SabnzbdConfig},
+    scheduler::{
+        adaptive::AdaptiveScheduler,
+        bandwidth::{BandwidthSettings, RateLimiter},
+    },
+    session::{self, Session},
+    settings::Settings,
+    stream,
+    stremio::{self, StremioAddon},
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub sessions: Arc<RwLock<HashMap<Uuid, Arc<Session>>>>,
+    pub scheduler: Arc<AdaptiveScheduler>,
+    pub stremio: Arc<StremioAddon>,
+    pub http: reqwest::Client,
+    pub nzb_dir: Option<PathBuf>,
+    pub complete_dir: PathBuf,
+    pub settings: Arc<Settings>,
+    /// Only set when there's a `sab.api_key`
+    pub sab: Option<Arc<SabnzbdConfig>>,
+    /// Only set when there's an indexer in the settings
+    pub newznab: Option<NewznabClient>,
+    pub mock_mode: bool,
+}
+
+/// Every endpoint, sharing `state`
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics_text))
+        .route("/upload", post(session::handlers::upload))
+        .route(
+            "/sessions",
+            get(session::handlers::list_sessions).post(session::handlers::session_from_source),
+        )
+        .route(
+            "/sessions/from-search",
+            post(session::handlers::session_from_search),
+        )
+        .route("/check", post(session::handlers::check_release))
+        .route("/bandwidth", get(get_bandwidth).put(set_bandwidth))
+        .route(
+            "/sessions/{session_id}/bandwidth",
+            get(session::handlers::get_session_bandwidth)
+                .put(session::handlers::set_session_bandwidth),
+        )
+        .route("/stream/{session_id}", get(stream::handlers::stream))
+        .route(
+            "/chunked/{session_id}",
+            get(stream::handlers::stream_chunked),
+        )
+        .route("/local/stream/{session_id}", get(stream::handlers::stream))
+        .route(
+            "/local/chunked/{session_id}",
+            get(stream::handlers::stream_chunked),
+        )
+        .route(
+            "/hls/{session_id}/index.m3u8",
+            get(stream::handlers::hls_playlist),
+        )
+        .route(
+            "/hls/{session_id}/{segment}",
+            get(stream::handlers::hls_segment),
+        )
+        .route(
+            "/sessions/{session_id}/events",
+            get(session::handlers::session_events),
+        )
+        .route(
+            "/sessions/{session_id}/subtitles",
+            get(stream::handlers::list_subtitles),
+        )
+        .route(
+            "/sessions/{session_id}/subtitles/{track}",
+            get(stream::handlers::subtitle_vtt),
+        )
+        .route(
+            "/sabnzbd/api",
+            get(sabnzbd::handlers::api).post(sabnzbd::handlers::addfile),
+        )
+        .route("/manifest.json", get(stremio::handlers::manifest))
+        .route("/catalog/{kind}/{id}", get(stremio::handlers::catalog))
+        .route("/meta/{kind}/{id}", get(stremio::handlers::meta))
+        .route("/stream/{kind}/{id}", get(stremio::handlers::streams))
+        .route("/stremio/play/{candidate_id}", get(stremio::handlers::play))
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+pub async fn serve(state: AppState, bind_addr: &str) {
+    let app = router(state);
+
+    let listener = TcpListener::bind(bind_addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind to {bind_addr}: {e}"));
+
+    info!("NZB streaming server started on {}", bind_addr);
+    info!("");
+    info!("Usage:");
+    info!(
+        "   curl -X POST -F 'nzb=@/tmp/unencrypted.nzb' http://{}/upload",
+        bind_addr
+    );
+    info!("   mpv http://{}/stream/{{session_id}}", bind_addr);
+    info!("   Stremio addon: http://{}/manifest.json", bind_addr);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap_or_else(|e| panic!("server error: {e}"));
+}
+
+pub async fn health() -> impl IntoResponse {
+    Json(json!({
+        "status": "healthy",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// Prometheus scrape endpoint
+pub async fn metrics_text(State(state): State<AppState>) -> impl IntoResponse {
+    state.scheduler.record_metrics();
+
+    let sessions: Vec<_> = state.sessions.read().await.values().cloned().collect();
+    METRICS.buffer_health.reset();
+    for session in &sessions {
+        METRICS
+            .buffer_health
+            .with_label_values(&[session.id.to_string().as_str()])
+            .set(metrics::health_value(session.orchestrator.health()));
+    }
+    let active_streams: usize = sessions
+        .iter()
+        .map(|session| session.orchestrator.active_streams())
+        .sum();
+    METRICS.active_streams.set(active_streams as i64);
+
+    let cache_dir = state.settings.cache.dir.clone();
+    match task::spawn_blocking(move || metrics::disk_usage(&cache_dir)).await {
+        Ok(Ok(bytes)) => METRICS.cache_bytes.set(bytes as i64),
+        Ok(Err(e)) => warn!("Could not measure the cache: {e}"),
+        Err(e) => warn!("Could not measure the cache: {e}"),
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.encode(),
+    )
+}
+
+/// The limit and schedule, along with the limit in force right now
+pub fn bandwidth_json(limiter: &RateLimiter) -> Json<serde_json::Value> {
+    Json(json!({
+        "settings": limiter.settings(),
+        "current_limit": limiter.current_limit(),
+    }))
+}
+
+pub async fn get_bandwidth(State(state): State<AppState>) -> impl IntoResponse {
+    bandwidth_json(state.scheduler.bandwidth())
+}
+
+/// Replaces the global limit, downloads already running pick it up
+pub async fn set_bandwidth(
+    State(state): State<AppState>,
+    Json(settings): Json<BandwidthSettings>,
+) -> impl IntoResponse {
+    info!("Global bandwidth limit set to {settings:?}");
+    state.scheduler.bandwidth().set(settings);
+
+    bandwidth_json(state.scheduler.bandwidth())
+}
+
+async fn shutdown_signal() {
+    use tokio::signal;
+
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {
+            info!("Received Ctrl+C signal");
+        },
+        _ = terminate => {
+            info!("Received terminate signal");
+        },
+    }
+}
@@ -0,0 +1,272 @@
+use std::{path::Path, sync::Arc};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    archive::par2::DownloadTask,
+    download::{self, error::DownloadError},
+    error::RestError,
+    media::{self, container::Container},
+    nzb::{self, Nzb},
+    scheduler::{adaptive::AdaptiveScheduler, availability::AvailabilityReport},
+    server::AppState,
+    session::{
+        Session, SessionFailure, SessionMode, SessionOutput,
+        events::SessionEvent,
+        prefetch::{prefetch_cues, prefetch_moov},
+        resolve::resolve_tasks,
+    },
+    stream::orchestrator::{BufferHealth, StreamOrchestrator},
+};
+
+/// Works out what to stream from an NZB, downloads enough to start and
+/// registers the session, leaving the index prefetch and the rest of the
+/// download running in the background
+pub async fn create_session(
+    state: &AppState,
+    content: &str,
+    upload_name: Option<&str>,
+    mode: SessionMode,
+) -> Result<Arc<Session>, RestError> {
+    let nzb = nzb::parse(content)?;
+    let meta = nzb.meta.clone();
+    let par2: Vec<_> = nzb.par2_candidates().into_iter().cloned().collect();
+
+    let session_id = Uuid::new_v4();
+    let session_dir = state.settings.cache.dir.join(session_id.to_string());
+    tokio::fs::create_dir_all(&session_dir).await?;
+
+    let Resolved {
+        tasks,
+        filename,
+        availability,
+    } = match resolve(state, nzb, &session_dir).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            if let Err(e) = tokio::fs::remove_dir_all(&session_dir).await {
+                warn!("Could not remove {}: {e}", session_dir.display());
+            }
+            return Err(e);
+        }
+    };
+
+    let (health_tx, health_rx) = watch::channel(BufferHealth::Critical);
+    let (position_tx, position_rx) = watch::channel(0);
+
+    let file_size = tasks.iter().map(|task| task.length()).sum();
+    let first_data = tasks
+        .first()
+        .map(DownloadTask::first_segment_data)
+        .unwrap_or_default();
+    let media = media::probe(&first_data, file_size);
+    info!(
+        "Streaming {:?} ({}), {} tracks",
+        media.container,
+        media.mime_type,
+        media.tracks.len()
+    );
+
+    let container = media.container;
+    let duration_secs = media.duration_secs;
+
+    // the real name, unless the archive or post didn't give one
+    let filename = filename
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name())
+        .map(|name| name.to_string_lossy().into_owned());
+    let stream_name = filename
+        .clone()
+        .unwrap_or_else(|| format!("stream.{}", media.container.extension()));
+
+    let orchestrator = StreamOrchestrator::new(
+        tasks.clone(),
+        session_dir.join(stream_name),
+        health_tx,
+        position_tx,
+        state.settings.health,
+    );
+    let session = Arc::new(Session::new(
+        session_id,
+        meta,
+        media,
+        upload_name,
+        filename,
+        mode,
+        orchestrator.clone(),
+    ));
+    if let Some(report) = availability {
+        let _ = session.availability.set(report);
+    }
+    state
+        .sessions
+        .write()
+        .await
+        .insert(session_id, Arc::clone(&session));
+
+    // players read the container's index before playing or seeking, fetch
+    // it ahead of everything else. HLS segments are planned from it too.
+    tokio::spawn({
+        let scheduler = Arc::clone(&state.scheduler);
+        let session = Arc::clone(&session);
+        let tasks = tasks.clone();
+        async move {
+            let orchestrator = &session.orchestrator;
+            let result = match container {
+                Container::Matroska | Container::WebM => {
+                    prefetch_cues(&scheduler, orchestrator, &tasks, &first_data, duration_secs)
+                        .await
+                }
+                Container::Mp4 | Container::QuickTime => {
+                    prefetch_moov(&scheduler, orchestrator, &tasks, &first_data).await
+                }
+                _ => Ok(None),
+            };
+
+            let plan = result.unwrap_or_else(|e| {
+                let message = format!("Could not prefetch {container:?} index: {e}");
+                warn!("{message}, playback may stall");
+                session.emit(SessionEvent::Error { message });
+                None
+            });
+            let _ = session.hls.set(plan);
+        }
+    });
+
+    let download = tokio::spawn({
+        let scheduler = Arc::clone(&state.scheduler);
+        let mmap = Arc::clone(&orchestrator.mmap);
+        let session = Arc::clone(&session);
+        let complete_dir = state.complete_dir.clone();
+        let finished = session.finish_on_drop();
+        async move {
+            let _finished = finished;
+            let output = match mode {
+                SessionMode::Stream => {
+                    info!(
+                        "Starting background download of remaining segments for {} RAR files",
+                        tasks.len()
+                    );
+
+                    let tenant = session.tenant();
+                    match scheduler
+                        .schedule_downloads(tasks, mmap, health_rx, position_rx, tenant)
+                        .await
+                    {
+                        // a stream is only watched, only downloads are saved
+                        Ok(()) => {
+                            info!("Background download complete");
+                            Ok(None)
+                        }
+                        Err(e) => Err(DownloadError::from(e)),
+                    }
+                }
+                SessionMode::Download => {
+                    info!("Downloading {} volumes in full", tasks.len());
+                    let volume_dir = session_dir.join("volumes");
+
+                    download_output(
+                        &scheduler,
+                        &session,
+                        tasks,
+                        &par2,
+                        &volume_dir,
+                        &complete_dir,
+                    )
+                    .await
+                    .map(Some)
+                }
+            };
+
+            match output {
+                Ok(None) => {}
+                Ok(Some(output)) => {
+                    info!("Saved {} to {}", session.title, output.path.display());
+                    let _ = session.output.set(output);
+                }
+                Err(e) => {
+                    warn!("Could not save {}: {e}", session.title);
+                    let _ = session.failure.set(SessionFailure {
+                        message: e.to_string(),
+                        failed_at: chrono::Utc::now(),
+                    });
+                }
+            }
+        }
+    });
+    let _ = session.download.set(download.abort_handle());
+
+    Ok(session)
+}
+
+/// What a session is created from
+struct Resolved {
+    tasks: Vec<DownloadTask>,
+    /// The real name, if the archive or post gives one
+    filename: Option<String>,
+    /// `None` if the check couldn't be made
+    availability: Option<AvailabilityReport>,
+}
+
+/// Checks the release is still there and works out what to download
+async fn resolve(state: &AppState, nzb: Nzb, session_dir: &Path) -> Result<Resolved, RestError> {
+    // a release that's been taken down stalls mid-playback, so find out now
+    let availability = state
+        .scheduler
+        .check_availability(&nzb, Some(state.settings.scheduler.availability_sample))
+        .await
+        .inspect_err(|e| warn!("Could not check availability, going ahead: {e}"))
+        .ok();
+    if let Some(report) = &availability {
+        info!(
+            "{:.1}% of sampled articles available, {} missing bytes against {} recovery bytes",
+            report.available_percent, report.missing_bytes, report.recovery_bytes
+        );
+        if !report.repairable {
+            return Err(RestError::Unavailable(report.available_percent));
+        }
+    }
+
+    let (tasks, filename) = resolve_tasks(nzb, None, &state.scheduler, session_dir).await?;
+    Ok(Resolved {
+        tasks,
+        filename,
+        availability,
+    })
+}
+
+/// Downloads the whole of every volume, verifies and repairs them against
+/// PAR2 and extracts the file, then moves it into a folder of its own under
+/// `complete_dir`
+async fn download_output(
+    scheduler: &AdaptiveScheduler,
+    session: &Arc<Session>,
+    tasks: Vec<DownloadTask>,
+    par2: &[nzb_rs::File],
+    volume_dir: &Path,
+    complete_dir: &Path,
+) -> Result<SessionOutput, DownloadError> {
+    let par2: Vec<_> = par2.iter().collect();
+    let mmap = Arc::clone(&session.orchestrator.mmap);
+    let tenant = session.tenant();
+    download::download_in_full(scheduler, tasks, &par2, volume_dir, mmap, &tenant).await?;
+
+    let dir = download::output_dir(complete_dir, &session.title).await?;
+    download::move_into(session.orchestrator.path(), &dir.join(output_name(session))).await?;
+    tokio::fs::remove_dir_all(volume_dir).await?;
+
+    Ok(SessionOutput {
+        path: dir,
+        completed_at: chrono::Utc::now(),
+    })
+}
+
+/// The file's real name, or the title if the archive or post didn't give one
+fn output_name(session: &Session) -> String {
+    let name = session
+        .filename
+        .clone()
+        .unwrap_or_else(|| format!("{}.{}", session.title, session.media.container.extension()));
+    download::path_component(&name)
+}
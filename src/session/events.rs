@@ -0,0 +1,293 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::session::Session;
+use crate::stream::orchestrator::{BufferHealth, Volume};
+
+/// How often the session's state is checked for changes
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A change in a session, sent to `GET /sessions/{id}/events` as a
+/// Server-Sent Event named by [`SessionEvent::name`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SessionEvent {
+    Progress {
+        /// Bytes playable from the start without gaps
+        available_bytes: u64,
+        downloaded_bytes: u64,
+        total_bytes: u64,
+    },
+    VolumeComplete {
+        index: usize,
+        name: String,
+    },
+    Health {
+        health: BufferHealth,
+        previous: Option<BufferHealth>,
+    },
+    /// The container's index is in or the buffer is healthy, so playback
+    /// can start without stalling
+    Ready {
+        available_bytes: u64,
+    },
+    /// Something went wrong. If the background download failed, it's the
+    /// last event.
+    Error {
+        message: String,
+    },
+    /// Every segment is in and the file has been saved
+    Complete {
+        path: PathBuf,
+    },
+}
+
+impl SessionEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Progress { .. } => "progress",
+            Self::VolumeComplete { .. } => "volume_complete",
+            Self::Health { .. } => "health",
+            Self::Ready { .. } => "ready",
+            Self::Error { .. } => "error",
+            Self::Complete { .. } => "complete",
+        }
+    }
+}
+
+/// What the events are worked out from
+#[derive(Debug, Clone, PartialEq)]
+struct Snapshot {
+    available_bytes: u64,
+    downloaded_bytes: u64,
+    total_bytes: u64,
+    volumes: Vec<bool>,
+    health: BufferHealth,
+    /// The HLS plan is in or the health reached good. Once ready, a session
+    /// stays ready.
+    ready: bool,
+    output: Option<PathBuf>,
+    failure: Option<String>,
+}
+
+impl Snapshot {
+    fn of(session: &Session) -> Self {
+        let orchestrator = &session.orchestrator;
+        let health = orchestrator.health();
+
+        Self {
+            available_bytes: orchestrator.get_available_bytes(),
+            downloaded_bytes: orchestrator.downloaded_bytes(),
+            total_bytes: orchestrator.total_size(),
+            volumes: orchestrator.completed_volumes(),
+            health,
+            ready: matches!(session.hls.get(), Some(Some(_)))
+                || matches!(health, BufferHealth::Good | BufferHealth::Excellent),
+            output: session.output.get().map(|output| output.path.clone()),
+            failure: session.failure.get().map(|failure| failure.message.clone()),
+        }
+    }
+
+    /// Whether the session is over, so there'll be no more events
+    fn is_final(&self) -> bool {
+        self.output.is_some() || self.failure.is_some()
+    }
+}
+
+/// The events for going from `previous` to `current`, or everything so far
+/// for a client that's just connected
+fn changes(
+    volumes: &[Volume],
+    previous: Option<&Snapshot>,
+    current: &Snapshot,
+) -> Vec<SessionEvent> {
+    let mut events = Vec::new();
+
+    let progressed = previous.is_none_or(|previous| {
+        (previous.available_bytes, previous.downloaded_bytes)
+            != (current.available_bytes, current.downloaded_bytes)
+    });
+    if progressed {
+        events.push(SessionEvent::Progress {
+            available_bytes: current.available_bytes,
+            downloaded_bytes: current.downloaded_bytes,
+            total_bytes: current.total_bytes,
+        });
+    }
+
+    for (index, &complete) in current.volumes.iter().enumerate() {
+        let was_complete = previous.is_some_and(|previous| previous.volumes[index]);
+        if complete && !was_complete {
+            events.push(SessionEvent::VolumeComplete {
+                index,
+                name: volumes[index].name.clone(),
+            });
+        }
+    }
+
+    let previous_health = previous.map(|previous| previous.health);
+    if previous_health != Some(current.health) {
+        events.push(SessionEvent::Health {
+            health: current.health,
+            previous: previous_health,
+        });
+    }
+
+    let was_ready = previous.is_some_and(|previous| previous.ready);
+    if current.ready && !was_ready {
+        events.push(SessionEvent::Ready {
+            available_bytes: current.available_bytes,
+        });
+    }
+
+    if let Some(path) = &current.output
+        && previous.is_none_or(|previous| previous.output.is_none())
+    {
+        events.push(SessionEvent::Complete { path: path.clone() });
+    }
+
+    if let Some(message) = &current.failure
+        && previous.is_none_or(|previous| previous.failure.is_none())
+    {
+        events.push(SessionEvent::Error {
+            message: message.clone(),
+        });
+    }
+
+    events
+}
+
+impl Session {
+    /// Tells anyone following the session's events, e.g. that the index
+    /// couldn't be prefetched
+    pub fn emit(&self, event: SessionEvent) {
+        // nobody following isn't a problem
+        let _ = self.events.send(event);
+    }
+
+    /// The session's events as they happen, starting with its state so far.
+    /// Ends once the file has been saved or the download has failed.
+    pub fn events(self: &Arc<Self>) -> impl Stream<Item = SessionEvent> + use<> {
+        let session = Arc::clone(self);
+        let mut emitted = self.events.subscribe();
+
+        async_stream::stream! {
+            let mut previous = None;
+            let mut snapshots = time::interval(SNAPSHOT_INTERVAL);
+
+            loop {
+                let events = tokio::select! {
+                    _ = snapshots.tick() => {
+                        let mut current = Snapshot::of(&session);
+                        current.ready |= previous.as_ref().is_some_and(|previous: &Snapshot| previous.ready);
+                        let volumes = session.orchestrator.volumes();
+                        let events = changes(volumes, previous.as_ref(), &current);
+                        previous = Some(current);
+                        events
+                    }
+                    event = emitted.recv() => match event {
+                        Ok(event) => vec![event],
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+
+                for event in events {
+                    yield event;
+                }
+                if previous.as_ref().is_some_and(Snapshot::is_final) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(available_bytes: u64, volumes: Vec<bool>, health: BufferHealth) -> Snapshot {
+        Snapshot {
+            available_bytes,
+            downloaded_bytes: available_bytes,
+            total_bytes: 200,
+            volumes,
+            health,
+            ready: false,
+            output: None,
+            failure: None,
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let volumes = [
+            Volume {
+                name: "movie.part1.rar".to_string(),
+                range: 0..100,
+            },
+            Volume {
+                name: "movie.part2.rar".to_string(),
+                range: 100..200,
+            },
+        ];
+
+        let first = snapshot(0, vec![false, false], BufferHealth::Critical);
+        assert_eq!(
+            changes(&volumes, None, &first),
+            [
+                SessionEvent::Progress {
+                    available_bytes: 0,
+                    downloaded_bytes: 0,
+                    total_bytes: 200
+                },
+                SessionEvent::Health {
+                    health: BufferHealth::Critical,
+                    previous: None
+                },
+            ]
+        );
+        assert!(changes(&volumes, Some(&first), &first).is_empty());
+
+        let second = Snapshot {
+            ready: true,
+            ..snapshot(100, vec![true, false], BufferHealth::Excellent)
+        };
+        assert_eq!(
+            changes(&volumes, Some(&first), &second)[1..],
+            [
+                SessionEvent::VolumeComplete {
+                    index: 0,
+                    name: "movie.part1.rar".to_string()
+                },
+                SessionEvent::Health {
+                    health: BufferHealth::Excellent,
+                    previous: Some(BufferHealth::Critical)
+                },
+                SessionEvent::Ready {
+                    available_bytes: 100
+                },
+            ]
+        );
+        assert!(changes(&volumes, Some(&second), &second).is_empty());
+
+        let failed = Snapshot {
+            failure: Some("Volumes still damaged".to_string()),
+            ..second.clone()
+        };
+        assert!(failed.is_final());
+        assert_eq!(
+            changes(&volumes, Some(&second), &failed),
+            [SessionEvent::Error {
+                message: "Volumes still damaged".to_string()
+            }]
+        );
+    }
+}
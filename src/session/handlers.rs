@@ -0,0 +1,232 @@
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::StatusCode,
+    response::{
+        IntoResponse, Json,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures::StreamExt;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    error::RestError,
+    newznab::{SearchQuery, error::NewznabError},
+    nzb::{self, source::NzbSource},
+    scheduler::{availability::AvailabilityReport, bandwidth::BandwidthSettings},
+    server::{AppState, bandwidth_json},
+    session::{Session, SessionMode, create::create_session},
+};
+
+#[derive(Deserialize)]
+pub struct SearchSessionRequest {
+    #[serde(flatten)]
+    query: SearchQuery,
+    /// GUID of the result to stream, the first one otherwise
+    guid: Option<String>,
+    #[serde(default)]
+    mode: SessionMode,
+}
+
+#[derive(Deserialize)]
+pub struct SourceSessionRequest {
+    #[serde(flatten)]
+    source: NzbSource,
+    #[serde(default)]
+    mode: SessionMode,
+}
+
+#[derive(Deserialize)]
+pub struct CheckRequest {
+    #[serde(flatten)]
+    source: NzbSource,
+    /// How many articles to ask about, every one of them without
+    sample: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    mode: SessionMode,
+}
+
+pub async fn list_sessions(State(state): State<AppState>) -> impl IntoResponse {
+    let sessions = state.sessions.read().await;
+    let summaries: Vec<_> = sessions
+        .values()
+        .map(|session| session.summary())
+        .sorted_by_key(|summary| summary.created_at)
+        .collect();
+
+    Json(json!({ "sessions": summaries }))
+}
+
+// TODO: handle duplicates
+pub async fn upload(
+    State(state): State<AppState>,
+    Query(params): Query<UploadParams>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, RestError> {
+    info!("NZB request received");
+
+    let (content, upload_name) = {
+        let mut body = None;
+
+        while let Some(field) = multipart.next_field().await? {
+            if field.name() == Some("nzb") {
+                let upload_name = field.file_name().map(str::to_string);
+                let bytes = field.bytes().await?;
+                body = Some((nzb::source::decode(&bytes)?, upload_name));
+                break;
+            }
+        }
+
+        body.ok_or_else(|| RestError::MissingNzb)?
+    };
+    let session = create_session(&state, &content, upload_name.as_deref(), params.mode).await?;
+
+    Ok(created(
+        &state,
+        &session,
+        "NZB uploaded successfully. Background processing initiated.",
+    ))
+}
+
+/// Starts a session from an NZB URL, a path under `--nzb-dir` or the NZB
+/// itself, for callers that don't have a file to upload
+pub async fn session_from_source(
+    State(state): State<AppState>,
+    Json(request): Json<SourceSessionRequest>,
+) -> Result<impl IntoResponse, RestError> {
+    let nzb = request
+        .source
+        .load(&state.http, state.nzb_dir.as_deref())
+        .await?;
+    let session = create_session(&state, &nzb.content, nzb.name.as_deref(), request.mode).await?;
+
+    Ok(created(
+        &state,
+        &session,
+        "NZB loaded successfully. Background processing initiated.",
+    ))
+}
+
+/// Searches the indexer and streams a result, the one with the given GUID or
+/// the first
+pub async fn session_from_search(
+    State(state): State<AppState>,
+    Json(request): Json<SearchSessionRequest>,
+) -> Result<impl IntoResponse, RestError> {
+    let newznab = state
+        .newznab
+        .as_ref()
+        .ok_or(RestError::IndexerNotConfigured)?;
+
+    let results = newznab.search(&request.query).await?;
+    let result = match &request.guid {
+        Some(guid) => results.iter().find(|result| &result.guid == guid),
+        None => results.first(),
+    }
+    .ok_or(NewznabError::NoResults)?;
+
+    info!("Streaming search result {}", result.title);
+    let content = newznab.fetch_nzb(result).await?;
+    let session = create_session(&state, &content, Some(&result.title), request.mode).await?;
+
+    let (status, Json(mut body)) = created(
+        &state,
+        &session,
+        "NZB fetched from indexer. Background processing initiated.",
+    );
+    body["result"] = json!(result);
+
+    Ok((status, Json(body)))
+}
+
+/// What the session-creating endpoints answer with
+fn created(state: &AppState, session: &Session, message: &str) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "session_id": session.id,
+            "title": session.title,
+            "session_mode": session.mode,
+            "availability": session.availability.get(),
+            "message": message,
+            "mode": if state.mock_mode { "mock" } else { "live" }
+        })),
+    )
+}
+
+/// Checks how much of a release is still on the server without creating a
+/// session, so a client can pick another before committing to it
+pub async fn check_release(
+    State(state): State<AppState>,
+    Json(request): Json<CheckRequest>,
+) -> Result<Json<AvailabilityReport>, RestError> {
+    let loaded = request
+        .source
+        .load(&state.http, state.nzb_dir.as_deref())
+        .await?;
+    let nzb = nzb::parse(&loaded.content)?;
+
+    Ok(Json(
+        state
+            .scheduler
+            .check_availability(&nzb, request.sample)
+            .await?,
+    ))
+}
+
+/// Progress, volume completion, buffer health, errors and the ready-to-play
+/// signal as Server-Sent Events, so clients needn't poll
+pub async fn session_events(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    let session = state
+        .sessions
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or(RestError::SessionNotFound)?;
+
+    let events = session
+        .events()
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn get_session_bandwidth(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    Ok(bandwidth_json(&session.bandwidth))
+}
+
+/// Replaces the session's own limit, the global one still applies
+pub async fn set_session_bandwidth(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+    Json(settings): Json<BandwidthSettings>,
+) -> Result<impl IntoResponse, RestError> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    info!("Bandwidth limit of {} set to {settings:?}", session.title);
+    session.bandwidth.set(settings);
+
+    Ok(bandwidth_json(&session.bandwidth))
+}
@@ -0,0 +1,224 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, watch},
+    task::AbortHandle,
+};
+use uuid::Uuid;
+
+pub mod create;
+pub mod events;
+pub mod handlers;
+pub mod prefetch;
+pub mod resolve;
+
+use crate::{
+    media::{MediaInfo, hls::HlsPlan},
+    nzb::NzbMeta,
+    scheduler::{
+        availability::AvailabilityReport,
+        bandwidth::{BandwidthSettings, RateLimiter},
+        share::{Demand, Tenant},
+    },
+    session::events::SessionEvent,
+    stream::orchestrator::{BufferHealth, StreamOrchestrator},
+};
+
+/// Events held for followers that fall behind
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub title: String,
+    /// The real name of the file being streamed, where the archive or post
+    /// gives one
+    pub filename: Option<String>,
+    pub mode: SessionMode,
+    pub meta: NzbMeta,
+    pub media: MediaInfo,
+    pub orchestrator: Arc<StreamOrchestrator>,
+    /// Set once the container's index has been fetched, to `None` if the
+    /// video can't be segmented
+    pub hls: OnceLock<Option<HlsPlan>>,
+    /// What a sample of `STAT`s found, unset if the server couldn't be asked
+    pub availability: OnceLock<AvailabilityReport>,
+    /// Limits this session's background download on top of the global
+    /// limit, unlimited until set through the API
+    pub bandwidth: Arc<RateLimiter>,
+    /// The background download, so it can be stopped when the session is
+    /// deleted
+    pub download: OnceLock<AbortHandle>,
+    /// Set once every segment is in and the file has been saved
+    pub output: OnceLock<SessionOutput>,
+    /// Set if the background download failed
+    pub failure: OnceLock<SessionFailure>,
+    /// Flips once the background download is over, however it ended
+    finished: watch::Sender<bool>,
+    /// Events that can't be read off the session's state, like errors
+    events: broadcast::Sender<SessionEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Held by the background download, see [`Session::finish_on_drop`]
+pub struct FinishGuard(watch::Sender<bool>);
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Whether a session downloads for playback or for the file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionMode {
+    /// Segments are fetched around the playback position
+    #[default]
+    Stream,
+    /// Whole volumes are fetched at full parallelism, verified and repaired
+    /// against PAR2, and the extracted file moved to the completed directory
+    Download,
+}
+
+/// Where a finished download was saved
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionOutput {
+    pub path: PathBuf,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// Why a download failed, for the SABnzbd history
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionFailure {
+    pub message: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// What we expose about a session over the API. Never includes the password.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub session_id: Uuid,
+    pub title: String,
+    pub filename: Option<String>,
+    pub mode: SessionMode,
+    pub category: Option<String>,
+    pub tags: Vec<String>,
+    pub groups: Vec<String>,
+    pub password_protected: bool,
+    pub media: MediaInfo,
+    pub available_bytes: u64,
+    /// Seconds of playback buffered ahead of the player, once the cues are in
+    pub buffered_secs: Option<f64>,
+    pub availability: Option<AvailabilityReport>,
+    pub bandwidth: BandwidthSettings,
+    /// How urgently its background download gets connections
+    pub demand: Demand,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Session {
+    /// Prefers the NZB's own title, then the uploaded filename, and falls back
+    /// to the session ID so there's always something to show.
+    pub fn new(
+        id: Uuid,
+        meta: NzbMeta,
+        media: MediaInfo,
+        upload_name: Option<&str>,
+        filename: Option<String>,
+        mode: SessionMode,
+        orchestrator: Arc<StreamOrchestrator>,
+    ) -> Self {
+        let title = meta
+            .title
+            .clone()
+            .or_else(|| upload_name.map(|name| name.trim_end_matches(".nzb").to_string()))
+            .unwrap_or_else(|| id.to_string());
+
+        Self {
+            id,
+            title,
+            filename,
+            mode,
+            meta,
+            media,
+            orchestrator,
+            hls: OnceLock::new(),
+            availability: OnceLock::new(),
+            bandwidth: Arc::default(),
+            download: OnceLock::new(),
+            output: OnceLock::new(),
+            failure: OnceLock::new(),
+            finished: watch::channel(false).0,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Marks the background download finished once dropped, so waiters are
+    /// let go even if it panics or is aborted
+    pub fn finish_on_drop(&self) -> FinishGuard {
+        FinishGuard(self.finished.clone())
+    }
+
+    /// Waits for the background download to finish, saved or not
+    pub async fn finished(&self) {
+        // the session holds the sender, so this can't be closed
+        let _ = self
+            .finished
+            .subscribe()
+            .wait_for(|finished| *finished)
+            .await;
+    }
+
+    /// How urgently the background download needs connections. Download
+    /// sessions always wait behind playback.
+    pub fn demand(&self) -> Demand {
+        match self.mode {
+            SessionMode::Download => Demand::Background,
+            SessionMode::Stream if !self.orchestrator.is_playing() => Demand::Background,
+            SessionMode::Stream => match self.orchestrator.health() {
+                BufferHealth::Critical | BufferHealth::Poor => Demand::Starving,
+                BufferHealth::Good | BufferHealth::Excellent => Demand::Streaming,
+            },
+        }
+    }
+
+    /// The background download's claim on the shared connections, under the
+    /// session's bandwidth limit
+    pub fn tenant(self: &Arc<Self>) -> Arc<Tenant> {
+        let session = Arc::downgrade(self);
+        let demand = move || {
+            session
+                .upgrade()
+                .map_or(Demand::Background, |session| session.demand())
+        };
+
+        Arc::new(Tenant::new(demand, Some(Arc::clone(&self.bandwidth))))
+    }
+
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            session_id: self.id,
+            title: self.title.clone(),
+            filename: self.filename.clone(),
+            mode: self.mode,
+            category: self.meta.category.clone(),
+            tags: self.meta.tags.clone(),
+            groups: self.meta.groups.clone(),
+            password_protected: self.meta.password.is_some(),
+            media: self.media.clone(),
+            available_bytes: self.orchestrator.get_available_bytes(),
+            buffered_secs: self.orchestrator.buffered_secs(),
+            availability: self.availability.get().cloned(),
+            bandwidth: self.bandwidth.settings(),
+            demand: self.demand(),
+            created_at: self.created_at,
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use tracing::info;
+
+use crate::{
+    archive::par2::DownloadTask,
+    error::RestError,
+    media::{ebml, error::MediaError, hls::HlsPlan, mp4},
+    scheduler::adaptive::AdaptiveScheduler,
+    stream::orchestrator::StreamOrchestrator,
+};
+
+const MOOV_SEARCH_LIMIT: usize = 8; // top-level MP4 boxes to skip looking for moov
+const MAX_INDEX_SIZE: u64 = 64 * 1024 * 1024; // Cues or moov, fetched in one go
+
+/// Players read the MKV Cues before seeking, and most muxers write them at the
+/// end of the file, so fetch them straight away rather than last
+///
+/// # Returns
+/// - The HLS segments planned from the Cues, if the tracks can be remuxed
+pub async fn prefetch_cues(
+    scheduler: &AdaptiveScheduler,
+    orchestrator: &StreamOrchestrator,
+    tasks: &[DownloadTask],
+    first_data: &[u8],
+    duration_secs: Option<f64>,
+) -> Result<Option<HlsPlan>, RestError> {
+    let layout = ebml::segment_layout(first_data)?;
+    let Some(cues_offset) = layout.cues_offset else {
+        info!("MKV SeekHead doesn't list the Cues, not prefetching");
+        return Ok(None);
+    };
+
+    let remaining = orchestrator.total_size().saturating_sub(cues_offset);
+    if remaining == 0 {
+        return Err(MediaError::Malformed {
+            container: "Matroska",
+            reason: "Cues past the end of the file",
+        }
+        .into());
+    }
+
+    let header = scheduler
+        .prefetch(
+            tasks,
+            &orchestrator.mmap,
+            cues_offset,
+            ebml::MAX_HEADER_SIZE.min(remaining),
+        )
+        .await?;
+    let cues_len = ebml::element_len(&header).ok_or(MediaError::Malformed {
+        container: "Matroska",
+        reason: "Cues element has unknown size",
+    })?;
+
+    // the size is the file's word, and it's all read into memory
+    if cues_len > MAX_INDEX_SIZE {
+        return Err(MediaError::Malformed {
+            container: "Matroska",
+            reason: "Cues element is implausibly large",
+        }
+        .into());
+    }
+
+    let cues_len = cues_len.min(remaining);
+    let ready = orchestrator.add_priority_range(cues_offset..cues_offset + cues_len);
+    let cues = scheduler
+        .prefetch(tasks, &orchestrator.mmap, cues_offset, cues_len)
+        .await?;
+    ready.send_replace(true);
+    let index = ebml::parse_cues(&cues, &layout)?;
+    info!(
+        "Prefetched {} cue points at offset {}",
+        index.len(),
+        cues_offset
+    );
+
+    // Cues written after the last Cluster mark where the media data ends
+    let clusters_end = match index.points().first() {
+        Some(first) if first.offset < cues_offset => cues_offset,
+        _ => orchestrator.total_size(),
+    };
+    let plan = HlsPlan::from_matroska(first_data, layout, &index, clusters_end, duration_secs)
+        .inspect_err(|e| info!("Not offering HLS: {e}"))
+        .ok();
+
+    orchestrator.set_cues(index);
+    Ok(plan)
+}
+
+/// Players can't start an MP4 without its `moov` box, which files not
+/// prepared for streaming put after the media data, so fetch it first
+///
+/// # Returns
+/// - The HLS segments planned from the sample tables, if the tracks can be
+///   remuxed
+pub async fn prefetch_moov(
+    scheduler: &AdaptiveScheduler,
+    orchestrator: &StreamOrchestrator,
+    tasks: &[DownloadTask],
+    first_data: &[u8],
+) -> Result<Option<HlsPlan>, RestError> {
+    let total_size = orchestrator.total_size();
+    let (offset, size) = match mp4::moov_search_start(first_data) {
+        Some(search_start) => find_moov(scheduler, orchestrator, tasks, search_start).await?,
+        None => mp4::boxes(first_data)
+            .find(|mp4_box| &mp4_box.kind == b"moov")
+            .map(|moov| (moov.offset as u64, moov.size))
+            .ok_or(MediaError::IncompleteData)?,
+    };
+
+    // the size is the file's word, and it's all read into memory
+    if size > MAX_INDEX_SIZE {
+        return Err(MediaError::Malformed {
+            container: "MP4",
+            reason: "moov box is implausibly large",
+        }
+        .into());
+    }
+    let length = size.min(total_size.saturating_sub(offset));
+
+    // a faststart moov is usually all in the first segment already
+    let fetched;
+    let moov = match first_data.get(offset as usize..(offset + length) as usize) {
+        Some(moov) => moov,
+        None => {
+            let ready = orchestrator.add_priority_range(offset..offset + length);
+            fetched = scheduler
+                .prefetch(tasks, &orchestrator.mmap, offset, length)
+                .await?;
+            ready.send_replace(true);
+            info!("Prefetched {length} byte moov at offset {offset}");
+            &fetched[..]
+        }
+    };
+
+    let moov = mp4::boxes(moov).next().ok_or(MediaError::IncompleteData)?;
+    Ok(HlsPlan::from_mp4(moov.data)
+        .inspect_err(|e| info!("Not offering HLS: {e}"))
+        .ok())
+}
+
+/// Walks the top-level boxes from `offset` to find `moov`, usually right
+/// after mdat, but there can be a free box or similar first
+///
+/// # Returns
+/// - The offset and size of `moov`
+async fn find_moov(
+    scheduler: &AdaptiveScheduler,
+    orchestrator: &StreamOrchestrator,
+    tasks: &[DownloadTask],
+    mut offset: u64,
+) -> Result<(u64, u64), RestError> {
+    let total_size = orchestrator.total_size();
+
+    for _ in 0..MOOV_SEARCH_LIMIT {
+        if offset >= total_size {
+            break;
+        }
+
+        let header = scheduler
+            .prefetch(
+                tasks,
+                &orchestrator.mmap,
+                offset,
+                mp4::MAX_BOX_HEADER_SIZE.min(total_size - offset),
+            )
+            .await?;
+        let Some(mp4_box) = mp4::boxes(&header).next() else {
+            break;
+        };
+
+        if &mp4_box.kind == b"moov" {
+            return Ok((offset, mp4_box.size));
+        }
+        // a corrupt size just ends the search at the end of the file
+        offset = offset.saturating_add(mp4_box.size);
+    }
+
+    Err(MediaError::Malformed {
+        container: "MP4",
+        reason: "moov not found after mdat",
+    }
+    .into())
+}
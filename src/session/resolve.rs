@@ -0,0 +1,440 @@
+use std::{path::Path, sync::Arc};
+
+use bytes::Bytes;
+use itertools::Itertools;
+use tokio::task;
+use tracing::{info, warn};
+
+use crate::{
+    archive::{
+        self, direct,
+        error::ArchiveError,
+        kind::{ContentKind, volume_index},
+        packet::{self, PACKET_HEADER_SIZE, Packet},
+        par2::{self, DownloadTask, Par2Manifest, create_download_tasks},
+        sevenz::{self, Header},
+        zip::{self, EndRecord},
+    },
+    error::RestError,
+    nntp::yenc::extract_filename,
+    nzb::Nzb,
+    scheduler::{
+        adaptive::{AdaptiveScheduler, FirstSegment},
+        error::SchedulerError,
+    },
+};
+
+/// Works out which files make up the stream and where in them its data is,
+/// downloading the first segment of each into `session_dir`
+///
+/// # Arguments
+/// - `manifest`: the PAR2 set, if it's already been read. Obfuscated files
+///   are named from it, and it's read here otherwise.
+///
+/// # Returns
+/// - The download tasks, and the real name of the file they make up
+pub async fn resolve_tasks(
+    nzb: Nzb,
+    manifest: Option<Par2Manifest>,
+    scheduler: &Arc<AdaptiveScheduler>,
+    session_dir: &Path,
+) -> Result<(Vec<DownloadTask>, Option<String>), RestError> {
+    if !nzb.obfuscated.is_empty() {
+        info!("NZB contains obfuscated files, decoding");
+        obfuscated(nzb, manifest, scheduler, session_dir).await
+    } else if nzb.rar.is_empty() && !nzb.sevenzip.is_empty() {
+        info!("NZB contains 7z volumes, reading archive header");
+        sevenzip(nzb.sevenzip, scheduler, session_dir).await
+    } else if nzb.rar.is_empty() && !nzb.zip.is_empty() {
+        info!("NZB contains ZIP volumes, reading central directory");
+        zip_archive(nzb.zip, scheduler, session_dir).await
+    } else {
+        info!("NZB contains plain files, serving");
+        let files = nzb
+            .plain_stream_files()
+            .ok_or(RestError::NoPlayableContent)?;
+        plain(files, nzb.meta.password.as_deref(), scheduler, session_dir).await
+    }
+}
+
+/// # Returns
+/// - The download tasks, and the real name of the file they make up
+async fn obfuscated(
+    nzb: Nzb,
+    manifest: Option<Par2Manifest>,
+    scheduler: &Arc<AdaptiveScheduler>,
+    session_dir: &Path,
+) -> Result<(Vec<DownloadTask>, Option<String>), RestError> {
+    let first_segments = download_first_segments(scheduler, nzb.obfuscated.clone()).await;
+
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => resolve_par2_manifest(&nzb, scheduler).await?,
+    };
+
+    info!("Waiting for first segment downloads to complete");
+    let first_segments = first_segments.await??;
+    info!("Downloaded {} first segments", first_segments.len());
+
+    let tasks = create_download_tasks(
+        manifest.hash_to_filename(),
+        &first_segments,
+        session_dir,
+        nzb.meta.password.as_deref(),
+    )
+    .await?;
+    info!("Created {} download tasks", tasks.len());
+
+    let downloaded_hashes: Vec<_> = first_segments
+        .iter()
+        .map(|segment| segment.hash16k.clone())
+        .collect();
+    let missing = manifest.find_missing_files(&downloaded_hashes);
+    if !missing.is_empty() {
+        warn!("Missing files: {:?}", missing);
+        // TODO: Attempt PAR2 recovery for missing files
+    }
+
+    let filename = par2::content_name(&tasks);
+    Ok((tasks, filename))
+}
+
+const MAX_PAR2_PACKET_SIZE: u64 = 16 * 1024 * 1024; // bigger than any packet describing a set
+
+/// Reads PAR2 files, smallest index file first, merging their FileDesc
+/// packets until every file ID declared by the Main packet is resolved.
+/// Volume files repeat those packets between their recovery slices, so only
+/// the segments holding them are downloaded.
+pub async fn resolve_par2_manifest(
+    nzb: &Nzb,
+    scheduler: &Arc<AdaptiveScheduler>,
+) -> Result<Par2Manifest, RestError> {
+    let mut manifest: Option<Par2Manifest> = None;
+
+    for file in nzb.par2_candidates() {
+        info!("Reading PAR2 file {}", file.subject);
+        let packets = read_par2_packets(scheduler, file).await?;
+
+        let parsed = match archive::parse_packets(packets) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Skipping unusable PAR2 file {}: {}", file.subject, e);
+                continue;
+            }
+        };
+
+        let merged = match manifest.take() {
+            Some(mut existing) => {
+                existing.merge(parsed);
+                existing
+            }
+            None => parsed,
+        };
+
+        if merged.is_complete() {
+            return Ok(merged);
+        }
+
+        info!(
+            "PAR2 manifest has {} unresolved file IDs, trying next PAR2 file",
+            merged.unresolved().len()
+        );
+        manifest = Some(merged);
+    }
+
+    manifest.ok_or(RestError::Par2(ArchiveError::NoFiles))
+}
+
+/// The packets of a PAR2 file other than its recovery slices, which are
+/// stepped over by the length in their headers without being downloaded
+async fn read_par2_packets(
+    scheduler: &AdaptiveScheduler,
+    file: &nzb_rs::File,
+) -> Result<Vec<Packet>, SchedulerError> {
+    let first = scheduler.download_first_segment(file.clone()).await?;
+    let Some(size) = first.file_size.filter(|_| !first.bytes.is_empty()) else {
+        // without its size there's no knowing where the file's segments end
+        let bytes = scheduler.download_file(file).await?;
+        return Ok(archive::scan_for_packets(&bytes));
+    };
+
+    let mut window = SegmentWindow {
+        scheduler,
+        file,
+        size,
+        start: 0,
+        bytes: first.bytes.clone(),
+        first: first.bytes,
+    };
+    let mut packets = Vec::new();
+    let mut offset = 0;
+
+    while offset + PACKET_HEADER_SIZE as u64 <= size {
+        let header = window
+            .read(offset, offset + PACKET_HEADER_SIZE as u64)
+            .await?;
+        let length = match packet::parse_header(&header) {
+            Some(header) if header.recovery => {
+                offset = offset.saturating_add(header.length);
+                continue;
+            }
+            Some(header)
+                if header.length <= MAX_PAR2_PACKET_SIZE && offset + header.length <= size =>
+            {
+                header.length
+            }
+            // look for the next packet a byte on, like `scan_for_packets`
+            _ => {
+                offset += 1;
+                continue;
+            }
+        };
+
+        let bytes = window.read(offset, offset + length).await?;
+        match packet::parse_packet(&bytes) {
+            Some((packet, _)) => {
+                packets.push(packet);
+                offset += length;
+            }
+            None => offset += 1,
+        }
+    }
+
+    Ok(packets)
+}
+
+/// The segments of a file read last, so packets next to each other don't
+/// download the same segment over and over
+struct SegmentWindow<'a> {
+    scheduler: &'a AdaptiveScheduler,
+    file: &'a nzb_rs::File,
+    first: Bytes,
+    size: u64,
+    start: u64,
+    bytes: Bytes,
+}
+
+impl SegmentWindow<'_> {
+    /// `from..to` of the file, downloading the segments covering it unless
+    /// they're the ones read last
+    async fn read(&mut self, from: u64, to: u64) -> Result<Bytes, SchedulerError> {
+        if from < self.start || to > self.start + self.bytes.len() as u64 {
+            let part_size = self.first.len() as u64;
+            let start = from / part_size * part_size;
+            let end = to
+                .div_ceil(part_size)
+                .saturating_mul(part_size)
+                .min(self.size);
+
+            self.bytes = self
+                .scheduler
+                .download_file_range(self.file, &self.first, start, end)
+                .await?;
+            self.start = start;
+        }
+
+        Ok(self
+            .bytes
+            .slice((from - self.start) as usize..(to - self.start) as usize))
+    }
+}
+
+/// # Returns
+/// - The download tasks, and the real name of the file they make up
+async fn plain(
+    files: Vec<nzb_rs::File>,
+    password: Option<&str>,
+    scheduler: &Arc<AdaptiveScheduler>,
+    session_dir: &Path,
+) -> Result<(Vec<DownloadTask>, Option<String>), RestError> {
+    let first_segments = download_first_segments(scheduler, files).await;
+    let segments = first_segments.await??;
+
+    let tasks = par2::create_download_tasks_plain(&segments, session_dir, password).await?;
+    info!("Created {} download tasks", tasks.len());
+
+    let filename = par2::content_name(&tasks);
+    Ok((tasks, filename))
+}
+
+/// # Returns
+/// - The download tasks, and the name of the entry they make up
+async fn sevenzip(
+    files: Vec<nzb_rs::File>,
+    scheduler: &Arc<AdaptiveScheduler>,
+    session_dir: &Path,
+) -> Result<(Vec<DownloadTask>, Option<String>), RestError> {
+    let segments = download_volume_segments(scheduler, files).await?;
+    let volumes = volume_sizes(&segments)?;
+
+    let first = segments.first().ok_or(RestError::NoPlayableContent)?;
+    let next_header = sevenz::parse_signature_header(&first.bytes)?;
+    let header = scheduler
+        .download_range(&volumes, next_header.offset, next_header.size)
+        .await?;
+
+    let entries = match sevenz::parse_header(&header)? {
+        Header::Plain(entries) => entries,
+        Header::Encoded(encoded) => {
+            info!("7z header is compressed, fetching packed header");
+            let packed = scheduler
+                .download_range(&volumes, encoded.offset, encoded.size)
+                .await?;
+
+            match sevenz::parse_header(&encoded.decode(&packed)?)? {
+                Header::Plain(entries) => entries,
+                Header::Encoded(_) => {
+                    return Err(ArchiveError::MalformedSevenZip("nested encoded header").into());
+                }
+            }
+        }
+    };
+
+    // prefer the biggest video, falling back to the biggest file of any kind
+    let entry = entries
+        .into_iter()
+        .max_by_key(|entry| (ContentKind::is_video(&entry.name), entry.size))
+        .ok_or(ArchiveError::NoFiles)?;
+    info!("Streaming '{}' from 7z archive", entry.name);
+
+    if !entry.stored {
+        return Err(ArchiveError::CompressedEntry(entry.name).into());
+    }
+
+    let tasks =
+        par2::create_download_tasks_spanning(&segments, entry.offset, entry.size, session_dir)?;
+    info!("Created {} download tasks", tasks.len());
+
+    Ok((tasks, Some(entry.name)))
+}
+
+/// # Returns
+/// - The download tasks, and the name of the entry they make up
+async fn zip_archive(
+    files: Vec<nzb_rs::File>,
+    scheduler: &Arc<AdaptiveScheduler>,
+    session_dir: &Path,
+) -> Result<(Vec<DownloadTask>, Option<String>), RestError> {
+    let segments = download_volume_segments(scheduler, files).await?;
+    let volumes = volume_sizes(&segments)?;
+    let sizes: Vec<_> = volumes.iter().map(|(_, size)| *size).collect();
+
+    // split ZIPs keep the central directory in the last volume, the `.zip`
+    let (last, last_size) = volumes.last().ok_or(RestError::NoPlayableContent)?;
+    let tail = scheduler
+        .download_tail(
+            last,
+            *last_size,
+            last_size.saturating_sub(zip::MAX_END_RECORD_SIZE),
+        )
+        .await?;
+
+    let directory = match zip::parse_end_record(&tail)? {
+        EndRecord::Plain(directory) => directory,
+        EndRecord::Zip64(location) => {
+            let start =
+                zip::absolute_range(location, zip::ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE, &sizes)?;
+            let record = scheduler
+                .download_range(&volumes, start, zip::ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE)
+                .await?;
+            zip::parse_zip64_end_record(&record)?
+        }
+    };
+    directory.ensure_bounded()?;
+
+    let start = zip::absolute_range(directory.start, directory.size, &sizes)?;
+    let central_directory = scheduler
+        .download_range(&volumes, start, directory.size)
+        .await?;
+    let entries = zip::parse_central_directory(&central_directory)?;
+    info!(
+        "Read {} of {} ZIP entries",
+        entries.len(),
+        directory.entries
+    );
+
+    // prefer the biggest video, falling back to the biggest file of any kind
+    let entry = entries
+        .into_iter()
+        .max_by_key(|entry| (ContentKind::is_video(&entry.name), entry.compressed_size))
+        .ok_or(ArchiveError::NoFiles)?;
+    info!("Streaming '{}' from ZIP archive", entry.name);
+    entry.ensure_stored()?;
+
+    // the local header usually sits at the start of a volume, so it's in the
+    // first segment we already have
+    let header_start = zip::absolute_offset(entry.header, &sizes)?;
+    let first_segment = &segments[entry.header.disk as usize].bytes;
+    let header_size = match first_segment
+        .get(entry.header.offset as usize..)
+        .map(zip::local_header_size)
+    {
+        Some(Ok(size)) => size,
+        Some(Err(ArchiveError::IncompleteData)) | None => {
+            let header = scheduler
+                .download_range(&volumes, header_start, zip::LOCAL_FILE_HEADER_SIZE as u64)
+                .await?;
+            zip::local_header_size(&header)?
+        }
+        Some(Err(e)) => return Err(e.into()),
+    };
+
+    let data_start = header_start
+        .checked_add(header_size)
+        .filter(|start| {
+            // summing can't overflow, `absolute_offset` already checked that
+            let archive_size = sizes.iter().sum::<u64>();
+            start
+                .checked_add(entry.compressed_size)
+                .is_some_and(|end| end <= archive_size)
+        })
+        .ok_or(ArchiveError::MalformedZip(
+            "entry data runs past the end of the archive",
+        ))?;
+
+    let tasks = par2::create_download_tasks_spanning(
+        &segments,
+        data_start,
+        entry.compressed_size,
+        session_dir,
+    )?;
+    info!("Created {} download tasks", tasks.len());
+
+    Ok((tasks, Some(entry.name)))
+}
+
+/// First segments of every volume of a multi-volume archive, in volume order
+async fn download_volume_segments(
+    scheduler: &Arc<AdaptiveScheduler>,
+    files: Vec<nzb_rs::File>,
+) -> Result<Vec<FirstSegment>, RestError> {
+    let first_segments = download_first_segments(scheduler, files).await;
+
+    Ok(first_segments
+        .await??
+        .into_iter()
+        .sorted_by_key(|segment| {
+            let filename = extract_filename(&segment.nzb.subject).unwrap_or_default();
+            volume_index(Path::new(filename))
+        })
+        .collect())
+}
+
+fn volume_sizes(segments: &[FirstSegment]) -> Result<Vec<(&FirstSegment, u64)>, ArchiveError> {
+    segments
+        .iter()
+        .map(|segment| Ok((segment, direct::analyse_direct(segment)?.1)))
+        .collect()
+}
+
+async fn download_first_segments(
+    scheduler: &Arc<AdaptiveScheduler>,
+    rars: Vec<nzb_rs::File>,
+) -> task::JoinHandle<Result<Vec<FirstSegment>, SchedulerError>> {
+    info!("Background downloading first RAR segments");
+
+    tokio::spawn({
+        let scheduler = Arc::clone(scheduler);
+        async move { scheduler.download_first_segments(&rars).await }
+    })
+}
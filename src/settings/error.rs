@@ -0,0 +1,19 @@
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::nntp::error::NntpError;
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Error reading settings")]
+    Load(#[from] config::ConfigError),
+
+    #[error("No [server] in the config file, and none from NNTP_* variables")]
+    NoServer(#[source] NntpError),
+
+    #[error(
+        "Invalid settings:\n{}",
+        .0.iter().map(|problem| format!("  - {problem}")).join("\n")
+    )]
+    Invalid(Vec<String>),
+}
@@ -0,0 +1,408 @@
+use std::path::{Path, PathBuf};
+
+use config::{Config, Environment, File, FileFormat};
+use serde::Deserialize;
+
+use crate::newznab::config::NewznabConfig;
+use crate::nntp::config::NntpConfig;
+use crate::scheduler::bandwidth::BandwidthSettings;
+use crate::settings::error::SettingsError;
+use crate::stream::orchestrator::HealthThresholds;
+
+pub mod error;
+
+/// Read from the working directory when no `--config` is given, if it's there
+pub const DEFAULT_FILE: &str = "nzb-streamer.toml";
+
+/// Everything that can be tuned, from a TOML file, then `NZB_STREAMER_*`
+/// variables (`NZB_STREAMER_HTTP__PORT` for `http.port`), then command line
+/// flags, each taking over from the last
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Where articles are downloaded from, and the only server: there's no
+    /// list of servers to fail over to or check availability against.
+    /// `NNTP_*` variables describe it when there's none in the file.
+    pub server: Option<NntpConfig>,
+    /// The only directory `POST /sessions` may read NZBs from by path,
+    /// reading from the server's disk is off without it
+    pub nzb_dir: Option<PathBuf>,
+    /// The indexer to search. `NEWZNAB_URL` and `NEWZNAB_APIKEY` describe it
+    /// when there's none in the file, search is off without either.
+    pub newznab: Option<NewznabConfig>,
+    pub scheduler: SchedulerSettings,
+    pub bandwidth: BandwidthSettings,
+    pub health: HealthThresholds,
+    pub cache: CacheSettings,
+    pub http: HttpSettings,
+    pub sab: SabSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerSettings {
+    /// Connections opened to the server at startup
+    pub warm_connections: usize,
+    /// Seconds a failing article keeps being retried before giving up
+    pub retry_budget_secs: u64,
+    /// Articles fetched at once outside the background download, e.g. first
+    /// segments and index data
+    pub prefetch_parallelism: usize,
+    /// Articles asked about before creating a session, 0 for every one
+    pub availability_sample: usize,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            warm_connections: 50,
+            retry_budget_secs: 30,
+            prefetch_parallelism: 20,
+            availability_sample: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// A directory per session, holding its file as it downloads
+    pub dir: PathBuf,
+    /// Finished downloads go here, in a folder per session, for Sonarr and
+    /// Radarr to import
+    pub complete_dir: PathBuf,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("/tmp/nzb-cache"),
+            complete_dir: PathBuf::from("/tmp/nzb-complete"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    pub host: String,
+    pub port: u16,
+    /// Bytes read at a time for a `/stream` response
+    pub ideal_chunk_size: usize,
+    /// Bytes read at a time for a `/chunked` response
+    pub max_chunk_size: usize,
+    /// Seconds an HLS segment waits for its data before the player is told
+    /// to retry
+    pub hls_segment_wait_secs: u64,
+    /// Where clients reach us, e.g. behind a reverse proxy, for the absolute
+    /// URLs handed to Stremio. Taken from the request when not set.
+    pub public_url: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 3005,
+            ideal_chunk_size: 8 * 1024 * 1024,
+            max_chunk_size: 16 * 1024 * 1024,
+            hls_segment_wait_secs: 20,
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SabSettings {
+    /// Key clients of the SABnzbd API must send, the API is off without one
+    pub api_key: Option<String>,
+    /// Categories the API offers
+    pub categories: Vec<String>,
+}
+
+impl Default for SabSettings {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            categories: vec!["tv".to_string(), "movies".to_string()],
+        }
+    }
+}
+
+/// Command line flags, which take over from the file and environment
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub cache_dir: Option<PathBuf>,
+    pub complete_dir: Option<PathBuf>,
+    pub max_rate: Option<u64>,
+    pub nzb_dir: Option<PathBuf>,
+    pub sab_api_key: Option<String>,
+    pub sab_categories: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// Merges the layers and checks the result
+    ///
+    /// # Arguments
+    /// - `file`: the config file, which has to exist. [`DEFAULT_FILE`] is
+    ///   read instead if there is one.
+    pub fn load(file: Option<&Path>, overrides: Overrides) -> Result<Self, SettingsError> {
+        let file = match file {
+            Some(path) => File::from(path).required(true),
+            None => File::from(Path::new(DEFAULT_FILE)).required(false),
+        };
+
+        let mut settings: Settings = Config::builder()
+            .add_source(file.format(FileFormat::Toml))
+            .add_source(
+                Environment::with_prefix("NZB_STREAMER")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .set_override_option("http.host", overrides.host)?
+            .set_override_option("http.port", overrides.port.map(u64::from))?
+            .set_override_option("cache.dir", overrides.cache_dir.map(path_value))?
+            .set_override_option("cache.complete_dir", overrides.complete_dir.map(path_value))?
+            .set_override_option("bandwidth.limit", overrides.max_rate)?
+            .set_override_option("nzb_dir", overrides.nzb_dir.map(path_value))?
+            .set_override_option("sab.api_key", overrides.sab_api_key)?
+            .set_override_option("sab.categories", overrides.sab_categories)?
+            .build()?
+            .try_deserialize()?;
+
+        if settings.server.is_none() {
+            let server = NntpConfig::from_env().map_err(SettingsError::NoServer)?;
+            settings.server = Some(server);
+        }
+        if settings.newznab.is_none() {
+            settings.newznab = NewznabConfig::from_env().ok();
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reports every problem at once, so they can all be fixed in one go
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        match &self.server {
+            None => problems.push("there is no server".to_string()),
+            Some(server) => {
+                if server.host.trim().is_empty() {
+                    problems.push("server.host is empty".to_string());
+                }
+                if server.port == 0 {
+                    problems.push("server.port can't be 0".to_string());
+                }
+                if *server.max_connections == 0 {
+                    problems.push("server.max_connections must be at least 1".to_string());
+                }
+                if *server.pipeline_depth == 0 {
+                    problems.push("server.pipeline_depth must be at least 1".to_string());
+                }
+            }
+        }
+
+        if self.scheduler.prefetch_parallelism == 0 {
+            problems.push("scheduler.prefetch_parallelism must be at least 1".to_string());
+        }
+
+        for (index, window) in self.bandwidth.schedule.iter().enumerate() {
+            if window.from == window.to {
+                problems.push(format!(
+                    "bandwidth.schedule[{index}] starts and ends at {}, so never applies",
+                    window.from
+                ));
+            }
+        }
+
+        let HealthThresholds {
+            critical,
+            poor,
+            good,
+        } = self.health;
+        if !(critical < poor && poor < good && good <= 100) {
+            problems.push(format!(
+                "health thresholds must rise from critical to good, up to 100%, \
+                 got critical {critical}, poor {poor}, good {good}"
+            ));
+        }
+
+        if let Some(newznab) = &self.newznab {
+            if !(newznab.url.starts_with("http://") || newznab.url.starts_with("https://")) {
+                problems.push(format!("newznab.url {} isn't an http(s) URL", newznab.url));
+            }
+            if newznab.apikey.trim().is_empty() {
+                problems.push("newznab.apikey is empty".to_string());
+            }
+        }
+
+        for (name, dir) in [
+            ("cache.dir", Some(&self.cache.dir)),
+            ("cache.complete_dir", Some(&self.cache.complete_dir)),
+            ("nzb_dir", self.nzb_dir.as_ref()),
+        ] {
+            let Some(dir) = dir else {
+                continue;
+            };
+            if dir.exists() && !dir.is_dir() {
+                problems.push(format!("{name} {} isn't a directory", dir.display()));
+            }
+        }
+
+        let http = &self.http;
+        if http.ideal_chunk_size == 0 {
+            problems.push("http.ideal_chunk_size must be at least 1".to_string());
+        }
+        if http.ideal_chunk_size > http.max_chunk_size {
+            problems.push(format!(
+                "http.ideal_chunk_size ({}) is bigger than http.max_chunk_size ({})",
+                http.ideal_chunk_size, http.max_chunk_size
+            ));
+        }
+        if let Some(url) = &http.public_url
+            && !(url.starts_with("http://") || url.starts_with("https://"))
+        {
+            problems.push(format!("http.public_url {url} isn't an http(s) URL"));
+        }
+
+        let SabSettings {
+            api_key,
+            categories,
+        } = &self.sab;
+        if api_key.as_deref().is_some_and(|key| key.trim().is_empty()) {
+            problems.push("sab.api_key is empty, leave it out to turn the API off".to_string());
+        }
+        if categories.iter().any(|category| category.trim().is_empty()) {
+            problems.push("sab.categories has an empty name".to_string());
+        }
+
+        problems
+    }
+}
+
+fn path_value(path: PathBuf) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nzb-streamer.toml");
+        std::fs::write(
+            &path,
+            r#"
+                nzb_dir = "/data/nzbs"
+
+                [server]
+                host = "news.example.com"
+                username = "user"
+                password = "secret"
+                port = 119
+                tls = false
+                max_connections = 30
+                pipeline_depth = 1
+
+                [health]
+                critical = 10
+
+                [http]
+                port = 8080
+                hls_segment_wait_secs = 5
+
+                [newznab]
+                url = "https://indexer.example.com"
+                apikey = "key"
+
+                [sab]
+                api_key = "sab-key"
+            "#,
+        )
+        .unwrap();
+
+        let overrides = Overrides {
+            port: Some(9000),
+            max_rate: Some(5_000_000),
+            sab_categories: Some(vec!["anime".to_string()]),
+            ..Default::default()
+        };
+        let settings = Settings::load(Some(&path), overrides).unwrap();
+
+        let server = settings.server.as_ref().unwrap();
+        assert_eq!(server.host, "news.example.com");
+        assert_eq!(*server.max_connections, 30);
+        assert_eq!(server.port, 119);
+        assert_eq!(*server.pipeline_depth, 1);
+        assert_eq!(settings.health.critical, 10);
+        assert_eq!(settings.health.good, 35);
+        assert_eq!(settings.http.port, 9000);
+        assert_eq!(settings.http.hls_segment_wait_secs, 5);
+        assert_eq!(settings.bandwidth.limit, Some(5_000_000));
+        assert_eq!(settings.scheduler.warm_connections, 50);
+        assert_eq!(settings.nzb_dir, Some(PathBuf::from("/data/nzbs")));
+        let newznab = settings.newznab.as_ref().unwrap();
+        assert_eq!(newznab.url, "https://indexer.example.com");
+        assert_eq!(settings.sab.api_key.as_deref(), Some("sab-key"));
+        assert_eq!(settings.sab.categories, ["anime"]);
+
+        assert!(
+            Settings::load(Some(&dir.path().join("missing.toml")), Overrides::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut settings = Settings {
+            server: Some(NntpConfig {
+                host: String::new(),
+                username: "user".to_string(),
+                password: "secret".to_string(),
+                port: 563,
+                tls: true,
+                max_connections: Default::default(),
+                idle_timeout: Default::default(),
+                pipeline_depth: Default::default(),
+            }),
+            ..Default::default()
+        };
+        settings.health.poor = 50;
+        settings.http.ideal_chunk_size = 32 * 1024 * 1024;
+        settings.http.public_url = Some("streamer.example.com".to_string());
+        settings.cache.dir = file.path().to_path_buf();
+        settings.newznab = Some(NewznabConfig {
+            url: "indexer.example.com".to_string(),
+            apikey: "key".to_string(),
+        });
+        settings.sab.api_key = Some(" ".to_string());
+
+        let Err(SettingsError::Invalid(problems)) = settings.validate() else {
+            panic!("the settings are invalid");
+        };
+        assert_eq!(problems.len(), 7);
+        assert_eq!(problems[0], "server.host is empty");
+        assert!(problems[1].starts_with("health thresholds must rise"));
+        assert!(problems[2].starts_with("newznab.url"));
+        assert!(problems[3].starts_with("cache.dir"));
+        assert!(problems[4].starts_with("http.ideal_chunk_size"));
+        assert!(problems[5].starts_with("http.public_url"));
+        assert!(problems[6].starts_with("sab.api_key"));
+    }
+}
@@ -16,4 +16,7 @@ pub enum StreamError {
 
     #[error(transparent)]
     Archive(#[from] ArchiveError),
+
+    #[error("Gave up waiting for data at offset {0}, the download has stalled")]
+    Stalled(u64),
 }
@@ -0,0 +1,311 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use http::{HeaderMap, header};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    error::RestError,
+    media::{
+        ebml,
+        subtitles::{self, SubtitleFormat},
+    },
+    metrics::METRICS,
+    server::AppState,
+    stream::range::ByteRange,
+};
+
+const SUBTITLE_HEADER_SIZE: u64 = 1024 * 1024; // holds the SeekHead and Info of any MKV
+
+const SUBTITLE_SPAN_SIZE: u64 = 64 * 1024 * 1024; // copied out of the file at a time for a scan
+
+pub async fn stream(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RestError> {
+    // not held while the stream starts, which waits on the download
+    let session = state
+        .sessions
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or(RestError::SessionNotFound)?;
+    let orchestrator = &session.orchestrator;
+
+    let available = orchestrator.get_available_bytes();
+    if available == 0 {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "2")
+            .body(Body::from("No data available yet"))
+            .unwrap());
+    }
+
+    // ranges are of the whole file, the stream waits on any part of it that
+    // isn't downloaded yet
+    let total = orchestrator.total_size();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    let (start, length, partial) = match ByteRange::parse(range, total) {
+        ByteRange::Whole => (0, total, false),
+        ByteRange::Partial { start, length } => (start, length, true),
+        ByteRange::Unsatisfiable => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{total}"))
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+
+    let stream = orchestrator
+        .get_stream(start, length, state.settings.http.ideal_chunk_size)
+        .await;
+    let body = Body::from_stream(stream);
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, session.media.mime_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, length);
+
+    let response = if partial {
+        let end = start + length - 1;
+        response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{total}"),
+            )
+            .header(header::CACHE_CONTROL, "public, max-age=3600")
+    } else {
+        response
+            .status(StatusCode::OK)
+            .header(header::CACHE_CONTROL, "no-cache")
+    };
+
+    Ok(response.body(body).unwrap())
+}
+
+pub async fn stream_chunked(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    // not held while the stream starts, which waits on the download
+    let session = state
+        .sessions
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or(RestError::SessionNotFound)?;
+    let orchestrator = &session.orchestrator;
+
+    let available = orchestrator.get_available_bytes();
+    if available == 0 {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "2")
+            .body(Body::from("No data available yet"))
+            .unwrap());
+    }
+
+    let stream = orchestrator
+        .get_stream(0, available, state.settings.http.max_chunk_size)
+        .await;
+    let body = Body::from_stream(stream);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, session.media.mime_type)
+        .header(header::TRANSFER_ENCODING, "chunked")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+pub async fn hls_playlist(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Response, RestError> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    // the segments come from the container's index, which is fetched first
+    let Some(plan) = session.hls.get() else {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "2")
+            .body(Body::from("Container index not fetched yet"))
+            .unwrap());
+    };
+    let plan = plan.as_ref().ok_or(RestError::HlsUnavailable)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(plan.playlist()))
+        .unwrap())
+}
+
+/// Remuxes one playlist segment, e.g. `12.ts`, once its bytes are in. Asking
+/// for a segment moves the playback position there like a seek would.
+pub async fn hls_segment(
+    Path((session_id, segment)): Path<(Uuid, String)>,
+    State(state): State<AppState>,
+) -> Result<Response, RestError> {
+    let session = state
+        .sessions
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or(RestError::SessionNotFound)?;
+
+    let plan = session
+        .hls
+        .get()
+        .and_then(Option::as_ref)
+        .ok_or(RestError::HlsUnavailable)?;
+    let index = segment
+        .strip_suffix(".ts")
+        .and_then(|index| index.parse::<usize>().ok())
+        .ok_or(RestError::SegmentNotFound)?;
+    let range = plan
+        .segments()
+        .get(index)
+        .ok_or(RestError::SegmentNotFound)?
+        .range
+        .clone();
+
+    let orchestrator = &session.orchestrator;
+    orchestrator.update_playback_position(range.start);
+    let wait = Duration::from_secs(state.settings.http.hls_segment_wait_secs);
+    let Some(data) = orchestrator.read_range(range, wait).await else {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "2")
+            .body(Body::from("Segment not downloaded yet"))
+            .unwrap());
+    };
+
+    let segment = plan.remux(index, &data)?;
+    METRICS.served_bytes.inc_by(segment.len() as u64);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "video/mp2t")
+        .header(header::CONTENT_LENGTH, segment.len())
+        .header(header::CACHE_CONTROL, "public, max-age=3600")
+        .body(Body::from(segment))
+        .unwrap())
+}
+
+/// The embedded text subtitle tracks that can be fetched as WebVTT
+pub async fn list_subtitles(
+    Path(session_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    let tracks: Vec<_> = session
+        .media
+        .tracks
+        .iter()
+        .filter_map(|track| {
+            let format = SubtitleFormat::from_codec(&track.codec)?;
+
+            Some(json!({
+                "track": track.number,
+                "codec": track.codec,
+                "format": format,
+                "language": track.language,
+                "name": track.name,
+                "url": format!("/sessions/{session_id}/subtitles/{}.vtt", track.number),
+            }))
+        })
+        .collect();
+
+    Ok(Json(json!({ "subtitles": tracks })))
+}
+
+/// Converts a subtitle track, e.g. `3.vtt`, to WebVTT. Only the parts of the
+/// file downloaded so far are read, so cues further in appear on later
+/// requests.
+pub async fn subtitle_vtt(
+    Path((session_id, track)): Path<(Uuid, String)>,
+    State(state): State<AppState>,
+) -> Result<Response, RestError> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    let number = track
+        .strip_suffix(".vtt")
+        .and_then(|number| number.parse::<u64>().ok())
+        .ok_or(RestError::SubtitleTrackNotFound)?;
+    let format = session
+        .media
+        .tracks
+        .iter()
+        .find(|track| track.number == number)
+        .and_then(|track| SubtitleFormat::from_codec(&track.codec))
+        .ok_or(RestError::SubtitleTrackNotFound)?;
+
+    let orchestrator = Arc::clone(&session.orchestrator);
+    drop(sessions);
+
+    // the scan reads through much of the file, so it runs off the runtime,
+    // copying a span at a time so downloads can write in between
+    let vtt = tokio::task::spawn_blocking(move || {
+        let header_end = orchestrator.get_available_bytes().min(SUBTITLE_HEADER_SIZE);
+        let header = orchestrator.mmap.read()[..header_end as usize].to_vec();
+        let layout = ebml::segment_layout(&header)?;
+
+        let mut cluster_starts: Vec<_> = layout
+            .clusters_offset
+            .into_iter()
+            .chain(
+                orchestrator
+                    .cues()
+                    .into_iter()
+                    .flat_map(|cues| cues.points().iter().map(|point| point.offset)),
+            )
+            .collect();
+        cluster_starts.sort_unstable();
+        cluster_starts.dedup();
+
+        let ranges = orchestrator.available_ranges();
+        let mut cues = Vec::new();
+        for span in subtitles::cluster_spans(&ranges, &cluster_starts, SUBTITLE_SPAN_SIZE) {
+            let data = orchestrator.mmap.read()[span.start as usize..span.end as usize].to_vec();
+            cues.extend(subtitles::read_cues(&data, &layout, number, format));
+        }
+        cues.sort_by_key(|cue| cue.start);
+
+        Ok::<_, RestError>(subtitles::to_webvtt(&cues))
+    })
+    .await??;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/vtt; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(vtt))
+        .unwrap())
+}
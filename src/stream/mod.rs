@@ -1,3 +1,5 @@
 pub mod error;
+pub mod handlers;
 pub mod orchestrator;
+pub mod range;
 pub mod virtual_file_streamer;
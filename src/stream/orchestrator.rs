@@ -1,19 +1,37 @@
 use bytes::Bytes;
 use futures::Stream;
 use memmap2::MmapMut;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rustix::fs::{SeekFrom, seek};
+use serde::{Deserialize, Serialize};
 use std::fs::File;
+use std::ops::Range;
 use std::os::fd::AsFd;
-use std::path::Path;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, warn};
 
 use crate::archive::par2::DownloadTask;
+use crate::media::cues::CueIndex;
+use crate::metrics::METRICS;
 use crate::stream::error::StreamError;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// How often `read_range` and streams check whether their data has arrived
+const RANGE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a stream waits on data that isn't downloading before giving up
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long after its last read a session still counts as playing. HLS
+/// players fetch a segment at a time, with gaps between.
+const PLAYING_GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum BufferHealth {
     Critical,
     Poor,
@@ -44,20 +62,103 @@ impl BufferHealth {
     }
 }
 
+/// Where buffer health changes, in percent of the rest of the file that's
+/// buffered ahead of the player
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthThresholds {
+    /// Critical up to and including this
+    pub critical: u64,
+    /// Poor up to and including this
+    pub poor: u64,
+    /// Good up to and including this, excellent above
+    pub good: u64,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        Self {
+            critical: 5,
+            poor: 15,
+            good: 35,
+        }
+    }
+}
+
+impl HealthThresholds {
+    pub fn health(&self, buffer_percentage: u64) -> BufferHealth {
+        if buffer_percentage <= self.critical {
+            BufferHealth::Critical
+        } else if buffer_percentage <= self.poor {
+            BufferHealth::Poor
+        } else if buffer_percentage <= self.good {
+            BufferHealth::Good
+        } else {
+            BufferHealth::Excellent
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct StreamOrchestrator {
     pub mmap: Arc<RwLock<MmapMut>>,
     file: Arc<File>,
+    path: PathBuf,
     total_size: u64,
     playback_position: Arc<AtomicU64>,
     health_tx: watch::Sender<BufferHealth>,
+    /// Playback position for the scheduler, moved back to the start of its
+    /// cluster once we have the cues
+    position_tx: watch::Sender<u64>,
+    cues: OnceLock<CueIndex>,
+    /// Index data fetched out of order, e.g. MP4 `moov` or MKV Cues at the
+    /// end of the file
+    priority_ranges: RwLock<Vec<PriorityRange>>,
+    /// Where each volume's stream data sits in the file, in order
+    volumes: Vec<Volume>,
+    /// Streams of the file still being sent
+    active_streams: Arc<AtomicUsize>,
+    last_read: Mutex<Option<Instant>>,
+    thresholds: HealthThresholds,
+}
+
+/// Counts a stream as active until it's dropped
+struct ActiveStream(Arc<AtomicUsize>);
+
+impl ActiveStream {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub name: String,
+    pub range: Range<u64>,
+}
+
+#[derive(Debug)]
+struct PriorityRange {
+    range: Range<u64>,
+    ready: watch::Receiver<bool>,
 }
 
 impl StreamOrchestrator {
+    /// Creates the file the tasks' stream data is laid out in at `path`,
+    /// with the first segments we already have written in
     pub fn new(
         tasks: Vec<DownloadTask>,
-        session_dir: &Path,
+        path: PathBuf,
         health_tx: watch::Sender<BufferHealth>,
+        position_tx: watch::Sender<u64>,
+        thresholds: HealthThresholds,
     ) -> Arc<Self> {
         // let files: Vec<_> = tasks
         //     .iter()
@@ -65,24 +166,35 @@ impl StreamOrchestrator {
         //     .collect();
         let total_size: u64 = tasks.iter().map(|f| f.length()).sum();
 
-        let path = session_dir.join("test.mkv");
         let file = std::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
-            .open(path)
+            .open(&path)
             .unwrap();
         file.set_len(total_size).unwrap();
         let mut mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
 
         let mut offset = 0;
+        let mut volumes = Vec::with_capacity(tasks.len());
         for task in tasks {
-            println!("Writing {} bytes at offset {}", task.bytes().len(), offset);
+            let name = task.path().file_name().map_or_else(
+                || task.path().to_string_lossy(),
+                |name| name.to_string_lossy(),
+            );
+            volumes.push(Volume {
+                name: name.into_owned(),
+                range: offset as u64..offset as u64 + task.length(),
+            });
 
-            let end = offset + task.bytes().len();
+            let data = task.first_segment_data();
+            debug!("Writing {} bytes at offset {}", data.len(), offset);
+
+            let end = offset + data.len();
 
             // Write the task's data to the correct offset in the memory map
-            mmap[offset..end].copy_from_slice(task.bytes());
+            mmap[offset..end].copy_from_slice(&data);
             offset += *task.length() as usize;
         }
 
@@ -91,103 +203,287 @@ impl StreamOrchestrator {
         Arc::new(Self {
             mmap: Arc::new(RwLock::new(mmap)),
             file: Arc::new(file),
+            path,
             total_size,
             playback_position: AtomicU64::new(0).into(),
             health_tx,
+            position_tx,
+            cues: OnceLock::new(),
+            priority_ranges: RwLock::new(Vec::new()),
+            volumes,
+            active_streams: Arc::default(),
+            last_read: Mutex::new(None),
+            thresholds,
         })
     }
 
-    fn update_health(&self) {
+    /// Marks a range as being fetched ahead of the background downloads.
+    /// Send `true` on the returned channel once it's in the file. Until then
+    /// reads of the range wait for it, and reading it doesn't move the
+    /// playback position, since players only jump there to read the index.
+    pub fn add_priority_range(&self, range: Range<u64>) -> watch::Sender<bool> {
+        let (ready_tx, ready) = watch::channel(false);
+        self.priority_ranges
+            .write()
+            .push(PriorityRange { range, ready });
+
+        ready_tx
+    }
+
+    fn is_priority(&self, position: u64) -> bool {
+        self.priority_ranges
+            .read()
+            .iter()
+            .any(|priority| priority.range.contains(&position))
+    }
+
+    /// Waits for any priority range overlapping `start..end` to be fetched.
+    /// If the fetch failed, the sender is gone and we serve what we have.
+    async fn wait_for_priority(&self, start: u64, end: u64) {
+        let pending: Vec<_> = self
+            .priority_ranges
+            .read()
+            .iter()
+            .filter(|priority| priority.range.start < end && start < priority.range.end)
+            .map(|priority| priority.ready.clone())
+            .collect();
+
+        for mut ready in pending {
+            let _ = ready.wait_for(|&ready| ready).await;
+        }
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// The sparse file the download is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_cues(&self, cues: CueIndex) {
+        if self.cues.set(cues).is_err() {
+            warn!("Cues already loaded for this session, ignoring");
+        }
+    }
+
+    pub fn cues(&self) -> Option<&CueIndex> {
+        self.cues.get()
+    }
+
+    /// Seconds of playback buffered ahead of the player, if we have the cues
+    /// to tell
+    pub fn buffered_secs(&self) -> Option<f64> {
+        let cues = self.cues.get()?;
+        let position = self.playback_position.load(Ordering::SeqCst);
+        let buffered_until = cues.time_at(self.contiguous_end(position))?;
+
+        Some((buffered_until - cues.time_at(position).unwrap_or(0.0)).max(0.0))
+    }
+
+    /// How much of the rest of the file is buffered ahead of the player. The
+    /// background download is told whenever it changes, so its batches
+    /// follow the same health the session's demand is judged by.
+    pub fn health(&self) -> BufferHealth {
         let playback_pos = self.playback_position.load(Ordering::SeqCst);
-        let available = self.get_available_bytes();
-        let buffer_ahead = available.saturating_sub(playback_pos);
+        let buffer_ahead = self.contiguous_end(playback_pos) - playback_pos;
 
         // Percentage of remaining video buffered
         let remaining = self.total_size.saturating_sub(playback_pos);
-        let buffer_percentage = if remaining > 0 {
-            (buffer_ahead * 100) / remaining
-        } else {
-            100
-        };
+        let buffer_percentage = (buffer_ahead * 100).checked_div(remaining).unwrap_or(100);
+
+        let health = self.thresholds.health(buffer_percentage);
+        self.health_tx
+            .send_if_modified(|current| std::mem::replace(current, health) != health);
+        health
+    }
+
+    pub fn volumes(&self) -> &[Volume] {
+        &self.volumes
+    }
+
+    /// Whether each volume's stream data has all been downloaded
+    pub fn completed_volumes(&self) -> Vec<bool> {
+        self.volumes
+            .iter()
+            .map(|volume| {
+                self.is_range_available(volume.range.start, volume.range.end - volume.range.start)
+            })
+            .collect()
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::SeqCst)
+    }
 
-        let health = match buffer_percentage {
-            0..=5 => BufferHealth::Critical,
-            6..=15 => BufferHealth::Poor,
-            16..=35 => BufferHealth::Good,
-            _ => BufferHealth::Excellent,
-        };
+    /// Whether a player is streaming the file or read from it recently
+    pub fn is_playing(&self) -> bool {
+        self.active_streams() > 0
+            || self
+                .last_read
+                .lock()
+                .is_some_and(|read| read.elapsed() < PLAYING_GRACE)
+    }
+
+    fn touch(&self) {
+        *self.last_read.lock() = Some(Instant::now());
     }
 
     pub fn update_playback_position(&self, position: u64) {
+        if self.is_priority(position) {
+            return;
+        }
+
         self.playback_position.store(position, Ordering::SeqCst);
+
+        let cluster_start = self
+            .cues
+            .get()
+            .and_then(|cues| cues.cluster_start(position))
+            .unwrap_or(position);
+        self.position_tx.send_replace(cluster_start);
     }
 
     /// Find the number of continuous bytes available from position 0
     pub fn get_available_bytes(&self) -> u64 {
+        self.contiguous_end(0)
+    }
+
+    /// End of the data available without gaps from `position`, or
+    /// `position` itself if there's nothing there yet
+    pub fn contiguous_end(&self, position: u64) -> u64 {
         let fd = self.file.as_fd();
 
-        // Start from beginning and find first hole
-        match seek(fd, SeekFrom::Data(0)) {
-            Ok(data_start) => {
-                if data_start > 0 {
-                    return 0;
-                }
-
-                match seek(fd, SeekFrom::Hole(0)) {
-                    Ok(hole_start) => hole_start,
-                    Err(_) => self.total_size,
-                }
+        match seek(fd, SeekFrom::Data(position)) {
+            Ok(data_start) if data_start == position => {
+                seek(fd, SeekFrom::Hole(position)).unwrap_or(self.total_size)
             }
-            Err(_) => 0,
+            _ => position,
         }
     }
 
+    /// Every downloaded part of the file, in order
+    pub fn available_ranges(&self) -> Vec<Range<u64>> {
+        let fd = self.file.as_fd();
+        let mut ranges = Vec::new();
+        let mut position = 0;
+
+        while position < self.total_size {
+            let Ok(start) = seek(fd, SeekFrom::Data(position)) else {
+                break;
+            };
+            let end = seek(fd, SeekFrom::Hole(start))
+                .unwrap_or(self.total_size)
+                .min(self.total_size);
+            if end <= start {
+                break;
+            }
+
+            ranges.push(start..end);
+            position = end;
+        }
+
+        ranges
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.available_ranges()
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
     pub fn is_range_available(&self, start: u64, length: u64) -> bool {
         let fd = self.file.as_fd();
         let end = start + length;
-        let pos = start;
-
-        while pos < end {
-            match seek(fd, SeekFrom::Data(pos)) {
-                Ok(data_pos) => {
-                    if data_pos > pos {
-                        return false;
-                    }
-                    match seek(fd, SeekFrom::Hole(pos)) {
-                        Ok(hole_pos) => {
-                            if hole_pos < end {
-                                return false;
-                            }
-                            return true;
-                        }
-                        Err(_) => return true,
-                    }
-                }
-                Err(_) => return false,
+        if start >= end {
+            return true;
+        }
+
+        match seek(fd, SeekFrom::Data(start)) {
+            Ok(data_pos) if data_pos > start => false,
+            Ok(_) => match seek(fd, SeekFrom::Hole(start)) {
+                Ok(hole_pos) => hole_pos >= end,
+                Err(_) => true,
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Waits for `start..end` to be downloaded, moving the playback position
+    /// there if it isn't so the scheduler fetches it next
+    async fn wait_for_range(&self, start: u64, end: u64) -> Result<(), StreamError> {
+        if self.is_range_available(start, end - start) {
+            return Ok(());
+        }
+
+        self.update_playback_position(start);
+        let mut contiguous = self.contiguous_end(start);
+        let mut stalled_since = Instant::now();
+
+        while !self.is_range_available(start, end - start) {
+            tokio::time::sleep(RANGE_POLL_INTERVAL).await;
+            self.touch();
+
+            // a slow download is fine, one that's stopped isn't
+            let now_contiguous = self.contiguous_end(start);
+            if now_contiguous > contiguous {
+                contiguous = now_contiguous;
+                stalled_since = Instant::now();
+            } else if stalled_since.elapsed() >= STREAM_STALL_TIMEOUT {
+                return Err(StreamError::Stalled(contiguous));
             }
         }
-        true
+
+        Ok(())
+    }
+
+    /// Copies `range` out of the file once all of it has been downloaded
+    ///
+    /// # Returns
+    /// - `None` if it hasn't arrived within `timeout`
+    pub async fn read_range(&self, range: Range<u64>, timeout: Duration) -> Option<Bytes> {
+        self.touch();
+        let deadline = Instant::now() + timeout;
+        while !self.is_range_available(range.start, range.end - range.start) {
+            if Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(RANGE_POLL_INTERVAL).await;
+        }
+
+        Some(Bytes::copy_from_slice(
+            &self.mmap.read()[range.start as usize..range.end as usize],
+        ))
     }
 
+    /// Streams `length` bytes at `start`, waiting on any that haven't been
+    /// downloaded yet. The background download is pointed at whatever the
+    /// stream is waiting on.
     pub async fn get_stream(
         self: &Arc<Self>,
         start: u64,
         length: u64,
         chunk_size: usize,
-    ) -> impl Stream<Item = Result<Bytes, StreamError>> + 'static {
+    ) -> impl Stream<Item = Result<Bytes, StreamError>> + use<> {
         self.update_playback_position(start);
+        self.touch();
+        let orchestrator = Arc::clone(self);
         let mmap = Arc::clone(&self.mmap);
         let end = start + length;
+        self.wait_for_priority(start, end).await;
+        let active = ActiveStream::new(&self.active_streams);
 
         async_stream::try_stream! {
+            let _active = active;
             let mut pos = start;
 
             while pos < end {
                 let chunk_end = (pos + chunk_size as u64).min(end);
                 let chunk_len = (chunk_end - pos) as usize;
 
-                // wait for data to be available
-                // TODO: check if range is available and wait/retry if not
+                orchestrator.wait_for_range(pos, chunk_end).await?;
 
                 let chunk = {
                     let start_idx = pos as usize;
@@ -195,6 +491,7 @@ impl StreamOrchestrator {
                     Bytes::copy_from_slice(&mmap.read()[start_idx..end_idx])
                 };
 
+                METRICS.served_bytes.inc_by(chunk_len as u64);
                 yield chunk;
                 pos += chunk_len as u64;
             }
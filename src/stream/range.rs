@@ -0,0 +1,96 @@
+/// What a `Range` request header asks for out of a file of known size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, or one we don't understand, so the whole file is sent
+    Whole,
+    Partial {
+        start: u64,
+        length: u64,
+    },
+    /// Starts past the end of the file, answered with 416
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses a single `bytes=` range against the file's `total_size`,
+    /// resolving open ends and suffixes (`bytes=-500`) against it. Ends past
+    /// the file are cut short, as RFC 9110 asks.
+    pub fn parse(header: Option<&str>, total_size: u64) -> Self {
+        let Some((start, end)) = header
+            .and_then(|header| header.strip_prefix("bytes="))
+            .and_then(|range| range.split_once('-'))
+        else {
+            return Self::Whole;
+        };
+
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => return Self::Unsatisfiable,
+                Ok(suffix) => (total_size.saturating_sub(suffix), None),
+                Err(_) => return Self::Whole,
+            },
+            (start, "") => match start.parse() {
+                Ok(start) => (start, None),
+                Err(_) => return Self::Whole,
+            },
+            (start, end) => match (start.parse(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => (start, Some(end)),
+                _ => return Self::Whole,
+            },
+        };
+
+        if start >= total_size {
+            return Self::Unsatisfiable;
+        }
+        let end = end.map_or(total_size - 1, |end| end.min(total_size - 1));
+
+        Self::Partial {
+            start,
+            length: end - start + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parse = |header| ByteRange::parse(Some(header), 1000);
+
+        assert_eq!(
+            parse("bytes=0-99"),
+            ByteRange::Partial {
+                start: 0,
+                length: 100
+            }
+        );
+        assert_eq!(
+            parse("bytes=900-"),
+            ByteRange::Partial {
+                start: 900,
+                length: 100
+            }
+        );
+        assert_eq!(
+            parse("bytes=-300"),
+            ByteRange::Partial {
+                start: 700,
+                length: 300
+            }
+        );
+        assert_eq!(
+            parse("bytes=990-5000"),
+            ByteRange::Partial {
+                start: 990,
+                length: 10
+            }
+        );
+        assert_eq!(parse("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=5-1"), ByteRange::Whole);
+        assert_eq!(parse("items=0-1"), ByteRange::Whole);
+        assert_eq!(ByteRange::parse(None, 1000), ByteRange::Whole);
+    }
+}
@@ -0,0 +1,19 @@
+use std::io;
+
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum StremioError {
+    #[error("Unsupported content type '{0}'")]
+    UnknownType(String),
+
+    #[error("Invalid content ID '{0}'")]
+    InvalidId(String),
+
+    #[error("Error reading NZBs from the library")]
+    Library(#[from] io::Error),
+
+    #[error("No stream {0}, it may have been listed before a restart")]
+    UnknownStream(Uuid),
+}
@@ -0,0 +1,137 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Json, Response},
+};
+use http::{HeaderMap, header};
+use itertools::Itertools;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    error::RestError,
+    server::AppState,
+    session::{SessionMode, create::create_session},
+    stream::handlers::stream,
+    stremio::{self, MediaId, MetaPreview, resolver::ResolvedNzb},
+};
+
+pub async fn manifest() -> impl IntoResponse {
+    Json(stremio::manifest())
+}
+
+/// The sessions catalog, newest first. Stremio asks for `{catalog}.json`.
+pub async fn catalog(
+    Path((kind, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    let id = id.strip_suffix(".json").unwrap_or(&id);
+    if !stremio::is_sessions_catalog(&kind, id) {
+        return Ok(Json(json!({ "metas": [] })));
+    }
+
+    let sessions = state.sessions.read().await;
+    let metas: Vec<_> = sessions
+        .values()
+        .sorted_by_key(|session| std::cmp::Reverse(session.created_at))
+        .map(|session| MetaPreview::for_session(session))
+        .collect();
+
+    Ok(Json(json!({ "metas": metas })))
+}
+
+pub async fn meta(
+    Path((_kind, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RestError> {
+    let id = id.strip_suffix(".json").unwrap_or(&id);
+    let session_id = stremio::session_id(id).ok_or(RestError::SessionNotFound)?;
+
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or(RestError::SessionNotFound)?;
+
+    Ok(Json(json!({ "meta": MetaPreview::for_session(session) })))
+}
+
+/// Streams for an IMDb title, starting sessions from the NZBs the resolver
+/// finds the first time it's asked for, or for one of our own sessions
+pub async fn streams(
+    Path((kind, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RestError> {
+    let id = id.strip_suffix(".json").unwrap_or(&id);
+    let base_url = base_url(&state, &headers);
+
+    if let Some(session_id) = stremio::session_id(id) {
+        let sessions = state.sessions.read().await;
+        let streams: Vec<_> = sessions
+            .get(&session_id)
+            .map(|session| stremio::Stream::for_session(&base_url, session))
+            .into_iter()
+            .collect();
+        return Ok(Json(json!({ "streams": streams })));
+    }
+
+    // candidates already opened are listed as their sessions, the rest only
+    // start downloading when Stremio opens them
+    let candidates = state
+        .stremio
+        .candidates(&MediaId::parse(&kind, id)?)
+        .await?;
+    let sessions = state.sessions.read().await;
+    let streams: Vec<_> = candidates
+        .iter()
+        .map(|candidate| {
+            match candidate
+                .session_id()
+                .and_then(|session_id| sessions.get(&session_id))
+            {
+                Some(session) => stremio::Stream::for_session(&base_url, session),
+                None => stremio::Stream::for_candidate(&base_url, candidate),
+            }
+        })
+        .collect();
+
+    Ok(Json(json!({ "streams": streams })))
+}
+
+/// Streams a candidate listed by [`streams`], creating its session
+/// the first time it's opened
+pub async fn play(
+    Path(candidate_id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    let candidate = state.stremio.candidate(candidate_id)?;
+    let session_id = candidate
+        .open(|nzb: &ResolvedNzb| {
+            let state = state.clone();
+            let nzb = nzb.clone();
+            async move {
+                create_session(&state, &nzb.content, Some(&nzb.title), SessionMode::Stream)
+                    .await
+                    .map(|session| session.id)
+            }
+        })
+        .await?;
+
+    stream(Path(session_id), State(state), headers)
+        .await
+        .map(IntoResponse::into_response)
+}
+
+/// Where the client reached us, for the absolute URLs Stremio needs.
+/// `http.public_url` wins over the request's own headers.
+fn base_url(state: &AppState, headers: &HeaderMap) -> String {
+    if let Some(url) = &state.settings.http.public_url {
+        return url.trim_end_matches('/').to_string();
+    }
+
+    let get = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = get("x-forwarded-proto").unwrap_or("http");
+    let host = get(header::HOST.as_str()).unwrap_or("localhost");
+
+    format!("{scheme}://{host}")
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    media::container::Container,
+    session::Session,
+    stremio::{
+        error::StremioError,
+        resolver::{NzbResolver, ResolvedNzb},
+    },
+};
+
+pub mod error;
+pub mod handlers;
+pub mod resolver;
+
+/// Our own sessions are listed in the catalog under `nzb:{session_id}`
+pub const SESSION_ID_PREFIX: &str = "nzb:";
+const IMDB_ID_PREFIX: &str = "tt";
+const SESSIONS_CATALOG: &str = "sessions";
+/// How long a title's candidates are kept after Stremio last asked for them
+const TITLE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Movie,
+    Series,
+}
+
+/// What Stremio asks streams for: an IMDb ID, with the season and episode
+/// for series, e.g. `tt0944947:1:2`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MediaId {
+    pub kind: MediaKind,
+    pub imdb_id: String,
+    /// Season and episode
+    pub episode: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub id: &'static str,
+    pub version: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub resources: Vec<Resource>,
+    pub types: Vec<MediaKind>,
+    pub catalogs: Vec<Catalog>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resource {
+    pub name: &'static str,
+    pub types: Vec<MediaKind>,
+    pub id_prefixes: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Catalog {
+    #[serde(rename = "type")]
+    pub kind: MediaKind,
+    pub id: &'static str,
+    pub name: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stream {
+    pub url: String,
+    pub name: String,
+    pub title: String,
+    pub behavior_hints: BehaviorHints,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BehaviorHints {
+    /// Stremio's web player only takes MP4 and WebM
+    pub not_web_ready: bool,
+    /// Only known once the stream has been opened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_size: Option<u64>,
+    pub filename: String,
+}
+
+/// A session as a catalog entry
+#[derive(Debug, Serialize)]
+pub struct MetaPreview {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: MediaKind,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+impl MediaKind {
+    pub fn parse(kind: &str) -> Result<Self, StremioError> {
+        match kind {
+            "movie" => Ok(Self::Movie),
+            "series" => Ok(Self::Series),
+            _ => Err(StremioError::UnknownType(kind.to_string())),
+        }
+    }
+}
+
+impl MediaId {
+    pub fn parse(kind: &str, id: &str) -> Result<Self, StremioError> {
+        let kind = MediaKind::parse(kind)?;
+        let invalid = || StremioError::InvalidId(id.to_string());
+
+        let mut parts = id.split(':');
+        let imdb_id = parts.next().unwrap_or_default();
+        let is_imdb = imdb_id.strip_prefix(IMDB_ID_PREFIX).is_some_and(|digits| {
+            !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit())
+        });
+        if !is_imdb {
+            return Err(invalid());
+        }
+
+        let episode = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => None,
+            (Some(season), Some(episode), None) => Some((
+                season.parse().map_err(|_| invalid())?,
+                episode.parse().map_err(|_| invalid())?,
+            )),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            kind,
+            imdb_id: imdb_id.to_string(),
+            episode,
+        })
+    }
+}
+
+impl Stream {
+    /// Points Stremio at the session's `/stream` endpoint on `base_url`
+    pub fn for_session(base_url: &str, session: &Session) -> Self {
+        let size = session.orchestrator.total_size();
+
+        Self {
+            url: format!("{base_url}/stream/{}", session.id),
+            name: "Usenet".to_string(),
+            title: format!("{}\n{:.2} GB", session.title, size as f64 / 1e9),
+            behavior_hints: BehaviorHints {
+                not_web_ready: !matches!(session.media.container, Container::Mp4 | Container::WebM),
+                video_size: Some(size),
+                filename: session.title.clone(),
+            },
+        }
+    }
+
+    /// Points Stremio at a candidate that hasn't been opened yet, which
+    /// becomes a session once it is. We can't tell the container until then,
+    /// so the web player is kept away.
+    pub fn for_candidate(base_url: &str, candidate: &Candidate) -> Self {
+        Self {
+            url: format!("{base_url}/stremio/play/{}", candidate.id),
+            name: "Usenet".to_string(),
+            title: candidate.nzb.title.clone(),
+            behavior_hints: BehaviorHints {
+                not_web_ready: true,
+                video_size: None,
+                filename: candidate.nzb.title.clone(),
+            },
+        }
+    }
+}
+
+impl MetaPreview {
+    pub fn for_session(session: &Session) -> Self {
+        Self {
+            id: format!("{SESSION_ID_PREFIX}{}", session.id),
+            kind: MediaKind::Movie,
+            name: session.title.clone(),
+            description: session.meta.category.clone(),
+        }
+    }
+}
+
+/// Declares what the addon serves: streams for IMDb titles from NZBs the
+/// resolver finds, and a catalog of the sessions we already have
+pub fn manifest() -> Manifest {
+    let all = vec![MediaKind::Movie, MediaKind::Series];
+
+    Manifest {
+        id: "org.nzb-streamer",
+        version: env!("CARGO_PKG_VERSION"),
+        name: "NZB Streamer",
+        description: "Stream video from Usenet while it downloads",
+        resources: vec![
+            Resource {
+                name: "catalog",
+                types: vec![MediaKind::Movie],
+                id_prefixes: vec![SESSION_ID_PREFIX],
+            },
+            Resource {
+                name: "meta",
+                types: vec![MediaKind::Movie],
+                id_prefixes: vec![SESSION_ID_PREFIX],
+            },
+            Resource {
+                name: "stream",
+                types: all.clone(),
+                id_prefixes: vec![IMDB_ID_PREFIX, SESSION_ID_PREFIX],
+            },
+        ],
+        types: all,
+        catalogs: vec![Catalog {
+            kind: MediaKind::Movie,
+            id: SESSIONS_CATALOG,
+            name: "Usenet sessions",
+        }],
+    }
+}
+
+pub fn is_sessions_catalog(kind: &str, id: &str) -> bool {
+    kind == "movie" && id == SESSIONS_CATALOG
+}
+
+/// The session a catalog ID like `nzb:{session_id}` refers to
+pub fn session_id(id: &str) -> Option<Uuid> {
+    id.strip_prefix(SESSION_ID_PREFIX)?.parse().ok()
+}
+
+/// An NZB resolved for a title, offered to Stremio as a stream and only
+/// downloaded once that stream is opened
+#[derive(Debug)]
+pub struct Candidate {
+    pub id: Uuid,
+    pub nzb: ResolvedNzb,
+    session: OnceCell<Uuid>,
+}
+
+impl Candidate {
+    fn new(nzb: ResolvedNzb) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            nzb,
+            session: OnceCell::new(),
+        }
+    }
+
+    /// The session the candidate was opened in, if it has been
+    pub fn session_id(&self) -> Option<Uuid> {
+        self.session.get().copied()
+    }
+
+    /// The candidate's session, created the first time it's opened. Opens at
+    /// the same time wait for the first. A failed attempt isn't remembered,
+    /// so the next open tries again.
+    pub async fn open<F, Fut, E>(&self, create_session: F) -> Result<Uuid, E>
+    where
+        F: FnOnce(&ResolvedNzb) -> Fut,
+        Fut: Future<Output = Result<Uuid, E>>,
+    {
+        self.session
+            .get_or_try_init(|| create_session(&self.nzb))
+            .await
+            .copied()
+    }
+}
+
+/// The candidates for one title, locked while they're resolved
+type Candidates = Arc<Mutex<Vec<Arc<Candidate>>>>;
+
+struct Title {
+    candidates: Candidates,
+    asked_at: Instant,
+}
+
+/// Remembers the NZBs resolved for each title, so Stremio asking again
+/// doesn't search again, and the candidates by ID for when one is opened.
+/// Titles nobody has asked for in a while are forgotten, along with
+/// their candidates.
+pub struct StremioAddon {
+    resolver: Arc<dyn NzbResolver>,
+    /// Each title's lock is held while resolving it, so concurrent requests
+    /// for a title wait for the first without holding up other titles
+    titles: parking_lot::Mutex<HashMap<MediaId, Title>>,
+    /// Owned by their titles, so they go when the title does
+    candidates: parking_lot::Mutex<HashMap<Uuid, Weak<Candidate>>>,
+    ttl: Duration,
+}
+
+impl StremioAddon {
+    pub fn new(resolver: Arc<dyn NzbResolver>) -> Self {
+        Self {
+            resolver,
+            titles: parking_lot::Mutex::default(),
+            candidates: parking_lot::Mutex::default(),
+            ttl: TITLE_TTL,
+        }
+    }
+
+    /// The NZBs for `id`, resolved the first time it's asked for. No
+    /// downloads start until one of them is opened.
+    pub async fn candidates(&self, id: &MediaId) -> Result<Vec<Arc<Candidate>>, StremioError> {
+        let title = {
+            let mut titles = self.titles.lock();
+            let now = Instant::now();
+            titles.retain(|_, title| now.duration_since(title.asked_at) < self.ttl);
+            self.candidates
+                .lock()
+                .retain(|_, candidate| candidate.strong_count() > 0);
+
+            let title = titles.entry(id.clone()).or_insert_with(|| Title {
+                candidates: Candidates::default(),
+                asked_at: now,
+            });
+            title.asked_at = now;
+            Arc::clone(&title.candidates)
+        };
+        let mut candidates = title.lock().await;
+
+        // nothing found yet, so look again
+        if candidates.is_empty() {
+            let nzbs = self.resolver.resolve(id).await?;
+            info!("Resolved {} NZBs for {}", nzbs.len(), id.imdb_id);
+
+            *candidates = nzbs
+                .into_iter()
+                .map(|nzb| Arc::new(Candidate::new(nzb)))
+                .collect();
+            self.candidates.lock().extend(
+                candidates
+                    .iter()
+                    .map(|candidate| (candidate.id, Arc::downgrade(candidate))),
+            );
+        }
+
+        Ok(candidates.clone())
+    }
+
+    pub fn candidate(&self, candidate_id: Uuid) -> Result<Arc<Candidate>, StremioError> {
+        self.candidates
+            .lock()
+            .get(&candidate_id)
+            .and_then(Weak::upgrade)
+            .ok_or(StremioError::UnknownStream(candidate_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+
+    /// Resolves every ID to the same NZBs and counts the lookups
+    struct StubResolver {
+        titles: Vec<&'static str>,
+        calls: AtomicUsize,
+    }
+
+    impl NzbResolver for StubResolver {
+        fn resolve<'a>(
+            &'a self,
+            _id: &'a MediaId,
+        ) -> BoxFuture<'a, Result<Vec<ResolvedNzb>, StremioError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let nzbs = self
+                .titles
+                .iter()
+                .map(|title| ResolvedNzb {
+                    title: title.to_string(),
+                    content: String::new(),
+                })
+                .collect();
+
+            Box::pin(async move { Ok(nzbs) })
+        }
+    }
+
+    #[test]
+    fn test_parse_id() {
+        let id = MediaId::parse("series", "tt0944947:1:2").unwrap();
+        assert_eq!(id.imdb_id, "tt0944947");
+        assert_eq!(id.episode, Some((1, 2)));
+
+        assert_eq!(MediaId::parse("movie", "tt0111161").unwrap().episode, None);
+        assert!(MediaId::parse("movie", "kitsu:1").is_err());
+        assert!(MediaId::parse("series", "tt0944947:1").is_err());
+        assert!(MediaId::parse("channel", "tt0111161").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_candidates_resolved_once_and_opened_lazily() {
+        let resolver = Arc::new(StubResolver {
+            titles: vec!["Movie.1080p", "Movie.broken"],
+            calls: AtomicUsize::new(0),
+        });
+        let addon = StremioAddon::new(resolver.clone());
+        let id = MediaId::parse("movie", "tt0111161").unwrap();
+
+        let first = addon.candidates(&id).await.unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(
+            first
+                .iter()
+                .all(|candidate| candidate.session_id().is_none())
+        );
+
+        let second = addon.candidates(&id).await.unwrap();
+        assert_eq!(first[0].id, second[0].id);
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 1);
+
+        let created = AtomicUsize::new(0);
+        let create = |nzb: &ResolvedNzb| {
+            created.fetch_add(1, Ordering::SeqCst);
+            let broken = nzb.title == "Movie.broken";
+            async move {
+                if broken {
+                    Err("no playable content")
+                } else {
+                    Ok(Uuid::new_v4())
+                }
+            }
+        };
+
+        let candidate = addon.candidate(first[0].id).unwrap();
+        let session_id = candidate.open(create).await.unwrap();
+        assert_eq!(candidate.open(create).await.unwrap(), session_id);
+        assert_eq!(candidate.session_id(), Some(session_id));
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        // a failed open is tried again
+        let broken = addon.candidate(first[1].id).unwrap();
+        assert!(broken.open(create).await.is_err());
+        assert!(broken.open(create).await.is_err());
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+
+        assert!(addon.candidate(Uuid::new_v4()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_titles_forgotten() {
+        let resolver = Arc::new(StubResolver {
+            titles: vec!["Movie.1080p"],
+            calls: AtomicUsize::new(0),
+        });
+        let addon = StremioAddon {
+            ttl: Duration::ZERO,
+            ..StremioAddon::new(resolver.clone())
+        };
+        let id = MediaId::parse("movie", "tt0111161").unwrap();
+
+        let stale = addon.candidates(&id).await.unwrap()[0].id;
+        let fresh = addon.candidates(&id).await.unwrap()[0].id;
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 2);
+        assert!(addon.candidate(stale).is_err());
+        assert!(addon.candidate(fresh).is_ok());
+        assert_eq!(addon.candidates.lock().len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{io, path::PathBuf};
+
+use futures::future::BoxFuture;
+
+use crate::stremio::{MediaId, error::StremioError};
+
+/// An NZB found for a movie or episode
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedNzb {
+    pub title: String,
+    pub content: String,
+}
+
+/// Finds NZBs for an IMDb ID. The addon doesn't care where they come from,
+/// a local library or an indexer search.
+pub trait NzbResolver: Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        id: &'a MediaId,
+    ) -> BoxFuture<'a, Result<Vec<ResolvedNzb>, StremioError>>;
+}
+
+/// Resolves from a directory of NZBs, one subdirectory per IMDb ID:
+///
+/// ```text
+/// library/
+///   tt0111161/The.Shawshank.Redemption.1994.1080p.nzb
+///   tt0944947/Game.of.Thrones.S01E02.720p.nzb
+/// ```
+///
+/// Episodes are matched by the `S01E02` in the file name.
+#[derive(Debug, Clone)]
+pub struct DirectoryResolver {
+    root: PathBuf,
+}
+
+impl DirectoryResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    async fn read(&self, id: &MediaId) -> Result<Vec<ResolvedNzb>, StremioError> {
+        let mut entries = match tokio::fs::read_dir(self.root.join(&id.imdb_id)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let episode = id
+            .episode
+            .map(|(season, episode)| format!("s{season:02}e{episode:02}"));
+
+        let mut nzbs = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(title) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".nzb"))
+            else {
+                continue;
+            };
+
+            if let Some(episode) = &episode
+                && !title.to_lowercase().contains(episode)
+            {
+                continue;
+            }
+
+            nzbs.push(ResolvedNzb {
+                title: title.to_string(),
+                content: tokio::fs::read_to_string(&path).await?,
+            });
+        }
+
+        nzbs.sort_by(|a, b| a.title.cmp(&b.title));
+        Ok(nzbs)
+    }
+}
+
+impl NzbResolver for DirectoryResolver {
+    fn resolve<'a>(
+        &'a self,
+        id: &'a MediaId,
+    ) -> BoxFuture<'a, Result<Vec<ResolvedNzb>, StremioError>> {
+        Box::pin(self.read(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stremio::MediaKind;
+
+    #[tokio::test]
+    async fn test_directory_resolver() {
+        let root = tempfile::tempdir().unwrap();
+        let show = root.path().join("tt0944947");
+        std::fs::create_dir(&show).unwrap();
+        std::fs::write(show.join("Show.S01E02.720p.nzb"), "<nzb/>").unwrap();
+        std::fs::write(show.join("Show.S01E03.720p.nzb"), "<nzb/>").unwrap();
+        std::fs::write(show.join("notes.txt"), "").unwrap();
+
+        let resolver = DirectoryResolver::new(root.path());
+        let id = MediaId::parse("series", "tt0944947:1:2").unwrap();
+        let nzbs = resolver.resolve(&id).await.unwrap();
+        assert_eq!(
+            nzbs,
+            vec![ResolvedNzb {
+                title: "Show.S01E02.720p".to_string(),
+                content: "<nzb/>".to_string(),
+            }]
+        );
+
+        let missing = MediaId {
+            kind: MediaKind::Movie,
+            imdb_id: "tt0000001".to_string(),
+            episode: None,
+        };
+        assert!(resolver.resolve(&missing).await.unwrap().is_empty());
+    }
+}